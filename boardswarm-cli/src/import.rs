@@ -0,0 +1,521 @@
+//! Conversion of device definitions from other lab tooling into boardswarm server configuration
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+const PDUDAEMON_DEFAULT_PORT: u16 = 16421;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ImportFormat {
+    /// labgrid environment yaml
+    Labgrid,
+    /// LAVA device dictionary
+    Lava,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Config {
+    providers: Vec<Provider>,
+    devices: Vec<Device>,
+}
+
+#[derive(Debug, Serialize)]
+struct Provider {
+    name: String,
+    provider: String,
+    parameters: serde_yaml::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Device {
+    name: String,
+    consoles: Vec<Console>,
+    modes: Vec<Mode>,
+}
+
+#[derive(Debug, Serialize)]
+struct Console {
+    name: String,
+    parameters: ConsoleParameters,
+    #[serde(rename = "match")]
+    match_: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConsoleParameters {
+    rate: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Mode {
+    name: String,
+    sequence: Vec<ActuatorStep>,
+}
+
+#[derive(Debug, Serialize)]
+struct ActuatorStep {
+    #[serde(rename = "match")]
+    match_: BTreeMap<String, String>,
+    parameters: ActuatorParameters,
+}
+
+#[derive(Debug, Serialize)]
+struct ActuatorParameters {
+    mode: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Pdu {
+    name: String,
+    ports: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PduDaemonParameters {
+    uri: String,
+    pdus: Vec<Pdu>,
+}
+
+/// A port on a pdu controlled by pdudaemon
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PduPort {
+    daemon: String,
+    pdu: String,
+    port: String,
+}
+
+/// Collects the converted configuration alongside notes about anything that couldn't be converted
+#[derive(Debug, Default)]
+struct Importer {
+    config: Config,
+    /// pdudaemon uri to provider name
+    daemons: HashMap<String, String>,
+    notes: Vec<String>,
+}
+
+impl Importer {
+    fn note<S: Into<String>>(&mut self, note: S) {
+        self.notes.push(note.into());
+    }
+
+    /// Name of the actuator for the given port, adding the pdudaemon provider setup as needed
+    fn pdu_actuator(&mut self, port: &PduPort) -> String {
+        let uri = if port.daemon.contains("://") {
+            port.daemon.clone()
+        } else if port.daemon.contains(':') {
+            format!("http://{}/", port.daemon)
+        } else {
+            format!("http://{}:{}/", port.daemon, PDUDAEMON_DEFAULT_PORT)
+        };
+
+        let provider = match self.daemons.get(&uri) {
+            Some(provider) => provider.clone(),
+            None => {
+                let provider = if self.daemons.is_empty() {
+                    "pdudaemon".to_string()
+                } else {
+                    format!("pdudaemon-{}", self.daemons.len())
+                };
+                self.daemons.insert(uri.clone(), provider.clone());
+                self.config.providers.push(Provider {
+                    name: provider.clone(),
+                    provider: "pdudaemon".to_string(),
+                    parameters: serde_yaml::to_value(PduDaemonParameters {
+                        uri,
+                        pdus: Vec::new(),
+                    })
+                    .unwrap(),
+                });
+                provider
+            }
+        };
+
+        // Register the port with the pdu in the provider parameters
+        let parameters = self
+            .config
+            .providers
+            .iter_mut()
+            .find(|p| p.name == provider)
+            .map(|p| &mut p.parameters)
+            .unwrap();
+        let pdus = parameters["pdus"].as_sequence_mut().unwrap();
+        let pdu = match pdus
+            .iter()
+            .position(|p| p["name"].as_str() == Some(port.pdu.as_str()))
+        {
+            Some(index) => &mut pdus[index],
+            None => {
+                pdus.push(
+                    serde_yaml::to_value(Pdu {
+                        name: port.pdu.clone(),
+                        ports: Vec::new(),
+                    })
+                    .unwrap(),
+                );
+                pdus.last_mut().unwrap()
+            }
+        };
+        let ports = pdu["ports"].as_sequence_mut().unwrap();
+        let value = serde_yaml::Value::String(port.port.clone());
+        if !ports.contains(&value) {
+            ports.push(value);
+        }
+
+        format!("{}.{}.port-{}", provider, port.pdu, port.port)
+    }
+
+    fn add_device(&mut self, name: String, consoles: Vec<Console>, power: Option<PduPort>) {
+        let modes = match power {
+            Some(port) => {
+                let actuator = self.pdu_actuator(&port);
+                ["on", "off"]
+                    .into_iter()
+                    .map(|mode| Mode {
+                        name: mode.to_string(),
+                        sequence: vec![ActuatorStep {
+                            match_: BTreeMap::from([(
+                                "boardswarm.name".to_string(),
+                                actuator.clone(),
+                            )]),
+                            parameters: ActuatorParameters {
+                                mode: mode.to_string(),
+                            },
+                        }],
+                    })
+                    .collect()
+            }
+            None => {
+                self.note(format!("No power control found for {name}"));
+                Vec::new()
+            }
+        };
+        if consoles.is_empty() {
+            self.note(format!("No console found for {name}"));
+        }
+        self.config.devices.push(Device {
+            name,
+            consoles,
+            modes,
+        });
+    }
+
+    fn to_yaml(&self) -> anyhow::Result<String> {
+        let mut out = String::new();
+        for note in &self.notes {
+            out.push_str(&format!("# {note}\n"));
+        }
+        out.push_str(&serde_yaml::to_string(&self.config)?);
+        Ok(out)
+    }
+}
+
+fn serial_console(name: &str, match_: BTreeMap<String, String>, rate: Option<u32>) -> Console {
+    Console {
+        name: name.to_string(),
+        parameters: ConsoleParameters {
+            rate: rate.unwrap_or(115_200),
+        },
+        match_,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LabgridEnvironment {
+    targets: BTreeMap<String, LabgridTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LabgridTarget {
+    #[serde(default)]
+    resources: LabgridResources,
+}
+
+/// Resources can either be given as a mapping or as a list of single entry mappings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LabgridResources {
+    Map(serde_yaml::Mapping),
+    List(Vec<serde_yaml::Mapping>),
+}
+
+impl Default for LabgridResources {
+    fn default() -> Self {
+        LabgridResources::List(Vec::new())
+    }
+}
+
+impl LabgridResources {
+    fn iter(&self) -> Box<dyn Iterator<Item = (&serde_yaml::Value, &serde_yaml::Value)> + '_> {
+        match self {
+            LabgridResources::Map(m) => Box::new(m.iter()),
+            LabgridResources::List(l) => Box::new(l.iter().flat_map(|m| m.iter())),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LabgridRawSerialPort {
+    port: String,
+    speed: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LabgridUsbSerialPort {
+    #[serde(rename = "match")]
+    match_: BTreeMap<String, String>,
+    speed: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LabgridPduDaemonPort {
+    host: String,
+    pdu: String,
+    index: serde_yaml::Value,
+}
+
+fn import_labgrid(importer: &mut Importer, input: &str) -> anyhow::Result<()> {
+    let env: LabgridEnvironment =
+        serde_yaml::from_str(input).context("Failed to parse labgrid environment")?;
+
+    for (target, config) in env.targets {
+        let mut consoles = Vec::new();
+        let mut power = None;
+        for (kind, resource) in config.resources.iter() {
+            let kind = kind.as_str().unwrap_or_default();
+            let console_name = if consoles.is_empty() {
+                "main".to_string()
+            } else {
+                format!("serial{}", consoles.len())
+            };
+            match kind {
+                "RawSerialPort" => {
+                    let port: LabgridRawSerialPort = serde_yaml::from_value(resource.clone())?;
+                    consoles.push(serial_console(
+                        &console_name,
+                        BTreeMap::from([("udev.DEVNAME".to_string(), port.port)]),
+                        port.speed,
+                    ));
+                }
+                "USBSerialPort" => {
+                    let port: LabgridUsbSerialPort = serde_yaml::from_value(resource.clone())?;
+                    // Parent attributes ('@' prefixed in labgrid) are part of the udev
+                    // properties in boardswarm
+                    let match_ = port
+                        .match_
+                        .into_iter()
+                        .map(|(k, v)| (format!("udev.{}", k.trim_start_matches('@')), v))
+                        .collect();
+                    consoles.push(serial_console(&console_name, match_, port.speed));
+                }
+                "PDUDaemonPort" => {
+                    let port: LabgridPduDaemonPort = serde_yaml::from_value(resource.clone())?;
+                    let index = match port.index {
+                        serde_yaml::Value::Number(n) => n.to_string(),
+                        serde_yaml::Value::String(s) => s,
+                        _ => anyhow::bail!("Invalid PDUDaemonPort index for {target}"),
+                    };
+                    power = Some(PduPort {
+                        daemon: port.host,
+                        pdu: port.pdu,
+                        port: index,
+                    });
+                }
+                _ => importer.note(format!("Skipped unsupported resource {kind} of {target}")),
+            }
+        }
+        importer.add_device(target, consoles, power);
+    }
+    Ok(())
+}
+
+/// Parse the `{% set key = value %}` statements of a LAVA device dictionary
+fn lava_variables(input: &str) -> BTreeMap<String, String> {
+    input
+        .lines()
+        .filter_map(|line| {
+            let statement = line
+                .trim()
+                .strip_prefix("{%")?
+                .strip_suffix("%}")?
+                .trim()
+                .strip_prefix("set ")?;
+            let (key, value) = statement.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('\'')
+                .and_then(|v| v.strip_suffix('\''))
+                .or_else(|| value.strip_prefix('"').and_then(|v| v.strip_suffix('"')))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Extract the pdu port from a `pduclient` invocation
+fn lava_pduclient(command: &str) -> Option<PduPort> {
+    let mut args = command
+        .split_whitespace()
+        .map(|a| a.trim_matches(['\'', '"', ',', '[', ']']))
+        .skip_while(|a| !a.ends_with("pduclient"))
+        .skip(1);
+    let (mut daemon, mut pdu, mut port) = (None, None, None);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--daemon" => daemon = args.next(),
+            "--hostname" => pdu = args.next(),
+            "--port" => port = args.next(),
+            _ => (),
+        }
+    }
+    Some(PduPort {
+        daemon: daemon?.to_string(),
+        pdu: pdu?.to_string(),
+        port: port?.to_string(),
+    })
+}
+
+fn import_lava(importer: &mut Importer, name: String, input: &str) -> anyhow::Result<()> {
+    let variables = lava_variables(input);
+
+    let mut consoles = Vec::new();
+    if let Some(connection) = variables.get("connection_command") {
+        match connection
+            .split_whitespace()
+            .find(|a| a.starts_with("/dev/"))
+        {
+            Some(dev) => consoles.push(serial_console(
+                "main",
+                BTreeMap::from([("udev.DEVNAME".to_string(), dev.to_string())]),
+                None,
+            )),
+            None => importer.note(format!("Skipped unsupported connection: {connection}")),
+        }
+    }
+
+    let power = [
+        "power_on_command",
+        "power_off_command",
+        "hard_reset_command",
+    ]
+    .iter()
+    .find_map(|v| lava_pduclient(variables.get(*v)?));
+    if power.is_none() {
+        if let Some(command) = variables.get("power_on_command") {
+            importer.note(format!("Skipped unsupported power command: {command}"));
+        }
+    }
+
+    importer.add_device(name, consoles, power);
+    Ok(())
+}
+
+/// Convert `path` to boardswarm server configuration yaml
+pub fn import(format: ImportFormat, path: &Path, name: Option<String>) -> anyhow::Result<String> {
+    let input = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut importer = Importer::default();
+    match format {
+        ImportFormat::Labgrid => {
+            import_labgrid(&mut importer, &input)?;
+            if let Some(name) = name {
+                match importer.config.devices.as_mut_slice() {
+                    [device] => device.name = name,
+                    _ => anyhow::bail!("A name can only be given for a single labgrid target"),
+                }
+            }
+        }
+        ImportFormat::Lava => {
+            let name = name
+                .or_else(|| path.file_stem().map(|s| s.to_string_lossy().into_owned()))
+                .context("No device name")?;
+            import_lava(&mut importer, name, &input)?;
+        }
+    }
+    importer.to_yaml()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labgrid() {
+        let env = r#"
+targets:
+  board:
+    resources:
+      USBSerialPort:
+        match:
+          "@ID_SERIAL_SHORT": "A12345"
+        speed: 1500000
+      PDUDaemonPort:
+        host: pdu.example.net
+        pdu: pdu-0
+        index: 3
+      NetworkService:
+        address: 192.168.1.2
+"#;
+        let mut importer = Importer::default();
+        import_labgrid(&mut importer, env).unwrap();
+
+        let device = &importer.config.devices[0];
+        assert_eq!(device.name, "board");
+        assert_eq!(device.consoles[0].parameters.rate, 1_500_000);
+        assert_eq!(
+            device.consoles[0].match_["udev.ID_SERIAL_SHORT"],
+            "A12345".to_string()
+        );
+        assert_eq!(device.modes.len(), 2);
+        assert_eq!(
+            device.modes[0].sequence[0].match_["boardswarm.name"],
+            "pdudaemon.pdu-0.port-3"
+        );
+
+        let provider = &importer.config.providers[0];
+        assert_eq!(
+            provider.parameters["uri"].as_str(),
+            Some("http://pdu.example.net:16421/")
+        );
+        assert_eq!(importer.notes.len(), 1);
+    }
+
+    #[test]
+    fn lava() {
+        let dict = r#"
+{% extends 'beaglebone-black.jinja2' %}
+{% set connection_command = 'picocom -b 115200 /dev/ttyUSB0' %}
+{% set power_off_command = 'pduclient --daemon localhost --hostname pdu-1 --command off --port 08' %}
+{% set power_on_command = "pduclient --daemon localhost --hostname pdu-1 --command on --port 08" %}
+"#;
+        let variables = lava_variables(dict);
+        assert_eq!(
+            variables["connection_command"],
+            "picocom -b 115200 /dev/ttyUSB0"
+        );
+
+        assert_eq!(
+            lava_pduclient(&variables["power_on_command"]),
+            Some(PduPort {
+                daemon: "localhost".to_string(),
+                pdu: "pdu-1".to_string(),
+                port: "08".to_string(),
+            })
+        );
+        assert_eq!(lava_pduclient("/usr/bin/relay-ctrl 1 on"), None);
+
+        let mut importer = Importer::default();
+        import_lava(&mut importer, "bbb-01".to_string(), dict).unwrap();
+        let device = &importer.config.devices[0];
+        assert_eq!(device.consoles[0].match_["udev.DEVNAME"], "/dev/ttyUSB0");
+        assert_eq!(
+            device.modes[1].sequence[0].match_["boardswarm.name"],
+            "pdudaemon.pdu-1.port-08"
+        );
+        assert!(importer.notes.is_empty());
+    }
+}
@@ -10,17 +10,22 @@ use anyhow::{anyhow, bail, Context};
 use async_compression::futures::bufread::GzipDecoder;
 use bmap_parser::Bmap;
 use boardswarm_client::{
-    client::{Boardswarm, BoardswarmBuilder, VolumeIoRW},
+    client::{Boardswarm, BoardswarmBuilder, UploadOptions, VolumeIoRW},
     config,
     device::{Device, DeviceVolume},
     oidc::{OidcClientBuilder, StdoutAuth},
 };
-use boardswarm_protocol::ItemType;
+use boardswarm_protocol::{
+    console_signal_request::Signal, device_task_progress, group_progress, session_queue_update,
+    AudioEncoding, ConsoleFilter, DeviceCheck, DeviceModeProgress, DeviceModeStepState,
+    DeviceTaskProgress, GroupProgress, GroupVolumeFetchRequest, ImageFormat, ItemType, Session,
+    UploadPhase, VolumeFetchProgress, VolumeTargetKind,
+};
 use bytes::{Bytes, BytesMut};
 use clap::{arg, builder::PossibleValue, Args, Parser, Subcommand, ValueEnum};
 use futures::{pin_mut, FutureExt, Stream, StreamExt, TryStreamExt};
 use http::Uri;
-use indicatif::ProgressBar;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use rockfile::boot::{
     RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
@@ -36,6 +41,8 @@ use tracing::{debug, info};
 use ui::TerminalSizeSetting;
 use utils::BatchWriter;
 
+mod import;
+mod topology;
 mod ui;
 mod ui_term;
 mod utils;
@@ -65,6 +72,7 @@ impl std::fmt::Display for ItemTypes {
                 ItemType::Console => f.write_str("console"),
                 ItemType::Actuator => f.write_str("actuator"),
                 ItemType::Volume => f.write_str("volume"),
+                ItemType::Audio => f.write_str("audio"),
             }
         }
     }
@@ -77,6 +85,7 @@ impl ValueEnum for ItemTypes {
             ItemTypes(ItemType::Console),
             ItemTypes(ItemType::Device),
             ItemTypes(ItemType::Volume),
+            ItemTypes(ItemType::Audio),
         ]
     }
 
@@ -86,6 +95,7 @@ impl ValueEnum for ItemTypes {
             ItemType::Console => PossibleValue::new("consoles"),
             ItemType::Device => PossibleValue::new("devices"),
             ItemType::Volume => PossibleValue::new("volumes"),
+            ItemType::Audio => PossibleValue::new("audio"),
         })
     }
 }
@@ -185,6 +195,230 @@ async fn write_bmap(io: VolumeIoRW, path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Interpret the image cache lookup of the server; Servers without the lookup don't cache images
+fn image_cached<E: std::fmt::Display>(lookup: Result<bool, E>) -> bool {
+    match lookup {
+        Ok(cached) => {
+            if cached {
+                println!("Image cached by the server, skipping the transfer");
+            }
+            cached
+        }
+        Err(e) => {
+            debug!("Image cache lookup failed: {}", e);
+            false
+        }
+    }
+}
+
+async fn show_fetch_progress<P, E>(mut progress: P) -> anyhow::Result<()>
+where
+    P: Stream<Item = Result<VolumeFetchProgress, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    println!("Server writing image");
+    let bar = ProgressBar::no_length().with_style(ProgressStyle::with_template(
+        "{wide_bar} {bytes}/{total_bytes} {msg}",
+    )?);
+    let mut written = 0;
+    while let Some(status) = progress.try_next().await? {
+        if let Some(total) = status.total {
+            bar.set_length(total);
+        }
+        bar.set_position(status.downloaded);
+        let phase = match status.phase() {
+            UploadPhase::Receiving => "receiving",
+            UploadPhase::Writing => "writing",
+            UploadPhase::Verifying => "verifying",
+            UploadPhase::Done => "done",
+        };
+        let mut message = format!("{} {}/s", phase, HumanBytes(status.rate));
+        if let Some(eta) = status.eta {
+            message.push_str(&format!(" eta {}", HumanDuration(Duration::from_secs(eta))));
+        }
+        if let Some(step) = &status.step {
+            message.push_str(&format!(": {step}"));
+        }
+        bar.set_message(message);
+        written = status.written;
+    }
+    bar.finish();
+    println!("Wrote {} bytes", written);
+    Ok(())
+}
+
+fn print_checks(checks: Vec<DeviceCheck>) {
+    for check in checks {
+        let status = if check.passed { "PASS" } else { "FAIL" };
+        match check.message {
+            Some(message) => println!("{status} {}: {message}", check.name),
+            None => println!("{status} {}", check.name),
+        }
+    }
+}
+
+/// Open a session for the devices, waiting in the queue for busy devices if requested
+async fn open_session(
+    boardswarm: &mut Boardswarm,
+    devices: Vec<u64>,
+    ttl: Duration,
+    queue: SessionQueueArgs,
+    record: bool,
+) -> anyhow::Result<Session> {
+    if !queue.wait {
+        return Ok(boardswarm.session_open(devices, ttl, record).await?);
+    }
+    let mut updates = boardswarm
+        .session_queue(devices, ttl, queue.priority, record)
+        .await?;
+    while let Some(update) = updates.message().await? {
+        match update.update {
+            Some(session_queue_update::Update::Position(position)) => {
+                eprintln!("Waiting for devices; {} caller(s) ahead", position)
+            }
+            Some(session_queue_update::Update::Session(session)) => return Ok(session),
+            None => (),
+        }
+    }
+    bail!("Queue ended without opening a session")
+}
+
+async fn show_mode_progress<P, E>(mut progress: P) -> anyhow::Result<()>
+where
+    P: Stream<Item = Result<DeviceModeProgress, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let result = async {
+        while let Some(step) = progress.try_next().await? {
+            let elapsed = step.elapsed as f64 / 1000.0;
+            match step.state() {
+                DeviceModeStepState::Started => {
+                    spinner.set_message(format!("{}: {}", step.mode, step.description))
+                }
+                DeviceModeStepState::Completed => spinner.println(format!(
+                    "[{elapsed:7.1}s] {}: {}",
+                    step.mode, step.description
+                )),
+                DeviceModeStepState::Failed => spinner.println(format!(
+                    "[{elapsed:7.1}s] {}: {} failed: {}",
+                    step.mode,
+                    step.description,
+                    step.error.as_deref().unwrap_or("unknown error")
+                )),
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    spinner.finish_and_clear();
+    result
+}
+
+/// Show the steps of a task as they're run, along with the progress within the current step
+async fn show_task_progress<P, E>(mut progress: P) -> anyhow::Result<()>
+where
+    P: Stream<Item = Result<DeviceTaskProgress, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let result = async {
+        while let Some(step) = progress.try_next().await? {
+            let elapsed = step.elapsed as f64 / 1000.0;
+            match step.state() {
+                DeviceModeStepState::Started => {
+                    let detail = match &step.detail {
+                        Some(device_task_progress::Detail::Mode(mode)) => {
+                            format!(" ({}: {})", mode.mode, mode.description)
+                        }
+                        Some(device_task_progress::Detail::Fetch(fetch)) => {
+                            format!(" ({})", HumanBytes(fetch.written))
+                        }
+                        None => String::new(),
+                    };
+                    spinner.set_message(format!("{}{}", step.description, detail))
+                }
+                DeviceModeStepState::Completed => {
+                    spinner.println(format!("[{elapsed:7.1}s] {}", step.description))
+                }
+                DeviceModeStepState::Failed => spinner.println(format!(
+                    "[{elapsed:7.1}s] {} failed: {}",
+                    step.description,
+                    step.error.as_deref().unwrap_or("unknown error")
+                )),
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    spinner.finish_and_clear();
+    result
+}
+
+/// Show how many devices of a group finished, printing the result of each device; Fails if the
+/// operation failed on any device
+async fn show_group_progress<P, E>(
+    boardswarm: &mut Boardswarm,
+    mut progress: P,
+) -> anyhow::Result<()>
+where
+    P: Stream<Item = Result<GroupProgress, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let names: std::collections::HashMap<u64, String> = boardswarm
+        .list(ItemType::Device)
+        .await?
+        .into_iter()
+        .map(|i| (i.id, i.name))
+        .collect();
+    let bar = ProgressBar::no_length().with_style(ProgressStyle::with_template(
+        "{wide_bar} {pos}/{len} {msg}",
+    )?);
+    let mut failed = 0;
+    let result = async {
+        while let Some(update) = progress.try_next().await? {
+            let name = names
+                .get(&update.device)
+                .cloned()
+                .unwrap_or_else(|| update.device.to_string());
+            bar.set_length(update.total.into());
+            bar.set_position(update.finished.into());
+            failed = update.failed;
+            if failed > 0 {
+                bar.set_message(format!("{failed} failed"));
+            }
+            match update.progress {
+                Some(group_progress::Progress::Mode(step)) => {
+                    if step.state() == DeviceModeStepState::Failed {
+                        bar.println(format!(
+                            "{name}: {}: {} failed: {}",
+                            step.mode,
+                            step.description,
+                            step.error.as_deref().unwrap_or("unknown error")
+                        ));
+                    }
+                }
+                Some(group_progress::Progress::Fetch(_)) => (),
+                Some(group_progress::Progress::Done(done)) => match done.error {
+                    Some(error) => bar.println(format!("{name}: failed: {error}")),
+                    None => bar.println(format!("{name}: done")),
+                },
+                None => (),
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    bar.finish();
+    result?;
+    if failed > 0 {
+        bail!("Failed on {} device(s)", failed);
+    }
+    Ok(())
+}
+
 async fn copy_output_to_stdout<O>(output: O) -> anyhow::Result<()>
 where
     O: Stream<Item = Bytes>,
@@ -328,7 +562,11 @@ async fn item_lookup<I: Into<ItemTypes>>(
                 .rsplit_once('@')
                 .map_or((name.as_str(), None), |(n, i)| (n, Some(i)));
 
-            items.retain(|i| i.name == name && i.instance.as_deref() == instance);
+            // Items can be referred to by their stable identity as well
+            items.retain(|i| {
+                (i.name == name || i.identity.as_deref() == Some(name))
+                    && i.instance.as_deref() == instance
+            });
 
             match items.len() {
                 0 => bail!("{item_type:#} not found"),
@@ -362,6 +600,14 @@ enum ActuatorCommand {
     Properties,
 }
 
+fn parse_item(item: &str) -> Result<ItemArg, Infallible> {
+    if let Ok(id) = item.parse() {
+        Ok(ItemArg::Id(id))
+    } else {
+        Ok(ItemArg::Name(item.to_string()))
+    }
+}
+
 fn parse_console(device: &str) -> Result<ItemArg, Infallible> {
     if let Ok(id) = device.parse() {
         Ok(ItemArg::Id(id))
@@ -381,24 +627,174 @@ enum ConsoleCommand {
     /// Configure a console
     Configure(ConsoleConfigure),
     /// Tail the output of a device console
-    Tail,
+    Tail {
+        /// Start with the recent output buffered by the server
+        #[arg(long)]
+        history: bool,
+        /// Prefix each line with the time the server read it from the console
+        #[arg(long)]
+        timestamps: bool,
+        /// Filter to apply to the output instead of those configured for the console; Can be
+        /// given multiple times
+        #[arg(long, conflicts_with = "raw")]
+        filter: Vec<OutputFilter>,
+        /// Don't apply the filters configured for the console
+        #[arg(long)]
+        raw: bool,
+    },
     /// Connect input and output to a device console
-    Connect,
+    Connect {
+        /// Hold the input lock of the console while connected, rejecting input from others
+        #[arg(long)]
+        lock: bool,
+        /// Start with the recent output buffered by the server
+        #[arg(long)]
+        history: bool,
+    },
+    /// Wait for a regular expression to show up in the console output
+    Expect {
+        pattern: String,
+        /// Seconds to wait for the pattern
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Also match the recent output buffered by the server
+        #[arg(long)]
+        history: bool,
+        /// Print the output leading up to the match rather than just the match
+        #[arg(long)]
+        output: bool,
+    },
+    /// Send a break condition to a serial console
+    Break {
+        /// Duration of the break in milliseconds
+        #[arg(long, default_value_t = 250)]
+        duration: u32,
+    },
+    /// Assert or deassert the DTR line of a serial console
+    Dtr { state: LineState },
+    /// Assert or deassert the RTS line of a serial console
+    Rts { state: LineState },
     /// Display console properties
     Properties,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFilter {
+    StripAnsi,
+    NormalizeNewlines,
+    SanitizeUtf8,
+}
+
+impl From<OutputFilter> for ConsoleFilter {
+    fn from(filter: OutputFilter) -> Self {
+        match filter {
+            OutputFilter::StripAnsi => ConsoleFilter::StripAnsi,
+            OutputFilter::NormalizeNewlines => ConsoleFilter::NormalizeNewlines,
+            OutputFilter::SanitizeUtf8 => ConsoleFilter::SanitizeUtf8,
+        }
+    }
+}
+
+/// Device items that can come and go, e.g. as the device changes modes
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DeviceItemType {
+    Console,
+    Volume,
+}
+
+impl From<DeviceItemType> for ItemType {
+    fn from(type_: DeviceItemType) -> Self {
+        match type_ {
+            DeviceItemType::Console => ItemType::Console,
+            DeviceItemType::Volume => ItemType::Volume,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LineState {
+    On,
+    Off,
+}
+
+impl LineState {
+    fn asserted(self) -> bool {
+        matches!(self, LineState::On)
+    }
+}
+
+fn parse_sha256(sha256: &str) -> anyhow::Result<[u8; 32]> {
+    if sha256.len() != 64 || !sha256.is_ascii() {
+        bail!("Expected 64 hexadecimal characters");
+    }
+    let mut digest = [0; 32];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&sha256[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(digest)
+}
+
+#[derive(Debug, Args)]
+struct ReadArgs {
+    /// Offset in bytes for reading to start
+    #[clap(short, long)]
+    offset: Option<u64>,
+    /// Amount of bytes to be read
+    #[clap(short, long)]
+    length: Option<u64>,
+    /// Target to read from
+    target: String,
+    /// Path to the file to write the read data to
+    file: PathBuf,
+}
+
 #[derive(Debug, Args)]
 struct WriteArgs {
     /// Offset in bytes to write to
     #[clap(short, long)]
     offset: Option<u64>,
+    /// Expected SHA-256 of the file; The server verifies the uploaded data against it
+    #[clap(long, value_parser = parse_sha256)]
+    sha256: Option<[u8; 32]>,
     /// Target to write to
     target: String,
     /// File to write
     file: PathBuf,
 }
 
+#[derive(Debug, Args)]
+struct FetchLayoutArgs {
+    /// The image is an android sparse image; Only its blocks holding data are written
+    #[arg(long, conflicts_with = "bmap")]
+    sparse: bool,
+    /// Url of a bmap file for the image; Only the mapped blocks are written
+    #[arg(long)]
+    bmap: Option<String>,
+}
+
+impl FetchLayoutArgs {
+    fn format(&self) -> ImageFormat {
+        if self.sparse {
+            ImageFormat::AndroidSparse
+        } else {
+            ImageFormat::Raw
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct FetchArgs {
+    /// Expected SHA-256 of the image; The server verifies the downloaded data against it
+    #[clap(long, value_parser = parse_sha256)]
+    sha256: Option<[u8; 32]>,
+    #[clap(flatten)]
+    layout: FetchLayoutArgs,
+    /// Target to write to
+    target: String,
+    /// Url of the image for the server to download
+    url: String,
+}
+
 #[derive(Debug, Args)]
 struct AimgWriteArgs {
     /// Target to write the bmap file to
@@ -427,20 +823,47 @@ fn parse_volume(device: &str) -> Result<ItemArg, Infallible> {
 enum VolumeCommand {
     /// Retrieve volume information
     Info,
+    /// Read data from the volume
+    Read(ReadArgs),
     /// Upload file to the volume
     Write(WriteArgs),
     /// Write a bmap file to the volume
     WriteBmap(BmapWriteArgs),
     /// Write a android sparse image to the volume
     WriteAimg(AimgWriteArgs),
+    /// Have the server download an image and write it to the volume
+    Fetch(FetchArgs),
     /// Commit upload
     Commit,
+    /// Cancel the uploads in progress
+    Cancel,
     /// Commit upload
     Erase { target: String },
     /// Display volume properties
     Properties,
 }
 
+fn parse_audio(audio: &str) -> Result<ItemArg, Infallible> {
+    if let Ok(id) = audio.parse() {
+        Ok(ItemArg::Id(id))
+    } else {
+        Ok(ItemArg::Name(audio.to_string()))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum AudioCommand {
+    /// Write captured audio to stdout
+    Capture {
+        /// Encode the audio as an ogg opus stream rather than raw signed 16 bit little endian
+        /// samples
+        #[arg(long)]
+        opus: bool,
+    },
+    /// Display audio properties
+    Properties,
+}
+
 #[derive(Clone, Debug)]
 enum DeviceArg {
     Id(u64),
@@ -477,10 +900,39 @@ struct DeviceConsoleArgs {
 
 #[derive(Debug, Args)]
 struct DeviceModeArgs {
+    /// Fail if another mode change is in progress rather than waiting for it
+    #[arg(long)]
+    no_wait: bool,
     /// Mode to change the device to
     mode: String,
 }
 
+#[derive(Debug, Args)]
+struct GroupFetchArgs {
+    /// Expected SHA-256 of the image; The server verifies the downloaded data against it
+    #[arg(long, value_parser = parse_sha256)]
+    sha256: Option<[u8; 32]>,
+    #[clap(flatten)]
+    layout: FetchLayoutArgs,
+    /// Commit the volume of each device after finishing the write
+    #[arg(short, long)]
+    commit: bool,
+    /// Name of the device volume to write to
+    volume: String,
+    /// Target to write to
+    target: String,
+    /// Url of the image for the server to download
+    url: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum GroupCommand {
+    /// Change the mode of all devices in the group
+    Mode(DeviceModeArgs),
+    /// Have the server download an image and write it to the volume of all devices in the group
+    Fetch(GroupFetchArgs),
+}
+
 #[derive(Debug, Args)]
 struct DeviceCommonVolumeArgs {
     /// Wait for the volume to appear
@@ -520,14 +972,6 @@ impl DeviceCommonVolumeTargetArgs {
         self.do_open(device, None).await
     }
 
-    async fn open_with_len(
-        &self,
-        device: &Device,
-        len: u64,
-    ) -> anyhow::Result<(DeviceVolume, VolumeIoRW)> {
-        self.do_open(device, Some(len)).await
-    }
-
     async fn do_open(
         &self,
         device: &Device,
@@ -566,6 +1010,9 @@ struct DeviceWriteArg {
     /// Write at the given offset rather then from the start
     #[arg(short, long)]
     offset: Option<u64>,
+    /// Expected SHA-256 of the file; The server verifies the uploaded data against it
+    #[arg(long, value_parser = parse_sha256)]
+    sha256: Option<[u8; 32]>,
     /// Commit the volume after finishing the write
     #[arg(short, long)]
     commit: bool,
@@ -575,6 +1022,47 @@ struct DeviceWriteArg {
     file: PathBuf,
 }
 
+#[derive(Debug, Args)]
+struct DeviceFetchArg {
+    /// Expected SHA-256 of the image; The server verifies the downloaded data against it
+    #[arg(long, value_parser = parse_sha256)]
+    sha256: Option<[u8; 32]>,
+    #[clap(flatten)]
+    layout: FetchLayoutArgs,
+    /// Commit the volume after finishing the write
+    #[arg(short, long)]
+    commit: bool,
+    #[clap(flatten)]
+    target: DeviceCommonVolumeTargetArgs,
+    /// Url of the image for the server to download
+    url: String,
+}
+
+/// Parse a `key=value` property
+fn parse_property(property: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = property
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <key>=<value>"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse a `target=file` upload
+fn parse_upload(upload: &str) -> anyhow::Result<(String, PathBuf)> {
+    let (target, file) = upload
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <target>=<file>"))?;
+    Ok((target.to_string(), file.into()))
+}
+
+#[derive(Debug, Args)]
+struct DeviceWriteManyArg {
+    #[clap(flatten)]
+    volume: DeviceCommonVolumeArgs,
+    /// Files to write to the volume targets, as `<target>=<file>`
+    #[arg(value_parser = parse_upload, required = true)]
+    uploads: Vec<(String, PathBuf)>,
+}
+
 #[derive(Debug, Args)]
 struct DeviceAimgWriteArg {
     /// Commit the volume after finishing the write
@@ -609,14 +1097,21 @@ enum DeviceCommand {
     Read(DeviceReadArg),
     /// Write data to a device volume
     Write(DeviceWriteArg),
+    /// Write files to several targets of a device volume, committing the volume only if all writes
+    /// succeeded
+    WriteMany(DeviceWriteManyArg),
     /// Write a android sparse image file to a device volume
     WriteAimg(DeviceAimgWriteArg),
     /// Write a bmap file to a device volume
     WriteBmap(DeviceBmapWriteArg),
+    /// Have the server download an image and write it to a device volume
+    Fetch(DeviceFetchArg),
     /// Erase a target from a volume
     Erase(DeviceEraseArg),
     /// Commit a volume
     Commit(DeviceCommonVolumeArgs),
+    /// Cancel the uploads to a volume in progress
+    Cancel(DeviceCommonVolumeArgs),
     /// Change device mode
     Mode(DeviceModeArgs),
     /// Turn the device off and on again
@@ -625,6 +1120,47 @@ enum DeviceCommand {
     Connect(DeviceConsoleArgs),
     /// Tail to the console
     Tail(DeviceConsoleArgs),
+    /// Register a console on the device fed from standard input
+    FeedConsole {
+        /// Name of the console
+        name: String,
+    },
+    /// Run the device self-test; This switches the device between modes
+    SelfTest,
+    /// Show the session reserving the device, if any
+    Reservation,
+    /// Run the device health probes; This switches the device between modes
+    Health,
+    /// Wait for a console or volume of the device to become available; Prints its id
+    Wait {
+        #[arg(value_enum)]
+        type_: DeviceItemType,
+        /// Name of the console or volume on the device
+        name: String,
+        /// Seconds to wait
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+    /// Run a task defined on the server on the device
+    Task {
+        /// Name of the task
+        task: String,
+    },
+    /// Show the power drawn by the device
+    Power {
+        /// Keep showing new readings
+        #[arg(short, long)]
+        follow: bool,
+        /// Fail if the device draws more than this many watts
+        #[arg(long)]
+        max: Option<f64>,
+    },
+    /// Record all interactions with the device as json lines until interrupted
+    Record {
+        /// File to write the recording to rather than standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
     /// Display device properties
     Properties,
 }
@@ -641,6 +1177,67 @@ enum RockCommand {
     },
 }
 
+#[derive(Debug, Args)]
+struct SessionQueueArgs {
+    /// Wait in the queue for busy devices rather than failing
+    #[arg(long)]
+    wait: bool,
+    /// Priority in the queue of devices ordering callers by priority; Higher goes first
+    #[arg(long, default_value_t = 0, requires = "wait")]
+    priority: i32,
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionCommand {
+    /// Reserve devices; Prints the id of the new session
+    Open {
+        #[arg(value_parser = parse_item, required = true)]
+        /// The devices to reserve
+        devices: Vec<ItemArg>,
+        /// Seconds until the session expires
+        #[clap(long, default_value_t = 3600)]
+        ttl: u64,
+        /// Record all interactions with the devices for as long as the session lasts
+        #[arg(long)]
+        record: bool,
+        #[command(flatten)]
+        queue: SessionQueueArgs,
+    },
+    /// Reserve devices until interrupted, renewing the session in the meantime; Prints the id of
+    /// the new session
+    Hold {
+        #[arg(value_parser = parse_item, required = true)]
+        /// The devices to reserve
+        devices: Vec<ItemArg>,
+        /// Seconds until the session expires if no longer renewed
+        #[clap(long, default_value_t = 60)]
+        ttl: u64,
+        /// Record all interactions with the devices for as long as the session lasts
+        #[arg(long)]
+        record: bool,
+        #[command(flatten)]
+        queue: SessionQueueArgs,
+    },
+    /// Restart the ttl of a session
+    Renew {
+        session: u64,
+        /// Seconds until the session expires
+        #[clap(long, default_value_t = 3600)]
+        ttl: u64,
+    },
+    /// Close a session, releasing its devices
+    Close { session: u64 },
+    /// List open sessions
+    List,
+    /// Download the recording of a session opened with --record as json lines
+    Recording {
+        session: u64,
+        /// File to write the recording to rather than standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Configure client authentication to boardswarm servers
@@ -674,6 +1271,14 @@ enum Command {
         #[command(subcommand)]
         command: VolumeCommand,
     },
+    /// Audio capture specific commands
+    Audio {
+        /// The audio capture to use
+        #[arg(value_parser = parse_audio)]
+        audio: ItemArg,
+        #[command(subcommand)]
+        command: AudioCommand,
+    },
     /// Device specific commands
     Device {
         #[arg(value_parser = parse_device)]
@@ -697,6 +1302,9 @@ enum Command {
         type_: ItemTypes,
         #[clap(long, short)]
         verbose: bool,
+        /// Only list items with the property, as `<key>=<value>`; e.g. a device label
+        #[clap(long = "match", value_parser = parse_property)]
+        match_: Vec<(String, String)>,
     },
     /// Monitor registered items of a given type
     Monitor {
@@ -705,6 +1313,61 @@ enum Command {
         type_: ItemTypes,
         #[clap(long, short)]
         verbose: bool,
+        /// Only monitor items with the property, as `<key>=<value>`; e.g. a console serial number
+        #[clap(long = "match", value_parser = parse_property)]
+        match_: Vec<(String, String)>,
+    },
+    /// Remove an item from the server, e.g. a stale item whose hardware vanished uncleanly
+    Remove {
+        #[arg(value_enum)]
+        /// The type of item to remove
+        type_: ItemTypes,
+        #[arg(value_parser = parse_item)]
+        /// The item to remove
+        item: ItemArg,
+        /// Remove the item even if it's used by active streams
+        #[clap(long)]
+        force: bool,
+    },
+    /// List recently removed items of a given type, e.g. to find out when a console vanished
+    Removed {
+        #[arg(value_enum)]
+        /// The type of items to list
+        type_: ItemTypes,
+        #[clap(long, short)]
+        verbose: bool,
+    },
+    /// Export the relationship graph of all items, e.g. to generate lab wiring diagrams
+    Topology {
+        #[arg(long, value_enum, default_value = "json")]
+        format: topology::TopologyFormat,
+    },
+    /// List the device groups and their members
+    Groups,
+    /// Operate on all devices of a group at once
+    Group {
+        /// Name of the group, as tagged in the device configuration
+        group: String,
+        #[command(subcommand)]
+        command: GroupCommand,
+    },
+    /// List the tasks defined on the server
+    Tasks,
+    /// Reserve devices for exclusive use
+    Session {
+        #[command(subcommand)]
+        command: SessionCommand,
+    },
+    /// Generate boardswarm server configuration from labgrid or LAVA device definitions
+    Import {
+        #[arg(value_enum)]
+        /// The format of the definitions to import
+        format: import::ImportFormat,
+        /// The file to import
+        path: PathBuf,
+        /// Name for the imported device; Defaults to the labgrid target or the file name
+        #[clap(short, long)]
+        name: Option<String>,
     },
     /// Open the UI for a given device
     Ui {
@@ -891,10 +1554,63 @@ struct Opts {
     /// instance name
     #[clap(short, long)]
     instance: Option<String>,
+    /// Request compressed console output; Useful for remote servers behind slow links
+    #[clap(long)]
+    compress: bool,
+    /// Session to change the mode of reserved devices in
+    #[clap(long)]
+    session: Option<u64>,
     #[command(subcommand)]
     command: Command,
 }
 
+fn record_event_json(event: boardswarm_protocol::DeviceRecordEvent) -> serde_json::Value {
+    use boardswarm_protocol::{device_record_event::Event, DeviceRecoveryState};
+    let mut json = match event.event {
+        Some(Event::ConsoleOutput(c)) => serde_json::json!({
+            "type": "console-output",
+            "console": c.console,
+            "data": String::from_utf8_lossy(&c.data),
+        }),
+        Some(Event::ConsoleInput(c)) => serde_json::json!({
+            "type": "console-input",
+            "console": c.console,
+            "data": String::from_utf8_lossy(&c.data),
+        }),
+        Some(Event::Mode(mode)) => serde_json::json!({
+            "type": "mode",
+            "mode": mode,
+        }),
+        Some(Event::Volume(v)) => serde_json::json!({
+            "type": "volume",
+            "volume": v.volume,
+            "target": v.target,
+            "operation": v.operation,
+            "offset": v.offset,
+            "length": v.length,
+        }),
+        Some(Event::Recovery(r)) => {
+            let state = match r.state() {
+                DeviceRecoveryState::Started => "started",
+                DeviceRecoveryState::Completed => "completed",
+                DeviceRecoveryState::Failed => "failed",
+                DeviceRecoveryState::Exhausted => "exhausted",
+            };
+            serde_json::json!({
+                "type": "recovery",
+                "reason": r.reason,
+                "attempt": r.attempt,
+                "attempts": r.attempts,
+                "state": state,
+                "error": r.error,
+            })
+        }
+        None => serde_json::json!({}),
+    };
+    json["timestamp"] = event.timestamp.into();
+    json
+}
+
 async fn print_item(
     boardswarm: &mut Boardswarm,
     item_type: ItemType,
@@ -903,12 +1619,22 @@ async fn print_item(
 ) -> anyhow::Result<()> {
     print!("{} {}", item.id, item.name);
     if let Some(ref instance) = item.instance {
-        println!(" on {instance}");
-    } else {
-        println!();
+        print!(" on {instance}");
     }
+    if let Some(ref identity) = item.identity {
+        print!(" [{identity}]");
+    }
+    println!();
     if verbose {
-        let properties = boardswarm.properties(item_type, item.id).await?;
+        // Older servers don't include the properties in the item list
+        let properties: std::collections::HashMap<String, String> = if item.property.is_empty() {
+            boardswarm.properties(item_type, item.id).await?
+        } else {
+            item.property
+                .iter()
+                .map(|p| (p.key.clone(), p.value.clone()))
+                .collect()
+        };
         for key in properties.keys().sorted_unstable() {
             println!(r#""{}" => "{}""#, key, properties[key]);
         }
@@ -948,6 +1674,10 @@ async fn main() -> anyhow::Result<()> {
 
     // Pre-connection handling
     let mut boardswarm = match opt.command {
+        Command::Import { format, path, name } => {
+            print!("{}", import::import(format, &path, name)?);
+            return Ok(());
+        }
         Command::Auth { command } => {
             let config = config
                 .clone()
@@ -1004,6 +1734,7 @@ async fn main() -> anyhow::Result<()> {
             if let Some(server) = server {
                 let mut builder: BoardswarmBuilder = server.to_boardswarm_builder();
                 builder.login_provider(StdoutAuth());
+                builder.compress_console_output(opt.compress);
                 builder.connect().await?
             } else if config.is_none() {
                 return Err(anyhow!(
@@ -1017,24 +1748,43 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    boardswarm.use_session(opt.session);
+
     match opt.command {
-        Command::Auth { .. } => {
+        Command::Auth { .. } | Command::Import { .. } => {
             unreachable!()
         }
         Command::LoginInfo => {
             println!("Info: {:#?}", boardswarm.login_info().await?);
             Ok(())
         }
-        Command::List { type_, verbose } => {
-            let items = boardswarm.list(type_.into()).await?;
+        Command::List {
+            type_,
+            verbose,
+            match_,
+        } => {
+            let filter = match_.into_iter().collect();
+            let items = if verbose {
+                boardswarm
+                    .list_with_properties(type_.into(), filter)
+                    .await?
+            } else {
+                boardswarm.list_filtered(type_.into(), filter).await?
+            };
             println!("{type_:#}s: ");
             for i in items {
                 print_item(&mut boardswarm, type_.into(), &i, verbose).await?;
             }
             Ok(())
         }
-        Command::Monitor { type_, verbose } => {
-            let events = boardswarm.monitor(type_.into()).await?;
+        Command::Monitor {
+            type_,
+            verbose,
+            match_,
+        } => {
+            let events = boardswarm
+                .monitor_filtered(type_.into(), match_.into_iter().collect())
+                .await?;
             println!("{type_:#}s: ");
             pin_mut!(events);
             while let Some(event) = events.next().await {
@@ -1046,6 +1796,196 @@ async fn main() -> anyhow::Result<()> {
                         }
                     }
                     ItemEvent::Removed(removed) => println!("Removed: {}", removed),
+                    ItemEvent::Updated(items) => {
+                        for i in items {
+                            print!("Updated: ");
+                            print_item(&mut boardswarm, type_.into(), &i, verbose).await?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Remove { type_, item, force } => {
+            let item = item_lookup(item, type_, boardswarm.clone()).await?;
+            boardswarm.remove(type_.into(), item, force).await?;
+            Ok(())
+        }
+        Command::Tasks => {
+            for task in boardswarm.task_list().await? {
+                match task.description {
+                    Some(description) => println!("{}: {}", task.name, description),
+                    None => println!("{}", task.name),
+                }
+                for step in task.steps {
+                    println!("  - {}", step);
+                }
+            }
+            Ok(())
+        }
+        Command::Groups => {
+            let names: std::collections::HashMap<u64, String> = boardswarm
+                .list(ItemType::Device)
+                .await?
+                .into_iter()
+                .map(|i| (i.id, i.name))
+                .collect();
+            for group in boardswarm.group_list().await? {
+                let members = group
+                    .devices
+                    .iter()
+                    .map(|id| names.get(id).cloned().unwrap_or_else(|| id.to_string()))
+                    .join(", ");
+                println!("{}: {}", group.name, members);
+            }
+            Ok(())
+        }
+        Command::Group { group, command } => {
+            let progress = match command {
+                GroupCommand::Mode(m) => {
+                    boardswarm
+                        .group_change_mode(group, m.mode, m.no_wait)
+                        .await?
+                }
+                GroupCommand::Fetch(fetch) => {
+                    boardswarm
+                        .group_volume_fetch(GroupVolumeFetchRequest {
+                            group,
+                            volume: fetch.volume,
+                            target: fetch.target,
+                            url: fetch.url,
+                            sha256: fetch.sha256.map(|s| Bytes::copy_from_slice(&s)),
+                            format: fetch.layout.format().into(),
+                            bmap_url: fetch.layout.bmap,
+                            session: None,
+                            commit: fetch.commit,
+                        })
+                        .await?
+                }
+            };
+            show_group_progress(&mut boardswarm, progress).await
+        }
+        Command::Topology { format } => {
+            let topology = boardswarm.topology().await?;
+            match format {
+                topology::TopologyFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&topology::to_json(&topology))?
+                ),
+                topology::TopologyFormat::Dot => print!("{}", topology::to_dot(&topology)),
+            }
+            Ok(())
+        }
+        Command::Removed { type_, verbose } => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            println!("Removed {type_}s: ");
+            for item in boardswarm.removed(type_.into()).await? {
+                let property = |key: &str| {
+                    item.property
+                        .iter()
+                        .find(|p| p.key == key)
+                        .map(|p| p.value.as_str())
+                };
+                print!(
+                    "{} {}",
+                    item.id,
+                    property("boardswarm.name").unwrap_or_default()
+                );
+                if let Some(instance) = property("boardswarm.instance") {
+                    print!(" on {instance}");
+                }
+                println!(" - removed {}s ago", now.saturating_sub(item.removed));
+                if verbose {
+                    for p in item
+                        .property
+                        .iter()
+                        .sorted_unstable_by(|a, b| a.key.cmp(&b.key))
+                    {
+                        println!(r#""{}" => "{}""#, p.key, p.value);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Session { command } => {
+            match command {
+                SessionCommand::Open {
+                    devices,
+                    ttl,
+                    record,
+                    queue,
+                } => {
+                    let mut ids = Vec::new();
+                    for device in devices {
+                        ids.push(item_lookup(device, ItemType::Device, boardswarm.clone()).await?);
+                    }
+                    let session = open_session(
+                        &mut boardswarm,
+                        ids,
+                        Duration::from_secs(ttl),
+                        queue,
+                        record,
+                    )
+                    .await?;
+                    println!("{}", session.id);
+                }
+                SessionCommand::Hold {
+                    devices,
+                    ttl,
+                    record,
+                    queue,
+                } => {
+                    let mut ids = Vec::new();
+                    for device in devices {
+                        ids.push(item_lookup(device, ItemType::Device, boardswarm.clone()).await?);
+                    }
+                    let ttl = Duration::from_secs(ttl);
+                    let session = open_session(&mut boardswarm, ids, ttl, queue, record).await?;
+                    println!("{}", session.id);
+                    let lease = boardswarm.session_keep_alive(session.id, ttl);
+                    tokio::signal::ctrl_c().await?;
+                    drop(lease);
+                    boardswarm.session_close(session.id).await?;
+                }
+                SessionCommand::Renew { session, ttl } => {
+                    boardswarm
+                        .session_renew(session, Duration::from_secs(ttl))
+                        .await?;
+                }
+                SessionCommand::Close { session } => {
+                    boardswarm.session_close(session).await?;
+                }
+                SessionCommand::List => {
+                    for session in boardswarm.session_list().await? {
+                        println!(
+                            "{}: devices {} - {}s remaining{}",
+                            session.id,
+                            session.devices.iter().join(", "),
+                            session.remaining,
+                            session
+                                .holder
+                                .map(|h| format!(" - held by {h}"))
+                                .unwrap_or_default()
+                        );
+                    }
+                }
+                SessionCommand::Recording { session, output } => {
+                    let mut out: Box<dyn tokio::io::AsyncWrite + Unpin> = match output {
+                        Some(path) => Box::new(File::create(path).await?),
+                        None => Box::new(tokio::io::stdout()),
+                    };
+                    let mut events = boardswarm.session_recording(session).await?;
+                    while let Some(event) = events.message().await? {
+                        let Some(record) = event.event else {
+                            continue;
+                        };
+                        let mut json = record_event_json(record);
+                        json["device"] = event.device.into();
+                        out.write_all(format!("{}\n", json).as_bytes()).await?;
+                    }
+                    out.flush().await?;
                 }
             }
             Ok(())
@@ -1077,18 +2017,103 @@ async fn main() -> anyhow::Result<()> {
                         .context("Failed to parse console configuration as JSON")?;
                     boardswarm.console_configure(console, p).await?;
                 }
-                ConsoleCommand::Tail => {
-                    let output = boardswarm.console_stream_output(console).await?;
-                    copy_output_to_stdout(output).await?;
+                ConsoleCommand::Tail {
+                    history,
+                    timestamps,
+                    filter,
+                    raw,
+                } => {
+                    if raw || !filter.is_empty() {
+                        boardswarm.console_output_filters(Some(
+                            filter.into_iter().map(Into::into).collect(),
+                        ));
+                    }
+                    if timestamps {
+                        let output = boardswarm
+                            .console_stream_output_prefixed(console, history)
+                            .await?;
+                        copy_output_to_stdout(output).await?;
+                    } else if history {
+                        let output = boardswarm
+                            .console_stream_output_with_history(console)
+                            .await?;
+                        copy_output_to_stdout(output).await?;
+                    } else {
+                        let output = boardswarm.console_stream_output(console).await?;
+                        copy_output_to_stdout(output).await?;
+                    }
                 }
-                ConsoleCommand::Connect => {
-                    let out =
-                        copy_output_to_stdout(boardswarm.console_stream_output(console).await?);
-                    let in_ = boardswarm.console_stream_input(console, input_stream());
-                    futures::select! {
-                        in_ = in_.fuse() => in_?,
-                        out = out.fuse() => out?,
+                ConsoleCommand::Connect { lock, history } => {
+                    let handle = if lock {
+                        Some(boardswarm.console_open(console).await?.handle)
+                    } else {
+                        None
+                    };
+                    let out = if history {
+                        copy_output_to_stdout(
+                            boardswarm
+                                .console_stream_output_with_history(console)
+                                .await?,
+                        )
+                        .boxed_local()
+                    } else {
+                        copy_output_to_stdout(boardswarm.console_stream_output(console).await?)
+                            .boxed_local()
+                    };
+                    let mut input = boardswarm.clone();
+                    let in_ = async move {
+                        match handle {
+                            Some(handle) => {
+                                input
+                                    .console_handle_stream_input(handle, input_stream())
+                                    .await
+                            }
+                            None => input.console_stream_input(console, input_stream()).await,
+                        }
+                    };
+                    let r = futures::select! {
+                        in_ = in_.fuse() => in_.map_err(anyhow::Error::from),
+                        out = out.fuse() => out,
+                    };
+                    if let Some(handle) = handle {
+                        boardswarm.console_close(handle).await?;
                     }
+                    r?
+                }
+                ConsoleCommand::Expect {
+                    pattern,
+                    timeout,
+                    history,
+                    output,
+                } => {
+                    let reply = boardswarm
+                        .console_expect(
+                            console,
+                            &pattern,
+                            timeout.map(Duration::from_secs),
+                            history,
+                        )
+                        .await?;
+                    let data = if output { reply.output } else { reply.matched };
+                    let mut stdout = tokio::io::stdout();
+                    stdout.write_all(&data).await?;
+                    stdout.write_all(b"\n").await?;
+                    stdout.flush().await?;
+                }
+                ConsoleCommand::Break { duration } => {
+                    boardswarm
+                        .console_signal(console, None, Signal::BreakMs(duration))
+                        .await?;
+                }
+                ConsoleCommand::Dtr { state } => {
+                    boardswarm
+                        .console_signal(console, None, Signal::Dtr(state.asserted()))
+                        .await?;
+                }
+                ConsoleCommand::Rts { state } => {
+                    boardswarm
+                        .console_signal(console, None, Signal::Rts(state.asserted()))
+                        .await?;
                 }
                 ConsoleCommand::Properties => {
                     let properties = boardswarm.properties(ItemType::Console, console).await?;
@@ -1105,6 +2130,9 @@ async fn main() -> anyhow::Result<()> {
             match command {
                 VolumeCommand::Info => {
                     let info = boardswarm.volume_info(volume).await?;
+                    if let Some(mode) = &info.mode {
+                        println!("Requires device mode: {mode}");
+                    }
                     if info.exhaustive {
                         println!("Volume targets:");
                     } else {
@@ -1123,25 +2151,73 @@ async fn main() -> anyhow::Result<()> {
                         } else {
                             print!("blocksize: unknown, ");
                         }
+                        match target.kind() {
+                            VolumeTargetKind::BlockDevice => print!("kind: block device, "),
+                            VolumeTargetKind::Protocol => print!("kind: protocol, "),
+                            VolumeTargetKind::Unknown => (),
+                        }
+                        if let Some(sha256) = &target.sha256 {
+                            let sha256: String =
+                                sha256.iter().map(|b| format!("{b:02x}")).collect();
+                            print!("sha256: {sha256}, ");
+                        }
                         println!(
                             "readable: {}, writable: {}, seekable: {}",
                             target.readable, target.writable, target.seekable
                         );
                     }
                 }
+                VolumeCommand::Read(read) => {
+                    let mut f = tokio::fs::File::create(read.file).await?;
+                    let mut r = boardswarm
+                        .volume_io_readwrite(volume, read.target, None)
+                        .await?;
+                    if let Some(offset) = read.offset {
+                        r.seek(SeekFrom::Start(offset)).await?;
+                    }
+
+                    // Read a megabyte at a time, as small reads are slow for most volumes
+                    let mut r = BufReader::with_capacity(1024 * 1024, r);
+                    if let Some(length) = read.length {
+                        let mut r = r.take(length);
+                        tokio::io::copy_buf(&mut r, &mut f).await?;
+                    } else {
+                        tokio::io::copy_buf(&mut r, &mut f).await?;
+                    }
+                    f.flush().await?;
+                }
                 VolumeCommand::Write(write) => {
                     let mut f = tokio::fs::File::open(write.file).await?;
                     let m = f.metadata().await?;
-                    let rw = boardswarm
-                        .volume_io_readwrite(volume, write.target, Some(m.len()))
-                        .await?;
-                    let mut rw = BatchWriter::new(rw).discard_flush();
-                    if let Some(offset) = write.offset {
-                        rw.seek(SeekFrom::Start(offset)).await?;
+                    let options = UploadOptions {
+                        sha256: write.sha256,
+                        ..Default::default()
+                    };
+                    let cached = match (write.offset, write.sha256) {
+                        (None, Some(sha256)) => image_cached(boardswarm.image_cached(sha256).await),
+                        _ => false,
+                    };
+                    if cached {
+                        let progress = boardswarm
+                            .volume_fetch(volume, write.target, "", ImageFormat::Raw, None, options)
+                            .await?;
+                        show_fetch_progress(progress).await?;
+                    } else {
+                        let rw = boardswarm
+                            .volume_io_readwrite_with_options(
+                                volume,
+                                write.target,
+                                Some(m.len()),
+                                options,
+                            )
+                            .await?;
+                        let mut rw = BatchWriter::new(rw).discard_flush();
+                        if let Some(offset) = write.offset {
+                            rw.seek(SeekFrom::Start(offset)).await?;
+                        }
+                        tokio::io::copy(&mut f, &mut rw).await?;
+                        rw.shutdown().await?;
                     }
-                    tokio::io::copy(&mut f, &mut rw).await?;
-                    rw.shutdown().await?;
-                    drop(rw);
                 }
                 VolumeCommand::WriteAimg(write) => {
                     let rw = boardswarm
@@ -1156,9 +2232,28 @@ async fn main() -> anyhow::Result<()> {
                         .await?;
                     write_bmap(rw, &write.file).await?;
                 }
+                VolumeCommand::Fetch(fetch) => {
+                    let progress = boardswarm
+                        .volume_fetch(
+                            volume,
+                            fetch.target,
+                            fetch.url,
+                            fetch.layout.format(),
+                            fetch.layout.bmap,
+                            UploadOptions {
+                                sha256: fetch.sha256,
+                                ..Default::default()
+                            },
+                        )
+                        .await?;
+                    show_fetch_progress(progress).await?;
+                }
                 VolumeCommand::Commit => {
                     boardswarm.volume_commit(volume).await?;
                 }
+                VolumeCommand::Cancel => {
+                    boardswarm.volume_cancel(volume).await?;
+                }
                 VolumeCommand::Erase { target } => {
                     boardswarm.volume_erase(volume, target).await?;
                 }
@@ -1171,6 +2266,33 @@ async fn main() -> anyhow::Result<()> {
             }
             Ok(())
         }
+        Command::Audio { audio, command } => {
+            let audio = item_lookup(audio, ItemType::Audio, boardswarm.clone()).await?;
+            match command {
+                AudioCommand::Capture { opus } => {
+                    let encoding = if opus {
+                        AudioEncoding::OggOpus
+                    } else {
+                        AudioEncoding::Pcm
+                    };
+                    let (info, data) = boardswarm.audio_stream(audio, encoding).await?;
+                    info!("Capturing {} channels at {} Hz", info.channels, info.rate);
+                    pin_mut!(data);
+                    let mut stdout = tokio::io::stdout();
+                    while let Some(data) = data.try_next().await? {
+                        stdout.write_all(&data).await?;
+                    }
+                    stdout.flush().await?;
+                }
+                AudioCommand::Properties => {
+                    let properties = boardswarm.properties(ItemType::Audio, audio).await?;
+                    for key in properties.keys().sorted_unstable() {
+                        println!(r#""{}" => "{}""#, key, properties[key]);
+                    }
+                }
+            }
+            Ok(())
+        }
         Command::Device { device, command } => {
             let device = device.device(boardswarm.clone()).await?;
             let device = device.ok_or_else(|| anyhow::anyhow!("Device not found"))?;
@@ -1200,6 +2322,7 @@ async fn main() -> anyhow::Result<()> {
                 }
                 DeviceCommand::Write(DeviceWriteArg {
                     offset,
+                    sha256,
                     commit,
                     target,
                     file,
@@ -1207,20 +2330,67 @@ async fn main() -> anyhow::Result<()> {
                     let mut f = tokio::fs::File::open(file).await?;
                     let m = f.metadata().await?;
 
-                    let (mut volume, rw) = target.open_with_len(&device, m.len()).await?;
-                    let mut rw = BatchWriter::new(rw).discard_flush();
-                    if let Some(offset) = offset {
-                        rw.seek(SeekFrom::Start(offset)).await?;
-                    }
+                    let options = UploadOptions {
+                        sha256,
+                        ..Default::default()
+                    };
+                    let mut volume = target.volume.open(&device).await?;
+                    let cached = match (offset, sha256) {
+                        (None, Some(sha256)) => image_cached(volume.image_cached(sha256).await),
+                        _ => false,
+                    };
+                    if cached {
+                        let progress = volume
+                            .fetch(&target.target, "", ImageFormat::Raw, None, options)
+                            .await?;
+                        show_fetch_progress(progress).await?;
+                    } else {
+                        let rw = volume
+                            .open_with_options(&target.target, Some(m.len()), options)
+                            .await?;
+                        let mut rw = BatchWriter::new(rw).discard_flush();
+                        if let Some(offset) = offset {
+                            rw.seek(SeekFrom::Start(offset)).await?;
+                        }
 
-                    tokio::io::copy(&mut f, &mut rw).await?;
-                    rw.shutdown().await.context("Volume shutdown")?;
-                    drop(rw);
+                        tokio::io::copy(&mut f, &mut rw).await?;
+                        rw.shutdown().await.context("Volume shutdown")?;
+                    }
 
                     if commit {
                         volume.commit().await?;
                     }
                 }
+                DeviceCommand::WriteMany(DeviceWriteManyArg { volume, uploads }) => {
+                    let mut volume = volume.open(&device).await?;
+                    let transaction = volume.transaction_open().await?;
+                    let options = UploadOptions {
+                        transaction: Some(transaction),
+                        ..Default::default()
+                    };
+                    let written = async {
+                        for (target, file) in uploads {
+                            let mut f = tokio::fs::File::open(&file).await?;
+                            let m = f.metadata().await?;
+                            println!("Writing {} to {}", file.display(), target);
+                            let rw = volume
+                                .open_with_options(&target, Some(m.len()), options.clone())
+                                .await?;
+                            let mut rw = BatchWriter::new(rw).discard_flush();
+                            tokio::io::copy(&mut f, &mut rw).await?;
+                            rw.shutdown()
+                                .await
+                                .with_context(|| format!("Volume shutdown of {target}"))?;
+                        }
+                        anyhow::Ok(())
+                    }
+                    .await;
+                    if let Err(e) = written {
+                        let _ = volume.transaction_abort(transaction).await;
+                        return Err(e);
+                    }
+                    volume.transaction_commit(transaction).await?;
+                }
                 DeviceCommand::WriteAimg(DeviceAimgWriteArg {
                     commit,
                     target,
@@ -1246,6 +2416,32 @@ async fn main() -> anyhow::Result<()> {
                         volume.commit().await?;
                     }
                 }
+                DeviceCommand::Fetch(DeviceFetchArg {
+                    sha256,
+                    layout,
+                    commit,
+                    target,
+                    url,
+                }) => {
+                    let mut volume = target.volume.open(&device).await?;
+                    let progress = volume
+                        .fetch(
+                            &target.target,
+                            url,
+                            layout.format(),
+                            layout.bmap,
+                            UploadOptions {
+                                sha256,
+                                ..Default::default()
+                            },
+                        )
+                        .await?;
+                    show_fetch_progress(progress).await?;
+
+                    if commit {
+                        volume.commit().await?;
+                    }
+                }
                 DeviceCommand::Erase(DeviceEraseArg { volume, target }) => {
                     let mut volume = volume.open(&device).await?;
                     volume.erase(target).await?;
@@ -1254,6 +2450,10 @@ async fn main() -> anyhow::Result<()> {
                     let mut volume = volume.open(&device).await?;
                     volume.commit().await?;
                 }
+                DeviceCommand::Cancel(volume) => {
+                    let mut volume = volume.open(&device).await?;
+                    volume.cancel().await?;
+                }
                 DeviceCommand::Info { follow } => {
                     let mut d = boardswarm.device_info(device.id()).await?;
                     while let Some(device) = d.try_next().await? {
@@ -1264,7 +2464,78 @@ async fn main() -> anyhow::Result<()> {
                     }
                 }
                 DeviceCommand::Mode(d) => {
-                    device.change_mode(d.mode).await?;
+                    let progress = device.change_mode_progress(d.mode, d.no_wait).await?;
+                    show_mode_progress(progress).await?;
+                }
+                DeviceCommand::Reservation => {
+                    let reservation = boardswarm.device_reservation(device.id()).await?;
+                    match reservation.session {
+                        Some(session) => println!(
+                            "Reserved by session {} ({}) - {}s remaining",
+                            session.id,
+                            session.holder.as_deref().unwrap_or("unknown"),
+                            session.remaining
+                        ),
+                        None => println!("Not reserved"),
+                    }
+                    if reservation.queued > 0 {
+                        println!("{} caller(s) queued", reservation.queued);
+                    }
+                }
+                DeviceCommand::SelfTest => {
+                    let report = device.self_test().await?;
+                    print_checks(report.checks);
+                    if !report.passed {
+                        bail!("Self-test failed");
+                    }
+                }
+                DeviceCommand::Health => {
+                    let health = device.health_check().await?;
+                    print_checks(health.checks);
+                    if !health.healthy {
+                        bail!("Device unhealthy");
+                    }
+                }
+                DeviceCommand::Wait {
+                    type_,
+                    name,
+                    timeout,
+                } => {
+                    let id = boardswarm
+                        .device_wait_for(
+                            device.id(),
+                            type_.into(),
+                            name,
+                            timeout.map(Duration::from_secs),
+                        )
+                        .await?;
+                    println!("{}", id);
+                }
+                DeviceCommand::Task { task } => {
+                    let progress = boardswarm.device_run_task(device.id(), task).await?;
+                    show_task_progress(progress).await?;
+                }
+                DeviceCommand::Power { follow, max } => {
+                    let mut d = boardswarm.device_info(device.id()).await?;
+                    let mut sampled = None;
+                    while let Some(info) = d.try_next().await? {
+                        let Some(power) = info.power else {
+                            bail!("Device has no power sensor");
+                        };
+                        if sampled == Some(power.sampled) {
+                            continue;
+                        }
+                        sampled = Some(power.sampled);
+                        println!("{:.2} W ({:.1} J used)", power.watts, power.energy);
+                        if let Some(max) = max {
+                            if power.watts > max {
+                                bail!("Device draws more than {} W", max);
+                            }
+                        }
+                        if !follow {
+                            break;
+                        }
+                    }
                 }
                 DeviceCommand::Reset {} => {
                     println!("Turning off");
@@ -1302,6 +2573,29 @@ async fn main() -> anyhow::Result<()> {
                     let output = console.stream_output().await?;
                     copy_output_to_stdout(output).await?;
                 }
+                DeviceCommand::FeedConsole { name } => {
+                    let stdin = tokio_util::io::ReaderStream::new(tokio::io::stdin())
+                        .take_while(|r| futures::future::ready(r.is_ok()))
+                        .filter_map(|r| async move { r.ok() });
+                    let registration = boardswarm
+                        .console_register(name, Some(device.id()), stdin)
+                        .await?;
+                    info!("Registered console {}", registration.id());
+                    registration.wait().await?;
+                }
+                DeviceCommand::Record { output } => {
+                    let mut out: Box<dyn tokio::io::AsyncWrite + Unpin> = match output {
+                        Some(path) => Box::new(File::create(path).await?),
+                        None => Box::new(tokio::io::stdout()),
+                    };
+                    let events = boardswarm.device_record(device.id()).await?;
+                    pin_mut!(events);
+                    while let Some(event) = events.try_next().await? {
+                        let line = format!("{}\n", record_event_json(event));
+                        out.write_all(line.as_bytes()).await?;
+                        out.flush().await?;
+                    }
+                }
                 DeviceCommand::Properties => {
                     let properties = boardswarm.properties(ItemType::Device, device.id()).await?;
                     for key in properties.keys().sorted_unstable() {
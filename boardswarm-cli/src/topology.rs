@@ -0,0 +1,193 @@
+//! Rendering of the farm topology for visualization
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use boardswarm_protocol::{ItemType, TopologyItem, TopologyReply};
+use clap::ValueEnum;
+use serde_json::json;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum TopologyFormat {
+    Json,
+    /// GraphViz dot
+    Dot,
+}
+
+fn type_name(type_: ItemType) -> &'static str {
+    match type_ {
+        ItemType::Device => "device",
+        ItemType::Console => "console",
+        ItemType::Actuator => "actuator",
+        ItemType::Volume => "volume",
+        ItemType::Audio => "audio",
+    }
+}
+
+fn node(type_: ItemType, id: u64) -> String {
+    format!("{}:{}", type_name(type_), id)
+}
+
+/// Node of the provider of an item; Providers are distinct per server as names only need to be
+/// unique within one server
+fn provider_node(item: &TopologyItem) -> Option<String> {
+    let provider = item.provider.as_deref()?;
+    Some(match &item.server {
+        Some(server) => format!("provider:{}/{}", server, provider),
+        None => format!("provider:{}", provider),
+    })
+}
+
+pub fn to_json(topology: &TopologyReply) -> serde_json::Value {
+    let items: Vec<_> = topology
+        .items
+        .iter()
+        .map(|i| {
+            json!({
+                "type": type_name(i.r#type()),
+                "id": i.id,
+                "name": i.name,
+                "provider": i.provider,
+                "server": i.server,
+                "instance": i.instance,
+            })
+        })
+        .collect();
+    let links: Vec<_> = topology
+        .links
+        .iter()
+        .map(|l| {
+            json!({
+                "device": l.device,
+                "type": type_name(l.r#type()),
+                "id": l.id,
+                "role": l.role,
+            })
+        })
+        .collect();
+    json!({ "items": items, "links": links })
+}
+
+/// Graph linking devices to the items they use, items to their providers and providers to the
+/// servers they run on
+pub fn to_dot(topology: &TopologyReply) -> String {
+    let mut dot = String::from("digraph boardswarm {\n  rankdir=LR;\n");
+    let mut providers = BTreeSet::new();
+    let mut servers = BTreeSet::new();
+    for item in &topology.items {
+        let shape = match item.r#type() {
+            ItemType::Device => "box",
+            ItemType::Console => "note",
+            ItemType::Actuator => "diamond",
+            ItemType::Volume => "cylinder",
+            ItemType::Audio => "ellipse",
+        };
+        let _ = writeln!(
+            dot,
+            "  \"{}\" [label=\"{}\" shape={}];",
+            node(item.r#type(), item.id),
+            item.name.escape_default(),
+            shape
+        );
+        if let Some(provider) = provider_node(item) {
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"{}\";",
+                node(item.r#type(), item.id),
+                provider.escape_default()
+            );
+            providers.insert((provider, item.provider.clone(), item.server.clone()));
+        } else if let Some(server) = &item.server {
+            // Items without a provider, like configured devices, belong to the server directly
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"server:{}\";",
+                node(item.r#type(), item.id),
+                server.escape_default()
+            );
+            servers.insert(server.clone());
+        }
+    }
+
+    for (provider, name, server) in providers {
+        let _ = writeln!(
+            dot,
+            "  \"{}\" [label=\"{}\" shape=ellipse];",
+            provider.escape_default(),
+            name.unwrap_or_default().escape_default()
+        );
+        if let Some(server) = server {
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"server:{}\";",
+                provider.escape_default(),
+                server.escape_default()
+            );
+            servers.insert(server);
+        }
+    }
+    for server in servers {
+        let _ = writeln!(
+            dot,
+            "  \"server:{}\" [label=\"{}\" shape=house];",
+            server.escape_default(),
+            server.escape_default()
+        );
+    }
+
+    for link in &topology.links {
+        let _ = writeln!(
+            dot,
+            "  \"{}\" -> \"{}\" [label=\"{}\"];",
+            node(ItemType::Device, link.device),
+            node(link.r#type(), link.id),
+            link.role.escape_default()
+        );
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use boardswarm_protocol::TopologyLink;
+
+    #[test]
+    fn dot() {
+        let item = |type_: ItemType, id, name: &str, provider: Option<&str>| TopologyItem {
+            r#type: type_.into(),
+            id,
+            name: name.to_string(),
+            provider: provider.map(ToString::to_string),
+            server: Some("rack-1".to_string()),
+            instance: None,
+        };
+        let topology = TopologyReply {
+            items: vec![
+                item(ItemType::Device, 1, "rock-5b", None),
+                item(ItemType::Console, 2, "ttyUSB0", Some("serial")),
+            ],
+            links: vec![TopologyLink {
+                device: 1,
+                r#type: ItemType::Console.into(),
+                id: 2,
+                role: "main".to_string(),
+            }],
+        };
+        assert_eq!(
+            to_dot(&topology),
+            r#"digraph boardswarm {
+  rankdir=LR;
+  "device:1" [label="rock-5b" shape=box];
+  "device:1" -> "server:rack-1";
+  "console:2" [label="ttyUSB0" shape=note];
+  "console:2" -> "provider:rack-1/serial";
+  "provider:rack-1/serial" [label="serial" shape=ellipse];
+  "provider:rack-1/serial" -> "server:rack-1";
+  "server:rack-1" [label="rack-1" shape=house];
+  "device:1" -> "console:2" [label="main"];
+}
+"#
+        );
+    }
+}
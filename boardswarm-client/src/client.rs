@@ -1,20 +1,35 @@
 use std::{
     collections::{HashMap, VecDeque},
     future::Future,
+    path::PathBuf,
     pin::Pin,
     sync::Arc,
     task::{ready, Poll},
+    time::{Duration, SystemTime},
 };
 
 use boardswarm_protocol::{
-    boardswarm_client::BoardswarmClient, console_input_request, volume_io_reply, volume_io_request,
-    ActuatorModeRequest, ConsoleConfigureRequest, ConsoleInputRequest, ConsoleOutputRequest,
-    DeviceModeRequest, DeviceRequest, Item, ItemPropertiesRequest, ItemType, ItemTypeRequest,
-    VolumeEraseRequest, VolumeInfoMsg, VolumeIoFlush, VolumeIoRead, VolumeIoReply, VolumeIoRequest,
+    audio_stream_reply, boardswarm_client::BoardswarmClient, console_input_request,
+    console_register_request, console_signal_request, device_console_input_request,
+    volume_io_reply, volume_io_request, ActuatorModeRequest, AudioEncoding, AudioInfo,
+    AudioStreamReply, AudioStreamRequest, ConsoleCloseRequest, ConsoleCompression,
+    ConsoleConfigureRequest, ConsoleExpectReply, ConsoleExpectRequest, ConsoleFilter,
+    ConsoleFilters, ConsoleHandle, ConsoleInputRequest, ConsoleOpenRequest, ConsoleOutput,
+    ConsoleOutputRequest, ConsoleRegister, ConsoleRegisterReply, ConsoleRegisterRequest,
+    ConsoleSignalRequest, ConsoleTimestamps, DeviceConsoleInputRequest, DeviceConsoleOutputRequest,
+    DeviceConsoleTarget, DeviceModeProgress, DeviceModeRequest, DeviceRequest,
+    DeviceReservationReply, DeviceTaskProgress, DeviceTaskRequest, DeviceWaitRequest, Group,
+    GroupModeRequest, GroupProgress, GroupVolumeFetchRequest, ImageFormat, Item,
+    ItemPropertiesRequest, ItemRemoveRequest, ItemType, ItemTypeRequest, RemovedItem, Session,
+    SessionOpenRequest, SessionQueueRequest, SessionQueueUpdate, SessionRenewRequest,
+    SessionRequest, Task, TopologyReply, VolumeEraseRequest, VolumeFetchProgress,
+    VolumeFetchRequest, VolumeInfoMsg, VolumeIoFlush, VolumeIoRead, VolumeIoReply, VolumeIoRequest,
     VolumeIoShutdown, VolumeIoTarget, VolumeIoWrite, VolumeRequest, VolumeTarget,
+    VolumeTransactionRequest,
 };
 use bytes::Bytes;
 use futures::{future::BoxFuture, stream, FutureExt, Stream, StreamExt};
+use hyper_util::rt::TokioIo;
 use tokio::{
     io::{AsyncRead, AsyncSeek, AsyncWrite},
     sync::{mpsc, oneshot},
@@ -29,11 +44,31 @@ use crate::{
     oidc::{LoginProvider, OidcClientBuilder},
 };
 
+/// A console registered by this client
+pub struct ConsoleRegistration {
+    id: u64,
+    replies: tonic::Streaming<ConsoleRegisterReply>,
+}
+
+impl ConsoleRegistration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait for the registration to end
+    pub async fn wait(mut self) -> Result<(), tonic::Status> {
+        while self.replies.message().await?.is_some() {}
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct BoardswarmBuilder {
     uri: tonic::transport::Uri,
     auth: Option<Auth>,
     login_provider: Option<Arc<dyn LoginProvider>>,
+    compress: bool,
+    unix_socket: Option<PathBuf>,
 }
 
 impl BoardswarmBuilder {
@@ -42,9 +77,21 @@ impl BoardswarmBuilder {
             uri,
             auth: None,
             login_provider: None,
+            compress: false,
+            unix_socket: None,
         }
     }
 
+    /// Connect over the unix socket at `path` rather than to the host of the uri
+    pub fn unix_socket<P: Into<PathBuf>>(&mut self, path: P) {
+        self.unix_socket = Some(path.into());
+    }
+
+    /// Request console output to be compressed; Useful for remote servers behind slow links
+    pub fn compress_console_output(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
     pub fn auth_static<S: Into<String>>(&mut self, token: S) {
         self.auth = Some(Auth::Token(token.into()));
     }
@@ -63,7 +110,20 @@ impl BoardswarmBuilder {
     pub async fn connect(self) -> Result<Boardswarm, tonic::transport::Error> {
         let endpoint = tonic::transport::Endpoint::from(self.uri)
             .tls_config(tonic::transport::ClientTlsConfig::new().with_enabled_roots())?;
-        let channel = endpoint.connect().await?;
+        let channel = match self.unix_socket {
+            Some(path) => {
+                endpoint
+                    .connect_with_connector(tower::service_fn(move |_| {
+                        let path = path.clone();
+                        async move {
+                            let stream = tokio::net::UnixStream::connect(path).await?;
+                            Ok::<_, std::io::Error>(TokioIo::new(stream))
+                        }
+                    }))
+                    .await?
+            }
+            None => endpoint.connect().await?,
+        };
         let authenticator = match self.auth {
             Some(Auth::Token(t)) => Authenticator::from_static(t),
             Some(Auth::Oidc {
@@ -83,13 +143,20 @@ impl BoardswarmBuilder {
         };
         let channel = authenticator.into_layer().layer(channel);
         let client = BoardswarmClient::new(channel);
-        Ok(Boardswarm { client })
+        Ok(Boardswarm {
+            client,
+            compress: self.compress,
+            filters: None,
+            session: None,
+        })
     }
 }
 
 pub enum ItemEvent {
     Added(Vec<Item>),
     Removed(u64),
+    /// The properties of the items changed, keeping their ids
+    Updated(Vec<Item>),
 }
 
 #[derive(Clone, Debug)]
@@ -114,12 +181,58 @@ pub struct LoginInfo {
     pub method: AuthMethod,
 }
 
+fn decompress_console_output(
+    decoder: &mut zstd::stream::write::Decoder<'static, Vec<u8>>,
+    output: ConsoleOutput,
+) -> Option<Bytes> {
+    use std::io::Write;
+    match output.compression() {
+        ConsoleCompression::None => Some(output.data),
+        ConsoleCompression::Zstd => {
+            if let Err(e) = decoder
+                .write_all(&output.data)
+                .and_then(|_| decoder.flush())
+            {
+                warn!("Failed to decompress console output: {}", e);
+                return None;
+            }
+            Some(std::mem::take(decoder.get_mut()).into())
+        }
+    }
+}
+
+/// Keeps a session renewed in the background; Renewing stops once dropped, letting the session
+/// expire unless closed
+pub struct SessionLease {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for SessionLease {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Boardswarm {
     client: BoardswarmClient<AuthenticatorService<tonic::transport::Channel>>,
+    compress: bool,
+    filters: Option<Vec<ConsoleFilter>>,
+    session: Option<u64>,
 }
 
 impl Boardswarm {
+    /// Session to act in for operations on devices reserved by it
+    pub fn use_session(&mut self, session: Option<u64>) {
+        self.session = session;
+    }
+
+    /// Filters for the server to apply to streamed console output; None uses the filters
+    /// configured for each console, an empty list requests the raw output
+    pub fn console_output_filters(&mut self, filters: Option<Vec<ConsoleFilter>>) {
+        self.filters = filters;
+    }
+
     pub async fn login_info(&mut self) -> Result<Vec<LoginInfo>, tonic::Status> {
         let info = self.client.login_info(()).await?;
         let info = info.into_inner();
@@ -137,10 +250,39 @@ impl Boardswarm {
     }
 
     pub async fn list(&mut self, type_: ItemType) -> Result<Vec<Item>, tonic::Status> {
+        self.list_filtered(type_, HashMap::new()).await
+    }
+
+    /// List the items having all of the given properties, e.g. devices by their labels
+    pub async fn list_filtered(
+        &mut self,
+        type_: ItemType,
+        filter: HashMap<String, String>,
+    ) -> Result<Vec<Item>, tonic::Status> {
+        self.list_items(type_, filter, false).await
+    }
+
+    /// List the items having all of the given properties along with all their properties
+    pub async fn list_with_properties(
+        &mut self,
+        type_: ItemType,
+        filter: HashMap<String, String>,
+    ) -> Result<Vec<Item>, tonic::Status> {
+        self.list_items(type_, filter, true).await
+    }
+
+    async fn list_items(
+        &mut self,
+        type_: ItemType,
+        filter: HashMap<String, String>,
+        properties: bool,
+    ) -> Result<Vec<Item>, tonic::Status> {
         let items = self
             .client
             .list(ItemTypeRequest {
                 r#type: type_.into(),
+                filter,
+                properties,
             })
             .await?;
 
@@ -167,14 +309,77 @@ impl Boardswarm {
             .collect())
     }
 
+    pub async fn remove(
+        &mut self,
+        type_: ItemType,
+        item: u64,
+        force: bool,
+    ) -> Result<(), tonic::Status> {
+        self.client
+            .item_remove(ItemRemoveRequest {
+                r#type: type_.into(),
+                item,
+                force,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Recently removed items with their last known properties, most recent first
+    pub async fn removed(&mut self, type_: ItemType) -> Result<Vec<RemovedItem>, tonic::Status> {
+        let removed = self
+            .client
+            .item_removed(ItemTypeRequest {
+                r#type: type_.into(),
+                filter: HashMap::new(),
+                properties: false,
+            })
+            .await?;
+        Ok(removed.into_inner().item)
+    }
+
+    /// Relationship graph of all items, the devices using them and their providers and servers
+    pub async fn topology(&mut self) -> Result<TopologyReply, tonic::Status> {
+        let topology = self.client.topology(()).await?;
+        Ok(topology.into_inner())
+    }
+
     pub async fn monitor(
         &mut self,
         type_: ItemType,
+    ) -> Result<impl Stream<Item = Result<ItemEvent, tonic::Status>>, tonic::Status> {
+        self.monitor_items(type_, HashMap::new(), false).await
+    }
+
+    /// Monitor the items having all of the given properties, e.g. consoles by serial number
+    pub async fn monitor_filtered(
+        &mut self,
+        type_: ItemType,
+        filter: HashMap<String, String>,
+    ) -> Result<impl Stream<Item = Result<ItemEvent, tonic::Status>>, tonic::Status> {
+        self.monitor_items(type_, filter, false).await
+    }
+
+    /// Monitor the items, including all properties of the added items
+    pub async fn monitor_with_properties(
+        &mut self,
+        type_: ItemType,
+    ) -> Result<impl Stream<Item = Result<ItemEvent, tonic::Status>>, tonic::Status> {
+        self.monitor_items(type_, HashMap::new(), true).await
+    }
+
+    async fn monitor_items(
+        &mut self,
+        type_: ItemType,
+        filter: HashMap<String, String>,
+        properties: bool,
     ) -> Result<impl Stream<Item = Result<ItemEvent, tonic::Status>>, tonic::Status> {
         let items = self
             .client
             .monitor(ItemTypeRequest {
                 r#type: type_.into(),
+                filter,
+                properties,
             })
             .await?
             .into_inner();
@@ -189,6 +394,9 @@ impl Boardswarm {
                         boardswarm_protocol::item_event::Event::Remove(removed) => {
                             ItemEvent::Removed(removed)
                         }
+                        boardswarm_protocol::item_event::Event::Update(updated) => {
+                            ItemEvent::Updated(updated.item)
+                        }
                     })
                 })
                 .transpose()
@@ -204,17 +412,100 @@ impl Boardswarm {
         Ok(r.into_inner())
     }
 
+    /// Stream the device information as the initial snapshot followed by only the changes
+    pub async fn device_info_changes(
+        &mut self,
+        device: u64,
+    ) -> Result<
+        impl Stream<Item = Result<boardswarm_protocol::DeviceChange, tonic::Status>>,
+        tonic::Status,
+    > {
+        let r = self
+            .client
+            .device_info_changes(DeviceRequest { device })
+            .await?;
+        Ok(r.into_inner())
+    }
+
+    /// Change the mode of the device, once any other mode change in progress finished
     pub async fn device_change_mode(
         &mut self,
         device: u64,
         mode: String,
     ) -> Result<(), tonic::Status> {
-        self.client
-            .device_change_mode(DeviceModeRequest { device, mode })
+        let mut progress = self
+            .device_change_mode_progress(device, mode, false)
             .await?;
+        while progress.message().await?.is_some() {}
         Ok(())
     }
 
+    /// Change the mode of the device, failing with an unavailable status if another mode change
+    /// is in progress
+    pub async fn device_try_change_mode(
+        &mut self,
+        device: u64,
+        mode: String,
+    ) -> Result<(), tonic::Status> {
+        let mut progress = self.device_change_mode_progress(device, mode, true).await?;
+        while progress.message().await?.is_some() {}
+        Ok(())
+    }
+
+    /// Change the mode of the device, streaming the progress of the steps run; The stream ends
+    /// with an error status if the mode change fails
+    pub async fn device_change_mode_progress(
+        &mut self,
+        device: u64,
+        mode: String,
+        no_wait: bool,
+    ) -> Result<tonic::Streaming<DeviceModeProgress>, tonic::Status> {
+        let r = self
+            .client
+            .device_change_mode(DeviceModeRequest {
+                device,
+                mode,
+                session: self.session,
+                no_wait,
+            })
+            .await?;
+        Ok(r.into_inner())
+    }
+
+    pub async fn device_record(
+        &mut self,
+        device: u64,
+    ) -> Result<
+        impl Stream<Item = Result<boardswarm_protocol::DeviceRecordEvent, tonic::Status>>,
+        tonic::Status,
+    > {
+        let r = self.client.device_record(DeviceRequest { device }).await?;
+        Ok(r.into_inner())
+    }
+
+    pub async fn device_self_test(
+        &mut self,
+        device: u64,
+    ) -> Result<boardswarm_protocol::DeviceSelfTestReport, tonic::Status> {
+        let r = self
+            .client
+            .device_self_test(DeviceRequest { device })
+            .await?;
+        Ok(r.into_inner())
+    }
+
+    /// Run the health probes of the device; Refused while the device is reserved
+    pub async fn device_health_check(
+        &mut self,
+        device: u64,
+    ) -> Result<boardswarm_protocol::DeviceHealth, tonic::Status> {
+        let r = self
+            .client
+            .device_health_check(DeviceRequest { device })
+            .await?;
+        Ok(r.into_inner())
+    }
+
     pub async fn console_stream_input<I>(
         &mut self,
         console: u64,
@@ -223,15 +514,101 @@ impl Boardswarm {
     where
         I: Stream<Item = Bytes> + Send + 'static,
     {
+        self.stream_input(console_input_request::TargetOrData::Console(console), input)
+            .await
+    }
+
+    /// Stream input to the console of an open handle; Ends with an error once the handle gets
+    /// closed
+    pub async fn console_handle_stream_input<I>(
+        &mut self,
+        handle: u64,
+        input: I,
+    ) -> Result<(), tonic::Status>
+    where
+        I: Stream<Item = Bytes> + Send + 'static,
+    {
+        self.stream_input(console_input_request::TargetOrData::Handle(handle), input)
+            .await
+    }
+
+    /// Stream input to a device console by name; Input is held back by the server while the
+    /// console is unavailable, e.g. while it re-enumerates
+    pub async fn device_console_stream_input<I>(
+        &mut self,
+        device: u64,
+        console: &str,
+        input: I,
+    ) -> Result<(), tonic::Status>
+    where
+        I: Stream<Item = Bytes> + Send + 'static,
+    {
+        let target = DeviceConsoleTarget {
+            device,
+            console: console.to_string(),
+        };
+        let session = self.session;
+        self.client
+            .device_console_input(
+                stream::once(async move {
+                    DeviceConsoleInputRequest {
+                        target_or_data: Some(device_console_input_request::TargetOrData::Target(
+                            target,
+                        )),
+                        session,
+                    }
+                })
+                .chain(input.map(|i| DeviceConsoleInputRequest {
+                    target_or_data: Some(device_console_input_request::TargetOrData::Data(i)),
+                    session: None,
+                })),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Stream the output of a device console by name; The stream follows the console when it
+    /// disappears and reappears, e.g. while it re-enumerates
+    pub async fn device_console_stream_output(
+        &mut self,
+        device: u64,
+        console: &str,
+        history: bool,
+    ) -> Result<impl Stream<Item = Bytes>, tonic::Status> {
+        let request = DeviceConsoleOutputRequest {
+            target: Some(DeviceConsoleTarget {
+                device,
+                console: console.to_string(),
+            }),
+            history,
+        };
+        let response = self.client.device_console_output(request).await?;
+        Ok(response
+            .into_inner()
+            .take_while(|output| futures::future::ready(output.is_ok()))
+            .filter_map(|output| async move { output.ok().map(|o| o.data) }))
+    }
+
+    async fn stream_input<I>(
+        &mut self,
+        target: console_input_request::TargetOrData,
+        input: I,
+    ) -> Result<(), tonic::Status>
+    where
+        I: Stream<Item = Bytes> + Send + 'static,
+    {
+        let session = self.session;
         self.client
             .console_stream_input(
                 stream::once(async move {
                     ConsoleInputRequest {
-                        target_or_data: Some(console_input_request::TargetOrData::Console(console)),
+                        target_or_data: Some(target),
+                        session,
                     }
                 })
                 .chain(input.map(|i| ConsoleInputRequest {
                     target_or_data: Some(console_input_request::TargetOrData::Data(i)),
+                    session: None,
                 })),
             )
             .await?;
@@ -242,15 +619,110 @@ impl Boardswarm {
         &mut self,
         console: u64,
     ) -> Result<impl Stream<Item = Bytes>, tonic::Status> {
-        let request = tonic::Request::new(ConsoleOutputRequest { console });
+        let output = self
+            .console_output(console, false, ConsoleTimestamps::None)
+            .await?;
+        Ok(output.map(|(_, data)| data))
+    }
+
+    /// Like [Self::console_stream_output], but starting with the recent output buffered by the
+    /// server, e.g. the boot messages of a device
+    pub async fn console_stream_output_with_history(
+        &mut self,
+        console: u64,
+    ) -> Result<impl Stream<Item = Bytes>, tonic::Status> {
+        let output = self
+            .console_output(console, true, ConsoleTimestamps::None)
+            .await?;
+        Ok(output.map(|(_, data)| data))
+    }
+
+    /// Like [Self::console_stream_output], with each chunk of output paired with the time the
+    /// server read it from the console
+    pub async fn console_stream_output_timestamped(
+        &mut self,
+        console: u64,
+        history: bool,
+    ) -> Result<impl Stream<Item = (SystemTime, Bytes)>, tonic::Status> {
+        let output = self
+            .console_output(console, history, ConsoleTimestamps::Field)
+            .await?;
+        // Older servers don't send timestamps; Fall back to the time of arrival
+        Ok(output.map(|(at, data)| (at.unwrap_or_else(SystemTime::now), data)))
+    }
+
+    /// Like [Self::console_stream_output], with each line of output prefixed by the server
+    /// with the time it was read from the console
+    pub async fn console_stream_output_prefixed(
+        &mut self,
+        console: u64,
+        history: bool,
+    ) -> Result<impl Stream<Item = Bytes>, tonic::Status> {
+        let output = self
+            .console_output(console, history, ConsoleTimestamps::Prefix)
+            .await?;
+        Ok(output.map(|(_, data)| data))
+    }
+
+    async fn console_output(
+        &mut self,
+        console: u64,
+        history: bool,
+        timestamps: ConsoleTimestamps,
+    ) -> Result<impl Stream<Item = (Option<SystemTime>, Bytes)>, tonic::Status> {
+        let compression = if self.compress {
+            ConsoleCompression::Zstd
+        } else {
+            ConsoleCompression::None
+        };
+        let mut decoder = zstd::stream::write::Decoder::new(Vec::new())
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        let request = tonic::Request::new(ConsoleOutputRequest {
+            console,
+            compression: compression.into(),
+            history,
+            timestamps: timestamps.into(),
+            filters: self.filters.as_ref().map(|filters| ConsoleFilters {
+                filter: filters.iter().map(|&f| f.into()).collect(),
+            }),
+        });
         let response = self.client.console_stream_output(request).await?;
         let stream = response.into_inner();
-        Ok(stream.filter_map(|output| async {
-            let output = output.ok()?;
-            Some(output.data)
+        Ok(stream.filter_map(move |output| {
+            let data = output.ok().and_then(|output| {
+                let at = output
+                    .timestamp
+                    .map(|t| SystemTime::UNIX_EPOCH + Duration::from_micros(t));
+                decompress_console_output(&mut decoder, output).map(|data| (at, data))
+            });
+            async move { data }
         }))
     }
 
+    /// Open a handle to the console, taking its input lock until the handle gets closed
+    pub async fn console_open(&mut self, console: u64) -> Result<ConsoleHandle, tonic::Status> {
+        let handle = self
+            .client
+            .console_open(ConsoleOpenRequest {
+                console,
+                session: self.session,
+            })
+            .await?;
+        Ok(handle.into_inner())
+    }
+
+    pub async fn console_close(&mut self, handle: u64) -> Result<(), tonic::Status> {
+        self.client
+            .console_close(ConsoleCloseRequest { handle })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn console_handle_list(&mut self) -> Result<Vec<ConsoleHandle>, tonic::Status> {
+        let handles = self.client.console_handle_list(()).await?;
+        Ok(handles.into_inner().handles)
+    }
+
     pub async fn console_configure(
         &mut self,
         console: u64,
@@ -264,6 +736,98 @@ impl Boardswarm {
         Ok(())
     }
 
+    /// Wait for the regular expression `pattern` to show up in the output of the console,
+    /// optionally also matching the output buffered by the server; The reply holds the matched
+    /// output and the output leading up to it
+    pub async fn console_expect(
+        &mut self,
+        console: u64,
+        pattern: &str,
+        timeout: Option<Duration>,
+        history: bool,
+    ) -> Result<ConsoleExpectReply, tonic::Status> {
+        let request = ConsoleExpectRequest {
+            console,
+            pattern: pattern.to_string(),
+            timeout_ms: timeout.map(|t| t.as_millis() as u64),
+            history,
+            capture: true,
+        };
+        Ok(self.client.console_expect(request).await?.into_inner())
+    }
+
+    /// Wait for a console or volume of the device to become available, returning its id
+    pub async fn device_wait_for(
+        &mut self,
+        device: u64,
+        type_: ItemType,
+        name: String,
+        timeout: Option<Duration>,
+    ) -> Result<u64, tonic::Status> {
+        let request = DeviceWaitRequest {
+            device,
+            r#type: type_.into(),
+            name,
+            timeout_ms: timeout.map(|t| t.as_millis() as u64),
+        };
+        Ok(self.client.device_wait_for(request).await?.into_inner().id)
+    }
+
+    /// Send a break or change a modem control line of the console; `handle` is required while
+    /// the console is locked
+    pub async fn console_signal(
+        &mut self,
+        console: u64,
+        handle: Option<u64>,
+        signal: console_signal_request::Signal,
+    ) -> Result<(), tonic::Status> {
+        let request = ConsoleSignalRequest {
+            console,
+            handle,
+            signal: Some(signal),
+        };
+        self.client.console_signal(request).await?;
+        Ok(())
+    }
+
+    /// Register a console fed by `input`, optionally attached to a device; The console exists
+    /// until the input stream ends
+    pub async fn console_register<N, I>(
+        &mut self,
+        name: N,
+        device: Option<u64>,
+        input: I,
+    ) -> Result<ConsoleRegistration, tonic::Status>
+    where
+        N: Into<String>,
+        I: Stream<Item = Bytes> + Send + 'static,
+    {
+        let register = ConsoleRegisterRequest {
+            register_or_data: Some(console_register_request::RegisterOrData::Register(
+                ConsoleRegister {
+                    name: name.into(),
+                    device,
+                },
+            )),
+        };
+        let response = self
+            .client
+            .console_register(stream::once(async move { register }).chain(input.map(|i| {
+                ConsoleRegisterRequest {
+                    register_or_data: Some(console_register_request::RegisterOrData::Data(i)),
+                }
+            })))
+            .await?;
+        let mut replies = response.into_inner();
+        let Some(reply) = replies.message().await? else {
+            return Err(tonic::Status::aborted("No registration reply"));
+        };
+        Ok(ConsoleRegistration {
+            id: reply.console,
+            replies,
+        })
+    }
+
     pub async fn actuator_change_mode(
         &mut self,
         actuator: u64,
@@ -277,6 +841,174 @@ impl Boardswarm {
         Ok(())
     }
 
+    /// Reserve devices until the ttl expires or the session is closed; If `record` is set all
+    /// interactions with the devices are recorded for as long as the session lasts
+    pub async fn session_open(
+        &mut self,
+        devices: Vec<u64>,
+        ttl: Duration,
+        record: bool,
+    ) -> Result<Session, tonic::Status> {
+        let session = self
+            .client
+            .session_open(SessionOpenRequest {
+                devices,
+                ttl: ttl.as_secs(),
+                record,
+            })
+            .await?;
+        Ok(session.into_inner())
+    }
+
+    /// Queue for reserving the devices once they're free; The stream reports the position in the
+    /// queue and ends with the opened session
+    pub async fn session_queue(
+        &mut self,
+        devices: Vec<u64>,
+        ttl: Duration,
+        priority: i32,
+        record: bool,
+    ) -> Result<tonic::Streaming<SessionQueueUpdate>, tonic::Status> {
+        let updates = self
+            .client
+            .session_queue(SessionQueueRequest {
+                devices,
+                ttl: ttl.as_secs(),
+                priority,
+                record,
+            })
+            .await?;
+        Ok(updates.into_inner())
+    }
+
+    /// Interactions recorded so far for a session opened with recording enabled
+    pub async fn session_recording(
+        &mut self,
+        session: u64,
+    ) -> Result<tonic::Streaming<boardswarm_protocol::SessionRecordEvent>, tonic::Status> {
+        let events = self
+            .client
+            .session_recording(SessionRequest { session })
+            .await?;
+        Ok(events.into_inner())
+    }
+
+    /// Restart the ttl of a session
+    pub async fn session_renew(
+        &mut self,
+        session: u64,
+        ttl: Duration,
+    ) -> Result<Session, tonic::Status> {
+        let session = self
+            .client
+            .session_renew(SessionRenewRequest {
+                session,
+                ttl: ttl.as_secs(),
+            })
+            .await?;
+        Ok(session.into_inner())
+    }
+
+    /// Renew the session every half of the ttl until the returned lease is dropped, such that it
+    /// doesn't expire while still in use
+    pub fn session_keep_alive(&self, session: u64, ttl: Duration) -> SessionLease {
+        let mut client = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(ttl / 2).await;
+                if let Err(e) = client.session_renew(session, ttl).await {
+                    warn!("Failed to renew session {}: {}", session, e);
+                    if e.code() == tonic::Code::NotFound {
+                        return;
+                    }
+                }
+            }
+        });
+        SessionLease { task }
+    }
+
+    pub async fn session_close(&mut self, session: u64) -> Result<(), tonic::Status> {
+        self.client
+            .session_close(SessionRequest { session })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn session_list(&mut self) -> Result<Vec<Session>, tonic::Status> {
+        let sessions = self.client.session_list(()).await?;
+        Ok(sessions.into_inner().sessions)
+    }
+
+    /// Session currently reserving the device, if any, and the number of callers queued for it
+    pub async fn device_reservation(
+        &mut self,
+        device: u64,
+    ) -> Result<DeviceReservationReply, tonic::Status> {
+        let reservation = self
+            .client
+            .device_reservation(DeviceRequest { device })
+            .await?;
+        Ok(reservation.into_inner())
+    }
+
+    /// Device groups with the ids of their members
+    pub async fn group_list(&mut self) -> Result<Vec<Group>, tonic::Status> {
+        let groups = self.client.group_list(()).await?;
+        Ok(groups.into_inner().groups)
+    }
+
+    /// Change the mode of all devices in the group, streaming the progress of each device
+    pub async fn group_change_mode(
+        &mut self,
+        group: String,
+        mode: String,
+        no_wait: bool,
+    ) -> Result<tonic::Streaming<GroupProgress>, tonic::Status> {
+        let r = self
+            .client
+            .group_change_mode(GroupModeRequest {
+                group,
+                mode,
+                session: self.session,
+                no_wait,
+            })
+            .await?;
+        Ok(r.into_inner())
+    }
+
+    /// Have the server write an image to the volume of all devices in the group, streaming the
+    /// progress of each device; The session of the client is used for reserved devices
+    pub async fn group_volume_fetch(
+        &mut self,
+        mut request: GroupVolumeFetchRequest,
+    ) -> Result<tonic::Streaming<GroupProgress>, tonic::Status> {
+        request.session = self.session;
+        Ok(self.client.group_volume_fetch(request).await?.into_inner())
+    }
+
+    /// Tasks defined on the server
+    pub async fn task_list(&mut self) -> Result<Vec<Task>, tonic::Status> {
+        let tasks = self.client.task_list(()).await?;
+        Ok(tasks.into_inner().tasks)
+    }
+
+    /// Run a task on the device, streaming the progress of its steps
+    pub async fn device_run_task(
+        &mut self,
+        device: u64,
+        task: String,
+    ) -> Result<tonic::Streaming<DeviceTaskProgress>, tonic::Status> {
+        let r = self
+            .client
+            .device_run_task(DeviceTaskRequest {
+                device,
+                task,
+                session: self.session,
+            })
+            .await?;
+        Ok(r.into_inner())
+    }
+
     pub async fn volume_info(&mut self, volume: u64) -> Result<VolumeInfoMsg, tonic::Status> {
         let request = tonic::Request::new(VolumeRequest { volume });
         let r = self.client.volume_info(request).await?;
@@ -291,7 +1023,22 @@ impl Boardswarm {
         target: S,
         length: Option<u64>,
     ) -> Result<VolumeIoRW, tonic::Status> {
-        let (info, io) = self.volume_io(volume, target, length).await?;
+        self.volume_io_readwrite_with_options(volume, target, length, UploadOptions::default())
+            .await
+    }
+
+    /// Like [Self::volume_io_readwrite], with the upload checked by the server as set in the
+    /// options
+    pub async fn volume_io_readwrite_with_options<S: Into<String> + AsRef<str>>(
+        &mut self,
+        volume: u64,
+        target: S,
+        length: Option<u64>,
+        options: UploadOptions,
+    ) -> Result<VolumeIoRW, tonic::Status> {
+        let (info, io) = self
+            .volume_io_with_options(volume, target, length, options)
+            .await?;
 
         Ok(VolumeIoRW::new(io, info))
     }
@@ -301,11 +1048,23 @@ impl Boardswarm {
         volume: u64,
         target: S,
         length: Option<u64>,
+    ) -> Result<(VolumeTarget, VolumeIo), tonic::Status> {
+        self.volume_io_with_options(volume, target, length, UploadOptions::default())
+            .await
+    }
+
+    pub async fn volume_io_with_options<S: Into<String>>(
+        &mut self,
+        volume: u64,
+        target: S,
+        length: Option<u64>,
+        options: UploadOptions,
     ) -> Result<(VolumeTarget, VolumeIo), tonic::Status> {
         let (requests, requests_rx) = mpsc::channel(1);
         let (outstanding_tx, outstanding_rx) = mpsc::unbounded_channel();
 
         let target = target.into();
+        let session = self.session;
         let mut replies = self
             .client
             .volume_io(
@@ -316,6 +1075,9 @@ impl Boardswarm {
                                 volume,
                                 target,
                                 length,
+                                sha256: options.sha256.map(|s| Bytes::copy_from_slice(&s)),
+                                transaction: options.transaction,
+                                session,
                             },
                         )),
                     }
@@ -357,10 +1119,125 @@ impl Boardswarm {
         let request = tonic::Request::new(VolumeEraseRequest {
             volume,
             target: target.into(),
+            session: self.session,
         });
         self.client.volume_erase(request).await?;
         Ok(())
     }
+
+    /// Have the server download the image at `url` and write it to the volume target; For sparse
+    /// images and raw images with a block map (`bmap_url`) only the blocks holding data are
+    /// written. With an empty `url` the image is taken from the image cache of the server by the
+    /// sha256 of the options, see [Boardswarm::image_cached]. Returns the progress of the download
+    /// and writes, ending once the target is shut down
+    pub async fn volume_fetch<S: Into<String>, U: Into<String>>(
+        &mut self,
+        volume: u64,
+        target: S,
+        url: U,
+        format: ImageFormat,
+        bmap_url: Option<String>,
+        options: UploadOptions,
+    ) -> Result<tonic::Streaming<VolumeFetchProgress>, tonic::Status> {
+        let request = tonic::Request::new(VolumeFetchRequest {
+            volume,
+            target: target.into(),
+            url: url.into(),
+            sha256: options.sha256.map(|s| Bytes::copy_from_slice(&s)),
+            format: format.into(),
+            bmap_url,
+            transaction: options.transaction,
+            session: self.session,
+        });
+        Ok(self.client.volume_fetch(request).await?.into_inner())
+    }
+
+    /// Whether the server has the image with the digest in its image cache
+    pub async fn image_cached(&mut self, sha256: [u8; 32]) -> Result<bool, tonic::Status> {
+        let request = tonic::Request::new(boardswarm_protocol::ImageCacheRequest {
+            sha256: Bytes::copy_from_slice(&sha256),
+        });
+        Ok(self
+            .client
+            .image_cache_lookup(request)
+            .await?
+            .into_inner()
+            .cached)
+    }
+
+    /// Cancel all uploads to the volume in progress, including those of other clients
+    pub async fn volume_cancel(&mut self, volume: u64) -> Result<(), tonic::Status> {
+        let request = tonic::Request::new(VolumeRequest { volume });
+        self.client.volume_cancel(request).await?;
+        Ok(())
+    }
+
+    /// Start a transaction for uploading several targets of the volume; Uploads join it through
+    /// [UploadOptions::transaction]
+    pub async fn volume_transaction_open(&mut self, volume: u64) -> Result<u64, tonic::Status> {
+        let request = tonic::Request::new(VolumeRequest { volume });
+        let transaction = self.client.volume_transaction_open(request).await?;
+        Ok(transaction.into_inner().id)
+    }
+
+    /// Commit the volume of the transaction; Fails without committing if any of the uploads of the
+    /// transaction failed
+    pub async fn volume_transaction_commit(
+        &mut self,
+        transaction: u64,
+    ) -> Result<(), tonic::Status> {
+        let request = tonic::Request::new(VolumeTransactionRequest { transaction });
+        self.client.volume_transaction_commit(request).await?;
+        Ok(())
+    }
+
+    pub async fn volume_transaction_abort(
+        &mut self,
+        transaction: u64,
+    ) -> Result<(), tonic::Status> {
+        let request = tonic::Request::new(VolumeTransactionRequest { transaction });
+        self.client.volume_transaction_abort(request).await?;
+        Ok(())
+    }
+
+    /// Stream captured audio; Returns the format of the audio and a stream of the audio data
+    pub async fn audio_stream(
+        &mut self,
+        audio: u64,
+        encoding: AudioEncoding,
+    ) -> Result<(AudioInfo, impl Stream<Item = Result<Bytes, tonic::Status>>), tonic::Status> {
+        let request = tonic::Request::new(AudioStreamRequest {
+            audio,
+            encoding: encoding.into(),
+        });
+        let mut stream = self.client.audio_stream(request).await?.into_inner();
+        let info = match stream.message().await? {
+            Some(AudioStreamReply {
+                reply: Some(audio_stream_reply::Reply::Info(info)),
+            }) => info,
+            _ => return Err(tonic::Status::internal("Missing audio information")),
+        };
+        let data = stream.filter_map(|reply| async move {
+            match reply {
+                Ok(AudioStreamReply {
+                    reply: Some(audio_stream_reply::Reply::Data(data)),
+                }) => Some(Ok(data)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok((info, data))
+    }
+}
+
+/// Checks by the server of an upload
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    /// Expected SHA-256 of all data written; Data has to be written sequentially and a mismatch
+    /// fails the shutdown
+    pub sha256: Option<[u8; 32]>,
+    /// Transaction the upload is part of; It only succeeds once the target is shut down
+    pub transaction: Option<u64>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -552,10 +1429,12 @@ impl VolumeIo {
         offset: u64,
     ) -> ((VolumeIoRequest, Outstanding), VolumeIoWriteRequest) {
         let (tx, rx) = oneshot::channel();
+        let crc32 = Some(crc32fast::hash(&data));
         let request = VolumeIoRequest {
             target_or_request: Some(volume_io_request::TargetOrRequest::Write(VolumeIoWrite {
                 data,
                 offset,
+                crc32,
             })),
         };
         let outstanding = Outstanding::Write(tx);
@@ -1,12 +1,14 @@
 use std::sync::{Arc, Mutex};
 
-use boardswarm_protocol::{VolumeInfoMsg, VolumeTarget};
+use boardswarm_protocol::{
+    DeviceModeProgress, ImageFormat, ItemType, VolumeFetchProgress, VolumeInfoMsg, VolumeTarget,
+};
 use bytes::Bytes;
 use futures::{pin_mut, Stream, StreamExt};
 use tokio::{select, sync::broadcast};
 use tracing::info;
 
-use crate::client::{Boardswarm, VolumeIo, VolumeIoRW};
+use crate::client::{Boardswarm, UploadOptions, VolumeIo, VolumeIoRW};
 
 #[derive(Debug, Clone)]
 pub struct DeviceBuilder {
@@ -101,12 +103,65 @@ impl DeviceVolume {
         target: S,
         length: Option<u64>,
     ) -> Result<VolumeIoRW, tonic::Status> {
+        self.open_with_options(target, length, UploadOptions::default())
+            .await
+    }
+
+    /// Open a target with the upload checked by the server as set in the options
+    pub async fn open_with_options<S: Into<String> + AsRef<str>>(
+        &mut self,
+        target: S,
+        length: Option<u64>,
+        options: UploadOptions,
+    ) -> Result<VolumeIoRW, tonic::Status> {
+        let id = self
+            .get_id()
+            .ok_or_else(|| tonic::Status::unavailable("Volume currently not available"))?;
+        self.device
+            .client
+            .volume_io_readwrite_with_options(id, target, length, options)
+            .await
+    }
+
+    /// Have the server download the image at `url` and write it to the target
+    pub async fn fetch<S: Into<String>, U: Into<String>>(
+        &mut self,
+        target: S,
+        url: U,
+        format: ImageFormat,
+        bmap_url: Option<String>,
+        options: UploadOptions,
+    ) -> Result<tonic::Streaming<VolumeFetchProgress>, tonic::Status> {
         let id = self
             .get_id()
             .ok_or_else(|| tonic::Status::unavailable("Volume currently not available"))?;
         self.device
             .client
-            .volume_io_readwrite(id, target, length)
+            .volume_fetch(id, target, url, format, bmap_url, options)
+            .await
+    }
+
+    /// Start a transaction for uploading several targets of the volume before committing it
+    pub async fn transaction_open(&mut self) -> Result<u64, tonic::Status> {
+        if let Some(id) = self.get_id() {
+            self.device.client.volume_transaction_open(id).await
+        } else {
+            Err(tonic::Status::unavailable("Volume currently not available"))
+        }
+    }
+
+    /// Commit the volume if all uploads of the transaction succeeded
+    pub async fn transaction_commit(&mut self, transaction: u64) -> Result<(), tonic::Status> {
+        self.device
+            .client
+            .volume_transaction_commit(transaction)
+            .await
+    }
+
+    pub async fn transaction_abort(&mut self, transaction: u64) -> Result<(), tonic::Status> {
+        self.device
+            .client
+            .volume_transaction_abort(transaction)
             .await
     }
 
@@ -118,6 +173,21 @@ impl DeviceVolume {
         }
     }
 
+    /// Whether the server has the image with the digest in its image cache, such that a fetch with
+    /// an empty url writes it without a transfer
+    pub async fn image_cached(&mut self, sha256: [u8; 32]) -> Result<bool, tonic::Status> {
+        self.device.client.image_cached(sha256).await
+    }
+
+    /// Cancel all uploads to the volume in progress
+    pub async fn cancel(&mut self) -> Result<(), tonic::Status> {
+        if let Some(id) = self.get_id() {
+            self.device.client.volume_cancel(id).await
+        } else {
+            Err(tonic::Status::unavailable("Volume currently not available"))
+        }
+    }
+
     pub async fn erase<S: Into<String>>(&mut self, target: S) -> Result<(), tonic::Status> {
         if let Some(id) = self.get_id() {
             self.device.client.volume_erase(id, target).await
@@ -155,27 +225,26 @@ impl DeviceConsole {
         self.get_id().is_some()
     }
 
+    /// Stream input to the console; Input is held back while the console is unavailable
     pub async fn stream_input<I>(&mut self, input: I) -> Result<(), tonic::Status>
     where
         I: Stream<Item = Bytes> + Send + 'static,
     {
-        if let Some(id) = self.get_id() {
-            self.device.client.console_stream_input(id, input).await
-        } else {
-            Err(tonic::Status::unavailable(
-                "Console currently not available",
-            ))
-        }
+        let id = self.device.id;
+        self.device
+            .client
+            .device_console_stream_input(id, &self.name, input)
+            .await
     }
 
+    /// Stream the output of the console; The stream follows the console when it disappears and
+    /// reappears, e.g. when the device resets its usb serial adapter
     pub async fn stream_output(&mut self) -> Result<impl Stream<Item = Bytes>, tonic::Status> {
-        if let Some(id) = self.get_id() {
-            self.device.client.console_stream_output(id).await
-        } else {
-            Err(tonic::Status::unavailable(
-                "Console currently not available",
-            ))
-        }
+        let id = self.device.id;
+        self.device
+            .client
+            .device_console_stream_output(id, &self.name, false)
+            .await
     }
 }
 
@@ -240,6 +309,72 @@ impl Device {
         Ok(())
     }
 
+    /// Change the mode, failing if another mode change is in progress
+    pub async fn try_change_mode<S: Into<String>>(&self, mode: S) -> Result<(), tonic::Status> {
+        let mut client = self.client.clone();
+        client.device_try_change_mode(self.id, mode.into()).await?;
+        Ok(())
+    }
+
+    /// Change the mode, streaming the progress of the steps run along the way
+    pub async fn change_mode_progress<S: Into<String>>(
+        &self,
+        mode: S,
+        no_wait: bool,
+    ) -> Result<tonic::Streaming<DeviceModeProgress>, tonic::Status> {
+        let mut client = self.client.clone();
+        client
+            .device_change_mode_progress(self.id, mode.into(), no_wait)
+            .await
+    }
+
+    /// Run the device self-test; Note that this switches the device between modes
+    pub async fn self_test(
+        &self,
+    ) -> Result<boardswarm_protocol::DeviceSelfTestReport, tonic::Status> {
+        let mut client = self.client.clone();
+        client.device_self_test(self.id).await
+    }
+
+    /// Run the device health probes; Note that this switches the device between modes
+    pub async fn health_check(&self) -> Result<boardswarm_protocol::DeviceHealth, tonic::Status> {
+        let mut client = self.client.clone();
+        client.device_health_check(self.id).await
+    }
+
+    /// Last reading of the power sensor of the device, if it has one
+    pub fn power(&self) -> Option<boardswarm_protocol::DevicePower> {
+        let d = self.inner.device.lock().unwrap();
+        d.power.clone()
+    }
+
+    /// Wait for the console to become available, e.g. a usb serial adapter showing up once the
+    /// device powered on
+    pub async fn wait_for_console(
+        &self,
+        name: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<DeviceConsole, tonic::Status> {
+        let mut client = self.client.clone();
+        client
+            .device_wait_for(self.id, ItemType::Console, name.to_string(), timeout)
+            .await?;
+        Ok(DeviceConsole::new(self.clone(), name.to_string()))
+    }
+
+    /// Wait for the volume to become available, e.g. once the device is in its recovery mode
+    pub async fn wait_for_volume(
+        &self,
+        name: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<DeviceVolume, tonic::Status> {
+        let mut client = self.client.clone();
+        client
+            .device_wait_for(self.id, ItemType::Volume, name.to_string(), timeout)
+            .await?;
+        Ok(DeviceVolume::new(self.clone(), name.to_string()))
+    }
+
     /// Get the default console
     pub fn console(&self) -> Option<DeviceConsole> {
         let d = self.inner.device.lock().unwrap();
@@ -326,6 +326,105 @@ impl<'de> serde::Deserializer<'de> for ParamValue {
     }
 }
 
+/// Diff items identified by their name, adding the changes needed to go from `old` to `new`
+fn diff_named<T: Clone + PartialEq>(
+    old: &[T],
+    new: &[T],
+    name: fn(&T) -> &str,
+    changed: fn(T) -> device_change::Change,
+    removed: fn(String) -> device_change::Change,
+    changes: &mut Vec<device_change::Change>,
+) {
+    for item in new {
+        if !old.contains(item) {
+            changes.push(changed(item.clone()));
+        }
+    }
+    for item in old {
+        if !new.iter().any(|n| name(n) == name(item)) {
+            changes.push(removed(name(item).to_string()));
+        }
+    }
+}
+
+fn update_named<T>(items: &mut Vec<T>, item: T, name: fn(&T) -> &str) {
+    match items.iter_mut().find(|i| name(i) == name(&item)) {
+        Some(existing) => *existing = item,
+        None => items.push(item),
+    }
+}
+
+impl Device {
+    /// Changes needed to go from this device information to `new`
+    pub fn changes(&self, new: &Device) -> Vec<device_change::Change> {
+        use device_change::Change;
+        let mut changes = Vec::new();
+        diff_named(
+            &self.consoles,
+            &new.consoles,
+            |c| &c.name,
+            Change::Console,
+            Change::ConsoleRemoved,
+            &mut changes,
+        );
+        diff_named(
+            &self.volumes,
+            &new.volumes,
+            |v| &v.name,
+            Change::Volume,
+            Change::VolumeRemoved,
+            &mut changes,
+        );
+        diff_named(
+            &self.modes,
+            &new.modes,
+            |m| &m.name,
+            Change::Mode,
+            Change::ModeRemoved,
+            &mut changes,
+        );
+        if self.current_mode != new.current_mode {
+            changes.push(Change::CurrentMode(DeviceCurrentMode {
+                mode: new.current_mode.clone(),
+            }));
+        }
+        if let Some(health) = &new.health {
+            if self.health.as_ref() != Some(health) {
+                changes.push(Change::Health(health.clone()));
+            }
+        }
+        if let Some(counters) = &new.counters {
+            if self.counters.as_ref() != Some(counters) {
+                changes.push(Change::Counters(counters.clone()));
+            }
+        }
+        if let Some(power) = &new.power {
+            if self.power.as_ref() != Some(power) {
+                changes.push(Change::Power(power.clone()));
+            }
+        }
+        changes
+    }
+
+    /// Apply a change as streamed by the device info changes call
+    pub fn apply(&mut self, change: device_change::Change) {
+        use device_change::Change;
+        match change {
+            Change::Snapshot(device) => *self = device,
+            Change::Console(console) => update_named(&mut self.consoles, console, |c| &c.name),
+            Change::ConsoleRemoved(name) => self.consoles.retain(|c| c.name != name),
+            Change::Volume(volume) => update_named(&mut self.volumes, volume, |v| &v.name),
+            Change::VolumeRemoved(name) => self.volumes.retain(|v| v.name != name),
+            Change::Mode(mode) => update_named(&mut self.modes, mode, |m| &m.name),
+            Change::ModeRemoved(name) => self.modes.retain(|m| m.name != name),
+            Change::CurrentMode(current) => self.current_mode = current.mode,
+            Change::Health(health) => self.health = Some(health),
+            Change::Counters(counters) => self.counters = Some(counters),
+            Change::Power(power) => self.power = Some(power),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use serde::Deserialize;
@@ -408,4 +507,53 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn device_changes() {
+        let console = |name: &str, id| Console {
+            name: name.to_string(),
+            id,
+        };
+        let mode = |name: &str, available| Mode {
+            name: name.to_string(),
+            depends: None,
+            available,
+        };
+        let old = Device {
+            consoles: vec![console("main", Some(1)), console("debug", Some(2))],
+            volumes: vec![],
+            modes: vec![mode("on", true), mode("off", true)],
+            current_mode: Some("off".to_string()),
+            mode_change: None,
+            health: None,
+            labels: Default::default(),
+            counters: None,
+            power: None,
+        };
+        let new = Device {
+            consoles: vec![console("main", None), console("runtime", Some(5))],
+            volumes: vec![],
+            modes: vec![mode("on", false), mode("off", true)],
+            current_mode: None,
+            mode_change: None,
+            health: Some(DeviceHealth {
+                healthy: true,
+                checked: 1,
+                checks: vec![],
+            }),
+            labels: Default::default(),
+            counters: None,
+            power: None,
+        };
+
+        let changes = old.changes(&new);
+        assert_eq!(changes.len(), 6);
+        let mut updated = old.clone();
+        for change in changes {
+            updated.apply(change);
+        }
+        assert_eq!(updated, new);
+
+        assert!(new.changes(&new).is_empty());
+    }
 }
@@ -0,0 +1,261 @@
+//! Interfaces for boardswarm providers
+//!
+//! Providers expose actuators, consoles, volumes and audio captures to boardswarm by implementing the traits in
+//! this crate and registering the items through a [Registrar].
+use std::pin::Pin;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, Sink};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+pub mod properties;
+
+pub use properties::Properties;
+
+#[derive(Error, Debug)]
+#[error("Actuator failed")]
+pub struct ActuatorError();
+
+#[async_trait::async_trait]
+pub trait Actuator: std::fmt::Debug + Send + Sync {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError>;
+}
+
+#[derive(Error, Debug)]
+pub enum ConsoleError {
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    #[error("Console was closed")]
+    Closed,
+    #[error("Not supported by this console")]
+    Unsupported,
+}
+
+impl From<ConsoleError> for tonic::Status {
+    fn from(e: ConsoleError) -> Self {
+        match e {
+            ConsoleError::Closed => tonic::Status::aborted(e.to_string()),
+            ConsoleError::Unavailable(msg) => tonic::Status::unavailable(msg),
+            ConsoleError::Unsupported => tonic::Status::unimplemented(e.to_string()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Console: std::fmt::Debug + Send + Sync {
+    fn configure(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError>;
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError>;
+    async fn output(&self)
+        -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError>;
+    /// Send a break or change a modem control line
+    async fn signal(&self, _signal: ConsoleSignal) -> Result<(), ConsoleError> {
+        Err(ConsoleError::Unsupported)
+    }
+}
+
+/// Out of band signals of serial consoles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleSignal {
+    /// Send a break condition for the given duration
+    Break(Duration),
+    /// Assert or deassert the DTR line
+    Dtr(bool),
+    /// Assert or deassert the RTS line
+    Rts(bool),
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum VolumeError {
+    #[error("Unknown target requested")]
+    UnknownTargetRequested,
+    #[error("Internal error: {0}")]
+    Internal(String),
+    #[error("Volume failure: {0}")]
+    Failure(String),
+}
+
+impl From<VolumeError> for tonic::Status {
+    fn from(e: VolumeError) -> Self {
+        match e {
+            VolumeError::UnknownTargetRequested => tonic::Status::not_found(e.to_string()),
+            VolumeError::Internal(e) => tonic::Status::internal(e),
+            VolumeError::Failure(e) => tonic::Status::aborted(e),
+        }
+    }
+}
+
+pub type VolumeTargetInfo = boardswarm_protocol::VolumeTarget;
+#[async_trait::async_trait]
+pub trait Volume: std::fmt::Debug + Send + Sync {
+    /// List of known targets and whether it's exhaustive
+    fn targets(&self) -> (&[VolumeTargetInfo], bool);
+    async fn open(
+        &self,
+        target: &str,
+        length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError>;
+    async fn commit(&self) -> Result<(), VolumeError>;
+    async fn erase(&self, _target: &str) -> Result<(), VolumeError> {
+        Err(VolumeError::UnknownTargetRequested)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AudioError {
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    #[error("Audio capture ended")]
+    Closed,
+}
+
+impl From<AudioError> for tonic::Status {
+    fn from(e: AudioError) -> Self {
+        match e {
+            AudioError::Closed => tonic::Status::aborted(e.to_string()),
+            AudioError::Unavailable(msg) => tonic::Status::unavailable(msg),
+        }
+    }
+}
+
+pub type AudioInfo = boardswarm_protocol::AudioInfo;
+#[async_trait::async_trait]
+pub trait Audio: std::fmt::Debug + Send + Sync {
+    /// Start capturing; Returns the format of the captured data and a stream of the data
+    async fn capture(
+        &self,
+    ) -> Result<(AudioInfo, BoxStream<'static, Result<Bytes, AudioError>>), AudioError>;
+}
+
+pub struct ReadCompletion(oneshot::Sender<Result<Bytes, tonic::Status>>);
+impl ReadCompletion {
+    pub fn new() -> (Self, oneshot::Receiver<Result<Bytes, tonic::Status>>) {
+        let (tx, rx) = oneshot::channel();
+        (Self(tx), rx)
+    }
+    pub fn complete(self, result: Result<Bytes, tonic::Status>) {
+        let _ = self.0.send(result);
+    }
+}
+
+pub struct WriteCompletion(oneshot::Sender<Result<u64, tonic::Status>>);
+impl WriteCompletion {
+    pub fn new() -> (Self, oneshot::Receiver<Result<u64, tonic::Status>>) {
+        let (tx, rx) = oneshot::channel();
+        (Self(tx), rx)
+    }
+    pub fn complete(self, result: Result<u64, tonic::Status>) {
+        let _ = self.0.send(result);
+    }
+}
+
+pub struct FlushCompletion(oneshot::Sender<Result<(), tonic::Status>>);
+impl FlushCompletion {
+    pub fn new() -> (Self, oneshot::Receiver<Result<(), tonic::Status>>) {
+        let (tx, rx) = oneshot::channel();
+        (Self(tx), rx)
+    }
+    pub fn complete(self, result: Result<(), tonic::Status>) {
+        let _ = self.0.send(result);
+    }
+}
+
+pub struct ShutdownCompletion {
+    tx: oneshot::Sender<Result<(), tonic::Status>>,
+    steps: Option<mpsc::UnboundedSender<String>>,
+}
+impl ShutdownCompletion {
+    pub fn new() -> (Self, oneshot::Receiver<Result<(), tonic::Status>>) {
+        let (tx, rx) = oneshot::channel();
+        (Self { tx, steps: None }, rx)
+    }
+    /// Like [Self::new], also returning the steps reported while the shutdown is in progress
+    pub fn with_steps() -> (
+        Self,
+        oneshot::Receiver<Result<(), tonic::Status>>,
+        mpsc::UnboundedReceiver<String>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        let (steps, steps_rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                tx,
+                steps: Some(steps),
+            },
+            rx,
+            steps_rx,
+        )
+    }
+    /// Report progress of finishing the upload, e.g. the board verifying or flashing the image
+    pub fn report_step<S: Into<String>>(&self, step: S) {
+        if let Some(steps) = &self.steps {
+            let _ = steps.send(step.into());
+        }
+    }
+    pub fn complete(self, result: Result<(), tonic::Status>) {
+        let _ = self.tx.send(result);
+    }
+}
+
+#[async_trait::async_trait]
+pub trait VolumeTarget: Send {
+    async fn read(&mut self, _length: u64, _offset: u64, completion: ReadCompletion) {
+        completion.complete(Err(tonic::Status::unimplemented("Target is not readable")));
+    }
+
+    async fn write(&mut self, _data: Bytes, _offset: u64, completion: WriteCompletion) {
+        completion.complete(Err(tonic::Status::unimplemented("Target is not writable")));
+    }
+
+    async fn flush(&mut self, completion: FlushCompletion) {
+        completion.complete(Ok(()))
+    }
+
+    async fn shutdown(&mut self, completion: ShutdownCompletion) {
+        // Take advantage of flush and shutdown returning an result, so we can convert one into
+        // the other
+        let rx = completion.tx;
+        let completion = FlushCompletion(rx);
+        self.flush(completion).await
+    }
+
+    /// Abort the upload, as it got cancelled or the client went away without shutting down the
+    /// target; Operations in progress may have been dropped midway. Targets should stop any
+    /// transfers and leave the device usable again
+    async fn abort(&mut self) {}
+}
+
+/// Registration of provider items with boardswarm
+///
+/// Each registration returns an id which can be used to unregister the item again, e.g. when the
+/// underlying hardware disappears.
+pub trait Registrar {
+    fn register_actuator<A>(&self, properties: Properties, actuator: A) -> u64
+    where
+        A: Actuator + 'static;
+    fn unregister_actuator(&self, id: u64);
+
+    fn register_console<C>(&self, properties: Properties, console: C) -> u64
+    where
+        C: Console + 'static;
+    fn unregister_console(&self, id: u64);
+
+    fn register_volume<V>(&self, properties: Properties, volume: V) -> u64
+    where
+        V: Volume + 'static;
+    fn unregister_volume(&self, id: u64);
+
+    fn register_audio<A>(&self, properties: Properties, audio: A) -> u64
+    where
+        A: Audio + 'static;
+    fn unregister_audio(&self, id: u64);
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+pub const NAME: &str = "boardswarm.name";
+pub const INSTANCE: &str = "boardswarm.instance";
+pub const PROVIDER: &str = "boardswarm.provider";
+pub const PROVIDER_NAME: &str = "boardswarm.provider.name";
+/// Name of the server the item is attached to, if configured
+pub const SERVER: &str = "boardswarm.server";
+/// Comma separated addresses the server the item is attached to can be reached at, if configured
+pub const SERVER_ADDRESS: &str = "boardswarm.server.address";
+/// Identity of the item that stays the same when the hardware is re-enumerated or the server
+/// restarts, as opposed to the item id; Only set if one is known
+pub const IDENTITY: &str = "boardswarm.identity";
+/// Prefix of the properties set by boardswarm itself, as opposed to labels set by users
+pub const RESERVED: &str = "boardswarm.";
+/// Namespace of the properties copied from the udev database, e.g. `udev.ID_SERIAL`
+pub const UDEV: &str = "udev";
+/// Prefix of the properties tagging a device as a member of a group, e.g. `boardswarm.tag.rpi4`
+pub const TAG: &str = "boardswarm.tag.";
+
+/// Interpret a property value as a boolean, as commonly written in udev and configuration files
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Test of a property value; Plain strings match the exact value
+pub trait ValueMatch {
+    fn matches_value(&self, value: &str) -> bool;
+}
+
+impl ValueMatch for str {
+    fn matches_value(&self, value: &str) -> bool {
+        self == value
+    }
+}
+
+impl ValueMatch for String {
+    fn matches_value(&self, value: &str) -> bool {
+        self == value
+    }
+}
+
+impl<T: ValueMatch + ?Sized> ValueMatch for &T {
+    fn matches_value(&self, value: &str) -> bool {
+        (**self).matches_value(value)
+    }
+}
+
+/// Property keys are namespaced by their first component, e.g. `udev` for the udev database,
+/// `boardswarm` for the properties set by boardswarm itself and the provider type for provider
+/// specific ones like `serial.error`
+#[derive(Clone, Debug)]
+pub struct Properties {
+    properties: HashMap<String, String>,
+}
+
+impl Properties {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(NAME.to_string(), name.into());
+
+        Self { properties }
+    }
+
+    pub fn name(&self) -> &str {
+        self.get(NAME).unwrap_or_default()
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        self.get(INSTANCE)
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.get(IDENTITY)
+    }
+
+    pub fn get(&self, prop: &str) -> Option<&str> {
+        self.properties.get(prop).map(String::as_ref)
+    }
+
+    /// Property value as an integer, either decimal or hexadecimal with a `0x` prefix
+    pub fn get_int(&self, prop: &str) -> Option<i64> {
+        let value = self.get(prop)?.trim();
+        match value.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+
+    pub fn get_bool(&self, prop: &str) -> Option<bool> {
+        self.get(prop).and_then(parse_bool)
+    }
+
+    /// Properties in the namespace, with the namespace stripped from their keys
+    pub fn namespace<'a>(&'a self, namespace: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.properties.iter().filter_map(move |(k, v)| {
+            k.strip_prefix(namespace)
+                .and_then(|k| k.strip_prefix('.'))
+                .map(|k| (k, v.as_str()))
+        })
+    }
+
+    /// Tests if matches is a subset of the properties
+    ///
+    /// If properties is from a remote instance (`boardswarm.instance` is set) that has to be
+    /// explicitly matched otherwise it's a pure subset match (e.g. an empty set matches)
+    pub fn matches<K, V, I>(&self, matches: I) -> bool
+    where
+        K: AsRef<str>,
+        V: ValueMatch,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut matched_instance = false;
+        let matched = matches.into_iter().all(|(k, v)| {
+            matched_instance |= k.as_ref() == INSTANCE;
+            if let Some(prop) = self.get(k.as_ref()) {
+                v.matches_value(prop)
+            } else {
+                false
+            }
+        });
+
+        // All the properties need to match and if the instance is declared in the properties that
+        // also needed to be matched against
+        matched && matched_instance == self.instance().is_some()
+    }
+
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.properties.insert(key.into(), value.into());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.properties.iter()
+    }
+}
+
+impl<K, V> Extend<(K, V)> for Properties
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (key, value) in iter.into_iter() {
+            self.properties.insert(key.into(), value.into());
+        }
+    }
+}
+
+impl<'a, K, V> Extend<&'a (K, V)> for Properties
+where
+    K: ToString,
+    V: ToString,
+{
+    fn extend<T: IntoIterator<Item = &'a (K, V)>>(&mut self, iter: T) {
+        for (key, value) in iter.into_iter() {
+            self.properties.insert(key.to_string(), value.to_string());
+        }
+    }
+}
+
+impl From<HashMap<String, String>> for Properties {
+    fn from(properties: HashMap<String, String>) -> Self {
+        Properties { properties }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn properties() {
+        let mut props = Properties::new("test");
+        props.insert("udev.BADGER", "5");
+
+        assert_eq!(props.get(NAME), Some("test"));
+        assert_eq!(props.name(), "test");
+
+        let mut t = HashMap::new();
+        t.insert(NAME.to_string(), "test".to_string());
+        assert!(props.matches(&t));
+
+        let empty: HashMap<String, String> = HashMap::new();
+        assert!(props.matches(empty));
+
+        assert!(props.matches([(NAME, "test")]));
+        assert!(props.matches([("udev.BADGER", "5")]));
+
+        assert!(!props.matches([(NAME, "no")]));
+        assert!(!props.matches([("udev.BADGER", "7")]));
+        assert!(!props.matches([("udev.SNAKE", "7")]));
+
+        assert!(props.matches([(NAME, "test"), ("udev.BADGER", "5")]));
+        assert!(!props.matches([(NAME, "test"), ("udev.BADGER", "7")]));
+        assert!(!props.matches([(NAME, "test"), ("udev.SNAKE", "5")]));
+    }
+
+    #[test]
+    fn typed() {
+        let mut props = Properties::new("test");
+        props.insert("udev.BUSNUM", "003");
+        props.insert("udev.ID_VENDOR_ID", "0x0403");
+        props.insert("udev.ID_USB_DRIVER", "ftdi_sio");
+        props.insert("serial.error", "busy");
+        props.insert("rack.powered", "yes");
+
+        assert_eq!(props.get_int("udev.BUSNUM"), Some(3));
+        assert_eq!(props.get_int("udev.ID_VENDOR_ID"), Some(0x403));
+        assert_eq!(props.get_int("udev.ID_USB_DRIVER"), None);
+        assert_eq!(props.get_bool("rack.powered"), Some(true));
+        assert_eq!(props.get_bool("serial.error"), None);
+
+        let mut udev: Vec<_> = props.namespace(UDEV).collect();
+        udev.sort();
+        assert_eq!(
+            udev,
+            [
+                ("BUSNUM", "003"),
+                ("ID_USB_DRIVER", "ftdi_sio"),
+                ("ID_VENDOR_ID", "0x0403")
+            ]
+        );
+        assert_eq!(props.namespace("rack").count(), 1);
+        assert_eq!(props.namespace("rac").count(), 0);
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Sink, StreamExt};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry, udev::DeviceEvent, utils::ProcessTarget, ConsoleError, Server, Volume, VolumeError,
+    VolumeTarget, VolumeTargetInfo,
+};
+
+pub const PROVIDER: &str = "adb";
+
+/// Target installing an OTA package through `adb sideload`
+const SIDELOAD: &str = "sideload";
+
+/// USB interface (class, subclass, protocol) of adb
+const ADB_INTERFACE: &str = ":ff4201:";
+
+/// Delay before restarting the shell after it ended, e.g. as the device rebooted
+const SHELL_RESTART: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Default)]
+struct AdbParameters {
+    /// Push targets, mapping target names to the path on the device the data gets pushed to
+    #[serde(default)]
+    push: HashMap<String, String>,
+}
+
+#[instrument(skip(parameters, server))]
+pub async fn start_provider(name: String, parameters: Option<serde_yaml::Value>, server: Server) {
+    let parameters: AdbParameters = parameters
+        .map(|p| serde_yaml::from_value(p).unwrap())
+        .unwrap_or_default();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+    let push = Arc::new(parameters.push);
+
+    let mut registrations = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("usb").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                if device.devnode().is_none()
+                    || !device
+                        .property("ID_USB_INTERFACES")
+                        .is_some_and(|i| i.contains(ADB_INTERFACE))
+                {
+                    continue;
+                }
+                let Some(serial) = device.property("ID_SERIAL_SHORT") else {
+                    continue;
+                };
+                info!("Found adb device {}", serial);
+                let adb = Arc::new(Adb {
+                    serial: serial.to_string(),
+                });
+
+                let mut properties = device.properties(serial);
+                properties.extend(provider_properties);
+                properties.insert(format!("{PROVIDER}.serial"), serial);
+
+                let (input, rx) = mpsc::channel(16);
+                let output = broadcast::channel(64).0;
+                tokio::spawn(run_shell(adb.clone(), rx, output.clone()));
+                let console =
+                    server.register_console(properties.clone(), AdbShell { input, output });
+                let volume = server.register_volume(properties, AdbVolume::new(adb, push.clone()));
+                registrations.insert(device.syspath().to_path_buf(), (console, volume));
+            }
+            DeviceEvent::Remove(device) => {
+                if let Some((console, volume)) = registrations.remove(device.syspath()) {
+                    server.unregister_console(console);
+                    server.unregister_volume(volume);
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Adb {
+    serial: String,
+}
+
+impl Adb {
+    fn command(&self) -> Command {
+        let mut command = Command::new("adb");
+        command.arg("-s").arg(&self.serial).kill_on_drop(true);
+        command
+    }
+}
+
+/// Keep an interactive shell on the device running for as long as the console exists
+async fn run_shell(adb: Arc<Adb>, input: mpsc::Receiver<Bytes>, output: broadcast::Sender<Bytes>) {
+    let input = Arc::new(Mutex::new(input));
+    loop {
+        // Force a pty even though stdin isn't a terminal
+        let child = adb
+            .command()
+            .args(["shell", "-t", "-t"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to start adb: {}", e);
+                return;
+            }
+        };
+
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let input = input.clone();
+        let feed = async move {
+            let mut input = input.lock().await;
+            while let Some(data) = input.recv().await {
+                if stdin.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let read = async {
+            let mut buf = BytesMut::with_capacity(4096);
+            loop {
+                buf.reserve(4096);
+                match stdout.read_buf(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        let _ = output.send(buf.split().freeze());
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            _ = feed => return,
+            _ = read => (),
+        }
+        let _ = child.wait().await;
+        tokio::time::sleep(SHELL_RESTART).await;
+    }
+}
+
+#[derive(Debug)]
+struct AdbShell {
+    input: mpsc::Sender<Bytes>,
+    output: broadcast::Sender<Bytes>,
+}
+
+#[async_trait::async_trait]
+impl crate::Console for AdbShell {
+    fn configure(
+        &self,
+        _parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        Ok(())
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let input = self.input.clone();
+        Ok(Box::pin(futures::sink::unfold(
+            input,
+            |input, data: Bytes| async move {
+                input.send(data).await.map_err(|_| ConsoleError::Closed)?;
+                Ok(input)
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("adb shell output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
+
+#[derive(Debug)]
+struct AdbVolume {
+    adb: Arc<Adb>,
+    push: Arc<HashMap<String, String>>,
+    targets: Vec<VolumeTargetInfo>,
+}
+
+impl AdbVolume {
+    fn new(adb: Arc<Adb>, push: Arc<HashMap<String, String>>) -> Self {
+        let targets = push
+            .keys()
+            .map(String::as_str)
+            .chain(std::iter::once(SIDELOAD))
+            .map(|name| VolumeTargetInfo {
+                name: name.to_string(),
+                readable: false,
+                writable: true,
+                seekable: false,
+                size: None,
+                blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                sha256: None,
+            })
+            .collect();
+        Self { adb, push, targets }
+    }
+}
+
+#[async_trait::async_trait]
+impl Volume for AdbVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (&self.targets, true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        _length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        let Some(info) = self.targets.iter().find(|t| t.name == target) else {
+            return Err(VolumeError::UnknownTargetRequested);
+        };
+        if target == SIDELOAD {
+            let target = SideloadTarget::new(self.adb.clone())
+                .await
+                .map_err(|e| VolumeError::Failure(e.to_string()))?;
+            return Ok((info.clone(), Box::new(target)));
+        }
+
+        let path = &self.push[target];
+        if path.contains('\'') {
+            return Err(VolumeError::Failure(format!("Invalid path: {path}")));
+        }
+        // Stream the data into the file on the device; exec-in doesn't mangle binary data
+        let child = self
+            .adb
+            .command()
+            .arg("exec-in")
+            .arg(format!("cat > '{path}'"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| VolumeError::Failure(format!("Failed to run adb: {e}")))?;
+        Ok((info.clone(), Box::new(ProcessTarget::new(child, "Push"))))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        Ok(())
+    }
+}
+
+/// OTA package to be sideloaded; adb needs it as a file, so it's stored temporarily
+struct SideloadTarget {
+    adb: Arc<Adb>,
+    path: PathBuf,
+    file: Option<File>,
+    written: u64,
+}
+
+impl SideloadTarget {
+    async fn new(adb: Arc<Adb>) -> std::io::Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "boardswarm-sideload-{}-{}.zip",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&path).await?;
+        Ok(Self {
+            adb,
+            path,
+            file: Some(file),
+            written: 0,
+        })
+    }
+
+    async fn sideload(&mut self) -> Result<(), String> {
+        let Some(mut file) = self.file.take() else {
+            return Err("Target was shut down".to_string());
+        };
+        file.flush().await.map_err(|e| e.to_string())?;
+        drop(file);
+        let output = self
+            .adb
+            .command()
+            .arg("sideload")
+            .arg(&self.path)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run adb: {e}"))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Sideload failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+impl Drop for SideloadTarget {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for SideloadTarget {
+    async fn write(&mut self, data: Bytes, offset: u64, completion: crate::WriteCompletion) {
+        let Some(file) = &mut self.file else {
+            completion.complete(Err(tonic::Status::failed_precondition(
+                "Target was shut down",
+            )));
+            return;
+        };
+        if offset != self.written {
+            completion.complete(Err(tonic::Status::out_of_range("Invalid offset")));
+            return;
+        }
+        match file.write_all(&data).await {
+            Ok(()) => {
+                self.written += data.len() as u64;
+                completion.complete(Ok(data.len() as u64))
+            }
+            Err(e) => completion.complete(Err(tonic::Status::aborted(e.to_string()))),
+        }
+    }
+
+    async fn shutdown(&mut self, completion: crate::ShutdownCompletion) {
+        completion.report_step("Sideloading package");
+        completion.complete(self.sideload().await.map_err(tonic::Status::aborted))
+    }
+}
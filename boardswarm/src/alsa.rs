@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, StreamExt};
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{debug, instrument, warn};
+
+use crate::{registry, udev::DeviceEvent, AudioError, AudioInfo, Server};
+
+pub const PROVIDER: &str = "alsa";
+
+fn default_rate() -> u32 {
+    48_000
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+#[derive(Deserialize, Debug)]
+struct AlsaParameters {
+    /// Sample rate to capture at; Converted by alsa if the hardware doesn't support it
+    #[serde(default = "default_rate")]
+    rate: u32,
+    #[serde(default = "default_channels")]
+    channels: u32,
+}
+
+impl Default for AlsaParameters {
+    fn default() -> Self {
+        Self {
+            rate: default_rate(),
+            channels: default_channels(),
+        }
+    }
+}
+
+/// Card and device number of a capture pcm node, e.g. pcmC1D0c
+fn parse_capture_node(name: &str) -> Option<(u32, u32)> {
+    let (card, device) = name
+        .strip_prefix("pcmC")?
+        .strip_suffix('c')?
+        .split_once('D')?;
+    Some((card.parse().ok()?, device.parse().ok()?))
+}
+
+#[instrument(skip(parameters, server))]
+pub async fn start_provider(name: String, parameters: Option<serde_yaml::Value>, server: Server) {
+    let parameters: AlsaParameters = parameters
+        .map(|p| serde_yaml::from_value(p).unwrap())
+        .unwrap_or_default();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let mut registrations = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("sound").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                let Some(node) = device
+                    .devnode()
+                    .and_then(|n| n.file_name())
+                    .map(|n| n.to_string_lossy().into_owned())
+                else {
+                    continue;
+                };
+                let Some((card, pcm)) = parse_capture_node(&node) else {
+                    continue;
+                };
+                debug!("Found capture device {}", node);
+
+                let mut properties = device.properties(&node);
+                properties.extend(provider_properties);
+                properties.insert(format!("{PROVIDER}.card"), card.to_string());
+                properties.insert(format!("{PROVIDER}.device"), pcm.to_string());
+                // The card id is the stable name of the card, e.g. HDMI or Device
+                if let Some(id) = device
+                    .parent()
+                    .and_then(|p| p.udev_device().attribute_value("id").map(ToOwned::to_owned))
+                {
+                    properties.insert(format!("{PROVIDER}.card_id"), id.to_string_lossy());
+                }
+
+                let capture = AlsaCapture::new(
+                    format!("plughw:{card},{pcm}"),
+                    parameters.rate,
+                    parameters.channels,
+                );
+                let id = server.register_audio(properties, capture);
+                registrations.insert(device.syspath().to_path_buf(), id);
+            }
+            DeviceEvent::Remove(device) => {
+                if let Some(id) = registrations.remove(device.syspath()) {
+                    server.unregister_audio(id);
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AlsaCapture {
+    device: String,
+    rate: u32,
+    channels: u32,
+    /// Captured data shared by all streams; None once the capture ended
+    output: broadcast::Sender<Option<Bytes>>,
+    /// Whether arecord is running; The pcm can only be opened once, so all streams share it
+    running: Arc<Mutex<bool>>,
+}
+
+impl AlsaCapture {
+    fn new(device: String, rate: u32, channels: u32) -> Self {
+        Self {
+            device,
+            rate,
+            channels,
+            output: broadcast::channel(64).0,
+            running: Default::default(),
+        }
+    }
+
+    /// Capture for as long as there are streams
+    async fn run(
+        mut command: Command,
+        output: broadcast::Sender<Option<Bytes>>,
+        running: Arc<Mutex<bool>>,
+    ) {
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run arecord: {}", e);
+                let mut running = running.lock().unwrap();
+                *running = false;
+                let _ = output.send(None);
+                return;
+            }
+        };
+        let mut stdout = child.stdout.take().unwrap();
+        let mut buf = BytesMut::with_capacity(4096);
+        loop {
+            buf.reserve(4096);
+            let read = stdout.read_buf(&mut buf).await;
+            let mut running = running.lock().unwrap();
+            match read {
+                Ok(0) | Err(_) => {
+                    warn!("Audio capture ended");
+                    *running = false;
+                    let _ = output.send(None);
+                    break;
+                }
+                Ok(_) => {
+                    if output.send(Some(buf.split().freeze())).is_err() {
+                        // No streams left
+                        *running = false;
+                        break;
+                    }
+                }
+            }
+        }
+        // Dropping the child kills arecord
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Audio for AlsaCapture {
+    async fn capture(
+        &self,
+    ) -> Result<(AudioInfo, BoxStream<'static, Result<Bytes, AudioError>>), AudioError> {
+        let receiver = {
+            let mut running = self.running.lock().unwrap();
+            let receiver = self.output.subscribe();
+            if !*running {
+                let mut command = Command::new("arecord");
+                command
+                    .args(["-q", "-t", "raw", "-f", "S16_LE"])
+                    .arg("-D")
+                    .arg(&self.device)
+                    .arg("-r")
+                    .arg(self.rate.to_string())
+                    .arg("-c")
+                    .arg(self.channels.to_string())
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true);
+                tokio::spawn(Self::run(
+                    command,
+                    self.output.clone(),
+                    self.running.clone(),
+                ));
+                *running = true;
+            }
+            receiver
+        };
+
+        let info = AudioInfo {
+            rate: self.rate,
+            channels: self.channels,
+            ..Default::default()
+        };
+        let stream = BroadcastStream::new(receiver)
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(data),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("Audio capture lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .take_while(|data| futures::future::ready(data.is_some()))
+            .filter_map(|data| async move { data.map(Ok) })
+            .boxed();
+        Ok((info, stream))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_node() {
+        assert_eq!(parse_capture_node("pcmC1D0c"), Some((1, 0)));
+        assert_eq!(parse_capture_node("pcmC0D12c"), Some((0, 12)));
+        assert_eq!(parse_capture_node("pcmC0D3p"), None);
+        assert_eq!(parse_capture_node("controlC0"), None);
+    }
+}
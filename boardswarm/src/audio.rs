@@ -0,0 +1,61 @@
+// Encoding of captured audio for clients
+use std::process::Stdio;
+
+use boardswarm_protocol::AudioEncoding;
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::{AudioError, AudioInfo};
+
+type AudioData = BoxStream<'static, Result<Bytes, AudioError>>;
+
+/// Encode pcm data to an ogg opus stream using opusenc
+pub fn encode_opus(
+    info: AudioInfo,
+    mut data: AudioData,
+) -> Result<(AudioInfo, AudioData), AudioError> {
+    let mut child = Command::new("opusenc")
+        .args(["--quiet", "--raw", "--raw-bits", "16"])
+        .arg("--raw-rate")
+        .arg(info.rate.to_string())
+        .arg("--raw-chan")
+        .arg(info.channels.to_string())
+        .args(["-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| AudioError::Unavailable(format!("Failed to run opusenc: {e}")))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    tokio::spawn(async move {
+        while let Some(Ok(pcm)) = data.next().await {
+            if stdin.write_all(&pcm).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let stdout = child.stdout.take().unwrap();
+    // Keep the child alive with the stream such that it gets killed once the client goes away
+    let encoded = futures::stream::unfold((child, stdout), |(child, mut stdout)| async move {
+        let mut buf = BytesMut::with_capacity(4096);
+        match stdout.read_buf(&mut buf).await {
+            Ok(0) => None,
+            Ok(_) => Some((Ok(buf.freeze()), (child, stdout))),
+            Err(e) => {
+                warn!("Failed to read encoded audio: {}", e);
+                None
+            }
+        }
+    })
+    .boxed();
+
+    let mut info = info;
+    info.set_encoding(AudioEncoding::OggOpus);
+    Ok((info, encoded))
+}
@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use jwt_authorizer::{
+    Authorizer, JwtAuthorizer, Refresh, RefreshStrategy, RegisteredClaims, Validation,
+};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::config::{self, Role};
+
+/// RPCs callers with the read-only role are allowed to use
+const READ_ONLY: &[&str] = &[
+    "LoginInfo",
+    "List",
+    "Monitor",
+    "ItemProperties",
+    "ItemRemoved",
+    "Topology",
+    "DeviceInfo",
+    "DeviceInfoChanges",
+    "DeviceRecord",
+    "DeviceConsoleOutput",
+    "ConsoleStreamOutput",
+    "ConsoleExpect",
+    "VolumeInfo",
+    "ImageCacheLookup",
+    "AudioStream",
+    "SessionList",
+    "DeviceReservation",
+    "DeviceWaitFor",
+    "ConsoleHandleList",
+    "GroupList",
+    "TaskList",
+];
+
+/// Authenticated caller; Available from the extensions of each authenticated request
+#[derive(Clone, Debug)]
+pub struct Identity {
+    /// Name of the token or subject of the jwt, if any
+    pub name: Option<String>,
+    pub role: Role,
+    /// Highest priority the caller may queue for devices with
+    pub max_priority: i32,
+}
+
+impl Role {
+    /// Whether the role allows calling the given RPC
+    pub fn allows(self, rpc: &str) -> bool {
+        match self {
+            Role::ReadOnly => READ_ONLY.contains(&rpc),
+            Role::Operator => true,
+        }
+    }
+}
+
+/// Role granted to callers without a token; Only set on requests coming in on the unix socket,
+/// where filesystem permissions control access
+#[derive(Clone, Copy, Debug)]
+pub struct LocalRole(pub Role);
+
+enum Method {
+    Token {
+        name: String,
+        /// Sha256 digest of the token; Compared against the digest of presented tokens such
+        /// that the comparison time doesn't depend on how much of the token got guessed
+        digest: [u8; 32],
+        role: Role,
+        max_priority: i32,
+    },
+    Jwt {
+        authorizer: Authorizer<RegisteredClaims>,
+        role: Role,
+        max_priority: i32,
+    },
+}
+
+pub struct Auth {
+    methods: Vec<Method>,
+}
+
+impl Auth {
+    pub async fn from_config(config: &[config::Authentication]) -> anyhow::Result<Self> {
+        let mut methods = Vec::new();
+        for auth in config {
+            let method = match auth {
+                config::Authentication::Oidc {
+                    uri,
+                    audience,
+                    role,
+                    max_priority,
+                    refresh,
+                    ..
+                } => {
+                    let v = Validation::new().aud(audience);
+                    let mut builder =
+                        JwtAuthorizer::<RegisteredClaims>::from_oidc(uri).validation(v);
+                    if let Some(interval) = refresh {
+                        builder = builder.refresh(Refresh {
+                            strategy: RefreshStrategy::Interval,
+                            refresh_interval: *interval,
+                            ..Default::default()
+                        });
+                    }
+                    let authorizer = builder
+                        .build()
+                        .await
+                        .context(format!("Failed to set up OIDC authentication for {}", uri))?;
+                    Method::Jwt {
+                        authorizer,
+                        role: *role,
+                        max_priority: *max_priority,
+                    }
+                }
+                config::Authentication::Jwks {
+                    path,
+                    role,
+                    max_priority,
+                } => {
+                    let authorizer =
+                        JwtAuthorizer::<RegisteredClaims>::from_jwks(path.to_str().unwrap())
+                            .build()
+                            .await
+                            .context(format!("Failed to load jwks file {}", path.display()))?;
+                    Method::Jwt {
+                        authorizer,
+                        role: *role,
+                        max_priority: *max_priority,
+                    }
+                }
+                config::Authentication::Token {
+                    name,
+                    token,
+                    role,
+                    max_priority,
+                } => Method::Token {
+                    name: name.clone(),
+                    digest: Sha256::digest(token).into(),
+                    role: *role,
+                    max_priority: *max_priority,
+                },
+            };
+            methods.push(method);
+        }
+        Ok(Self { methods })
+    }
+
+    async fn identify(&self, bearer: &str) -> Option<Identity> {
+        let bearer_digest: [u8; 32] = Sha256::digest(bearer).into();
+        for method in &self.methods {
+            match method {
+                Method::Token {
+                    name,
+                    digest,
+                    role,
+                    max_priority,
+                } if digests_match(digest, &bearer_digest) => {
+                    return Some(Identity {
+                        name: Some(name.clone()),
+                        role: *role,
+                        max_priority: *max_priority,
+                    })
+                }
+                Method::Token { .. } => (),
+                Method::Jwt {
+                    authorizer,
+                    role,
+                    max_priority,
+                } => match authorizer.check_auth(bearer).await {
+                    Ok(data) => {
+                        return Some(Identity {
+                            name: data.claims.sub,
+                            role: *role,
+                            max_priority: *max_priority,
+                        })
+                    }
+                    Err(e) => debug!("Token rejected: {}", e),
+                },
+            }
+        }
+        None
+    }
+}
+
+/// Compare two digests in constant time
+fn digests_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Middleware rejecting requests without a valid bearer token or whose role doesn't allow the
+/// called RPC
+pub async fn authenticate(
+    State(auth): State<Arc<Auth>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let bearer = request
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(ToOwned::to_owned);
+    let identity = match bearer {
+        Some(bearer) => auth.identify(&bearer).await,
+        None => request
+            .extensions()
+            .get::<LocalRole>()
+            .map(|LocalRole(role)| Identity {
+                name: None,
+                role: *role,
+                max_priority: 0,
+            }),
+    };
+    let Some(identity) = identity else {
+        return tonic::Status::unauthenticated("Missing or invalid token")
+            .into_http()
+            .map(axum::body::Body::new);
+    };
+
+    let rpc = request.uri().path().rsplit('/').next().unwrap_or_default();
+    if !identity.role.allows(rpc) {
+        return tonic::Status::permission_denied(format!("Not allowed to call {}", rpc))
+            .into_http()
+            .map(axum::body::Body::new);
+    }
+
+    request.extensions_mut().insert(identity);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roles() {
+        assert!(Role::ReadOnly.allows("ConsoleStreamOutput"));
+        assert!(!Role::ReadOnly.allows("ConsoleStreamInput"));
+        assert!(!Role::ReadOnly.allows("DeviceChangeMode"));
+        assert!(Role::Operator.allows("DeviceChangeMode"));
+    }
+
+    #[tokio::test]
+    async fn tokens() {
+        let auth = Auth::from_config(&[config::Authentication::Token {
+            name: "ci".to_string(),
+            token: "secret".to_string(),
+            role: Role::ReadOnly,
+            max_priority: 5,
+        }])
+        .await
+        .unwrap();
+        let identity = auth.identify("secret").await.unwrap();
+        assert_eq!(identity.name.as_deref(), Some("ci"));
+        assert_eq!(identity.role, Role::ReadOnly);
+        assert_eq!(identity.max_priority, 5);
+        assert!(auth.identify("secre").await.is_none());
+        assert!(auth.identify("secret2").await.is_none());
+        assert!(auth.identify("").await.is_none());
+    }
+}
@@ -0,0 +1,64 @@
+use std::pin::Pin;
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, Sink, StreamExt};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::warn;
+
+use crate::ConsoleError;
+
+pub const PROVIDER: &str = "client";
+
+/// Console registered at runtime by a client; The client feeds the output, which is available
+/// to all other subscribers
+#[derive(Debug)]
+pub struct ClientConsole {
+    output: broadcast::Sender<Bytes>,
+}
+
+impl ClientConsole {
+    pub fn new() -> Self {
+        Self {
+            output: broadcast::channel(64).0,
+        }
+    }
+
+    pub fn sender(&self) -> broadcast::Sender<Bytes> {
+        self.output.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Console for ClientConsole {
+    fn configure(
+        &self,
+        _parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        Ok(())
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        Err(ConsoleError::Unavailable(
+            "Console only accepts data from its client".to_string(),
+        ))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("Client console output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
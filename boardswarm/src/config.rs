@@ -9,18 +9,233 @@ use anyhow::Result;
 use serde::Deserialize;
 use tracing::info;
 
+use crate::property_match::PropertyMatch;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server: Server,
     pub providers: Vec<Provider>,
+    #[serde(default)]
+    pub overrides: Vec<Override>,
     pub devices: Vec<Device>,
+    /// Named operations that can be run on any device in a single call
+    #[serde(default)]
+    pub tasks: Vec<Task>,
 }
 
 #[derive(Default, Debug, Deserialize)]
 pub struct Server {
-    pub listen: Option<String>,
+    /// Name identifying this server, e.g. the host it runs on
+    pub name: Option<String>,
+    /// Addresses to listen on, either a single one or a list
+    #[serde(default)]
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<String>,
+    pub interface: Option<String>,
+    /// Addresses other servers and clients can reach this server at, either a single one or a
+    /// list; Needed when the listen addresses are wildcard, link-local or otherwise not reachable
+    /// as is, e.g. behind NAT or a tunnel
+    #[serde(default)]
+    #[serde(deserialize_with = "one_or_many")]
+    pub advertise: Vec<String>,
+    /// Unix socket to serve on; Only served on in addition to TCP when listen addresses are
+    /// configured as well
+    pub unix: Option<UnixSocket>,
     pub certificate: Option<Certificate>,
     pub authentication: Vec<Authentication>,
+    #[serde(default)]
+    pub tunnels: Vec<Tunnel>,
+    #[serde(default)]
+    pub channels: Channels,
+    /// Default stabilisation after actuator steps, by provider type
+    #[serde(default)]
+    pub stabilisation: HashMap<String, humantime_serde::Serde<Duration>>,
+    #[serde(default)]
+    pub recording: Recording,
+    #[serde(default)]
+    pub upload_limit: UploadLimit,
+    /// Maximum number of uploads running at once, by provider type; Others queue until one
+    /// finishes
+    #[serde(default)]
+    pub upload_slots: HashMap<String, usize>,
+    /// Maximum number of heavy provider jobs (e.g. decompressing uploads) running at once;
+    /// Defaults to one less than the number of cpus
+    pub workers: Option<usize>,
+    /// Bytes of recent output kept per console, replayed to clients on request; 0 disables
+    /// keeping history
+    #[serde(default = "default_console_history")]
+    pub console_history: usize,
+    /// Write the output of all device consoles to log files
+    pub console_log: Option<ConsoleLog>,
+    /// Allow clients to have the server download images to write to volumes
+    pub fetch: Option<Fetch>,
+    /// Keep verified images written to volumes for writing them again without a transfer
+    pub image_cache: Option<ImageCache>,
+    /// Directory to keep device modes, counters and sessions in across restarts, relative to the
+    /// configuration file
+    pub state: Option<PathBuf>,
+}
+
+fn default_console_history() -> usize {
+    64 * 1024
+}
+
+/// Rotating on-disk logs of console output
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConsoleLog {
+    /// Directory to write the logs to, relative to the configuration file; Logs are stored in a
+    /// subdirectory per device
+    pub directory: PathBuf,
+    /// Start a new log file once the current one reaches this size in bytes
+    pub max_size: Option<u64>,
+    /// Start a new log file once the current one has been written to for this long
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+    /// Number of log files to keep per console
+    #[serde(default = "default_console_log_keep")]
+    pub keep: usize,
+}
+
+fn default_console_log_keep() -> usize {
+    5
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+fn sha256<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let Some(hex) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let invalid = || serde::de::Error::custom("expected 64 hexadecimal characters");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut digest = [0; 32];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(Some(digest))
+}
+
+/// Bandwidth caps for data uploaded to volumes, in bytes per second
+#[derive(Clone, Default, Debug, Deserialize)]
+pub struct UploadLimit {
+    /// Cap for each upload
+    pub upload: Option<u64>,
+    /// Cap for all uploads combined
+    pub global: Option<u64>,
+}
+
+/// Downloading of images by the server on behalf of clients
+#[derive(Clone, Debug, Deserialize)]
+pub struct Fetch {
+    /// Url prefixes images can be downloaded from, e.g. a local mirror
+    pub allow: Vec<String>,
+}
+
+/// Images kept by the server, keyed by their SHA-256
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageCache {
+    /// Directory to keep the images in, relative to the configuration file
+    pub directory: PathBuf,
+    /// Maximum total size of the images in bytes; The least recently used are removed first
+    pub max_size: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Recording {
+    /// Collapse identical console lines repeated within this window
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub dedup: Option<Duration>,
+    /// Bytes of console data kept per recorded session; The oldest events are dropped beyond that
+    #[serde(default = "default_session_size")]
+    pub session_size: usize,
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self {
+            dedup: None,
+            session_size: default_session_size(),
+        }
+    }
+}
+
+fn default_session_size() -> usize {
+    16 * 1024 * 1024
+}
+
+/// Sizes of the internal change notification channels
+///
+/// Monitors that fall further behind than this resync from scratch
+#[derive(Clone, Debug, Deserialize)]
+pub struct Channels {
+    /// Number of item changes buffered for registry monitors
+    #[serde(default = "default_registry_capacity")]
+    pub registry: usize,
+    /// Number of updates buffered for device monitors
+    #[serde(default = "default_device_capacity")]
+    pub device: usize,
+}
+
+fn default_registry_capacity() -> usize {
+    crate::registry::DEFAULT_CAPACITY
+}
+
+fn default_device_capacity() -> usize {
+    1
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self {
+            registry: default_registry_capacity(),
+            device: default_device_capacity(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tunnel {
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity: Option<PathBuf>,
+    pub remote: String,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub retry: Option<Duration>,
+}
+
+/// Level of access granted to an authenticated caller
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Only allowed to observe, e.g. list items and stream console output
+    ReadOnly,
+    /// Allowed to do anything, e.g. change modes and write volumes
+    #[default]
+    Operator,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -32,15 +247,54 @@ pub enum Authentication {
         uri: String,
         client: String,
         audience: Vec<String>,
+        #[serde(default)]
+        role: Role,
+        #[serde(default)]
+        max_priority: i32,
+        /// Interval to refresh the cached keys of the issuer; By default keys are only fetched
+        /// again when a token is signed by an unknown key
+        #[serde(default)]
+        #[serde(with = "humantime_serde")]
+        refresh: Option<Duration>,
     },
     #[serde(rename = "jwks")]
-    Jwks { path: PathBuf },
+    Jwks {
+        path: PathBuf,
+        #[serde(default)]
+        role: Role,
+        #[serde(default)]
+        max_priority: i32,
+    },
+    /// Static bearer token
+    #[serde(rename = "token")]
+    Token {
+        /// Name identifying the user of the token
+        name: String,
+        token: String,
+        #[serde(default)]
+        role: Role,
+        /// Highest priority users may queue for devices with; Requests beyond it are capped
+        #[serde(default)]
+        max_priority: i32,
+    },
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Certificate {
     pub chain: PathBuf,
     pub key: PathBuf,
+    /// CA certificates used to verify client certificates; If set clients have to present a valid
+    /// certificate to connect
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    /// Permissions of the socket, e.g. 0o660
+    pub mode: Option<u32>,
+    /// Role of callers on the socket not presenting a token; If unset tokens are required as usual
+    pub role: Option<Role>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,6 +302,25 @@ pub struct Provider {
     pub name: String,
     pub provider: String,
     pub parameters: Option<serde_yaml::Value>,
+    /// Default stabilisation after steps using actuators of this provider
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub stabilisation: Option<Duration>,
+    /// Maximum number of uploads to volumes of this provider running at once; Takes precedence
+    /// over the limit of the provider type
+    pub max_uploads: Option<usize>,
+}
+
+/// Extra properties and/or a new name for provider items matching the given properties
+#[derive(Clone, Debug, Deserialize)]
+pub struct Override {
+    #[serde(rename = "match")]
+    pub match_: HashMap<String, PropertyMatch>,
+    pub name: Option<String>,
+    /// Stable identity of the matching item, taking precedence over the derived one
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,6 +330,184 @@ pub struct Device {
     pub modes: Vec<Mode>,
     #[serde(default)]
     pub volumes: Vec<Volume>,
+    #[serde(default)]
+    pub selftest: Vec<SelfTestStep>,
+    /// Probes checking the device still works
+    pub health: Option<Health>,
+    /// Unattended recovery when the device stops working
+    pub recovery: Option<Recovery>,
+    /// Mode to switch the device to once a session reserving it ends
+    pub safe_mode: Option<String>,
+    /// Order in which callers queued for the device get it
+    #[serde(default)]
+    pub queue: crate::session::QueueOrder,
+    /// Names of the users (token names or jwt subjects) allowed to use the device; Everyone is
+    /// allowed if not set
+    pub access: Option<Vec<String>>,
+    /// Groups the device is a member of, for operating on all members at once
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Free-form labels added to the device properties, e.g. its SoC, memory size or owner
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Sensor measuring the power drawn by the device
+    pub power: Option<PowerMeter>,
+}
+
+/// Health probes of a device, run on demand and optionally periodically
+#[derive(Debug, Deserialize)]
+pub struct Health {
+    /// Time between health checks; Only run on demand if not set
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub interval: Option<Duration>,
+    pub probes: Vec<SelfTestStep>,
+}
+
+/// Recovery of a wedged device, triggered by failing health checks or console inactivity
+#[derive(Debug, Deserialize)]
+pub struct Recovery {
+    /// Modes to switch the device through, e.g. a mode re-flashing a known-good image followed by
+    /// the mode the device normally runs in
+    pub modes: Vec<String>,
+    /// Consecutive attempts before giving up, until the device is seen working again
+    #[serde(default = "default_recovery_attempts")]
+    pub attempts: u32,
+    /// Recover once the console was silent for this long while the device is in the last of the
+    /// recovery modes
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub inactivity: Option<Duration>,
+    /// Name of the device console watched for inactivity; Defaults to the first console
+    pub console: Option<String>,
+}
+
+fn default_recovery_attempts() -> u32 {
+    3
+}
+
+/// Sequence of steps run on a device on request; Modes, volumes and consoles are referred to by
+/// their name on the device the task runs on
+#[derive(Debug, Deserialize)]
+pub struct Task {
+    pub name: String,
+    pub description: Option<String>,
+    pub steps: Vec<TaskStep>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TaskStep {
+    Mode(TaskModeStep),
+    Fetch(TaskFetchStep),
+    Console(TaskConsoleStep),
+    Sleep(SleepStep),
+}
+
+/// Switches the device to a mode
+#[derive(Debug, Deserialize)]
+pub struct TaskModeStep {
+    pub mode: String,
+}
+
+/// Has the server download an image and write it to a volume target of the device
+#[derive(Debug, Deserialize)]
+pub struct TaskFetchStep {
+    pub fetch: TaskFetch,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TaskFetch {
+    /// Name of the device volume
+    pub volume: String,
+    pub target: String,
+    pub url: String,
+    /// Expected SHA-256 of the image in hex
+    #[serde(default, deserialize_with = "sha256")]
+    pub sha256: Option<[u8; 32]>,
+    /// Whether the image is an android sparse image
+    #[serde(default)]
+    pub sparse: bool,
+    pub bmap_url: Option<String>,
+    /// Commit the volume once the image is written
+    #[serde(default)]
+    pub commit: bool,
+}
+
+/// Sends to a device console and waits for a pattern in its output; Unlike console steps of
+/// modes the task fails if the pattern doesn't show up
+#[derive(Debug, Deserialize)]
+pub struct TaskConsoleStep {
+    /// Name of the device console to use
+    pub console: String,
+    /// Data to send to the console
+    pub send: Option<String>,
+    /// Regular expression to wait for in the console output after sending
+    pub expect: Option<String>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+}
+
+/// Power sensor of a device, sampled periodically
+#[derive(Debug, Deserialize)]
+pub struct PowerMeter {
+    #[serde(flatten)]
+    pub source: PowerSource,
+    /// Time between samples
+    #[serde(default = "default_power_interval")]
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+fn default_power_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerSource {
+    /// Linux hwmon power sensor, e.g. of an INA219 on the power rail of the device
+    Hwmon {
+        /// Name of the hwmon device (as in /sys/class/hwmon/*/name) or path of its directory
+        hwmon: String,
+        /// Power channel of the device
+        #[serde(default = "default_power_sensor")]
+        sensor: String,
+    },
+    /// Command printing the current reading, e.g. snmpget querying the outlet power of a PDU
+    Command {
+        command: Vec<String>,
+        /// Watts per unit of the reading
+        #[serde(default = "default_power_scale")]
+        scale: f64,
+    },
+}
+
+fn default_power_sensor() -> String {
+    "power1".to_string()
+}
+
+fn default_power_scale() -> f64 {
+    1.0
+}
+
+/// Mode switch done as part of a device self-test or health probe
+#[derive(Debug, Deserialize)]
+pub struct SelfTestStep {
+    /// Mode to switch the device into
+    pub mode: String,
+    /// Name of the device console to check; Defaults to the first console
+    pub console: Option<String>,
+    /// Regular expression expected in the console output after switching mode; If only a
+    /// console is given any output will do
+    pub expect: Option<String>,
+    /// Name of a device volume expected to show up after switching mode, e.g. the uploader of a
+    /// recovery mode
+    pub volume: Option<String>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -65,15 +516,27 @@ pub struct Console {
     #[serde(default)]
     pub default: bool,
     pub parameters: serde_yaml::Value,
+    /// Filters applied to the output streamed to clients, unless they request otherwise
+    #[serde(default)]
+    pub filters: Vec<crate::console_filter::Filter>,
+    /// Pacing of input sent to the console by clients and device steps
+    #[serde(default)]
+    pub input: crate::console_input::InputPacing,
+    /// Expose the console through a stable wrapper which follows the matching console across
+    /// hotplug, such that attached clients survive e.g. a usb serial adapter re-enumerating
+    #[serde(default)]
+    pub persistent: bool,
     #[serde(rename = "match")]
-    pub match_: HashMap<String, String>,
+    pub match_: HashMap<String, PropertyMatch>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Volume {
     pub name: String,
     #[serde(rename = "match")]
-    pub match_: HashMap<String, String>,
+    pub match_: HashMap<String, PropertyMatch>,
+    /// Mode the device needs to be in for the volume to be used
+    pub mode: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -81,22 +544,435 @@ pub struct Mode {
     pub name: String,
     pub depends: Option<String>,
     pub sequence: Vec<ModeStep>,
+    /// Steps run when the sequence fails partway, to leave the device in a known state (e.g.
+    /// powered off)
+    #[serde(default)]
+    pub rollback: Vec<ModeStep>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct ModeStep {
+#[serde(untagged)]
+pub enum ModeStep {
+    Actuator(ActuatorStep),
+    // Boot steps name a console as well, so have to be tried before plain console steps
+    Boot(BootStep),
+    Console(ConsoleStep),
+    Wait(WaitStep),
+    Sleep(SleepStep),
+}
+
+impl ModeStep {
+    pub fn stabilisation(&self) -> Option<Duration> {
+        match self {
+            ModeStep::Actuator(a) => a.stabilisation,
+            ModeStep::Console(c) => c.stabilisation,
+            ModeStep::Boot(b) => b.stabilisation,
+            ModeStep::Wait(w) => w.stabilisation,
+            ModeStep::Sleep(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActuatorStep {
     #[serde(rename = "match")]
-    pub match_: HashMap<String, String>,
+    pub match_: HashMap<String, PropertyMatch>,
     pub parameters: serde_yaml::Value,
     #[serde(default)]
     #[serde(with = "humantime_serde")]
     pub stabilisation: Option<Duration>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ConsoleStep {
+    /// Name of the device console to use
+    pub console: String,
+    /// Data to send to the console
+    pub send: Option<String>,
+    /// Regular expression to wait for in the console output after sending
+    pub expect: Option<String>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub stabilisation: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WaitItem {
+    Actuator,
+    Console,
+    Volume,
+}
+
+impl std::fmt::Display for WaitItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitItem::Actuator => write!(f, "actuator"),
+            WaitItem::Console => write!(f, "console"),
+            WaitItem::Volume => write!(f, "volume"),
+        }
+    }
+}
+
+/// Waits for an item to be registered, e.g. a volume appearing once the device powered on in its
+/// recovery mode
+#[derive(Debug, Deserialize)]
+pub struct WaitStep {
+    /// Type of the item to wait for
+    pub wait: WaitItem,
+    #[serde(rename = "match")]
+    pub match_: HashMap<String, PropertyMatch>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub stabilisation: Option<Duration>,
+}
+
+/// Waits for a fixed time
+#[derive(Debug, Deserialize)]
+pub struct SleepStep {
+    #[serde(with = "humantime_serde")]
+    pub sleep: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootLoader {
+    UBoot,
+    UefiShell,
+    Custom,
+}
+
+/// Interrupts a bootloader over the console and runs commands at its prompt
+#[derive(Debug, Deserialize)]
+pub struct BootStep {
+    pub boot: BootLoader,
+    /// Name of the device console to use
+    pub console: String,
+    /// Regular expression for the output signalling the boot can be interrupted
+    pub interrupt: Option<String>,
+    /// Keys to send to interrupt the boot
+    pub keys: Option<String>,
+    /// Regular expression matching the bootloader prompt
+    pub prompt: Option<String>,
+    /// Commands to run at the prompt
+    #[serde(default)]
+    pub commands: Vec<String>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub stabilisation: Option<Duration>,
+}
+
+impl Device {
+    /// Check the mode dependencies; Returns a description of each problem found
+    pub fn mode_errors(&self) -> Vec<String> {
+        let modes: HashMap<&str, &Mode> = self.modes.iter().map(|m| (m.name.as_str(), m)).collect();
+        let mut errors = Vec::new();
+
+        for mode in &self.modes {
+            if let Some(depends) = &mode.depends {
+                if !modes.contains_key(depends.as_str()) {
+                    errors.push(format!(
+                        "device {}: mode {} depends on unknown mode {}",
+                        self.name, mode.name, depends
+                    ));
+                }
+            }
+        }
+
+        for (i, volume) in self.volumes.iter().enumerate() {
+            if let Some(mode) = &volume.mode {
+                if !modes.contains_key(mode.as_str()) {
+                    errors.push(format!(
+                        "device {}: volume {} requires unknown mode {}",
+                        self.name, volume.name, mode
+                    ));
+                }
+            }
+            // Volumes sharing a name are bound depending on the mode, so each needs its own mode
+            let clash = self.volumes[..i].iter().any(|v| {
+                v.name == volume.name
+                    && (v.mode.is_none() || volume.mode.is_none() || v.mode == volume.mode)
+            });
+            if clash {
+                errors.push(format!(
+                    "device {}: volume {} is listed multiple times without distinct modes",
+                    self.name, volume.name
+                ));
+            }
+        }
+
+        for probe in self.health.iter().flat_map(|h| &h.probes) {
+            if !modes.contains_key(probe.mode.as_str()) {
+                errors.push(format!(
+                    "device {}: health probe uses unknown mode {}",
+                    self.name, probe.mode
+                ));
+            }
+            if let Some(volume) = &probe.volume {
+                if !self.volumes.iter().any(|v| &v.name == volume) {
+                    errors.push(format!(
+                        "device {}: health probe expects unknown volume {}",
+                        self.name, volume
+                    ));
+                }
+            }
+        }
+
+        if let Some(recovery) = &self.recovery {
+            for mode in &recovery.modes {
+                if !modes.contains_key(mode.as_str()) {
+                    errors.push(format!(
+                        "device {}: recovery uses unknown mode {}",
+                        self.name, mode
+                    ));
+                }
+            }
+            if let Some(console) = &recovery.console {
+                if !self.consoles.iter().any(|c| &c.name == console) {
+                    errors.push(format!(
+                        "device {}: recovery watches unknown console {}",
+                        self.name, console
+                    ));
+                }
+            }
+        }
+
+        for label in self
+            .labels
+            .keys()
+            .filter(|l| l.starts_with(crate::registry::RESERVED))
+        {
+            errors.push(format!(
+                "device {}: label {} uses the reserved {} prefix",
+                self.name,
+                label,
+                crate::registry::RESERVED
+            ));
+        }
+
+        if let Some(PowerMeter {
+            source: PowerSource::Command { command, .. },
+            ..
+        }) = &self.power
+        {
+            if command.is_empty() {
+                errors.push(format!("device {}: power command is empty", self.name));
+            }
+        }
+
+        if let Some(mode) = &self.safe_mode {
+            if !modes.contains_key(mode.as_str()) {
+                errors.push(format!(
+                    "device {}: safe mode {} is unknown",
+                    self.name, mode
+                ));
+            }
+        }
+
+        // Follow the dependencies of each mode until a mode without dependencies; Modes whose
+        // dependencies loop can never be reached
+        for mode in &self.modes {
+            let mut chain = vec![mode.name.as_str()];
+            let mut current = mode;
+            while let Some(next) = current.depends.as_ref().and_then(|d| modes.get(d.as_str())) {
+                if let Some(pos) = chain.iter().position(|m| *m == next.name) {
+                    let cycle = chain[pos..].join(" -> ");
+                    if pos == 0 {
+                        errors.push(format!(
+                            "device {}: mode {} is part of a dependency cycle: {} -> {}",
+                            self.name, mode.name, cycle, next.name
+                        ));
+                    } else {
+                        errors.push(format!(
+                            "device {}: mode {} is unreachable as it depends on the cycle: {} -> {}",
+                            self.name, mode.name, cycle, next.name
+                        ));
+                    }
+                    break;
+                }
+                chain.push(&next.name);
+                current = next;
+            }
+        }
+
+        errors
+    }
+}
+
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
         info!("Loading configuration file {}", path.as_ref().display());
         let file = std::fs::File::open(path)?;
-        Ok(serde_yaml::from_reader(file)?)
+        let config: Config = serde_yaml::from_reader(file)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut errors: Vec<String> = self.devices.iter().flat_map(Device::mode_errors).collect();
+        for (i, task) in self.tasks.iter().enumerate() {
+            if self.tasks[..i].iter().any(|t| t.name == task.name) {
+                errors.push(format!("task {} is defined multiple times", task.name));
+            }
+            for step in &task.steps {
+                if let TaskStep::Console(TaskConsoleStep {
+                    expect: Some(expect),
+                    ..
+                }) = step
+                {
+                    if let Err(e) = regex::bytes::Regex::new(expect) {
+                        errors.push(format!("task {}: invalid pattern: {}", task.name, e));
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid configuration:\n{}", errors.join("\n"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn example_config() {
+        let config =
+            Config::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/share/server.conf")).unwrap();
+        let boot = config
+            .devices
+            .iter()
+            .flat_map(|d| &d.modes)
+            .flat_map(|m| &m.sequence)
+            .find_map(|s| match s {
+                ModeStep::Boot(b) => Some(b),
+                _ => None,
+            })
+            .unwrap();
+        assert!(matches!(boot.boot, BootLoader::UBoot));
+        assert_eq!(boot.commands, vec!["run bootcmd_usb0".to_string()]);
+        assert!(config
+            .devices
+            .iter()
+            .flat_map(|d| &d.modes)
+            .flat_map(|m| m.sequence.iter().chain(&m.rollback))
+            .any(|s| matches!(s, ModeStep::Console(c) if c.console == "main")));
+    }
+
+    fn device(modes: &[(&str, Option<&str>)]) -> Device {
+        Device {
+            name: "test".to_string(),
+            consoles: vec![],
+            modes: modes
+                .iter()
+                .map(|(name, depends)| Mode {
+                    name: name.to_string(),
+                    depends: depends.map(ToString::to_string),
+                    sequence: vec![],
+                    rollback: vec![],
+                })
+                .collect(),
+            volumes: vec![],
+            selftest: vec![],
+            health: None,
+            recovery: None,
+            safe_mode: None,
+            queue: Default::default(),
+            access: None,
+            tags: vec![],
+            labels: HashMap::new(),
+            power: None,
+        }
+    }
+
+    #[test]
+    fn volume_bindings() {
+        let volume = |name: &str, mode: Option<&str>| Volume {
+            name: name.to_string(),
+            match_: HashMap::new(),
+            mode: mode.map(ToString::to_string),
+        };
+        let mut d = device(&[("recovery", None), ("bootloader", None)]);
+        d.volumes = vec![
+            volume("flash", Some("recovery")),
+            volume("flash", Some("bootloader")),
+        ];
+        assert!(d.mode_errors().is_empty());
+
+        d.volumes.push(volume("flash", None));
+        d.volumes.push(volume("emmc", Some("recovery")));
+        d.volumes.push(volume("emmc", Some("recovery")));
+        assert_eq!(
+            d.mode_errors(),
+            vec![
+                "device test: volume flash is listed multiple times without distinct modes",
+                "device test: volume emmc is listed multiple times without distinct modes",
+            ]
+        );
+    }
+
+    #[test]
+    fn mode_dependencies() {
+        let valid = device(&[("off", None), ("on", Some("off")), ("boot", Some("on"))]);
+        assert!(valid.mode_errors().is_empty());
+
+        let unknown = device(&[("off", None), ("on", Some("of"))]);
+        assert_eq!(
+            unknown.mode_errors(),
+            vec!["device test: mode on depends on unknown mode of"]
+        );
+
+        let cycle = device(&[("a", Some("b")), ("b", Some("a")), ("c", Some("b"))]);
+        assert_eq!(
+            cycle.mode_errors(),
+            vec![
+                "device test: mode a is part of a dependency cycle: a -> b -> a",
+                "device test: mode b is part of a dependency cycle: b -> a -> b",
+                "device test: mode c is unreachable as it depends on the cycle: b -> a -> b",
+            ]
+        );
+    }
+    #[test]
+    fn task_steps() {
+        let task: Task = serde_yaml::from_str(
+            r#"
+name: provision
+steps:
+  - mode: maskrom
+  - fetch:
+      volume: rockusb
+      target: "0"
+      url: https://example.com/image.img
+      sha256: 00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff00ff
+  - console: main
+    expect: "login:"
+  - sleep: 1s
+"#,
+        )
+        .unwrap();
+        assert!(matches!(&task.steps[0], TaskStep::Mode(m) if m.mode == "maskrom"));
+        let TaskStep::Fetch(fetch) = &task.steps[1] else {
+            panic!("Not a fetch step: {:?}", task.steps[1]);
+        };
+        assert_eq!(fetch.fetch.sha256.unwrap()[..2], [0x00, 0xff]);
+        assert!(!fetch.fetch.commit);
+        assert!(matches!(&task.steps[2], TaskStep::Console(c) if c.console == "main"));
+        assert!(matches!(&task.steps[3], TaskStep::Sleep(_)));
+
+        assert!(
+            serde_yaml::from_str::<TaskFetch>("{volume: a, target: b, url: c, sha256: abc}")
+                .is_err()
+        );
     }
 }
@@ -1,22 +1,99 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
+use boardswarm_protocol::{DeviceModeProgress, DeviceModeStepState, DeviceRecoveryState};
+use bytes::Bytes;
+use futures::{stream::BoxStream, Sink, SinkExt, StreamExt};
+use thiserror::Error;
 use tokio::sync::broadcast;
-use tracing::warn;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
 
 use crate::{
+    config::{
+        BootLoader, BootStep, ConsoleStep, ModeStep, PowerMeter, Recovery, SelfTestStep, WaitItem,
+        WaitStep,
+    },
+    console_output::wait_for,
+    power_meter, recording,
     registry::{self, Properties, RegistryChange},
-    ActuatorError, Console, DeviceConfigItem, DeviceMonitor, DeviceSetModeError, Server,
+    ActuatorError, Console, ConsoleError, DeviceCheck, DeviceConfigItem, DeviceHealth,
+    DeviceMonitor, DevicePower, DeviceSelfTestError, DeviceSetModeError, ModeProgress, Server,
 };
 
+const DEFAULT_CONSOLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time a wait step waits for its item to appear by default
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time input to a persistent console is held back waiting for the console to come back
+const PERSISTENT_INPUT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+enum ConsoleStepError {
+    #[error("Console not available")]
+    NotAvailable,
+    #[error("Invalid pattern: {0}")]
+    Pattern(#[from] regex::Error),
+    #[error(transparent)]
+    Console(#[from] ConsoleError),
+    #[error("Timed out waiting for the expected output")]
+    Timeout,
+    #[error("No {0} pattern configured")]
+    MissingPattern(&'static str),
+}
+
+#[derive(Debug, Error)]
+enum SelfTestError {
+    #[error(transparent)]
+    SetMode(#[from] DeviceSetModeError),
+    #[error(transparent)]
+    Console(#[from] ConsoleStepError),
+    #[error("Volume {0} didn't show up")]
+    VolumeMissing(String),
+}
+
+/// Default console interaction for known bootloaders
+struct BootPreset {
+    interrupt: &'static str,
+    keys: &'static str,
+    prompt: &'static str,
+    newline: &'static str,
+}
+
+fn boot_preset(boot: BootLoader) -> Option<BootPreset> {
+    match boot {
+        BootLoader::UBoot => Some(BootPreset {
+            interrupt: "Hit any key to stop autoboot",
+            keys: " ",
+            prompt: "=> ",
+            newline: "\n",
+        }),
+        BootLoader::UefiShell => Some(BootPreset {
+            interrupt: r"Press ESC in \d+ seconds",
+            keys: "\x1b",
+            prompt: "Shell> ",
+            newline: "\r",
+        }),
+        BootLoader::Custom => None,
+    }
+}
+
 // TODO deal with closing
 struct DeviceNotifier {
     sender: broadcast::Sender<()>,
 }
 
 impl DeviceNotifier {
-    fn new() -> Self {
+    fn new(capacity: usize) -> Self {
         Self {
-            sender: broadcast::channel(1).0,
+            sender: broadcast::channel(capacity.max(1)).0,
         }
     }
 
@@ -34,17 +111,93 @@ struct DeviceMode {
     name: String,
     depends: Option<String>,
     sequence: Vec<DeviceItem<crate::config::ModeStep>>,
+    rollback: Vec<DeviceItem<crate::config::ModeStep>>,
+}
+
+impl DeviceMode {
+    /// Steps of both the sequence and the rollback
+    fn steps(&self) -> impl Iterator<Item = &DeviceItem<crate::config::ModeStep>> + Clone {
+        self.sequence.iter().chain(self.rollback.iter())
+    }
+}
+
+/// Reports the progress of the steps run during a mode change, if anyone is listening
+struct ModeReporter {
+    progress: Option<ModeProgress>,
+    start: Instant,
+}
+
+impl ModeReporter {
+    fn new(progress: Option<ModeProgress>) -> Self {
+        Self {
+            progress,
+            start: Instant::now(),
+        }
+    }
+
+    fn report(
+        &self,
+        mode: &str,
+        step: usize,
+        description: &str,
+        state: DeviceModeStepState,
+        error: Option<String>,
+    ) {
+        if let Some(progress) = &self.progress {
+            let _ = progress.send(DeviceModeProgress {
+                mode: mode.to_string(),
+                step: step as u32,
+                description: description.to_string(),
+                state: state.into(),
+                elapsed: self.start.elapsed().as_millis() as u64,
+                error,
+            });
+        }
+    }
 }
 
 impl From<crate::config::Mode> for DeviceMode {
     fn from(config: crate::config::Mode) -> Self {
         let sequence = config.sequence.into_iter().map(DeviceItem::new).collect();
+        let rollback = config.rollback.into_iter().map(DeviceItem::new).collect();
         DeviceMode {
             name: config.name,
             depends: config.depends,
             sequence,
+            rollback,
+        }
+    }
+}
+
+/// Modes to go through to get from the current mode to the target mode, ending with the target;
+/// Follows the dependencies of the target until one is satisfied by the current mode or a mode
+/// without dependencies is reached, which can be entered from any mode
+fn mode_path<'a>(
+    modes: &'a [DeviceMode],
+    current: Option<&str>,
+    target: &str,
+) -> Result<Vec<&'a DeviceMode>, DeviceSetModeError> {
+    let mut mode = modes
+        .iter()
+        .find(|m| m.name == target)
+        .ok_or(DeviceSetModeError::ModeNotFound)?;
+    let mut path = vec![mode];
+    while let Some(depend) = &mode.depends {
+        if current == Some(depend.as_str()) {
+            break;
         }
+        mode = modes
+            .iter()
+            .find(|m| &m.name == depend)
+            .ok_or(DeviceSetModeError::WrongCurrentMode)?;
+        // Dependency cycles are refused when loading the configuration, but don't loop forever
+        if path.iter().any(|m| m.name == mode.name) {
+            return Err(DeviceSetModeError::WrongCurrentMode);
+        }
+        path.push(mode);
     }
+    path.reverse();
+    Ok(path)
 }
 
 struct DeviceItem<C> {
@@ -100,9 +253,29 @@ struct DeviceInner {
     notifier: DeviceNotifier,
     name: String,
     current_mode: std::sync::Mutex<Option<String>>,
+    /// Held while running mode sequences, so concurrent mode changes don't interleave
+    mode_lock: tokio::sync::Mutex<()>,
+    /// Mode being switched to while holding the mode lock
+    mode_change: std::sync::Mutex<Option<String>>,
     consoles: Vec<DeviceItem<crate::config::Console>>,
     volumes: Vec<DeviceItem<crate::config::Volume>>,
     modes: Vec<DeviceMode>,
+    selftest: Vec<SelfTestStep>,
+    health: Option<crate::config::Health>,
+    /// Result of the last health check
+    last_health: Mutex<Option<DeviceHealth>>,
+    recovery: Option<Recovery>,
+    power: Option<PowerMeter>,
+    /// Last reading of the power sensor
+    last_power: Mutex<Option<DevicePower>>,
+    safe_mode: Option<String>,
+    queue: crate::session::QueueOrder,
+    access: Option<Vec<String>>,
+    tags: Vec<String>,
+    labels: HashMap<String, String>,
+    runtime_consoles: Mutex<Vec<crate::DeviceConsole>>,
+    /// Ids of the persistent wrappers of consoles by name
+    persistent_consoles: Mutex<HashMap<String, u64>>,
     server: Server,
 }
 
@@ -116,183 +289,1179 @@ impl Device {
         let name = config.name;
         let consoles = config.consoles.into_iter().map(DeviceItem::new).collect();
         let volumes = config.volumes.into_iter().map(DeviceItem::new).collect();
-        let notifier = DeviceNotifier::new();
-        let modes = config.modes.into_iter().map(Into::into).collect();
+        let notifier = DeviceNotifier::new(server.inner.channels.device);
+        let modes: Vec<DeviceMode> = config.modes.into_iter().map(Into::into).collect();
+        // Modes that got removed from the configuration meanwhile can't be switched away from
+        let current_mode = server
+            .inner
+            .state
+            .as_ref()
+            .and_then(|s| s.device(&name).mode)
+            .filter(|mode| modes.iter().any(|m| &m.name == mode));
         let device = Device {
             inner: Arc::new(DeviceInner {
                 notifier,
                 name,
-                current_mode: Mutex::new(None),
+                current_mode: Mutex::new(current_mode),
+                mode_lock: tokio::sync::Mutex::new(()),
+                mode_change: Mutex::new(None),
                 consoles,
                 volumes,
                 modes,
+                selftest: config.selftest,
+                health: config.health,
+                last_health: Mutex::new(None),
+                recovery: config.recovery,
+                power: config.power,
+                last_power: Mutex::new(None),
+                safe_mode: config.safe_mode,
+                queue: config.queue,
+                access: config.access,
+                tags: config.tags,
+                labels: config.labels,
+                runtime_consoles: Mutex::new(Vec::new()),
+                persistent_consoles: Mutex::new(HashMap::new()),
                 server,
             }),
         };
-        let d = device.clone();
-        tokio::spawn(async move {
-            loop {
-                d.monitor_items().await
-            }
-        });
+        device.register_persistent_consoles();
         device
     }
 
+    fn register_persistent_consoles(&self) {
+        let server = &self.inner.server;
+        for c in self.inner.consoles.iter().filter(|c| c.config().persistent) {
+            let name = &c.config().name;
+            let mut properties = Properties::new(format!("{}-{}", self.inner.name, name));
+            properties.insert(registry::PROVIDER_NAME, self.inner.name.as_str());
+            properties.insert(registry::PROVIDER, PERSISTENT_PROVIDER);
+            let console = PersistentConsole {
+                binding: Arc::new(ConsoleBinding {
+                    device: Arc::downgrade(&self.inner),
+                    console: name.clone(),
+                }),
+            };
+            let id = server.register_console(properties, console);
+            server.set_console_settings(id, console_settings(c.config()));
+            // Keep the output across reconnects such that clients can catch up
+            server.keep_console_history(id);
+            self.inner
+                .persistent_consoles
+                .lock()
+                .unwrap()
+                .insert(name.clone(), id);
+        }
+    }
+
     pub fn name(&self) -> &str {
         &self.inner.name
     }
 
-    async fn monitor_items(&self) {
-        fn add_item_with<'a, C, I, F, IT>(items: I, id: u64, item: registry::Item<IT>, f: F) -> bool
-        where
-            C: DeviceConfigItem + 'a,
-            I: Iterator<Item = &'a DeviceItem<C>>,
-            F: Fn(&DeviceItem<C>, &IT),
-        {
-            items.fold(false, |changed, i| {
-                if i.set_if_matches(id, &item.properties()) {
-                    f(i, item.inner());
-                    true
-                } else {
-                    changed
+    fn console_by_name(&self, name: &str) -> Option<(u64, Arc<dyn Console>)> {
+        let id = self
+            .inner
+            .consoles
+            .iter()
+            .find(|c| c.config().name == name)
+            .and_then(|c| c.get())
+            .or_else(|| {
+                let runtime = self.inner.runtime_consoles.lock().unwrap();
+                runtime.iter().find(|c| c.name == name).and_then(|c| c.id)
+            })?;
+        Some((id, self.inner.server.get_console(id)?))
+    }
+
+    async fn console_step(&self, step: &ConsoleStep) -> Result<(), ConsoleStepError> {
+        let (id, console) = self
+            .console_by_name(&step.console)
+            .ok_or(ConsoleStepError::NotAvailable)?;
+        let expect = step
+            .expect
+            .as_deref()
+            .map(regex::bytes::Regex::new)
+            .transpose()?;
+
+        // Subscribe to the output before sending so the response can't be missed
+        let output = match expect {
+            Some(_) => Some(self.inner.server.console_output(id, false).await?),
+            None => None,
+        };
+
+        if let Some(send) = &step.send {
+            let mut input = self
+                .inner
+                .server
+                .console_input(id, console.as_ref())
+                .await?;
+            input.send(Bytes::from(send.clone())).await?;
+        }
+
+        if let (Some(expect), Some(mut output)) = (expect, output) {
+            let timeout = step.timeout.unwrap_or(DEFAULT_CONSOLE_TIMEOUT);
+            tokio::time::timeout(timeout, wait_for(&mut output, &expect))
+                .await
+                .map_err(|_| ConsoleStepError::Timeout)??;
+        }
+        Ok(())
+    }
+
+    async fn boot_step(&self, step: &BootStep) -> Result<(), ConsoleStepError> {
+        let (id, console) = self
+            .console_by_name(&step.console)
+            .ok_or(ConsoleStepError::NotAvailable)?;
+        let preset = boot_preset(step.boot);
+        let interrupt = step
+            .interrupt
+            .as_deref()
+            .or(preset.as_ref().map(|p| p.interrupt))
+            .ok_or(ConsoleStepError::MissingPattern("interrupt"))?;
+        let interrupt = regex::bytes::Regex::new(interrupt)?;
+        let prompt = step
+            .prompt
+            .as_deref()
+            .or(preset.as_ref().map(|p| p.prompt))
+            .ok_or(ConsoleStepError::MissingPattern("prompt"))?;
+        let prompt = regex::bytes::Regex::new(prompt)?;
+        let keys = step
+            .keys
+            .as_deref()
+            .or(preset.as_ref().map(|p| p.keys))
+            .unwrap_or("\n");
+        let newline = preset.as_ref().map(|p| p.newline).unwrap_or("\n");
+
+        let mut output = self.inner.server.console_output(id, false).await?;
+        let mut input = self
+            .inner
+            .server
+            .console_input(id, console.as_ref())
+            .await?;
+        let timeout = step.timeout.unwrap_or(DEFAULT_BOOT_TIMEOUT);
+        tokio::time::timeout(timeout, async {
+            wait_for(&mut output, &interrupt).await?;
+            input.send(Bytes::from(keys.to_string())).await?;
+            for command in &step.commands {
+                wait_for(&mut output, &prompt).await?;
+                input
+                    .send(Bytes::from(format!("{command}{newline}")))
+                    .await?;
+            }
+            Ok::<_, ConsoleError>(())
+        })
+        .await
+        .map_err(|_| ConsoleStepError::Timeout)??;
+        Ok(())
+    }
+
+    async fn selftest_step(&self, step: &SelfTestStep) -> Result<(), SelfTestError> {
+        // With only a console given any output will do
+        let expect = step
+            .expect
+            .as_deref()
+            .or(step.console.as_ref().map(|_| "(?s)."))
+            .map(regex::bytes::Regex::new)
+            .transpose()
+            .map_err(ConsoleStepError::from)?;
+
+        // Subscribe to the output before switching so early output can't be missed
+        let output = match expect {
+            Some(_) => {
+                let (id, _) = step
+                    .console
+                    .as_deref()
+                    .or_else(|| {
+                        self.inner
+                            .consoles
+                            .first()
+                            .map(|c| c.config().name.as_str())
+                    })
+                    .and_then(|name| self.console_by_name(name))
+                    .ok_or(ConsoleStepError::NotAvailable)?;
+                Some(
+                    self.inner
+                        .server
+                        .console_output(id, false)
+                        .await
+                        .map_err(ConsoleStepError::from)?,
+                )
+            }
+            None => None,
+        };
+
+        crate::Device::set_mode(self, &step.mode).await?;
+
+        if let (Some(expect), Some(mut output)) = (expect, output) {
+            let timeout = step.timeout.unwrap_or(DEFAULT_CONSOLE_TIMEOUT);
+            tokio::time::timeout(timeout, wait_for(&mut output, &expect))
+                .await
+                .map_err(|_| ConsoleStepError::Timeout)?
+                .map_err(ConsoleStepError::from)?;
+        }
+        if let Some(volume) = &step.volume {
+            let timeout = step.timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT);
+            if !self.wait_for_volume(volume, timeout).await {
+                return Err(SelfTestError::VolumeMissing(volume.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for a device volume by name to be available
+    async fn wait_for_volume(&self, name: &str, timeout: Duration) -> bool {
+        let mut monitor = self.inner.notifier.watch();
+        tokio::time::timeout(timeout, async {
+            loop {
+                if self
+                    .inner
+                    .volumes
+                    .iter()
+                    .any(|v| v.config().name == name && v.get().is_some())
+                {
+                    return Some(());
                 }
-            })
+                monitor.wait().await.ok()?;
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+        .is_some()
+    }
+
+    /// Availability of the consoles, volumes and modes of the device
+    async fn item_checks(&self) -> Vec<DeviceCheck> {
+        let mut checks = Vec::new();
+
+        for console in &self.inner.consoles {
+            let result = match console.get() {
+                Some(id) => self
+                    .inner
+                    .server
+                    .console_output(id, false)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+                None => Err("Console not available".to_string()),
+            };
+            checks.push(DeviceCheck::new(
+                format!("console {}", console.config().name),
+                result,
+            ));
         }
 
-        fn add_item<'a, T, C, I>(items: I, id: u64, item: registry::Item<T>) -> bool
-        where
-            C: DeviceConfigItem + 'a,
-            I: Iterator<Item = &'a DeviceItem<C>>,
-        {
-            add_item_with(items, id, item, |_, _| {})
+        for volume in &self.inner.volumes {
+            let result = match volume.get().and_then(|id| self.inner.server.get_volume(id)) {
+                Some(_) => Ok(()),
+                None => Err("Volume not available"),
+            };
+            let name = match &volume.config().mode {
+                Some(mode) => format!("volume {} ({})", volume.config().name, mode),
+                None => format!("volume {}", volume.config().name),
+            };
+            checks.push(DeviceCheck::new(name, result));
         }
 
-        fn change_with<'a, T, C, I, F>(items: I, change: RegistryChange<T>, f: F) -> bool
-        where
-            C: DeviceConfigItem + 'a,
-            I: Iterator<Item = &'a DeviceItem<C>>,
-            F: Fn(&DeviceItem<C>, &T),
-        {
-            match change {
-                registry::RegistryChange::Added { id, item } => add_item_with(items, id, item, f),
-                registry::RegistryChange::Removed(id) => {
-                    items.fold(false, |changed, c| c.unset_if_matches(id) || changed)
+        for mode in crate::Device::modes(self) {
+            let result = if mode.available {
+                Ok(())
+            } else {
+                Err("Actuator not available")
+            };
+            checks.push(DeviceCheck::new(format!("mode {}", mode.name), result));
+        }
+
+        checks
+    }
+
+    /// Groups the device is a member of
+    pub fn tags(&self) -> &[String] {
+        &self.inner.tags
+    }
+
+    /// Free-form labels of the device
+    pub fn labels(&self) -> &HashMap<String, String> {
+        &self.inner.labels
+    }
+
+    /// Time between periodic health checks, if configured
+    pub fn health_interval(&self) -> Option<Duration> {
+        self.inner.health.as_ref().and_then(|h| h.interval)
+    }
+
+    /// Whether the device is configured to be recovered unattended
+    pub fn has_recovery(&self) -> bool {
+        self.inner.recovery.is_some()
+    }
+
+    /// Whether the device has a power sensor to sample
+    pub fn has_power_meter(&self) -> bool {
+        self.inner.power.is_some()
+    }
+
+    /// Switch the device through the recovery modes
+    async fn recover(&self, recovery: &Recovery) -> Result<(), DeviceSetModeError> {
+        for mode in &recovery.modes {
+            crate::Device::set_mode(self, mode).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the console inactivity of the device is watched, which is only the case while it
+    /// is settled in the last recovery mode
+    fn watch_inactivity(&self, recovery: &Recovery) -> bool {
+        let changing = self.inner.mode_change.lock().unwrap().is_some();
+        !changing && recovery.modes.last() == self.inner.current_mode.lock().unwrap().as_ref()
+    }
+
+    /// Wait for the recovery console to be silent for the inactivity timeout; Output once a full
+    /// timeout passed since starting to wait shows the device is working again, resetting the
+    /// recovery attempts. Returns `None` if the device goes away
+    async fn wait_inactive(&self, recovery: &Recovery, attempt: &AtomicU32) -> Option<Duration> {
+        let Some(timeout) = recovery.inactivity else {
+            return std::future::pending().await;
+        };
+        let start = Instant::now();
+        let mut monitor = self.inner.notifier.watch();
+        loop {
+            let console = recovery
+                .console
+                .as_deref()
+                .or_else(|| {
+                    self.inner
+                        .consoles
+                        .first()
+                        .map(|c| c.config().name.as_str())
+                })
+                .and_then(|name| self.console_by_name(name));
+            let output = match console {
+                Some((id, _)) if self.watch_inactivity(recovery) => {
+                    self.inner.server.console_output(id, false).await.ok()
                 }
+                _ => None,
+            };
+            let Some(mut output) = output else {
+                monitor.wait().await.ok()?;
+                continue;
+            };
+
+            let mut deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => return Some(timeout),
+                    data = output.next() => match data {
+                        Some(Ok(_)) => {
+                            if start.elapsed() >= timeout {
+                                attempt.store(0, Ordering::Relaxed);
+                            }
+                            deadline = tokio::time::Instant::now() + timeout;
+                        }
+                        _ => break,
+                    },
+                    r = monitor.wait() => {
+                        r.ok()?;
+                        if !self.watch_inactivity(recovery) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for the item of the step to be registered
+    async fn wait_step(&self, step: &WaitStep) -> Result<(), DeviceSetModeError> {
+        let server = &self.inner.server.inner;
+        let wait = async {
+            match step.wait {
+                WaitItem::Actuator => server.actuators.wait_for(&step.match_).await.0,
+                WaitItem::Console => server.consoles.wait_for(&step.match_).await.0,
+                WaitItem::Volume => server.volumes.wait_for(&step.match_).await.0,
+            }
+        };
+        let timeout = step.timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT);
+        let id = tokio::time::timeout(timeout, wait).await.map_err(|_| {
+            DeviceSetModeError::WaitTimeout(format!("{} matching {:?}", step.wait, step.match_))
+        })?;
+        info!("Found {} {} for device {}", step.wait, id, self.inner.name);
+        Ok(())
+    }
+
+    /// Take the mode lock and switch to the mode; If another mode change is in progress either
+    /// wait for it to finish or fail as busy
+    async fn lock_and_change_mode(
+        &self,
+        mode: &str,
+        wait: bool,
+        reporter: ModeReporter,
+    ) -> Result<(), DeviceSetModeError> {
+        let _lock = match self.inner.mode_lock.try_lock() {
+            Ok(lock) => lock,
+            Err(_) if wait => {
+                info!(
+                    "Waiting for the switch to mode {} to finish before switching to {}",
+                    crate::Device::mode_change(self).unwrap_or_default(),
+                    mode
+                );
+                self.inner.mode_lock.lock().await
+            }
+            Err(_) => {
+                return Err(DeviceSetModeError::Busy(
+                    crate::Device::mode_change(self).unwrap_or_default(),
+                ))
             }
+        };
+        self.change_mode(mode, &reporter).await
+    }
+
+    /// Switch to the mode, going through the modes it depends on; Only to be called with the mode
+    /// lock held
+    async fn change_mode(
+        &self,
+        mode: &str,
+        reporter: &ModeReporter,
+    ) -> Result<(), DeviceSetModeError> {
+        {
+            let mut change = self.inner.mode_change.lock().unwrap();
+            *change = Some(mode.to_string());
         }
-        fn change<'a, T, C: DeviceConfigItem + 'a, I: Iterator<Item = &'a DeviceItem<C>>>(
-            items: I,
-            change: RegistryChange<T>,
-        ) -> bool {
-            change_with(items, change, |_, _| {})
+        self.inner.notifier.notify().await;
+        let result = self.switch_path(mode, reporter).await;
+        {
+            let mut change = self.inner.mode_change.lock().unwrap();
+            *change = None;
         }
-        fn setup_console(dev: &DeviceItem<crate::config::Console>, console: &Arc<dyn Console>) {
-            if let Err(e) = console.configure(Box::new(<dyn erased_serde::Deserializer>::erase(
-                dev.config().parameters.clone(),
-            ))) {
-                warn!("Failed to configure console: {}", e);
+        self.inner.notifier.notify().await;
+        result
+    }
+
+    async fn switch_path(
+        &self,
+        mode: &str,
+        reporter: &ModeReporter,
+    ) -> Result<(), DeviceSetModeError> {
+        let path = {
+            let mut current = self.inner.current_mode.lock().unwrap();
+            let path = mode_path(&self.inner.modes, current.as_deref(), mode)?;
+            *current = None;
+            path
+        };
+        self.persist_mode(None);
+
+        for target in path {
+            if target.name != mode {
+                info!("Switching to mode {} on the way to {}", target.name, mode);
+            }
+            self.enter_mode(target, reporter).await?;
+            {
+                let mut current = self.inner.current_mode.lock().unwrap();
+                *current = Some(target.name.clone());
             }
+            self.persist_mode(Some(&target.name));
+            self.inner.notifier.notify().await;
         }
+        Ok(())
+    }
 
-        let mut actuator_monitor = self.inner.server.inner.actuators.monitor();
-        let mut console_monitor = self.inner.server.inner.consoles.monitor();
-        let mut volume_monitor = self.inner.server.inner.volumes.monitor();
-        let mut changed = false;
+    /// Keep the mode the device is in across restarts, counting the times each mode is entered
+    fn persist_mode(&self, entered: Option<&str>) {
+        if let Some(state) = &self.inner.server.inner.state {
+            state.update_device(&self.inner.name, |d| {
+                d.mode = entered.map(ToOwned::to_owned);
+                if let Some(mode) = entered {
+                    *d.modes.entry(mode.to_string()).or_default() += 1;
+                }
+            });
+        }
+    }
 
-        for (id, item) in self.inner.server.inner.actuators.contents() {
-            changed |= add_item(
-                self.inner.modes.iter().flat_map(|m| m.sequence.iter()),
-                id,
-                item,
+    /// Run the sequence of the mode, running its rollback if the sequence fails
+    async fn enter_mode(
+        &self,
+        target: &DeviceMode,
+        reporter: &ModeReporter,
+    ) -> Result<(), DeviceSetModeError> {
+        let result = self
+            .run_steps(&target.name, &target.sequence, false, reporter)
+            .await;
+        if let Err(e) = &result {
+            if !target.rollback.is_empty() {
+                warn!(
+                    "Switching to mode {} failed: {}; Rolling back",
+                    target.name, e
+                );
+                if let Err(e) = self
+                    .run_steps(&target.name, &target.rollback, true, reporter)
+                    .await
+                {
+                    warn!("Rollback of mode {} failed: {}", target.name, e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Run the steps of the sequence or rollback of a mode, reporting the progress of each
+    async fn run_steps(
+        &self,
+        mode: &str,
+        steps: &[DeviceItem<crate::config::ModeStep>],
+        rollback: bool,
+        reporter: &ModeReporter,
+    ) -> Result<(), DeviceSetModeError> {
+        for (index, step) in steps.iter().enumerate() {
+            let step = step.config();
+            let mut description = self.describe_step(step);
+            if rollback {
+                description = format!("rollback: {description}");
+            }
+            reporter.report(
+                mode,
+                index,
+                &description,
+                DeviceModeStepState::Started,
+                None,
             );
+            match self.run_step(step).await {
+                Ok(()) => reporter.report(
+                    mode,
+                    index,
+                    &description,
+                    DeviceModeStepState::Completed,
+                    None,
+                ),
+                Err(e) => {
+                    reporter.report(
+                        mode,
+                        index,
+                        &description,
+                        DeviceModeStepState::Failed,
+                        Some(e.to_string()),
+                    );
+                    return Err(e);
+                }
+            }
         }
+        Ok(())
+    }
 
-        for (id, item) in self.inner.server.inner.consoles.contents() {
-            changed |= add_item_with(self.inner.consoles.iter(), id, item, setup_console);
+    /// Short description of a mode step for progress reports
+    fn describe_step(&self, step: &ModeStep) -> String {
+        match step {
+            ModeStep::Actuator(step) => match self.inner.server.find_actuator(&step.match_) {
+                Some(actuator) => format!("actuator {actuator}"),
+                None => format!("actuator matching {:?}", step.match_),
+            },
+            ModeStep::Console(step) => format!("console {}", step.console),
+            ModeStep::Boot(step) => format!("boot on console {}", step.console),
+            ModeStep::Wait(step) => format!("wait for {} matching {:?}", step.wait, step.match_),
+            ModeStep::Sleep(step) => format!("sleep {:?}", step.sleep),
         }
+    }
 
-        for (id, item) in self.inner.server.inner.volumes.contents() {
-            changed |= add_item(self.inner.volumes.iter(), id, item);
+    /// Run a single mode step, including its stabilisation period
+    async fn run_step(&self, step: &ModeStep) -> Result<(), DeviceSetModeError> {
+        let default_stabilisation = match step {
+            ModeStep::Actuator(step) => {
+                if let Some(actuator) = self.inner.server.find_actuator(&step.match_) {
+                    actuator
+                        .inner()
+                        .set_mode(Box::new(<dyn erased_serde::Deserializer>::erase(
+                            step.parameters.clone(),
+                        )))
+                        .await?;
+                    self.inner.server.actuator_stabilisation(&actuator)
+                } else {
+                    warn!("Provider {:?} not found", &step.match_);
+                    return Err(ActuatorError {}.into());
+                }
+            }
+            // Console steps are best effort; On failure the following steps (e.g. a power
+            // cut) act as the fallback
+            ModeStep::Console(step) => {
+                if let Err(e) = self.console_step(step).await {
+                    warn!("Console step on {} failed: {}", step.console, e);
+                }
+                None
+            }
+            // Unlike plain console steps, the mode can't be reached if the boot selection
+            // failed
+            ModeStep::Boot(step) => {
+                if let Err(e) = self.boot_step(step).await {
+                    warn!("Boot step on {} failed: {}", step.console, e);
+                    return Err(DeviceSetModeError::ConsoleFailed(e.to_string()));
+                }
+                None
+            }
+            ModeStep::Wait(step) => {
+                self.wait_step(step).await?;
+                None
+            }
+            ModeStep::Sleep(step) => {
+                tokio::time::sleep(step.sleep).await;
+                None
+            }
+        };
+        if let Some(duration) = step.stabilisation().or(default_stabilisation) {
+            tokio::time::sleep(duration).await;
         }
+        Ok(())
+    }
 
-        if changed {
-            self.inner.notifier.notify().await;
+    /// Drop items that are no longer registered and pick up any registered ones
+    fn resync(&self) -> bool {
+        let server = &self.inner.server.inner;
+        let mut changed = false;
+
+        changed |= resync_items(
+            self.inner.modes.iter().flat_map(DeviceMode::steps),
+            &server.actuators,
+            |_, _, _| {},
+        );
+        changed |= resync_items(
+            self.inner.consoles.iter(),
+            &server.consoles,
+            |dev, id, c| setup_console(&self.inner.server, &self.inner.name, dev, id, c),
+        );
+        changed |= resync_items(self.inner.volumes.iter(), &server.volumes, |_, _, _| {});
+
+        changed
+    }
+
+    fn actuator_changed(&self, change: &RegistryChange<Arc<dyn crate::Actuator>>) -> bool {
+        change_with(
+            self.inner.modes.iter().flat_map(DeviceMode::steps),
+            change,
+            |_, _, _| {},
+        )
+    }
+
+    fn console_changed(&self, change: &RegistryChange<Arc<dyn Console>>) -> bool {
+        change_with(self.inner.consoles.iter(), change, |dev, id, c| {
+            setup_console(&self.inner.server, &self.inner.name, dev, id, c)
+        })
+    }
+
+    fn volume_changed(&self, change: &RegistryChange<Arc<dyn crate::Volume>>) -> bool {
+        change_with(self.inner.volumes.iter(), change, |_, _, _| {})
+    }
+}
+
+fn add_item_with<'a, C, I, F, T>(items: I, id: u64, item: &registry::Item<T>, f: F) -> bool
+where
+    C: DeviceConfigItem + 'a,
+    I: Iterator<Item = &'a DeviceItem<C>>,
+    F: Fn(&DeviceItem<C>, u64, &T),
+{
+    items.fold(false, |changed, i| {
+        if i.set_if_matches(id, &item.properties()) {
+            f(i, id, item.inner());
+            true
+        } else {
+            changed
         }
+    })
+}
 
-        loop {
-            let changed = tokio::select! {
-                msg = console_monitor.recv() => {
-                    match msg {
-                        Ok(c) => change_with(self.inner.consoles.iter(), c, setup_console),
-                        Err(e) => {
-                            warn!("Issue with monitoring consoles: {:?}", e); return },
-                    }
+fn change_with<'a, T, C, I, F>(items: I, change: &RegistryChange<T>, f: F) -> bool
+where
+    C: DeviceConfigItem + 'a,
+    I: Iterator<Item = &'a DeviceItem<C>>,
+    F: Fn(&DeviceItem<C>, u64, &T),
+{
+    match change {
+        RegistryChange::Added { id, item } => add_item_with(items, *id, item, f),
+        // The item may start or stop matching, while items still matching stay in use
+        RegistryChange::Updated { id, item } => {
+            let properties = item.properties();
+            items.fold(false, |changed, i| {
+                if i.get() == Some(*id) {
+                    let dropped = !i.config.matches(&properties) && i.unset_if_matches(*id);
+                    dropped || changed
+                } else if i.set_if_matches(*id, &properties) {
+                    f(i, *id, item.inner());
+                    true
+                } else {
+                    changed
                 }
-                msg = actuator_monitor.recv() => {
-                    match msg {
-                        Ok(c) => change(
-                            self.inner.modes.iter().flat_map(|m| m.sequence.iter()),
-                            c),
-                        Err(e) => {
-                            warn!("Issue with monitoring actuators: {:?}", e); return },
-                        }
+            })
+        }
+        RegistryChange::Removed(id) => {
+            items.fold(false, |changed, c| c.unset_if_matches(*id) || changed)
+        }
+    }
+}
+
+fn resync_items<'a, T, C, I, F>(items: I, registry: &registry::Registry<T>, f: F) -> bool
+where
+    T: Clone,
+    C: DeviceConfigItem + 'a,
+    I: Iterator<Item = &'a DeviceItem<C>> + Clone,
+    F: Fn(&DeviceItem<C>, u64, &T),
+{
+    let mut changed = false;
+    for i in items.clone() {
+        if let Some(id) = i.get() {
+            if registry.lookup(id).is_none() {
+                changed |= i.unset_if_matches(id);
+            }
+        }
+    }
+    for (id, item) in registry.contents() {
+        changed |= add_item_with(items.clone(), id, &item, &f);
+    }
+    changed
+}
+
+fn console_settings(config: &crate::config::Console) -> crate::ConsoleSettings {
+    let (line, pacing) = match &config.parameters {
+        serde_yaml::Value::Null => Default::default(),
+        parameters => {
+            let line = serde::Deserialize::deserialize(parameters.clone()).unwrap_or_else(|e| {
+                warn!("Invalid parameters of console {}: {}", config.name, e);
+                Default::default()
+            });
+            let pacing: crate::console_input::ParameterPacing =
+                serde::Deserialize::deserialize(parameters.clone()).unwrap_or_default();
+            (line, pacing)
+        }
+    };
+    if pacing.is_set() {
+        warn!(
+            "Console {}: chunk_size and chunk_delay in the parameters are deprecated, use input instead",
+            config.name
+        );
+    }
+    crate::ConsoleSettings {
+        filters: config.filters.clone(),
+        input: config.input.with_parameters(pacing),
+        line,
+    }
+}
+
+fn setup_console(
+    server: &Server,
+    device: &str,
+    dev: &DeviceItem<crate::config::Console>,
+    id: u64,
+    console: &Arc<dyn Console>,
+) {
+    if let Err(e) = console.configure(Box::new(<dyn erased_serde::Deserializer>::erase(
+        dev.config().parameters.clone(),
+    ))) {
+        warn!("Failed to configure console: {}", e);
+    }
+    server.set_console_settings(id, console_settings(dev.config()));
+    // Keep the output from here on, such that early boot messages can be replayed
+    server.keep_console_history(id);
+    server.log_console(device, &dev.config().name, id);
+}
+
+/// Provider type of persistent console wrappers
+const PERSISTENT_PROVIDER: &str = "persistent";
+
+/// Console of a device configuration entry currently backing a persistent console
+struct ConsoleBinding {
+    device: Weak<DeviceInner>,
+    console: String,
+}
+
+impl ConsoleBinding {
+    fn bound(&self) -> Option<u64> {
+        let device = self.device.upgrade()?;
+        let console = device
+            .consoles
+            .iter()
+            .find(|c| c.config().name == self.console)?;
+        console.get()
+    }
+
+    fn current(&self) -> Option<(u64, Arc<dyn Console>)> {
+        let device = self.device.upgrade()?;
+        let id = self.bound()?;
+        Some((id, device.server.get_console(id)?))
+    }
+
+    /// Wait up to `timeout` for the console to be available
+    async fn wait(&self, timeout: Duration) -> Option<(u64, Arc<dyn Console>)> {
+        let mut monitor = self.device.upgrade()?.notifier.watch();
+        tokio::time::timeout(timeout, async {
+            loop {
+                if let Some(current) = self.current() {
+                    return Some(current);
                 }
-                msg = volume_monitor.recv() => {
-                    match msg {
-                        Ok(c) => change(self.inner.volumes.iter(), c),
-                        Err(e) => {
-                            warn!("Issue with monitoring volumes: {:?}", e); return },
+                monitor.wait().await.ok()?;
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+}
+
+/// Console following the console matching a device configuration entry as it comes and goes,
+/// e.g. when a device reset makes its usb serial adapter re-enumerate
+#[derive(Debug)]
+struct PersistentConsole {
+    binding: Arc<ConsoleBinding>,
+}
+
+impl std::fmt::Debug for ConsoleBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConsoleBinding")
+            .field("console", &self.console)
+            .finish()
+    }
+}
+
+struct PersistentInput {
+    binding: Arc<ConsoleBinding>,
+    current: Option<(u64, Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>)>,
+}
+
+impl PersistentInput {
+    async fn send(&mut self, data: Bytes) {
+        // Retry once, as the console may have gone away while it was still bound
+        for _ in 0..2 {
+            let Some((id, console)) = self.binding.wait(PERSISTENT_INPUT_TIMEOUT).await else {
+                break;
+            };
+            if self.current.as_ref().map(|(current, _)| *current) != Some(id) {
+                // Input is paced by the server as input of the persistent console already
+                match console.input().await {
+                    Ok(input) => self.current = Some((id, input)),
+                    Err(e) => {
+                        warn!("Failed to open input of {}: {}", self.binding.console, e);
+                        self.current = None;
+                        continue;
                     }
                 }
-            };
-            if changed {
-                self.inner.notifier.notify().await;
             }
+            if let Some((_, input)) = &mut self.current {
+                if input.send(data.clone()).await.is_ok() {
+                    return;
+                }
+            }
+            self.current = None;
         }
+        warn!(
+            "Console {} unavailable, dropping input",
+            self.binding.console
+        );
     }
 }
 
 #[async_trait::async_trait]
-impl crate::Device for Device {
-    async fn set_mode(&self, mode: &str) -> Result<(), DeviceSetModeError> {
-        let target = self
-            .inner
-            .modes
-            .iter()
-            .find(|m| m.name == mode)
-            .ok_or(DeviceSetModeError::ModeNotFound)?;
-        {
-            let mut current = self.inner.current_mode.lock().unwrap();
-            if let Some(depend) = &target.depends {
-                if current.as_ref() != Some(depend) {
-                    return Err(DeviceSetModeError::WrongCurrentMode);
+impl Console for PersistentConsole {
+    fn configure(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        match self.binding.current() {
+            Some((_, console)) => console.configure(parameters),
+            None => Err(ConsoleError::Unavailable(
+                "Console currently not available".to_string(),
+            )),
+        }
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let input = PersistentInput {
+            binding: self.binding.clone(),
+            current: None,
+        };
+        Ok(Box::pin(futures::sink::unfold(
+            input,
+            |mut input, data: Bytes| async move {
+                input.send(data).await;
+                Ok(input)
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        let device = self.binding.device.upgrade().ok_or(ConsoleError::Closed)?;
+        // Subscribe before resolving the console such that no change can be missed
+        let monitor = device.notifier.watch();
+        let binding = self.binding.clone();
+        let output = crate::device_console::follow_output(
+            device.server.clone(),
+            monitor,
+            move || binding.bound(),
+            false,
+        );
+        Ok(ReceiverStream::new(output).boxed())
+    }
+
+    async fn signal(&self, signal: crate::ConsoleSignal) -> Result<(), ConsoleError> {
+        match self.binding.current() {
+            Some((_, console)) => console.signal(signal).await,
+            None => Err(ConsoleError::Unavailable(
+                "Console currently not available".to_string(),
+            )),
+        }
+    }
+}
+
+/// Run the health check of the device every `interval`; Reserved devices are skipped, as the
+/// probes would disrupt whoever holds them
+pub async fn health_monitor(device: Device, id: u64, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if device.inner.server.inner.sessions.holding(id).is_some() {
+            info!(
+                "Skipping health check of reserved device {}",
+                device.inner.name
+            );
+            continue;
+        }
+        if let Err(e) = crate::Device::health_check(&device).await {
+            warn!("Health check of device {} failed: {}", device.inner.name, e);
+        }
+    }
+}
+
+/// Sample the power sensor of the device, adding up the energy it used; Failing reads are only
+/// logged when the error changes, as broken sensors tend to stay broken
+pub async fn power_monitor(device: Device) {
+    let Some(meter) = device.inner.power.as_ref() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(meter.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut energy = 0.0;
+    let mut last: Option<Instant> = None;
+    let mut failure = None;
+    loop {
+        interval.tick().await;
+        match power_meter::read(&meter.source).await {
+            Ok(watts) => {
+                let now = Instant::now();
+                if let Some(last) = last {
+                    energy += watts * (now - last).as_secs_f64();
                 }
+                last = Some(now);
+                failure = None;
+                *device.inner.last_power.lock().unwrap() = Some(DevicePower {
+                    watts,
+                    energy,
+                    sampled: SystemTime::now(),
+                });
+                device.inner.notifier.notify().await;
+            }
+            Err(e) => {
+                let message = e.to_string();
+                if failure.as_ref() != Some(&message) {
+                    warn!(
+                        "Reading the power of device {} failed: {}",
+                        device.inner.name, message
+                    );
+                    failure = Some(message);
+                }
+                // What the device used while the sensor couldn't be read is unknown
+                last = None;
             }
-            *current = None;
         }
+    }
+}
 
-        for step in &target.sequence {
-            let step = step.config();
-            if let Some(provider) = self.inner.server.find_actuator(&step.match_) {
-                provider
-                    .set_mode(Box::new(<dyn erased_serde::Deserializer>::erase(
-                        step.parameters.clone(),
-                    )))
-                    .await?;
-            } else {
-                warn!("Provider {:?} not found", &step.match_);
-                return Err(ActuatorError {}.into());
+/// Recover the device when a health check fails or its console goes silent; After the configured
+/// number of consecutive attempts recovery is given up until the device is seen working again.
+/// Reserved devices are left alone
+pub async fn recovery_monitor(device: Device, id: u64) {
+    let Some(recovery) = device.inner.recovery.as_ref() else {
+        return;
+    };
+    let name = &device.inner.name;
+    let mut monitor = device.inner.notifier.watch();
+    let mut checked = crate::Device::health(&device).map(|h| h.checked);
+    let attempt = AtomicU32::new(0);
+    let mut exhausted = false;
+    // Only restarted once it fired or the device got recovered, such that unrelated device
+    // changes don't reset the inactivity timeout
+    let mut inactive = Box::pin(device.wait_inactive(recovery, &attempt));
+
+    loop {
+        let reason = tokio::select! {
+            r = monitor.wait() => {
+                if r.is_err() {
+                    return;
+                }
+                match crate::Device::health(&device) {
+                    Some(health) if Some(health.checked) != checked => {
+                        checked = Some(health.checked);
+                        if health.healthy() {
+                            attempt.store(0, Ordering::Relaxed);
+                            continue;
+                        }
+                        "health check failed".to_string()
+                    }
+                    _ => continue,
+                }
             }
-            if let Some(duration) = step.stabilisation {
-                tokio::time::sleep(duration).await;
+            silent = &mut inactive => match silent {
+                Some(silent) => format!(
+                    "console silent for {}",
+                    humantime::format_duration(silent)
+                ),
+                None => return,
+            },
+        };
+        inactive = Box::pin(device.wait_inactive(recovery, &attempt));
+
+        if device.inner.server.inner.sessions.holding(id).is_some() {
+            info!("Not recovering reserved device {}: {}", name, reason);
+            continue;
+        }
+
+        let event = |state, error: Option<String>| {
+            device
+                .inner
+                .server
+                .record(recording::Interaction::Recovery {
+                    device: id,
+                    reason: reason.clone(),
+                    attempt: attempt.load(Ordering::Relaxed),
+                    attempts: recovery.attempts,
+                    state,
+                    error,
+                })
+        };
+
+        if attempt.load(Ordering::Relaxed) >= recovery.attempts {
+            if !exhausted {
+                warn!(
+                    "Giving up recovering device {} after {} attempts: {}",
+                    name, recovery.attempts, reason
+                );
+                event(DeviceRecoveryState::Exhausted, None);
+                exhausted = true;
             }
+            continue;
         }
-        {
-            let mut current = self.inner.current_mode.lock().unwrap();
-            *current = Some(mode.to_string());
+        exhausted = false;
+
+        attempt.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Recovering device {} ({}/{}): {}",
+            name,
+            attempt.load(Ordering::Relaxed),
+            recovery.attempts,
+            reason
+        );
+        event(DeviceRecoveryState::Started, None);
+        match device.recover(recovery).await {
+            Ok(()) => event(DeviceRecoveryState::Completed, None),
+            Err(e) => {
+                warn!("Recovery of device {} failed: {}", name, e);
+                event(DeviceRecoveryState::Failed, Some(e.to_string()));
+            }
         }
-        self.inner.notifier.notify().await;
-        Ok(())
+
+        // Check whether the recovery worked right away rather than waiting for the next interval;
+        // A failure triggers the next attempt
+        if device.inner.health.is_some() {
+            if let Err(e) = crate::Device::health_check(&device).await {
+                warn!("Health check of device {} failed: {}", name, e);
+            }
+        }
+        inactive = Box::pin(device.wait_inactive(recovery, &attempt));
+    }
+}
+
+/// Route registry changes to the configured devices
+///
+/// A single task watches the actuator, console and volume registries on behalf of all devices
+/// rather than each device subscribing by itself. Devices are picked up from the device registry,
+/// including ones registered later on. If the dispatcher falls behind, all devices are
+/// resynchronized against the current registry contents.
+pub async fn monitor_devices(server: Server) {
+    let mut device_monitor = server.inner.devices.monitor();
+    let mut actuator_monitor = server.inner.actuators.monitor();
+    let mut console_monitor = server.inner.consoles.monitor();
+    let mut volume_monitor = server.inner.volumes.monitor();
+
+    loop {
+        let mut devices: HashMap<u64, Device> = server
+            .inner
+            .devices
+            .contents()
+            .into_iter()
+            .filter_map(|(id, d)| Some((id, d.inner().config_device()?)))
+            .collect();
+        for device in devices.values() {
+            if device.resync() {
+                device.inner.notifier.notify().await;
+            }
+        }
+
+        loop {
+            let changed: Result<Vec<&Device>, _> = tokio::select! {
+                msg = device_monitor.recv() => match msg {
+                    Ok(RegistryChange::Added { id, item }) => {
+                        match item.inner().config_device() {
+                            Some(device) => {
+                                let device = devices.entry(id).or_insert(device);
+                                Ok(if device.resync() { vec![&*device] } else { Vec::new() })
+                            }
+                            None => Ok(Vec::new()),
+                        }
+                    }
+                    Ok(RegistryChange::Removed(id)) => {
+                        devices.remove(&id);
+                        Ok(Vec::new())
+                    }
+                    Ok(RegistryChange::Updated { .. }) => Ok(Vec::new()),
+                    Err(e) => Err(e),
+                },
+                msg = console_monitor.recv() => msg.map(|c| {
+                    devices.values().filter(|d| d.console_changed(&c)).collect()
+                }),
+                msg = actuator_monitor.recv() => msg.map(|c| {
+                    devices.values().filter(|d| d.actuator_changed(&c)).collect()
+                }),
+                msg = volume_monitor.recv() => msg.map(|c| {
+                    devices.values().filter(|d| d.volume_changed(&c)).collect()
+                }),
+            };
+            match changed {
+                Ok(changed) => {
+                    for device in changed {
+                        device.inner.notifier.notify().await;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Device monitor missed {missed} registry changes; Resyncing");
+                    break;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Device for Device {
+    async fn set_mode(&self, mode: &str) -> Result<(), DeviceSetModeError> {
+        self.lock_and_change_mode(mode, true, ModeReporter::new(None))
+            .await
+    }
+
+    async fn try_set_mode(&self, mode: &str) -> Result<(), DeviceSetModeError> {
+        self.lock_and_change_mode(mode, false, ModeReporter::new(None))
+            .await
+    }
+
+    async fn set_mode_with_progress(
+        &self,
+        mode: &str,
+        wait: bool,
+        progress: ModeProgress,
+    ) -> Result<(), DeviceSetModeError> {
+        self.lock_and_change_mode(mode, wait, ModeReporter::new(Some(progress)))
+            .await
     }
 
     fn updates(&self) -> DeviceMonitor {
@@ -303,24 +1472,74 @@ impl crate::Device for Device {
         self.inner
             .consoles
             .iter()
-            .map(|c| crate::DeviceConsole {
-                name: c.config().name.clone(),
-                id: c.get(),
+            .map(|c| {
+                let persistent = self.inner.persistent_consoles.lock().unwrap();
+                crate::DeviceConsole {
+                    name: c.config().name.clone(),
+                    id: persistent
+                        .get(&c.config().name)
+                        .copied()
+                        .or_else(|| c.get()),
+                }
             })
+            .chain(self.inner.runtime_consoles.lock().unwrap().iter().map(|c| {
+                crate::DeviceConsole {
+                    name: c.name.clone(),
+                    id: c.id,
+                }
+            }))
             .collect()
     }
 
-    fn volumes(&self) -> Vec<crate::DeviceVolume> {
+    fn bound_consoles(&self) -> Vec<(u64, u64)> {
+        let persistent = self.inner.persistent_consoles.lock().unwrap();
+        self.inner
+            .consoles
+            .iter()
+            .filter_map(|c| Some((*persistent.get(&c.config().name)?, c.get()?)))
+            .collect()
+    }
+
+    fn volume_entries(&self) -> Vec<crate::DeviceVolume> {
         self.inner
             .volumes
             .iter()
             .map(|v| crate::DeviceVolume {
                 name: v.config().name.clone(),
                 id: v.get(),
+                mode: v.config().mode.clone(),
             })
             .collect()
     }
 
+    fn volumes(&self) -> Vec<crate::DeviceVolume> {
+        let current = self.inner.current_mode.lock().unwrap().clone();
+        let mut volumes: Vec<crate::DeviceVolume> = Vec::new();
+        for v in &self.inner.volumes {
+            let volume = crate::DeviceVolume {
+                name: v.config().name.clone(),
+                id: v.get(),
+                mode: v.config().mode.clone(),
+            };
+            // Volumes sharing a name are bound to the one for the current mode, falling back to
+            // the first one available
+            match volumes.iter_mut().find(|o| o.name == volume.name) {
+                Some(other) => {
+                    let preferred = if current.is_some() && volume.mode == current {
+                        true
+                    } else {
+                        other.mode != current && other.id.is_none() && volume.id.is_some()
+                    };
+                    if preferred {
+                        *other = volume;
+                    }
+                }
+                None => volumes.push(volume),
+            }
+        }
+        volumes
+    }
+
     fn modes(&self) -> Vec<crate::DeviceMode> {
         self.inner
             .modes
@@ -328,13 +1547,170 @@ impl crate::Device for Device {
             .map(|m| crate::DeviceMode {
                 name: m.name.clone(),
                 depends: m.depends.clone(),
-                available: m.sequence.iter().all(|s| s.get().is_some()),
+                available: m
+                    .sequence
+                    .iter()
+                    .all(|s| !matches!(s.config(), ModeStep::Actuator(_)) || s.get().is_some()),
             })
             .collect()
     }
 
+    fn actuators(&self) -> Vec<crate::DeviceActuator> {
+        let mut actuators: Vec<crate::DeviceActuator> = Vec::new();
+        for m in &self.inner.modes {
+            for id in m.sequence.iter().filter_map(DeviceItem::get) {
+                if !actuators.iter().any(|a| a.mode == m.name && a.id == id) {
+                    actuators.push(crate::DeviceActuator {
+                        mode: m.name.clone(),
+                        id,
+                    });
+                }
+            }
+        }
+        actuators
+    }
+
     fn current_mode(&self) -> Option<String> {
         let mode = self.inner.current_mode.lock().unwrap();
         mode.clone()
     }
+
+    fn mode_change(&self) -> Option<String> {
+        let mode = self.inner.mode_change.lock().unwrap();
+        mode.clone()
+    }
+
+    fn safe_mode(&self) -> Option<String> {
+        self.inner.safe_mode.clone()
+    }
+
+    fn queue_order(&self) -> crate::session::QueueOrder {
+        self.inner.queue
+    }
+
+    fn access(&self) -> Option<Vec<String>> {
+        self.inner.access.clone()
+    }
+
+    async fn self_test(&self) -> Result<Vec<DeviceCheck>, DeviceSelfTestError> {
+        let mut checks = self.item_checks().await;
+        for step in &self.inner.selftest {
+            let result = self.selftest_step(step).await;
+            checks.push(DeviceCheck::new(format!("switch to {}", step.mode), result));
+        }
+        Ok(checks)
+    }
+
+    async fn health_check(&self) -> Result<DeviceHealth, DeviceSelfTestError> {
+        let health = self
+            .inner
+            .health
+            .as_ref()
+            .ok_or(DeviceSelfTestError::Unsupported)?;
+        let mut checks = self.item_checks().await;
+        for probe in &health.probes {
+            let result = self.selftest_step(probe).await;
+            let name = match &probe.volume {
+                Some(volume) => format!("volume {} in {}", volume, probe.mode),
+                None => format!("switch to {}", probe.mode),
+            };
+            checks.push(DeviceCheck::new(name, result));
+        }
+        for check in checks.iter().filter(|c| !c.passed) {
+            warn!(
+                "Health check of device {} failed: {}: {}",
+                self.inner.name,
+                check.name,
+                check.message.as_deref().unwrap_or_default()
+            );
+        }
+        let health = DeviceHealth {
+            checked: SystemTime::now(),
+            checks,
+        };
+        *self.inner.last_health.lock().unwrap() = Some(health.clone());
+        self.inner.notifier.notify().await;
+        Ok(health)
+    }
+
+    fn health(&self) -> Option<DeviceHealth> {
+        self.inner.last_health.lock().unwrap().clone()
+    }
+
+    fn power(&self) -> Option<DevicePower> {
+        self.inner.last_power.lock().unwrap().clone()
+    }
+
+    fn config_device(&self) -> Option<Device> {
+        Some(self.clone())
+    }
+
+    async fn attach_console(&self, name: String, id: u64) -> bool {
+        self.inner
+            .runtime_consoles
+            .lock()
+            .unwrap()
+            .push(crate::DeviceConsole { name, id: Some(id) });
+        self.inner.notifier.notify().await;
+        true
+    }
+
+    async fn detach_console(&self, id: u64) {
+        self.inner
+            .runtime_consoles
+            .lock()
+            .unwrap()
+            .retain(|c| c.id != Some(id));
+        self.inner.notifier.notify().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mode(name: &str, depends: Option<&str>) -> DeviceMode {
+        DeviceMode {
+            name: name.to_string(),
+            depends: depends.map(ToString::to_string),
+            sequence: Vec::new(),
+            rollback: Vec::new(),
+        }
+    }
+
+    fn names(path: Result<Vec<&DeviceMode>, DeviceSetModeError>) -> Vec<&str> {
+        path.unwrap().iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[test]
+    fn path() {
+        let modes = [
+            mode("off", None),
+            mode("on", Some("off")),
+            mode("maskrom", Some("off")),
+            mode("loader", Some("maskrom")),
+        ];
+        assert_eq!(names(mode_path(&modes, Some("off"), "on")), ["on"]);
+        assert_eq!(names(mode_path(&modes, Some("on"), "on")), ["off", "on"]);
+        assert_eq!(
+            names(mode_path(&modes, Some("on"), "loader")),
+            ["off", "maskrom", "loader"]
+        );
+        assert_eq!(
+            names(mode_path(&modes, Some("maskrom"), "loader")),
+            ["loader"]
+        );
+        assert_eq!(names(mode_path(&modes, None, "on")), ["off", "on"]);
+        assert_eq!(names(mode_path(&modes, Some("on"), "off")), ["off"]);
+        assert!(matches!(
+            mode_path(&modes, None, "unknown"),
+            Err(DeviceSetModeError::ModeNotFound)
+        ));
+
+        let cycle = [mode("a", Some("b")), mode("b", Some("a"))];
+        assert!(matches!(
+            mode_path(&cycle, None, "a"),
+            Err(DeviceSetModeError::WrongCurrentMode)
+        ));
+    }
 }
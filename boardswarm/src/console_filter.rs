@@ -0,0 +1,214 @@
+// Filters cleaning up console output for text tooling
+use bytes::Bytes;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+    /// Drop ANSI escape sequences, e.g. colors and cursor movement
+    StripAnsi,
+    /// Turn CR LF, LF CR and lone CR line endings into LF
+    NormalizeNewlines,
+    /// Replace invalid UTF-8 with the unicode replacement character
+    SanitizeUtf8,
+}
+
+impl Filter {
+    pub fn from_protocol(filter: boardswarm_protocol::ConsoleFilter) -> Option<Self> {
+        match filter {
+            boardswarm_protocol::ConsoleFilter::None => None,
+            boardswarm_protocol::ConsoleFilter::StripAnsi => Some(Filter::StripAnsi),
+            boardswarm_protocol::ConsoleFilter::NormalizeNewlines => {
+                Some(Filter::NormalizeNewlines)
+            }
+            boardswarm_protocol::ConsoleFilter::SanitizeUtf8 => Some(Filter::SanitizeUtf8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum AnsiState {
+    #[default]
+    Ground,
+    /// After ESC
+    Escape,
+    /// Control sequence, ESC [
+    Csi,
+    /// String sequence (e.g. OSC), terminated by BEL or ESC \
+    String,
+    /// ESC within a string sequence
+    StringEscape,
+}
+
+/// Filter with the state carried over between chunks of output, as e.g. escape sequences or
+/// multi-byte characters may be split over multiple chunks
+#[derive(Debug)]
+enum Stage {
+    StripAnsi(AnsiState),
+    /// Last line ending seen if it was directly preceding
+    NormalizeNewlines(Option<u8>),
+    /// Incomplete character at the end of the previous chunk
+    SanitizeUtf8(Vec<u8>),
+}
+
+impl Stage {
+    fn new(filter: Filter) -> Self {
+        match filter {
+            Filter::StripAnsi => Stage::StripAnsi(AnsiState::Ground),
+            Filter::NormalizeNewlines => Stage::NormalizeNewlines(None),
+            Filter::SanitizeUtf8 => Stage::SanitizeUtf8(Vec::new()),
+        }
+    }
+
+    fn apply(&mut self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Stage::StripAnsi(state) => strip_ansi(state, data),
+            Stage::NormalizeNewlines(last) => normalize_newlines(last, data),
+            Stage::SanitizeUtf8(pending) => sanitize_utf8(pending, data),
+        }
+    }
+}
+
+fn strip_ansi(state: &mut AnsiState, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        *state = match (*state, b) {
+            (AnsiState::Ground, 0x1b) => AnsiState::Escape,
+            (AnsiState::Ground, b) => {
+                out.push(b);
+                AnsiState::Ground
+            }
+            (AnsiState::Escape, b'[') => AnsiState::Csi,
+            (AnsiState::Escape, b']' | b'P' | b'X' | b'^' | b'_') => AnsiState::String,
+            // Intermediate bytes, e.g. selecting a character set
+            (AnsiState::Escape, 0x20..=0x2f) => AnsiState::Escape,
+            (AnsiState::Escape, _) => AnsiState::Ground,
+            (AnsiState::Csi, 0x40..=0x7e) => AnsiState::Ground,
+            (AnsiState::Csi, _) => AnsiState::Csi,
+            (AnsiState::String, 0x07) => AnsiState::Ground,
+            (AnsiState::String, 0x1b) => AnsiState::StringEscape,
+            (AnsiState::String, _) => AnsiState::String,
+            (AnsiState::StringEscape, b'\\') => AnsiState::Ground,
+            (AnsiState::StringEscape, _) => AnsiState::String,
+        }
+    }
+    out
+}
+
+fn normalize_newlines(last: &mut Option<u8>, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        match b {
+            b'\r' | b'\n' => {
+                // Second half of a CR LF or LF CR pair
+                if last.is_some_and(|l| l != b) {
+                    *last = None;
+                } else {
+                    out.push(b'\n');
+                    *last = Some(b);
+                }
+            }
+            b => {
+                out.push(b);
+                *last = None;
+            }
+        }
+    }
+    out
+}
+
+fn sanitize_utf8(pending: &mut Vec<u8>, data: &[u8]) -> Vec<u8> {
+    let mut input = std::mem::take(pending);
+    input.extend_from_slice(data);
+    let mut out = Vec::with_capacity(input.len());
+    let mut rest = &input[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                out.extend_from_slice(valid.as_bytes());
+                break;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                out.extend_from_slice(valid);
+                match e.error_len() {
+                    Some(len) => {
+                        out.extend_from_slice(char::REPLACEMENT_CHARACTER.to_string().as_bytes());
+                        rest = &invalid[len..];
+                    }
+                    None => {
+                        // Incomplete character, wait for the remainder
+                        pending.extend_from_slice(invalid);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Filters applied in order to the output of a single stream
+#[derive(Debug)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new(filters: &[Filter]) -> Self {
+        Self {
+            stages: filters.iter().copied().map(Stage::new).collect(),
+        }
+    }
+
+    pub fn apply(&mut self, data: Bytes) -> Bytes {
+        if self.stages.is_empty() {
+            return data;
+        }
+        let mut data = data.to_vec();
+        for stage in &mut self.stages {
+            data = stage.apply(&data);
+        }
+        data.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(filters: &[Filter], chunks: &[&[u8]]) -> Vec<u8> {
+        let mut pipeline = Pipeline::new(filters);
+        chunks
+            .iter()
+            .flat_map(|c| pipeline.apply(Bytes::copy_from_slice(c)))
+            .collect()
+    }
+
+    #[test]
+    fn ansi() {
+        assert_eq!(
+            apply(
+                &[Filter::StripAnsi],
+                &[b"\x1b[1;3", b"2mgreen\x1b[0m \x1b]0;title\x07done\x1b(B"]
+            ),
+            b"green done"
+        );
+    }
+
+    #[test]
+    fn newlines() {
+        assert_eq!(
+            apply(&[Filter::NormalizeNewlines], &[b"a\r", b"\nb\n\rc\rd\n\ne"]),
+            b"a\nb\nc\nd\n\ne"
+        );
+    }
+
+    #[test]
+    fn utf8() {
+        assert_eq!(
+            apply(&[Filter::SanitizeUtf8], &[b"caf\xc3", b"\xa9 \xff!"]),
+            "café \u{fffd}!".as_bytes()
+        );
+    }
+}
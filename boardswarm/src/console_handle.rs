@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HandleError {
+    #[error("Console handle not found")]
+    NotFound,
+    #[error("Console {console} is locked by handle {handle}")]
+    Locked { console: u64, handle: u64 },
+    #[error("Console handle {0} is owned by another caller")]
+    NotOwner(u64),
+}
+
+impl From<HandleError> for tonic::Status {
+    fn from(e: HandleError) -> Self {
+        match e {
+            HandleError::NotFound => tonic::Status::not_found(e.to_string()),
+            HandleError::Locked { .. } => tonic::Status::failed_precondition(e.to_string()),
+            HandleError::NotOwner(_) => tonic::Status::permission_denied(e.to_string()),
+        }
+    }
+}
+
+/// Open console holding its input lock until closed
+#[derive(Clone, Debug)]
+pub struct Handle {
+    pub console: u64,
+    /// Name of the caller that opened the handle, if known
+    pub owner: Option<String>,
+    /// Cancelled once the handle is closed
+    pub closed: CancellationToken,
+}
+
+impl Handle {
+    /// Handles can only be used by the caller that opened them
+    fn check_owner(&self, id: u64, owner: Option<&str>) -> Result<(), HandleError> {
+        if self.owner.as_deref() == owner {
+            Ok(())
+        } else {
+            Err(HandleError::NotOwner(id))
+        }
+    }
+
+    pub fn to_message(&self, id: u64) -> boardswarm_protocol::ConsoleHandle {
+        boardswarm_protocol::ConsoleHandle {
+            handle: id,
+            console: self.console,
+            owner: self.owner.clone(),
+        }
+    }
+}
+
+/// Console handles by id
+#[derive(Default)]
+pub struct Handles {
+    next: Mutex<u64>,
+    handles: Mutex<HashMap<u64, Handle>>,
+}
+
+impl Handles {
+    /// Open a handle to `console`; Fails if another handle to it is open already
+    pub fn open(&self, console: u64, owner: Option<String>) -> Result<u64, HandleError> {
+        let mut handles = self.handles.lock().unwrap();
+        if let Some((&handle, _)) = handles.iter().find(|(_, h)| h.console == console) {
+            return Err(HandleError::Locked { console, handle });
+        }
+
+        let mut next = self.next.lock().unwrap();
+        *next += 1;
+        handles.insert(
+            *next,
+            Handle {
+                console,
+                owner,
+                closed: CancellationToken::new(),
+            },
+        );
+        Ok(*next)
+    }
+
+    /// Release the handle on behalf of `owner`; Users of the handle are notified through its
+    /// `closed` token
+    pub fn close(&self, id: u64, owner: Option<&str>) -> Result<Handle, HandleError> {
+        let mut handles = self.handles.lock().unwrap();
+        handles
+            .get(&id)
+            .ok_or(HandleError::NotFound)?
+            .check_owner(id, owner)?;
+        let handle = handles.remove(&id).unwrap();
+        handle.closed.cancel();
+        Ok(handle)
+    }
+
+    /// Release all handles of a console, e.g. when it's removed
+    pub fn close_console(&self, console: u64) {
+        self.handles.lock().unwrap().retain(|_, h| {
+            let keep = h.console != console;
+            if !keep {
+                h.closed.cancel();
+            }
+            keep
+        });
+    }
+
+    /// Handle to use on behalf of `owner`
+    pub fn get(&self, id: u64, owner: Option<&str>) -> Result<Handle, HandleError> {
+        let handles = self.handles.lock().unwrap();
+        let handle = handles.get(&id).ok_or(HandleError::NotFound)?;
+        handle.check_owner(id, owner)?;
+        Ok(handle.clone())
+    }
+
+    pub fn list(&self) -> Vec<(u64, Handle)> {
+        let mut handles: Vec<_> = self
+            .handles
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, h)| (id, h.clone()))
+            .collect();
+        handles.sort_by_key(|(id, _)| *id);
+        handles
+    }
+
+    /// Check whether input to `console` may be sent without a handle
+    pub fn check_unlocked(&self, console: u64) -> Result<(), HandleError> {
+        match self
+            .handles
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, h)| h.console == console)
+        {
+            Some((&handle, _)) => Err(HandleError::Locked { console, handle }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locking() {
+        let handles = Handles::default();
+
+        let first = handles.open(1, Some("alice".to_string())).unwrap();
+        assert_eq!(
+            handles.open(1, None),
+            Err(HandleError::Locked {
+                console: 1,
+                handle: first
+            })
+        );
+        assert_eq!(
+            handles.check_unlocked(1),
+            Err(HandleError::Locked {
+                console: 1,
+                handle: first
+            })
+        );
+        assert_eq!(handles.check_unlocked(2), Ok(()));
+        let second = handles.open(2, None).unwrap();
+
+        // Only the owner can use or close the handle
+        assert_eq!(
+            handles.get(first, Some("bob")).unwrap_err(),
+            HandleError::NotOwner(first)
+        );
+        assert_eq!(
+            handles.close(first, None).unwrap_err(),
+            HandleError::NotOwner(first)
+        );
+
+        let closed = handles.get(first, Some("alice")).unwrap().closed;
+        assert!(!closed.is_cancelled());
+        assert_eq!(
+            handles
+                .close(first, Some("alice"))
+                .unwrap()
+                .owner
+                .as_deref(),
+            Some("alice")
+        );
+        assert!(closed.is_cancelled());
+        assert_eq!(
+            handles.close(first, Some("alice")).unwrap_err(),
+            HandleError::NotFound
+        );
+        assert_eq!(handles.check_unlocked(1), Ok(()));
+
+        handles.close_console(2);
+        assert_eq!(
+            handles.get(second, None).unwrap_err(),
+            HandleError::NotFound
+        );
+        assert!(handles.list().is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+// Pacing of console input, as bootloaders tend to drop characters when input comes in too fast
+use std::pin::Pin;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::ConsoleError;
+
+pub type ConsoleInput = Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>;
+
+/// Writes per second used to spread out input when only a rate is configured
+const RATE_CHUNKS_PER_SECOND: u64 = 10;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct InputPacing {
+    /// Maximum number of bytes per second
+    pub rate: Option<u64>,
+    /// Maximum number of bytes per write
+    pub chunk_size: Option<usize>,
+    /// Delay after each write
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub chunk_delay: Option<Duration>,
+}
+
+/// Pacing keys which used to be handled by the serial provider; Still accepted in the console
+/// parameters for all consoles
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ParameterPacing {
+    pub chunk_size: Option<usize>,
+    #[serde(with = "humantime_serde")]
+    pub chunk_delay: Option<Duration>,
+}
+
+impl ParameterPacing {
+    pub fn is_set(&self) -> bool {
+        self.chunk_size.is_some() || self.chunk_delay.is_some()
+    }
+}
+
+impl InputPacing {
+    /// Override the chunking with the keys given in the console parameters
+    pub fn with_parameters(self, parameters: ParameterPacing) -> Self {
+        InputPacing {
+            chunk_size: match parameters.chunk_size {
+                // A chunk size of 0 used to mean unlimited
+                Some(0) => None,
+                Some(size) => Some(size),
+                None => self.chunk_size,
+            },
+            chunk_delay: parameters.chunk_delay.or(self.chunk_delay),
+            ..self
+        }
+    }
+
+    fn is_unpaced(&self) -> bool {
+        self.rate.is_none() && self.chunk_size.is_none() && self.chunk_delay.is_none()
+    }
+
+    fn chunk_size(&self, len: usize) -> usize {
+        let size = match (self.chunk_size, self.rate) {
+            (Some(size), _) => size,
+            (None, Some(rate)) => (rate / RATE_CHUNKS_PER_SECOND) as usize,
+            (None, None) => len,
+        };
+        size.max(1)
+    }
+
+    /// Time a write of `len` bytes should take
+    fn write_time(&self, len: usize) -> Duration {
+        let rate = self
+            .rate
+            .map(|rate| Duration::from_secs_f64(len as f64 / rate.max(1) as f64))
+            .unwrap_or_default();
+        rate.max(self.chunk_delay.unwrap_or_default())
+    }
+}
+
+/// Split input into chunks, sent no faster than the pacing allows
+pub fn paced(input: ConsoleInput, pacing: InputPacing) -> ConsoleInput {
+    if pacing.is_unpaced() {
+        return input;
+    }
+    Box::pin(futures::sink::unfold(
+        input,
+        move |mut input, data: Bytes| async move {
+            let size = pacing.chunk_size(data.len());
+            let mut offset = 0;
+            while offset < data.len() {
+                let chunk = data.slice(offset..data.len().min(offset + size));
+                offset += chunk.len();
+                let until = Instant::now() + pacing.write_time(chunk.len());
+                input.send(chunk).await?;
+                tokio::time::sleep_until(until).await;
+            }
+            Ok(input)
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pacing() {
+        let pacing = InputPacing {
+            rate: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(pacing.chunk_size(64), 10);
+        assert_eq!(pacing.write_time(10), Duration::from_millis(100));
+
+        let pacing = InputPacing {
+            chunk_size: Some(16),
+            chunk_delay: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        assert_eq!(pacing.chunk_size(64), 16);
+        assert_eq!(pacing.write_time(16), Duration::from_millis(10));
+        assert!(InputPacing::default().is_unpaced());
+    }
+
+    #[test]
+    fn parameter_pacing() {
+        let parameters: serde_yaml::Value =
+            serde_yaml::from_str("{ rate: 9600, chunk_size: 8, chunk_delay: 5ms }").unwrap();
+        let parameters = ParameterPacing::deserialize(parameters).unwrap();
+        assert!(parameters.is_set());
+
+        let input = InputPacing {
+            rate: Some(100),
+            chunk_size: Some(16),
+            ..Default::default()
+        };
+        assert_eq!(
+            input.with_parameters(parameters),
+            InputPacing {
+                rate: Some(100),
+                chunk_size: Some(8),
+                chunk_delay: Some(Duration::from_millis(5)),
+            }
+        );
+        assert_eq!(input.with_parameters(ParameterPacing::default()), input);
+
+        let unlimited = ParameterPacing {
+            chunk_size: Some(0),
+            ..Default::default()
+        };
+        assert!(InputPacing::default()
+            .with_parameters(unlimited)
+            .is_unpaced());
+    }
+}
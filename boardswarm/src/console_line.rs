@@ -0,0 +1,141 @@
+// Line discipline handled by the server, independent of the provider backing the console
+use bytes::Bytes;
+use futures::SinkExt;
+use serde::Deserialize;
+
+use crate::console_input::ConsoleInput;
+use crate::ConsoleError;
+
+/// Bytes shown per line of a hex dump
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// Generic options in the console parameters, understood for all consoles; Providers ignore
+/// these like any other parameter they don't know about
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct LineDiscipline {
+    /// Echo input back into the console output, for consoles which don't echo themselves
+    pub local_echo: bool,
+    /// Translate LF line endings in the input into CR LF
+    pub crlf: bool,
+    /// Stream the output to clients as a hex dump, e.g. for binary protocols
+    pub hex_dump: bool,
+}
+
+impl LineDiscipline {
+    fn is_raw_input(&self) -> bool {
+        !self.local_echo && !self.crlf
+    }
+}
+
+/// Translate lone LFs into CR LF; `last_cr` tracks whether the previous input ended with a CR
+fn translate_crlf(data: &[u8], last_cr: &mut bool) -> Bytes {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if b == b'\n' && !*last_cr {
+            out.push(b'\r');
+        }
+        out.push(b);
+        *last_cr = b == b'\r';
+    }
+    out.into()
+}
+
+/// Apply the input side of the line discipline; `echo` gets called with all input as sent by the
+/// client
+pub fn input<F>(input: ConsoleInput, line: LineDiscipline, echo: F) -> ConsoleInput
+where
+    F: Fn(Bytes) + Send + 'static,
+{
+    if line.is_raw_input() {
+        return input;
+    }
+    let mut last_cr = false;
+    Box::pin(input.with(move |data: Bytes| {
+        if line.local_echo {
+            echo(data.clone());
+        }
+        let data = if line.crlf {
+            translate_crlf(&data, &mut last_cr)
+        } else {
+            data
+        };
+        futures::future::ready(Ok::<_, ConsoleError>(data))
+    }))
+}
+
+/// Hex dump of a stream of output, with offsets continuing over chunks
+#[derive(Debug, Default)]
+pub struct HexDump {
+    offset: u64,
+}
+
+impl HexDump {
+    pub fn apply(&mut self, data: &[u8]) -> Bytes {
+        let mut out = String::new();
+        for line in data.chunks(HEX_DUMP_WIDTH) {
+            out.push_str(&format!("{:08x} ", self.offset));
+            for b in line {
+                out.push_str(&format!(" {b:02x}"));
+            }
+            out.push_str(&"   ".repeat(HEX_DUMP_WIDTH - line.len()));
+            out.push_str("  |");
+            out.extend(line.iter().map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            }));
+            out.push_str("|\n");
+            self.offset += line.len() as u64;
+        }
+        out.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crlf() {
+        let mut last_cr = false;
+        assert_eq!(
+            translate_crlf(b"a\nb\r", &mut last_cr),
+            Bytes::from_static(b"a\r\nb\r")
+        );
+        assert_eq!(
+            translate_crlf(b"\nc\n", &mut last_cr),
+            Bytes::from_static(b"\nc\r\n")
+        );
+    }
+
+    #[test]
+    fn hex_dump() {
+        let mut dump = HexDump::default();
+        assert_eq!(
+            dump.apply(b"boot\x00\xff"),
+            Bytes::from_static(
+                b"00000000  62 6f 6f 74 00 ff                                |boot..|\n"
+            )
+        );
+        let dump = dump.apply(&[b'a'; 17]);
+        assert!(dump.starts_with(b"00000006  61"));
+        assert!(dump.ends_with(b"00000016  61                                               |a|\n"));
+    }
+
+    #[test]
+    fn parameters() {
+        let parameters: serde_yaml::Value =
+            serde_yaml::from_str("rate: 115200\nlocal_echo: true\nhex_dump: true").unwrap();
+        assert_eq!(
+            LineDiscipline::deserialize(parameters).unwrap(),
+            LineDiscipline {
+                local_echo: true,
+                crlf: false,
+                hex_dump: true
+            }
+        );
+    }
+}
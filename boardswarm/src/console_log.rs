@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use futures::StreamExt;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::{config, Server};
+
+/// Log file currently being written
+struct LogFile {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+/// Rotating log files of a single console, named after the console and the time they were
+/// started, e.g. `main-2024-01-01T12:00:00.000Z.log`
+struct ConsoleLog {
+    directory: PathBuf,
+    console: String,
+    config: config::ConsoleLog,
+    current: Option<LogFile>,
+}
+
+impl ConsoleLog {
+    fn new(directory: PathBuf, console: String, config: config::ConsoleLog) -> Self {
+        Self {
+            directory,
+            console,
+            config,
+            current: None,
+        }
+    }
+
+    fn needs_rotation(&self, current: &LogFile) -> bool {
+        self.config.max_size.is_some_and(|max| current.size >= max)
+            || self
+                .config
+                .max_age
+                .is_some_and(|max| current.opened.elapsed() >= max)
+    }
+
+    /// Rotated log files of the console, oldest first
+    async fn files(&self) -> std::io::Result<Vec<PathBuf>> {
+        let prefix = format!("{}-", self.console);
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(".log") {
+                files.push(entry.path());
+            }
+        }
+        // The timestamps in the names sort chronologically
+        files.sort();
+        Ok(files)
+    }
+
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.directory.join(format!(
+            "{}-{}.log",
+            self.console,
+            humantime::format_rfc3339_millis(SystemTime::now())
+        ));
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        self.current = Some(LogFile {
+            file,
+            size: 0,
+            opened: Instant::now(),
+        });
+
+        let files = self.files().await?;
+        let excess = files.len().saturating_sub(self.config.keep.max(1));
+        for old in &files[..excess] {
+            if let Err(e) = tokio::fs::remove_file(old).await {
+                warn!("Failed to remove old console log {}: {}", old.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let rotate = match &self.current {
+            Some(current) => self.needs_rotation(current),
+            None => true,
+        };
+        if rotate {
+            self.rotate().await?;
+        }
+        let current = self.current.as_mut().unwrap();
+        current.file.write_all(data).await?;
+        current.file.flush().await?;
+        current.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// File name safe version of an item name
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\0' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Write all output of the console to log files in the device's directory, until the console
+/// goes away
+async fn run(server: Server, config: config::ConsoleLog, device: String, console: String, id: u64) {
+    let directory = server
+        .config_dir()
+        .join(&config.directory)
+        .join(sanitize(&device));
+    let mut output = match server.console_output(id, false).await {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to log console {} of {}: {}", console, device, e);
+            return;
+        }
+    };
+    info!(
+        "Logging console {} of {} to {}",
+        console,
+        device,
+        directory.display()
+    );
+
+    let mut log = ConsoleLog::new(directory, sanitize(&console), config);
+    while let Some(Ok(data)) = output.next().await {
+        if let Err(e) = log.write(&data).await {
+            warn!(
+                "Failed to write log of console {} of {}: {}",
+                console, device, e
+            );
+            // Retry with a new file for the next output
+            log.current = None;
+        }
+    }
+}
+
+/// Loggers of all device consoles by console id
+pub struct Loggers {
+    config: Option<config::ConsoleLog>,
+    active: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Loggers {
+    pub fn new(config: Option<config::ConsoleLog>) -> Self {
+        Self {
+            config,
+            active: Default::default(),
+        }
+    }
+
+    /// Start logging the console if enabled and not logged already; Logging stops once the
+    /// console is removed
+    pub fn start(&self, server: &Server, device: &str, console: &str, id: u64) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        if !self.active.lock().unwrap().insert(id) {
+            return;
+        }
+        let active = self.active.clone();
+        let run = run(
+            server.clone(),
+            config.clone(),
+            device.to_string(),
+            console.to_string(),
+            id,
+        );
+        tokio::spawn(async move {
+            run.await;
+            active.lock().unwrap().remove(&id);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(sanitize("main"), "main");
+        assert_eq!(sanitize("usb/serial"), "usb_serial");
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{stream::BoxStream, StreamExt};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{Console, ConsoleError};
+
+/// Amount of output kept around to match patterns split over multiple chunks
+const WAIT_BUFFER: usize = 4096;
+
+type ConsoleData = BoxStream<'static, Result<Bytes, ConsoleError>>;
+type TimestampedData = BoxStream<'static, Result<(SystemTime, Bytes), ConsoleError>>;
+
+/// Output of a console, read once and shared by all its users; The most recent output is kept
+/// such that clients attaching late can see e.g. early boot messages
+pub struct SharedOutput {
+    /// Bytes of history to keep
+    size: usize,
+    state: Mutex<OutputState>,
+    /// Cancelled once the console is removed
+    stopped: CancellationToken,
+}
+
+struct OutputState {
+    /// Buffered output chunks with the time they were read
+    history: VecDeque<(SystemTime, Bytes)>,
+    /// Total bytes in the history
+    buffered: usize,
+    /// Output following the history; None once reading the console stopped
+    live: Option<broadcast::Sender<(SystemTime, Bytes)>>,
+}
+
+impl SharedOutput {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            state: Mutex::new(OutputState {
+                history: VecDeque::new(),
+                buffered: 0,
+                live: Some(broadcast::channel(64).0),
+            }),
+            stopped: CancellationToken::new(),
+        }
+    }
+
+    fn push(&self, at: SystemTime, data: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        if self.size > 0 {
+            state.buffered += data.len();
+            state.history.push_back((at, data.clone()));
+            while state.buffered > self.size {
+                let excess = state.buffered - self.size;
+                let (_, front) = state.history.front_mut().unwrap();
+                if front.len() <= excess {
+                    state.buffered -= front.len();
+                    state.history.pop_front();
+                } else {
+                    *front = front.slice(excess..);
+                    state.buffered -= excess;
+                }
+            }
+        }
+        if let Some(live) = &state.live {
+            let _ = live.send((at, data));
+        }
+    }
+
+    fn end(&self) {
+        self.state.lock().unwrap().live = None;
+    }
+
+    fn is_running(&self) -> bool {
+        self.state.lock().unwrap().live.is_some()
+    }
+
+    /// The buffered history and a receiver for the output following it; None if the console
+    /// isn't being read (anymore)
+    #[allow(clippy::type_complexity)]
+    pub fn subscribe(
+        &self,
+    ) -> Option<(
+        Vec<(SystemTime, Bytes)>,
+        broadcast::Receiver<(SystemTime, Bytes)>,
+    )> {
+        let state = self.state.lock().unwrap();
+        let live = state.live.as_ref()?.subscribe();
+        Some((state.history.iter().cloned().collect(), live))
+    }
+
+    /// Stream of the live output, optionally preceded by the buffered history; None if the
+    /// console isn't being read (anymore)
+    pub fn stream(&self, history: bool) -> Option<ConsoleData> {
+        let stream = self
+            .stream_timestamped(history)?
+            .map(|data| data.map(|(_, data)| data));
+        Some(stream.boxed())
+    }
+
+    /// Like [Self::stream], with each chunk of output paired with the time it was read from the
+    /// console
+    pub fn stream_timestamped(&self, history: bool) -> Option<TimestampedData> {
+        let (buffered, live) = self.subscribe()?;
+        let buffered = if history { buffered } else { Vec::new() };
+        let buffered = buffered.into_iter().map(Ok::<_, ConsoleError>);
+        let live = BroadcastStream::new(live).filter_map(|data| async move {
+            match data {
+                Ok(data) => Some(Ok(data)),
+                Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                    warn!("Console output lagged, lost {} messages", lost);
+                    None
+                }
+            }
+        });
+        Some(futures::stream::iter(buffered).chain(live).boxed())
+    }
+
+    async fn read(shared: Weak<SharedOutput>, mut output: ConsoleData, stopped: CancellationToken) {
+        loop {
+            let data = tokio::select! {
+                data = output.next() => data,
+                _ = stopped.cancelled() => None,
+            };
+            // Stop once the console is gone
+            let Some(current) = shared.upgrade() else {
+                return;
+            };
+            match data {
+                Some(Ok(data)) => current.push(SystemTime::now(), data),
+                _ => {
+                    current.end();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Shared output of all consoles by console id
+pub struct Outputs {
+    /// Bytes of history kept per console; 0 disables keeping history
+    history: usize,
+    outputs: Mutex<HashMap<u64, Arc<SharedOutput>>>,
+}
+
+impl Outputs {
+    pub fn new(history: usize) -> Self {
+        Self {
+            history,
+            outputs: Mutex::default(),
+        }
+    }
+
+    pub fn keeps_history(&self) -> bool {
+        self.history > 0
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<SharedOutput>> {
+        self.outputs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .filter(|o| o.is_running())
+            .cloned()
+    }
+
+    /// Shared output of the console; Reading the console starts at the first call and continues
+    /// until the console is removed or its output ends
+    pub async fn get_or_start(
+        &self,
+        id: u64,
+        console: &dyn Console,
+    ) -> Result<Arc<SharedOutput>, ConsoleError> {
+        if let Some(shared) = self.get(id) {
+            return Ok(shared);
+        }
+        let output = console.output().await?;
+
+        let mut outputs = self.outputs.lock().unwrap();
+        // Another user may have started reading in the meantime
+        if let Some(shared) = outputs.get(&id).filter(|o| o.is_running()) {
+            return Ok(shared.clone());
+        }
+        let shared = Arc::new(SharedOutput::new(self.history));
+        tokio::spawn(SharedOutput::read(
+            Arc::downgrade(&shared),
+            output,
+            shared.stopped.clone(),
+        ));
+        outputs.insert(id, shared.clone());
+        Ok(shared)
+    }
+
+    /// Add data to the output of the console as if it was read from it, e.g. to echo input; Ignored
+    /// if the console isn't being read
+    pub fn echo(&self, id: u64, data: Bytes) {
+        if let Some(shared) = self.get(id) {
+            shared.push(SystemTime::now(), data);
+        }
+    }
+
+    /// Stop reading a console, e.g. when it's removed; This ends the streams of all its users
+    pub fn remove(&self, id: u64) {
+        if let Some(shared) = self.outputs.lock().unwrap().remove(&id) {
+            shared.stopped.cancel();
+            shared.end();
+        }
+    }
+}
+
+/// Wait for the pattern to show up in the output; Returns the most recent output up to and
+/// including the match, together with the range of the match within it
+pub async fn wait_for(
+    output: &mut ConsoleData,
+    pattern: &regex::bytes::Regex,
+) -> Result<(Bytes, std::ops::Range<usize>), ConsoleError> {
+    let mut buffer = BytesMut::new();
+    while let Some(data) = output.next().await {
+        buffer.extend_from_slice(&data?);
+        if let Some(m) = pattern.find(&buffer) {
+            let range = m.range();
+            buffer.truncate(range.end);
+            return Ok((buffer.freeze(), range));
+        }
+        if buffer.len() > WAIT_BUFFER {
+            buffer.advance(buffer.len() - WAIT_BUFFER);
+        }
+    }
+    Err(ConsoleError::Closed)
+}
+
+/// Prefix each line of output with the time it was read; `line_start` tracks whether the next
+/// output starts a new line
+pub fn timestamp_lines(at: SystemTime, data: &[u8], line_start: &mut bool) -> Bytes {
+    let prefix = format!("[{}] ", humantime::format_rfc3339_micros(at));
+    let mut out = Vec::with_capacity(data.len() + prefix.len());
+    for &b in data {
+        if *line_start {
+            out.extend_from_slice(prefix.as_bytes());
+        }
+        out.push(b);
+        *line_start = b == b'\n';
+    }
+    out.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn concat(chunks: Vec<(SystemTime, Bytes)>) -> Bytes {
+        chunks
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    #[test]
+    fn history() {
+        let shared = SharedOutput::new(8);
+        shared.push(SystemTime::now(), Bytes::from_static(b"hello"));
+        let (buffered, mut live) = shared.subscribe().unwrap();
+        assert_eq!(concat(buffered), Bytes::from_static(b"hello"));
+
+        shared.push(SystemTime::now(), Bytes::from_static(b" world"));
+        assert_eq!(live.try_recv().unwrap().1, Bytes::from_static(b" world"));
+        let (buffered, _) = shared.subscribe().unwrap();
+        assert_eq!(concat(buffered), Bytes::from_static(b"lo world"));
+
+        shared.end();
+        assert!(shared.subscribe().is_none());
+        assert!(live.try_recv().is_err());
+    }
+
+    #[test]
+    fn no_history() {
+        let shared = SharedOutput::new(0);
+        shared.push(SystemTime::now(), Bytes::from_static(b"hello"));
+        let (buffered, _) = shared.subscribe().unwrap();
+        assert!(buffered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait() {
+        let chunks = ["booting\nlog", "in: ", "more"]
+            .map(|c| Ok::<_, ConsoleError>(Bytes::from_static(c.as_bytes())));
+        let mut output = futures::stream::iter(chunks).boxed();
+        let pattern = regex::bytes::Regex::new("login: ").unwrap();
+        let (data, range) = wait_for(&mut output, &pattern).await.unwrap();
+        assert_eq!(data, Bytes::from_static(b"booting\nlogin: "));
+        assert_eq!(&data[range], b"login: ");
+
+        let pattern = regex::bytes::Regex::new("never").unwrap();
+        assert!(wait_for(&mut output, &pattern).await.is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        let at = SystemTime::UNIX_EPOCH;
+        let mut line_start = true;
+        assert_eq!(
+            timestamp_lines(at, b"one\ntw", &mut line_start),
+            Bytes::from_static(
+                b"[1970-01-01T00:00:00.000000Z] one\n[1970-01-01T00:00:00.000000Z] tw"
+            )
+        );
+        assert_eq!(
+            timestamp_lines(at, b"o\n", &mut line_start),
+            Bytes::from_static(b"o\n")
+        );
+        assert!(line_start);
+    }
+}
@@ -0,0 +1,160 @@
+// Device consoles addressed by name, following the console as it comes and goes
+use std::sync::Arc;
+
+use boardswarm_protocol::{device_console_input_request, ConsoleOutput, DeviceConsoleInputRequest};
+use bytes::Bytes;
+use futures::{stream::BoxStream, SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Streaming;
+use tracing::warn;
+
+use crate::console_input::ConsoleInput;
+use crate::{recording, ActiveStream, ConsoleError, Device, DeviceMonitor, Server};
+
+/// Id of the console currently bound to the device console with the given name
+fn console_id(device: &dyn Device, name: &str) -> Option<u64> {
+    device
+        .consoles()
+        .into_iter()
+        .find(|c| c.name == name)
+        .and_then(|c| c.id)
+}
+
+/// Follow the output of whichever console `resolve` returns, resolving it again whenever the
+/// device changes; Ends once the device is removed or the receiver is dropped
+pub fn follow_output<F>(
+    server: Server,
+    mut monitor: DeviceMonitor,
+    resolve: F,
+    history: bool,
+) -> mpsc::Receiver<Result<Bytes, ConsoleError>>
+where
+    F: Fn() -> Option<u64> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut last = None;
+        loop {
+            if let Some(id) = resolve() {
+                // Replay the history of consoles that (re)appeared, as that is output the client
+                // hasn't seen yet
+                let history = history || last.is_some_and(|last| last != id);
+                match server.console_output(id, history).await {
+                    Ok(mut output) => {
+                        let _guard =
+                            server.stream_guard(boardswarm_protocol::ItemType::Console, id);
+                        last = Some(id);
+                        loop {
+                            let data = tokio::select! {
+                                data = output.next() => data,
+                                _ = tx.closed() => return,
+                            };
+                            let Some(Ok(data)) = data else {
+                                break;
+                            };
+                            if tx.send(Ok(data)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Failed to stream console {}: {}", id, e),
+                }
+            }
+            // Wait for the console to (re)appear
+            tokio::select! {
+                gone = monitor.wait() => {
+                    if gone.is_err() {
+                        let _ = tx.send(Err(ConsoleError::Closed)).await;
+                        return;
+                    }
+                }
+                _ = tx.closed() => return,
+            }
+        }
+    });
+    rx
+}
+
+/// Output of the named device console; Whenever the console goes away the stream waits for it
+/// to come back, until the device itself is removed or the client goes away
+pub fn output(
+    server: Server,
+    device: Arc<dyn Device>,
+    name: String,
+    history: bool,
+    guard: ActiveStream,
+) -> BoxStream<'static, Result<ConsoleOutput, tonic::Status>> {
+    // Subscribe before looking up the console such that no change can be missed
+    let monitor = device.updates();
+    let output = follow_output(
+        server,
+        monitor,
+        move || console_id(device.as_ref(), &name),
+        history,
+    );
+    ReceiverStream::new(output)
+        .map(move |data| {
+            // Keep the device in use for as long as the stream
+            let _guard = &guard;
+            Ok::<_, tonic::Status>(ConsoleOutput {
+                data: data?,
+                ..Default::default()
+            })
+        })
+        .boxed()
+}
+
+/// Feed input to the named device console; Input is held back while the console is unavailable
+/// and sent to its replacement once it comes back
+pub async fn input(
+    server: Server,
+    device: Arc<dyn Device>,
+    name: String,
+    mut rx: Streaming<DeviceConsoleInputRequest>,
+) -> Result<(), tonic::Status> {
+    let mut monitor = device.updates();
+    let mut current: Option<(u64, ConsoleInput, ActiveStream)> = None;
+    while let Some(request) = rx.message().await? {
+        let Some(device_console_input_request::TargetOrData::Data(data)) = request.target_or_data
+        else {
+            return Err(tonic::Status::invalid_argument("Target cannot be changed"));
+        };
+        loop {
+            let id = console_id(device.as_ref(), &name);
+            if id.is_none() || current.as_ref().map(|(current, ..)| *current) != id {
+                current = None;
+            }
+            if current.is_none() {
+                if let Some((id, console)) = id.and_then(|id| Some((id, server.get_console(id)?))) {
+                    server.inner.console_handles.check_unlocked(id)?;
+                    match server.console_input(id, console.as_ref()).await {
+                        Ok(input) => {
+                            let guard =
+                                server.stream_guard(boardswarm_protocol::ItemType::Console, id);
+                            current = Some((id, input, guard));
+                        }
+                        Err(e) => warn!("Failed to open console {} ({}) input: {}", name, id, e),
+                    }
+                }
+            }
+
+            if let Some((id, input, _)) = &mut current {
+                server.record(recording::Interaction::ConsoleInput {
+                    console: *id,
+                    data: data.clone(),
+                });
+                if input.send(data.clone()).await.is_ok() {
+                    break;
+                }
+                current = None;
+            }
+            // Wait for the console to (re)appear
+            monitor
+                .wait()
+                .await
+                .map_err(|_| tonic::Status::not_found("Device was removed"))?;
+        }
+    }
+    Ok(())
+}
@@ -4,7 +4,10 @@ use anyhow::bail;
 use bytes::Bytes;
 use dfu_nusb::{DfuASync, DfuNusb};
 use futures::StreamExt;
-use nusb::descriptors::language_id::US_ENGLISH;
+use nusb::{
+    descriptors::language_id::US_ENGLISH,
+    transfer::{ControlOut, ControlType, Recipient},
+};
 use tokio::sync::{
     mpsc::{self, Receiver},
     oneshot,
@@ -19,6 +22,10 @@ use crate::{
 };
 pub const PROVIDER: &str = "dfu";
 
+/// DFU class requests to get the device back to the idle state
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_ABORT: u8 = 6;
+
 #[instrument(skip(server))]
 pub async fn start_provider(name: String, server: Server) {
     let provider_properties = &[
@@ -67,6 +74,7 @@ pub async fn start_provider(name: String, server: Server) {
                     server.unregister_volume(id)
                 }
             }
+            DeviceEvent::Change(_) => (),
         }
     }
 }
@@ -91,6 +99,8 @@ impl Dfu {
                 seekable: false,
                 size: None,
                 blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                sha256: None,
             })
             .collect();
         Self { device, targets }
@@ -109,11 +119,17 @@ impl Volume for Dfu {
         length: Option<u64>,
     ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
         if let Some(volume_target) = self.targets.iter().find(|t| t.name == target) {
-            let tx = self
+            let (tx, abort) = self
                 .device
                 .start_download(target.to_owned(), length.unwrap_or_default() as u32)
                 .await;
-            Ok((volume_target.clone(), Box::new(DfuTarget { tx })))
+            Ok((
+                volume_target.clone(),
+                Box::new(DfuTarget {
+                    tx,
+                    abort: Some(abort),
+                }),
+            ))
         } else {
             warn!("Unknown target requested");
             Err(VolumeError::UnknownTargetRequested)
@@ -128,6 +144,7 @@ impl Volume for Dfu {
 
 struct DfuTarget {
     tx: mpsc::Sender<Bytes>,
+    abort: Option<oneshot::Sender<oneshot::Sender<()>>>,
 }
 
 #[async_trait::async_trait]
@@ -137,6 +154,17 @@ impl VolumeTarget for DfuTarget {
         let _ = self.tx.send(data).await;
         completion.complete(Ok(len as u64));
     }
+
+    async fn abort(&mut self) {
+        // Stop the download before the data ends, as the end of the data would otherwise
+        // complete the download with a partial image
+        if let Some(abort) = self.abort.take() {
+            let (tx, rx) = oneshot::channel();
+            if abort.send(tx).is_ok() {
+                let _ = rx.await;
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -146,6 +174,8 @@ enum DfuCommand {
         target: String,
         length: u32,
         data: Receiver<Bytes>,
+        /// Aborts the download, replying once the device is idle again
+        abort: oneshot::Receiver<oneshot::Sender<()>>,
     },
     Reset(oneshot::Sender<()>),
 }
@@ -165,16 +195,22 @@ impl DfuDevice {
         rx.await.unwrap_or_default()
     }
 
-    async fn start_download(&self, target: String, length: u32) -> mpsc::Sender<Bytes> {
+    async fn start_download(
+        &self,
+        target: String,
+        length: u32,
+    ) -> (mpsc::Sender<Bytes>, oneshot::Sender<oneshot::Sender<()>>) {
         let (tx, data) = tokio::sync::mpsc::channel(1);
+        let (abort_tx, abort) = oneshot::channel();
         let command = DfuCommand::Download {
             target,
             length,
             data,
+            abort,
         };
 
         self.0.send(command).await.unwrap();
-        tx
+        (tx, abort_tx)
     }
 
     async fn reset(&self) {
@@ -270,6 +306,28 @@ async fn dfu_download(
     Ok(())
 }
 
+/// Bring the interface back to the idle state after an interrupted download
+async fn dfu_abort(device: &DfuData, interface: &DfuInterface) -> anyhow::Result<()> {
+    let i = device.device.claim_interface(interface.interface)?;
+    let request = |request| ControlOut {
+        control_type: ControlType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value: 0,
+        index: interface.interface.into(),
+        data: &[],
+    };
+    if i.control_out(request(DFU_ABORT))
+        .await
+        .into_result()
+        .is_err()
+    {
+        // Abort isn't accepted in the error state, which clearing the status leaves instead
+        i.control_out(request(DFU_CLRSTATUS)).await.into_result()?;
+    }
+    Ok(())
+}
+
 async fn dfu_reset(device: &DfuData, interface: &DfuInterface) -> anyhow::Result<()> {
     let dfu = device.open(interface)?;
     let _ = dfu.detach().await;
@@ -295,10 +353,23 @@ async fn dfu_task(bus: u8, dev: u8, mut control: mpsc::Receiver<DfuCommand>) {
                 target,
                 length,
                 data,
+                mut abort,
             } => {
                 if let Some(interface) = device.interfaces.get(&target) {
-                    if let Err(e) = dfu_download(&device, interface, length, data).await {
-                        warn!("Download failed: {}", e);
+                    tokio::select! {
+                        biased;
+                        Ok(done) = &mut abort => {
+                            info!("Download aborted");
+                            if let Err(e) = dfu_abort(&device, interface).await {
+                                warn!("Abort failed: {}", e);
+                            }
+                            let _ = done.send(());
+                        }
+                        r = dfu_download(&device, interface, length, data) => {
+                            if let Err(e) = r {
+                                warn!("Download failed: {}", e);
+                            }
+                        }
                     }
                 } else {
                     warn!("Target not found: {}", target);
@@ -0,0 +1,314 @@
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Sink, StreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    utils::ProcessTarget,
+    ActuatorError, ConsoleError, Server, Volume, VolumeError, VolumeTarget, VolumeTargetInfo,
+};
+
+pub const PROVIDER: &str = "docker";
+
+/// Name of the single target of the rootfs volume
+const TARGET: &str = "rootfs";
+
+/// Delay before attaching again after the container's tty went away, e.g. as it was stopped
+const ATTACH_RETRY: Duration = Duration::from_secs(2);
+
+fn default_docker() -> String {
+    "docker".to_string()
+}
+
+fn default_destination() -> String {
+    "/".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct Container {
+    name: String,
+    /// Name or id of the (pre-created) container
+    container: String,
+    /// Directory in the container the rootfs tarball gets extracted into
+    #[serde(default = "default_destination")]
+    destination: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DockerParameters {
+    /// Container engine command, e.g. podman
+    #[serde(default = "default_docker")]
+    docker: String,
+    containers: Vec<Container>,
+}
+
+#[derive(Debug)]
+struct Docker {
+    docker: String,
+    container: Container,
+}
+
+impl Docker {
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.docker);
+        command.kill_on_drop(true);
+        command
+    }
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: DockerParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for container in parameters.containers {
+        let mut properties = Properties::new(&container.name);
+        properties.extend(provider_properties);
+        properties.insert(
+            format!("{PROVIDER}.container"),
+            container.container.as_str(),
+        );
+
+        let docker = Arc::new(Docker {
+            docker: parameters.docker.clone(),
+            container,
+        });
+        let (input, rx) = mpsc::channel(16);
+        let output = broadcast::channel(64).0;
+        tokio::spawn(attach(docker.clone(), rx, output.clone()));
+
+        server.register_actuator(properties.clone(), ContainerActuator(docker.clone()));
+        server.register_console(properties.clone(), ContainerConsole { input, output });
+        server.register_volume(properties, ContainerVolume::new(docker));
+    }
+}
+
+/// Keep attached to the tty of the container for as long as the console exists
+async fn attach(
+    docker: Arc<Docker>,
+    input: mpsc::Receiver<Bytes>,
+    output: broadcast::Sender<Bytes>,
+) {
+    let input = Arc::new(Mutex::new(input));
+    loop {
+        let child = docker
+            .command()
+            .args(["attach", "--sig-proxy=false"])
+            .arg(&docker.container.container)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to start {}: {}", docker.docker, e);
+                return;
+            }
+        };
+
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let input = input.clone();
+        let feed = async move {
+            let mut input = input.lock().await;
+            while let Some(data) = input.recv().await {
+                if stdin.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let read = async {
+            let mut buf = BytesMut::with_capacity(4096);
+            loop {
+                buf.reserve(4096);
+                match stdout.read_buf(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        let _ = output.send(buf.split().freeze());
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            _ = feed => return,
+            _ = read => (),
+        }
+        let _ = child.wait().await;
+        tokio::time::sleep(ATTACH_RETRY).await;
+    }
+}
+
+#[derive(Debug)]
+struct ContainerActuator(Arc<Docker>);
+
+#[async_trait::async_trait]
+impl crate::Actuator for ContainerActuator {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let action = match parameters.mode.as_str() {
+            "on" => "start",
+            "off" => "stop",
+            "reset" => "restart",
+            mode => {
+                warn!("Unsupported container mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        };
+        let output = self
+            .0
+            .command()
+            .arg(action)
+            .arg(&self.0.container.container)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| {
+                warn!("Failed to run {}: {}", self.0.docker, e);
+                ActuatorError {}
+            })?;
+        if output.status.success() {
+            info!("Container {} {}", self.0.container.container, action);
+            Ok(())
+        } else {
+            warn!(
+                "Failed to {} container {}: {}",
+                action,
+                self.0.container.container,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Err(ActuatorError {})
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ContainerConsole {
+    input: mpsc::Sender<Bytes>,
+    output: broadcast::Sender<Bytes>,
+}
+
+#[async_trait::async_trait]
+impl crate::Console for ContainerConsole {
+    fn configure(
+        &self,
+        _parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        Ok(())
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let input = self.input.clone();
+        Ok(Box::pin(futures::sink::unfold(
+            input,
+            |input, data: Bytes| async move {
+                input.send(data).await.map_err(|_| ConsoleError::Closed)?;
+                Ok(input)
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("Container output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
+
+#[derive(Debug)]
+struct ContainerVolume {
+    docker: Arc<Docker>,
+    targets: [VolumeTargetInfo; 1],
+}
+
+impl ContainerVolume {
+    fn new(docker: Arc<Docker>) -> Self {
+        Self {
+            docker,
+            targets: [VolumeTargetInfo {
+                name: TARGET.to_string(),
+                readable: false,
+                writable: true,
+                seekable: false,
+                size: None,
+                blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                sha256: None,
+            }],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Volume for ContainerVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (&self.targets, true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        _length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        if target != TARGET {
+            return Err(VolumeError::UnknownTargetRequested);
+        }
+        // Extract the tarball streamed on stdin into the container
+        let child = self
+            .docker
+            .command()
+            .arg("cp")
+            .arg("-")
+            .arg(format!(
+                "{}:{}",
+                self.docker.container.container, self.docker.container.destination
+            ))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                VolumeError::Failure(format!("Failed to run {}: {}", self.docker.docker, e))
+            })?;
+        Ok((
+            self.targets[0].clone(),
+            Box::new(ProcessTarget::new(child, "Copying rootfs")),
+        ))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use futures::{stream::BoxStream, Sink, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    ActuatorError, ConsoleError, FlushCompletion, Server, VolumeError, VolumeTarget,
+    VolumeTargetInfo, WriteCompletion,
+};
+
+pub const PROVIDER: &str = "external";
+
+/// Delays before restarting an exited process; Doubled after every exit up to the maximum and
+/// reset once the process kept running for longer than that
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug)]
+struct ExternalParameters {
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ItemKind {
+    Actuator,
+    Console,
+    Volume,
+}
+
+#[derive(Deserialize, Debug)]
+struct Registration {
+    id: String,
+    kind: ItemKind,
+    name: String,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+    /// Targets of a volume
+    #[serde(default)]
+    targets: Vec<String>,
+}
+
+/// Messages sent by the external process on its stdout, one per line
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Register(Registration),
+    Unregister {
+        id: String,
+    },
+    Output {
+        id: String,
+        data: String,
+    },
+    Reply {
+        request: u64,
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+/// Requests sent to the external process on its stdin, one per line
+#[derive(Serialize, Debug)]
+struct Request<'a> {
+    /// Only set if a reply is expected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<u64>,
+    id: &'a str,
+    #[serde(flatten)]
+    operation: Operation,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+enum Operation {
+    SetMode {
+        parameters: serde_json::Value,
+    },
+    Configure {
+        parameters: serde_json::Value,
+    },
+    Input {
+        data: String,
+    },
+    Open {
+        target: String,
+        length: Option<u64>,
+    },
+    Write {
+        target: String,
+        offset: u64,
+        data: String,
+    },
+    Flush {
+        target: String,
+    },
+    Commit,
+    Erase {
+        target: String,
+    },
+}
+
+/// Connection to the external process, shared by all items it registered
+#[derive(Debug)]
+struct Process {
+    input: mpsc::UnboundedSender<String>,
+    next_request: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<(), String>>>>,
+}
+
+impl Process {
+    fn write(&self, request: Request) -> Result<(), String> {
+        let line = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+        self.input
+            .send(line)
+            .map_err(|_| "External process exited".to_string())
+    }
+
+    /// Send a request without waiting for a reply
+    fn notify(&self, id: &str, operation: Operation) -> Result<(), String> {
+        self.write(Request {
+            request: None,
+            id,
+            operation,
+        })
+    }
+
+    /// Send a request and wait for the process to reply to it
+    async fn call(&self, id: &str, operation: Operation) -> Result<(), String> {
+        let request = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request, tx);
+
+        if let Err(e) = self.write(Request {
+            request: Some(request),
+            id,
+            operation,
+        }) {
+            self.pending.lock().unwrap().remove(&request);
+            return Err(e);
+        }
+
+        rx.await
+            .map_err(|_| "External process exited".to_string())?
+    }
+
+    fn reply(&self, request: u64, error: Option<String>) {
+        if let Some(tx) = self.pending.lock().unwrap().remove(&request) {
+            let _ = tx.send(error.map_or(Ok(()), Err));
+        } else {
+            warn!("Reply to unknown request {}", request);
+        }
+    }
+}
+
+enum Item {
+    Actuator(u64),
+    Console(u64, broadcast::Sender<Bytes>),
+    Volume(u64),
+}
+
+impl Item {
+    fn unregister(self, server: &Server) {
+        match self {
+            Item::Actuator(id) => server.unregister_actuator(id),
+            Item::Console(id, _) => server.unregister_console(id),
+            Item::Volume(id) => server.unregister_volume(id),
+        }
+    }
+}
+
+fn register(
+    server: &Server,
+    process: &Arc<Process>,
+    provider_properties: &[(&str, &str)],
+    registration: Registration,
+) -> Item {
+    let Registration {
+        id,
+        kind,
+        name,
+        properties: extra,
+        targets,
+    } = registration;
+    let mut properties = Properties::new(name);
+    properties.extend(provider_properties);
+    properties.extend(extra);
+    properties.insert("external.id", id.as_str());
+
+    match kind {
+        ItemKind::Actuator => {
+            let actuator = ExternalActuator {
+                id,
+                process: process.clone(),
+            };
+            Item::Actuator(server.register_actuator(properties, actuator))
+        }
+        ItemKind::Console => {
+            let output = broadcast::channel(64).0;
+            let console = ExternalConsole {
+                id,
+                process: process.clone(),
+                output: output.clone(),
+            };
+            Item::Console(server.register_console(properties, console), output)
+        }
+        ItemKind::Volume => {
+            let targets = targets
+                .into_iter()
+                .map(|name| VolumeTargetInfo {
+                    name,
+                    readable: false,
+                    writable: true,
+                    seekable: false,
+                    ..Default::default()
+                })
+                .collect();
+            let volume = ExternalVolume {
+                id,
+                process: process.clone(),
+                targets,
+            };
+            Item::Volume(server.register_volume(properties, volume))
+        }
+    }
+}
+
+#[instrument(skip(parameters, server))]
+pub async fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: ExternalParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        run(&parameters, provider_properties, &server).await;
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        info!(
+            "Restarting {} in {}s",
+            parameters.command.display(),
+            backoff.as_secs()
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Run the process until it exits; All items it registered are removed afterwards
+async fn run(
+    parameters: &ExternalParameters,
+    provider_properties: &[(&str, &str)],
+    server: &Server,
+) {
+    let mut child = match Command::new(&parameters.command)
+        .args(&parameters.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to start {}: {}", parameters.command.display(), e);
+            return;
+        }
+    };
+    info!("Started {}", parameters.command.display());
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+
+    let (input, mut requests) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(mut line) = requests.recv().await {
+            line.push('\n');
+            if let Err(e) = stdin.write_all(line.as_bytes()).await {
+                warn!("Failed to write to external process: {}", e);
+                break;
+            }
+        }
+    });
+
+    let process = Arc::new(Process {
+        input,
+        next_request: AtomicU64::new(0),
+        pending: Mutex::new(HashMap::new()),
+    });
+    let mut items: HashMap<String, Item> = HashMap::new();
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Failed to read from external process: {}", e);
+                break;
+            }
+        };
+        let message: Message = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Invalid message from external process: {}", e);
+                continue;
+            }
+        };
+
+        match message {
+            Message::Register(registration) => {
+                let id = registration.id.clone();
+                if let Some(item) = items.remove(&id) {
+                    item.unregister(server);
+                }
+                let item = register(server, &process, provider_properties, registration);
+                items.insert(id, item);
+            }
+            Message::Unregister { id } => {
+                if let Some(item) = items.remove(&id) {
+                    item.unregister(server);
+                }
+            }
+            Message::Output { id, data } => match (items.get(&id), BASE64.decode(data)) {
+                (Some(Item::Console(_, output)), Ok(data)) => {
+                    let _ = output.send(data.into());
+                }
+                (Some(Item::Console(..)), Err(e)) => {
+                    warn!("Invalid output data for {}: {}", id, e)
+                }
+                _ => warn!("Output for unknown console {}", id),
+            },
+            Message::Reply { request, error } => process.reply(request, error),
+        }
+    }
+
+    // Fail all outstanding requests and drop everything the process registered
+    process.pending.lock().unwrap().clear();
+    for item in items.into_values() {
+        item.unregister(server);
+    }
+    match child.wait().await {
+        Ok(status) => warn!("External process exited: {}", status),
+        Err(e) => warn!("Failed to wait for external process: {}", e),
+    }
+}
+
+#[derive(Debug)]
+struct ExternalActuator {
+    id: String,
+    process: Arc<Process>,
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for ExternalActuator {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        let parameters = serde_json::Value::deserialize(parameters).map_err(|e| {
+            warn!("Invalid mode parameters for {}: {}", self.id, e);
+            ActuatorError {}
+        })?;
+        self.process
+            .call(&self.id, Operation::SetMode { parameters })
+            .await
+            .map_err(|e| {
+                warn!("Failed to set mode on {}: {}", self.id, e);
+                ActuatorError {}
+            })
+    }
+}
+
+#[derive(Debug)]
+struct ExternalConsole {
+    id: String,
+    process: Arc<Process>,
+    output: broadcast::Sender<Bytes>,
+}
+
+#[async_trait::async_trait]
+impl crate::Console for ExternalConsole {
+    fn configure(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        let parameters = serde_json::Value::deserialize(parameters)
+            .map_err(|e| ConsoleError::Unavailable(e.to_string()))?;
+        self.process
+            .notify(&self.id, Operation::Configure { parameters })
+            .map_err(ConsoleError::Unavailable)
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let id = self.id.clone();
+        let process = self.process.clone();
+        Ok(Box::pin(futures::sink::unfold(
+            (),
+            move |_, data: Bytes| {
+                let data = BASE64.encode(data);
+                let result = process
+                    .notify(&id, Operation::Input { data })
+                    .map_err(|_| ConsoleError::Closed);
+                async move { result }
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("External console output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
+
+#[derive(Debug)]
+struct ExternalVolume {
+    id: String,
+    process: Arc<Process>,
+    targets: Vec<VolumeTargetInfo>,
+}
+
+#[async_trait::async_trait]
+impl crate::Volume for ExternalVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (self.targets.as_slice(), true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        let info = self
+            .targets
+            .iter()
+            .find(|t| t.name == target)
+            .ok_or(VolumeError::UnknownTargetRequested)?;
+        self.process
+            .call(
+                &self.id,
+                Operation::Open {
+                    target: target.to_string(),
+                    length,
+                },
+            )
+            .await
+            .map_err(VolumeError::Failure)?;
+
+        Ok((
+            info.clone(),
+            Box::new(ExternalVolumeTarget {
+                id: self.id.clone(),
+                process: self.process.clone(),
+                target: target.to_string(),
+            }),
+        ))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        self.process
+            .call(&self.id, Operation::Commit)
+            .await
+            .map_err(VolumeError::Failure)
+    }
+
+    async fn erase(&self, target: &str) -> Result<(), VolumeError> {
+        if !self.targets.iter().any(|t| t.name == target) {
+            return Err(VolumeError::UnknownTargetRequested);
+        }
+        self.process
+            .call(
+                &self.id,
+                Operation::Erase {
+                    target: target.to_string(),
+                },
+            )
+            .await
+            .map_err(VolumeError::Failure)
+    }
+}
+
+struct ExternalVolumeTarget {
+    id: String,
+    process: Arc<Process>,
+    target: String,
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for ExternalVolumeTarget {
+    async fn write(&mut self, data: Bytes, offset: u64, completion: WriteCompletion) {
+        let written = data.len() as u64;
+        let operation = Operation::Write {
+            target: self.target.clone(),
+            offset,
+            data: BASE64.encode(data),
+        };
+        let result = self.process.call(&self.id, operation).await;
+        completion.complete(result.map(|_| written).map_err(tonic::Status::aborted));
+    }
+
+    async fn flush(&mut self, completion: FlushCompletion) {
+        let operation = Operation::Flush {
+            target: self.target.clone(),
+        };
+        let result = self.process.call(&self.id, operation).await;
+        completion.complete(result.map_err(tonic::Status::aborted));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Registers a console, answers the first request with some output and exits on the second
+    const SCRIPT: &str = r#"
+        echo '{"type": "register", "id": "uart", "kind": "console", "name": "uart"}'
+        read request
+        echo '{"type": "output", "id": "uart", "data": "aGVsbG8="}'
+        read request
+    "#;
+
+    #[tokio::test]
+    async fn restart() {
+        let server = crate::test::test_server();
+        let parameters = serde_yaml::to_value(HashMap::from([
+            ("command", serde_yaml::to_value("sh").unwrap()),
+            ("args", serde_yaml::to_value(["-c", SCRIPT]).unwrap()),
+        ]))
+        .unwrap();
+        tokio::spawn(start_provider(
+            "fake".to_string(),
+            parameters,
+            server.clone(),
+        ));
+
+        let matches = HashMap::from([(registry::NAME, "uart")]);
+        let wait = async {
+            let (id, console) = server.inner.consoles.wait_for(&matches).await;
+            assert_eq!(
+                console.properties().get(registry::PROVIDER_NAME),
+                Some("fake")
+            );
+            let configure = || {
+                console
+                    .inner()
+                    .configure(Box::new(<dyn erased_serde::Deserializer>::erase(
+                        serde_json::json!({}),
+                    )))
+                    .unwrap()
+            };
+            let mut output = server.console_output(id, false).await.unwrap();
+            configure();
+            assert_eq!(output.next().await.unwrap().unwrap(), "hello");
+            configure();
+
+            // The console goes away along with the process, which then gets started again
+            let mut monitor = server.inner.consoles.monitor();
+            while server.get_console(id).is_some() {
+                monitor.recv().await.unwrap();
+            }
+            let (restarted, _) = server.inner.consoles.wait_for(&matches).await;
+            assert_ne!(restarted, id);
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap();
+    }
+}
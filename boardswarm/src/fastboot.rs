@@ -7,10 +7,12 @@ use fastboot_protocol::nusb::{DownloadError, NusbFastBoot};
 use serde::Deserialize;
 use tokio::sync::oneshot;
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 use tracing::{info, instrument};
 
 use crate::{
+    property_match::PropertyMatch,
     registry::{self, Properties},
     udev::{DeviceEvent, DeviceRegistrations, PreRegistration, UsbInterface},
     Server, Volume, VolumeError, VolumeTarget, VolumeTargetInfo,
@@ -21,7 +23,7 @@ pub const PROVIDER: &str = "fastboot";
 #[derive(Deserialize, Debug, Default)]
 struct FastbootParameters {
     #[serde(rename = "match")]
-    match_: HashMap<String, String>,
+    match_: HashMap<String, PropertyMatch>,
     #[serde(default)]
     targets: Vec<String>,
 }
@@ -94,6 +96,7 @@ pub async fn start_provider(name: String, parameters: Option<serde_yaml::Value>,
                 });
             }
             DeviceEvent::Remove(device) => registrations.remove(&device),
+            DeviceEvent::Change(_) => (),
         }
     }
 }
@@ -108,6 +111,8 @@ fn fastboot_volume_target<S: Into<String>>(target: S, size: Option<u64>) -> Volu
         seekable: true,
         size,
         blocksize: Some(FASTBOOT_BLOCKSIZE as u32),
+        kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+        sha256: None,
     }
 }
 
@@ -322,6 +327,8 @@ enum FastbootCommand {
     Flash {
         target: String,
         data: FastbootData,
+        /// Skips the flash if cancelled before it got started
+        cancel: CancellationToken,
         result: oneshot::Sender<Result<(), fastboot_protocol::nusb::DownloadError>>,
     },
     Probe {
@@ -346,8 +353,13 @@ async fn process(
             FastbootCommand::Flash {
                 target,
                 data,
+                cancel,
                 result,
             } => {
+                if cancel.is_cancelled() {
+                    debug!("Skipping cancelled flash of {target}");
+                    continue;
+                }
                 async fn do_download_raw(
                     fastboot: &mut NusbFastBoot,
                     target: &str,
@@ -483,11 +495,13 @@ impl FastbootDevice {
         &self,
         target: S,
         data: FastbootData,
+        cancel: CancellationToken,
     ) -> Result<FlashResult, tonic::Status> {
         let (tx, result) = oneshot::channel();
         let cmd = FastbootCommand::Flash {
             target: target.into(),
             data,
+            cancel,
             result: tx,
         };
         self.0
@@ -600,6 +614,7 @@ struct FastbootVolumeTarget {
     target: String,
     max_download: u32,
     buffered: FastbootData,
+    cancel: CancellationToken,
 }
 
 impl FastbootVolumeTarget {
@@ -609,6 +624,7 @@ impl FastbootVolumeTarget {
             target,
             max_download,
             buffered: FastbootData::default(),
+            cancel: CancellationToken::new(),
         }
     }
 }
@@ -629,7 +645,11 @@ impl VolumeTarget for FastbootVolumeTarget {
                     .append(offset as usize, &mut data, self.max_download as usize);
             if !appended || self.buffered.flash_size() > threshold {
                 let buffer = std::mem::take(&mut self.buffered);
-                match self.device.flash(&self.target, buffer).await {
+                match self
+                    .device
+                    .flash(&self.target, buffer, self.cancel.clone())
+                    .await
+                {
                     Ok(flash) => flashes.push(flash),
                     Err(e) => {
                         completion.complete(Err(e));
@@ -660,7 +680,11 @@ impl VolumeTarget for FastbootVolumeTarget {
         async fn do_flush(me: &mut FastbootVolumeTarget) -> Result<(), tonic::Status> {
             if !me.buffered.is_empty() {
                 me.device
-                    .flash(&me.target, std::mem::take(&mut me.buffered))
+                    .flash(
+                        &me.target,
+                        std::mem::take(&mut me.buffered),
+                        me.cancel.clone(),
+                    )
                     .await?
                     .result()
                     .await?;
@@ -671,4 +695,12 @@ impl VolumeTarget for FastbootVolumeTarget {
         }
         completion.complete(do_flush(self).await);
     }
+
+    async fn abort(&mut self) {
+        // Neither flash the buffered data nor the queued flashes; A download in progress can't be
+        // interrupted, so wait for the device to be done with it
+        self.buffered = FastbootData::default();
+        self.cancel.cancel();
+        let _ = self.device.ping().await;
+    }
 }
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use futures::StreamExt;
 use serde::Deserialize;
@@ -7,6 +7,7 @@ use tracing::instrument;
 use tracing::{debug, warn};
 
 use crate::{
+    property_match::PropertyMatch,
     registry::{self, Properties},
     udev::DeviceEvent,
     Server,
@@ -19,12 +20,15 @@ struct Line {
     line_name: Option<String>,
     line_number: Option<tokio_gpiod::LineId>,
     name: String,
+    /// Whether the line is active when low, e.g. for relays switching on a low level
+    #[serde(default)]
+    active_low: bool,
 }
 
 #[derive(Deserialize, Debug)]
 struct GpioParameters {
     #[serde(rename = "match")]
-    match_: HashMap<String, String>,
+    match_: HashMap<String, PropertyMatch>,
     lines: Vec<Line>,
 }
 
@@ -92,6 +96,7 @@ pub async fn start_provider(name: String, parameters: serde_yaml::Value, server:
                     }
                 }
             }
+            DeviceEvent::Change(_) => (),
         }
     }
 }
@@ -115,7 +120,7 @@ async fn setup_gpio_chip(
 
     for line in parameters.lines_by_number() {
         let line_number = line.line_number.unwrap();
-        let id = setup_gpio_line(&chip, line_number, &line.name, properties.clone(), server).await;
+        let id = setup_gpio_line(&chip, line_number, line, properties.clone(), server).await;
         ids.push(id);
     }
 
@@ -126,7 +131,7 @@ async fn setup_gpio_chip(
                 .lines_by_name()
                 .find(|l| l.line_name.as_deref().unwrap() == info.name)
             {
-                let id = setup_gpio_line(&chip, i, &line.name, properties.clone(), server).await;
+                let id = setup_gpio_line(&chip, i, line, properties.clone(), server).await;
                 ids.push(id);
             }
         }
@@ -137,18 +142,24 @@ async fn setup_gpio_chip(
 async fn setup_gpio_line(
     chip: &Chip,
     line: tokio_gpiod::LineId,
-    name: &str,
+    config: &Line,
     mut properties: Properties,
     server: &Server,
 ) -> u64 {
     let info = chip.line_info(line).await.unwrap();
-    properties.insert(registry::NAME, name);
+    properties.insert(registry::NAME, config.name.as_str());
     if !info.name.is_empty() {
         properties.insert("gpio.line_name", info.name);
     }
     properties.insert("gpio.line_number", format!("{}", line));
+    let active = if config.active_low {
+        tokio_gpiod::Active::Low
+    } else {
+        tokio_gpiod::Active::High
+    };
     let opts = tokio_gpiod::Options::output([line])
         .drive(tokio_gpiod::Drive::PushPull)
+        .active(active)
         .consumer("boardswarm");
     let line = chip.request_lines(opts).await.unwrap();
     server.register_actuator(properties, GpioLine { line })
@@ -165,6 +176,15 @@ impl std::fmt::Debug for GpioLine {
     }
 }
 
+impl GpioLine {
+    async fn set(&self, value: bool) -> Result<(), crate::ActuatorError> {
+        self.line.set_values([value]).await.map_err(|e| {
+            warn!("Failed to set gpio line: {}", e);
+            crate::ActuatorError {}
+        })
+    }
+}
+
 #[async_trait::async_trait]
 impl crate::Actuator for GpioLine {
     async fn set_mode(
@@ -174,9 +194,20 @@ impl crate::Actuator for GpioLine {
         #[derive(Deserialize)]
         struct ModeParameters {
             value: bool,
+            /// Restore the opposite value after this long, e.g. to press a reset button
+            #[serde(default)]
+            #[serde(with = "humantime_serde")]
+            pulse: Option<Duration>,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|e| {
+            warn!("Invalid gpio parameters: {}", e);
+            crate::ActuatorError {}
+        })?;
+        self.set(parameters.value).await?;
+        if let Some(pulse) = parameters.pulse {
+            tokio::time::sleep(pulse).await;
+            self.set(!parameters.value).await?;
         }
-        let parameters = ModeParameters::deserialize(parameters).unwrap();
-        self.line.set_values([parameters.value]).await.unwrap();
         Ok(())
     }
 }
@@ -0,0 +1,122 @@
+// Devices grouped by the tags in their configuration, such that operations like mode changes and
+// image writes can be applied to all members at once
+use std::collections::{BTreeMap, HashMap};
+
+use boardswarm_protocol::{group_progress, Group, GroupDeviceDone, GroupProgress};
+use futures::{stream, Future, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamMap};
+
+use crate::{auth, registry, Server};
+
+pub type GroupStream = ReceiverStream<Result<GroupProgress, tonic::Status>>;
+
+/// Groups tagged on the registered devices, with the ids of their members
+pub fn groups(server: &Server) -> Vec<Group> {
+    let mut groups: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for (id, item) in server.inner.devices.contents() {
+        for (key, _) in item.properties().iter() {
+            if let Some(group) = key.strip_prefix(registry::TAG) {
+                groups.entry(group.to_string()).or_default().push(id);
+            }
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(name, devices)| Group { name, devices })
+        .collect()
+}
+
+/// Ids of the devices tagged as members of the group
+pub fn members(server: &Server, group: &str) -> Vec<u64> {
+    let key = format!("{}{}", registry::TAG, group);
+    server
+        .inner
+        .devices
+        .contents()
+        .into_iter()
+        .filter(|(_, item)| item.properties().get(&key).is_some())
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Request for running the operation on a single member on behalf of the caller, such that the
+/// access and session checks of the member apply
+pub fn request<T>(message: T, identity: Option<auth::Identity>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    if let Some(identity) = identity {
+        request.extensions_mut().insert(identity);
+    }
+    request
+}
+
+/// Start an operation on all members of the group, merging their progress into a single stream
+///
+/// Members on which the operation can't be started are reported as failed rather than failing the
+/// whole group. Like for single devices the operations keep running if the client goes away.
+pub async fn run<F, Fut, S, P>(
+    server: &Server,
+    group: &str,
+    start: F,
+    wrap: fn(P) -> group_progress::Progress,
+) -> Result<GroupStream, tonic::Status>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<S, tonic::Status>> + Send,
+    S: Stream<Item = Result<P, tonic::Status>> + Send + Unpin + 'static,
+    P: Send + 'static,
+{
+    let members = members(server, group);
+    if members.is_empty() {
+        return Err(tonic::Status::not_found("No devices in that group"));
+    }
+    let total = members.len() as u32;
+    let started = futures::future::join_all(members.iter().map(|&id| start(id))).await;
+
+    // Finished streams are dropped from the map silently, so each one gets an end marker
+    let mut streams = StreamMap::new();
+    let mut errors = HashMap::new();
+    for (id, started) in members.into_iter().zip(started) {
+        let progress = match started {
+            Ok(progress) => progress.map(Some).boxed(),
+            Err(e) => {
+                errors.insert(id, e.message().to_string());
+                stream::empty().boxed()
+            }
+        };
+        streams.insert(id, progress.chain(stream::iter([None])));
+    }
+
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(async move {
+        let mut finished = 0;
+        let mut failed = 0;
+        while let Some((device, item)) = streams.next().await {
+            let progress = match item {
+                Some(Ok(progress)) => wrap(progress),
+                Some(Err(e)) => {
+                    errors.insert(device, e.message().to_string());
+                    continue;
+                }
+                None => {
+                    let error = errors.remove(&device);
+                    finished += 1;
+                    if error.is_some() {
+                        failed += 1;
+                    }
+                    group_progress::Progress::Done(GroupDeviceDone { error })
+                }
+            };
+            let _ = tx
+                .send(Ok(GroupProgress {
+                    device,
+                    progress: Some(progress),
+                    finished,
+                    failed,
+                    total,
+                }))
+                .await;
+        }
+    });
+    Ok(ReceiverStream::new(rx))
+}
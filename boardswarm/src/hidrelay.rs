@@ -0,0 +1,214 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use futures::StreamExt;
+use nusb::transfer::{Control, ControlType, Recipient};
+use serde::Deserialize;
+use tracing::{debug, instrument, warn};
+
+use crate::{registry, udev::DeviceEvent, ActuatorError, Server};
+
+pub const PROVIDER: &str = "hidrelay";
+
+const RELAY_VENDOR: u64 = 0x16c0;
+const RELAY_PRODUCT: u64 = 0x05df;
+
+/// HID feature report requests; The boards use report 0 of 8 bytes
+const HID_GET_REPORT: u8 = 0x01;
+const HID_SET_REPORT: u8 = 0x09;
+const HID_REPORT_FEATURE: u16 = 0x03 << 8;
+const REPORT_SIZE: usize = 8;
+
+const RELAY_ON: u8 = 0xff;
+const RELAY_OFF: u8 = 0xfd;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Default)]
+struct HidRelayParameters {
+    /// Names for relay channels, by board serial and channel, e.g. `ABCDE_1`
+    #[serde(default)]
+    names: HashMap<String, String>,
+}
+
+#[instrument(skip(parameters, server))]
+pub async fn start_provider(name: String, parameters: Option<serde_yaml::Value>, server: Server) {
+    let parameters: HidRelayParameters = parameters
+        .map(|p| serde_yaml::from_value(p).unwrap())
+        .unwrap_or_default();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let mut registrations: HashMap<PathBuf, Vec<u64>> = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("usb").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                if device.devnode().is_none()
+                    || device.property_u64("ID_VENDOR_ID", 16) != Some(RELAY_VENDOR)
+                    || device.property_u64("ID_MODEL_ID", 16) != Some(RELAY_PRODUCT)
+                {
+                    continue;
+                }
+                // The product name encodes the number of channels, e.g. USBRelay2
+                let Some(channels) = device
+                    .property("ID_MODEL")
+                    .and_then(|m| m.strip_prefix("USBRelay"))
+                    .and_then(|c| c.parse::<u8>().ok())
+                else {
+                    continue;
+                };
+                let (Some(bus), Some(dev)) = (
+                    device
+                        .property_u64("BUSNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                    device
+                        .property_u64("DEVNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                ) else {
+                    continue;
+                };
+                let board = Board { bus, dev };
+                let serial = match tokio::task::spawn_blocking(move || board.serial()).await {
+                    Ok(Ok(serial)) => serial,
+                    Ok(Err(e)) => {
+                        warn!("Failed to read relay board serial: {}", e);
+                        continue;
+                    }
+                    Err(_) => continue,
+                };
+                debug!("Found {} channel relay board {}", channels, serial);
+
+                let ids = (1..=channels)
+                    .map(|channel| {
+                        let id = format!("{serial}_{channel}");
+                        let name = parameters.names.get(&id).unwrap_or(&id);
+                        let mut properties = device.properties(name);
+                        properties.extend(provider_properties);
+                        properties.insert(format!("{PROVIDER}.serial"), serial.as_str());
+                        properties.insert(format!("{PROVIDER}.channel"), channel.to_string());
+                        server.register_actuator(properties, Relay { board, channel })
+                    })
+                    .collect();
+                registrations.insert(device.syspath().to_path_buf(), ids);
+            }
+            DeviceEvent::Remove(device) => {
+                for id in registrations.remove(device.syspath()).unwrap_or_default() {
+                    server.unregister_actuator(id)
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Board {
+    bus: u8,
+    dev: u8,
+}
+
+impl Board {
+    fn interface(&self) -> Result<nusb::Interface, String> {
+        let info = crate::utils::nusb_info_from_bus_dev(self.bus, self.dev)
+            .ok_or_else(|| "Relay board not found".to_string())?;
+        let device = info.open().map_err(|e| e.to_string())?;
+        device
+            .detach_and_claim_interface(0)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Serial of the board, stored in the first bytes of its feature report
+    fn serial(&self) -> Result<String, String> {
+        let mut report = [0; REPORT_SIZE];
+        self.interface()?
+            .control_in_blocking(
+                Control {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Interface,
+                    request: HID_GET_REPORT,
+                    value: HID_REPORT_FEATURE,
+                    index: 0,
+                },
+                &mut report,
+                CONTROL_TIMEOUT,
+            )
+            .map_err(|e| e.to_string())?;
+        let serial = &report[..5];
+        let len = serial.iter().position(|&b| b == 0).unwrap_or(serial.len());
+        Ok(String::from_utf8_lossy(&serial[..len]).into_owned())
+    }
+
+    fn set(&self, channel: u8, on: bool) -> Result<(), String> {
+        let mut report = [0; REPORT_SIZE];
+        report[0] = if on { RELAY_ON } else { RELAY_OFF };
+        report[1] = channel;
+        self.interface()?
+            .control_out_blocking(
+                Control {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Interface,
+                    request: HID_SET_REPORT,
+                    value: HID_REPORT_FEATURE,
+                    index: 0,
+                },
+                &report,
+                CONTROL_TIMEOUT,
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// A single channel of a relay board
+#[derive(Debug)]
+struct Relay {
+    board: Board,
+    channel: u8,
+}
+
+impl Relay {
+    async fn set(&self, on: bool) -> Result<(), ActuatorError> {
+        let (board, channel) = (self.board, self.channel);
+        tokio::task::spawn_blocking(move || board.set(channel, on))
+            .await
+            .map_err(|_e| ActuatorError {})?
+            .map_err(|e| {
+                warn!("Failed to switch relay {}: {}", channel, e);
+                ActuatorError {}
+            })
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for Relay {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+            /// Restore the opposite state after this long, e.g. to press a reset button
+            #[serde(default)]
+            #[serde(with = "humantime_serde")]
+            pulse: Option<Duration>,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let on = match parameters.mode.as_str() {
+            "on" => true,
+            "off" => false,
+            mode => {
+                warn!("Unsupported relay mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        };
+        self.set(on).await?;
+        if let Some(pulse) = parameters.pulse {
+            tokio::time::sleep(pulse).await;
+            self.set(!on).await?;
+        }
+        Ok(())
+    }
+}
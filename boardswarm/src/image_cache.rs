@@ -0,0 +1,261 @@
+// Images kept by the server keyed by their SHA-256, such that flashing the same image to many
+// boards only transfers it to the server once
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::config;
+
+/// Size of the chunks read from cached images
+const READ_SIZE: usize = 1024 * 1024;
+/// Prefix of images still being added to the cache
+const PARTIAL_PREFIX: &str = ".partial-";
+
+fn name(sha256: &[u8]) -> Option<String> {
+    if sha256.len() != 32 {
+        return None;
+    }
+    Some(sha256.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+pub struct ImageCache {
+    directory: PathBuf,
+    max_size: u64,
+}
+
+impl ImageCache {
+    /// Cache in the configured directory, relative to the configuration directory
+    pub fn new(config_dir: &std::path::Path, config: &config::ImageCache) -> Self {
+        // Creating a directory ending in `.` fails if its parent doesn't exist yet
+        let directory: PathBuf = config_dir
+            .join(&config.directory)
+            .components()
+            .filter(|c| !matches!(c, std::path::Component::CurDir))
+            .collect();
+        let directory = if directory.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            directory
+        };
+        // Partial images of a previous run will never be completed
+        if let Ok(entries) = std::fs::read_dir(&directory) {
+            for entry in entries.flatten() {
+                if entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(PARTIAL_PREFIX)
+                {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+        Self {
+            directory,
+            max_size: config.max_size,
+        }
+    }
+
+    /// Open the cached image with the digest, if any, marking it as recently used
+    pub async fn open(&self, sha256: &[u8]) -> Option<Cached> {
+        let path = self.directory.join(name(sha256)?);
+        let file = File::open(&path).await.ok()?;
+        let file = file.into_std().await;
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            warn!("Failed to mark {} as used: {}", path.display(), e);
+        }
+        let length = file.metadata().ok()?.len();
+        Some(Cached {
+            file: File::from_std(file),
+            length,
+        })
+    }
+
+    /// Whether an image with the digest is cached
+    pub async fn contains(&self, sha256: &[u8]) -> bool {
+        match name(sha256) {
+            Some(name) => tokio::fs::try_exists(self.directory.join(name))
+                .await
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Start adding an image with the digest; None if it's already cached or can't be added
+    pub async fn insert(&self, sha256: &[u8]) -> Option<Insert> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let name = name(sha256)?;
+        if self.contains(sha256).await {
+            return None;
+        }
+        if let Err(e) = tokio::fs::create_dir_all(&self.directory).await {
+            warn!("Failed to create image cache directory: {}", e);
+            return None;
+        }
+        let partial = self.directory.join(format!(
+            "{}{}-{}",
+            PARTIAL_PREFIX,
+            name,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = match File::create(&partial).await {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Failed to add image to the cache: {}", e);
+                return None;
+            }
+        };
+        Some(Insert {
+            file,
+            partial,
+            path: self.directory.join(name),
+            length: 0,
+            committed: false,
+        })
+    }
+
+    /// Remove the least recently used images until the cache fits its maximum size
+    async fn evict(&self) -> std::io::Result<()> {
+        let mut images = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(PARTIAL_PREFIX)
+            {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            images.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+        images.sort();
+        let mut size: u64 = images.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in images {
+            if size <= self.max_size {
+                break;
+            }
+            info!("Evicting {} from the image cache", path.display());
+            tokio::fs::remove_file(&path).await?;
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Image read from the cache
+pub struct Cached {
+    file: File,
+    pub length: u64,
+}
+
+impl Cached {
+    pub async fn chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        let mut data = BytesMut::with_capacity(READ_SIZE);
+        if self.file.read_buf(&mut data).await? == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(data.freeze()))
+        }
+    }
+}
+
+/// Image being added to the cache; Only written sequentially from the start. The partial image is
+/// removed unless committed
+pub struct Insert {
+    file: File,
+    partial: PathBuf,
+    path: PathBuf,
+    length: u64,
+    committed: bool,
+}
+
+impl Insert {
+    /// Append data of the image; Fails if the data doesn't directly follow the data written before
+    pub async fn write(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if offset != self.length {
+            return Err(std::io::Error::other("Image not written sequentially"));
+        }
+        self.file.write_all(data).await?;
+        self.length += data.len() as u64;
+        Ok(())
+    }
+
+    /// Add the image to the cache; Only to be called once the data was verified against the digest
+    pub async fn commit(mut self, cache: &ImageCache) {
+        let added = async {
+            self.file.flush().await?;
+            tokio::fs::rename(&self.partial, &self.path).await
+        }
+        .await;
+        match added {
+            Ok(()) => {
+                self.committed = true;
+                info!("Added {} to the image cache", self.path.display());
+                if let Err(e) = cache.evict().await {
+                    warn!("Failed to evict images from the cache: {}", e);
+                }
+            }
+            Err(e) => warn!("Failed to add image to the cache: {}", e),
+        }
+    }
+}
+
+impl Drop for Insert {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.partial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn cache() {
+        let directory = std::env::temp_dir().join(format!(
+            "boardswarm-image-cache-test-{}",
+            std::process::id()
+        ));
+        let cache = ImageCache::new(
+            &directory,
+            &config::ImageCache {
+                directory: PathBuf::from("./images/."),
+                max_size: 8,
+            },
+        );
+        let first = [1; 32];
+        let second = [2; 32];
+
+        let mut insert = cache.insert(&first).await.unwrap();
+        insert.write(0, b"hello").await.unwrap();
+        assert!(insert.write(0, b"hello").await.is_err());
+        insert.commit(&cache).await;
+        assert!(cache.insert(&first).await.is_none());
+        let mut cached = cache.open(&first).await.unwrap();
+        assert_eq!(cached.length, 5);
+        assert_eq!(cached.chunk().await.unwrap().unwrap(), &b"hello"[..]);
+        assert!(cached.chunk().await.unwrap().is_none());
+
+        // Dropped inserts don't end up in the cache
+        let mut insert = cache.insert(&second).await.unwrap();
+        insert.write(0, b"world").await.unwrap();
+        drop(insert);
+        assert!(!cache.contains(&second).await);
+
+        // Adding the second image exceeds the maximum size, evicting the least recently used
+        let mut insert = cache.insert(&second).await.unwrap();
+        insert.write(0, b"world").await.unwrap();
+        insert.commit(&cache).await;
+        assert!(cache.contains(&second).await);
+        assert!(!cache.contains(&first).await);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
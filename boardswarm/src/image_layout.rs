@@ -0,0 +1,301 @@
+// Layout of images on the target, such that only the parts of an image holding data get written;
+// For mostly empty images this cuts the time to flash them drastically
+use std::ops::Range;
+
+use android_sparse_image::{
+    ChunkHeader, ChunkType, FileHeader, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use thiserror::Error;
+use tracing::debug;
+
+/// Maximum size of a single write of filled data
+const FILL_WRITE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum LayoutError {
+    #[error("Invalid sparse image: {0}")]
+    Sparse(#[from] android_sparse_image::ParseError),
+    #[error("Invalid block map: {0}")]
+    BlockMap(String),
+    #[error("Unexpected end of image")]
+    Truncated,
+}
+
+impl From<LayoutError> for tonic::Status {
+    fn from(e: LayoutError) -> Self {
+        tonic::Status::invalid_argument(e.to_string())
+    }
+}
+
+/// Data to write at an offset of the target
+pub type Placement = (u64, Bytes);
+
+/// Turns the data of an image, pushed in order, into the writes to the target
+pub trait Layout: Send {
+    fn push(&mut self, data: Bytes) -> Result<Vec<Placement>, LayoutError>;
+    /// Check the complete image was pushed
+    fn finish(&mut self) -> Result<(), LayoutError>;
+}
+
+/// Raw image, written as is
+#[derive(Default)]
+pub struct Raw {
+    offset: u64,
+}
+
+impl Layout for Raw {
+    fn push(&mut self, data: Bytes) -> Result<Vec<Placement>, LayoutError> {
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        Ok(vec![(offset, data)])
+    }
+
+    fn finish(&mut self) -> Result<(), LayoutError> {
+        Ok(())
+    }
+}
+
+enum SparseState {
+    FileHeader,
+    ChunkHeader,
+    Raw(usize),
+    Fill(usize),
+    Skip(usize),
+    Done,
+}
+
+/// Android sparse image; Raw and fill chunks are written, don't care chunks are skipped
+pub struct AndroidSparse {
+    buffer: BytesMut,
+    state: SparseState,
+    header: Option<FileHeader>,
+    chunks_left: u32,
+    /// Offset on the target of the current chunk
+    offset: u64,
+}
+
+impl AndroidSparse {
+    pub fn new() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            state: SparseState::FileHeader,
+            header: None,
+            chunks_left: 0,
+            offset: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> SparseState {
+        if self.chunks_left == 0 {
+            SparseState::Done
+        } else {
+            self.chunks_left -= 1;
+            SparseState::ChunkHeader
+        }
+    }
+}
+
+impl Layout for AndroidSparse {
+    fn push(&mut self, data: Bytes) -> Result<Vec<Placement>, LayoutError> {
+        self.buffer.extend_from_slice(&data);
+        let mut out = Vec::new();
+        loop {
+            self.state = match self.state {
+                SparseState::FileHeader => {
+                    if self.buffer.len() < FILE_HEADER_BYTES_LEN {
+                        break;
+                    }
+                    let bytes = self.buffer.split_to(FILE_HEADER_BYTES_LEN);
+                    let header = FileHeader::from_bytes(bytes[..].try_into().unwrap())?;
+                    self.chunks_left = header.chunks;
+                    self.header = Some(header);
+                    self.next_chunk()
+                }
+                SparseState::ChunkHeader => {
+                    if self.buffer.len() < CHUNK_HEADER_BYTES_LEN {
+                        break;
+                    }
+                    let bytes = self.buffer.split_to(CHUNK_HEADER_BYTES_LEN);
+                    let chunk = ChunkHeader::from_bytes(bytes[..].try_into().unwrap())?;
+                    let out_size = chunk.out_size(self.header.as_ref().unwrap());
+                    match chunk.chunk_type {
+                        ChunkType::Raw => SparseState::Raw(out_size),
+                        ChunkType::Fill => SparseState::Fill(out_size),
+                        ChunkType::DontCare => {
+                            self.offset += out_size as u64;
+                            self.next_chunk()
+                        }
+                        ChunkType::Crc32 => SparseState::Skip(chunk.data_size()),
+                    }
+                }
+                SparseState::Raw(left) => {
+                    if left == 0 {
+                        self.next_chunk()
+                    } else if self.buffer.is_empty() {
+                        break;
+                    } else {
+                        let len = left.min(self.buffer.len());
+                        out.push((self.offset, self.buffer.split_to(len).freeze()));
+                        self.offset += len as u64;
+                        SparseState::Raw(left - len)
+                    }
+                }
+                SparseState::Fill(size) => {
+                    if self.buffer.len() < 4 {
+                        break;
+                    }
+                    let pattern = self.buffer.split_to(4);
+                    // Fill chunks can be huge, so write them in pieces sharing a single buffer
+                    let fill: Bytes = pattern
+                        .iter()
+                        .cycle()
+                        .take(size.min(FILL_WRITE_SIZE))
+                        .copied()
+                        .collect::<Vec<_>>()
+                        .into();
+                    let mut left = size;
+                    while left > 0 {
+                        let len = left.min(fill.len());
+                        out.push((self.offset, fill.slice(..len)));
+                        self.offset += len as u64;
+                        left -= len;
+                    }
+                    self.next_chunk()
+                }
+                SparseState::Skip(left) => {
+                    if left == 0 {
+                        self.next_chunk()
+                    } else if self.buffer.is_empty() {
+                        break;
+                    } else {
+                        let len = left.min(self.buffer.len());
+                        self.buffer.advance(len);
+                        SparseState::Skip(left - len)
+                    }
+                }
+                SparseState::Done => {
+                    if !self.buffer.is_empty() {
+                        debug!("Ignoring {} bytes after sparse image", self.buffer.len());
+                        self.buffer.clear();
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<(), LayoutError> {
+        match self.state {
+            SparseState::Done => Ok(()),
+            _ => Err(LayoutError::Truncated),
+        }
+    }
+}
+
+/// Raw image with a bmaptool style block map; Only the mapped ranges are written
+pub struct BlockMap {
+    /// Mapped byte ranges of the image, in order
+    ranges: Vec<Range<u64>>,
+    /// Offset in the image of the next data pushed
+    offset: u64,
+}
+
+impl BlockMap {
+    pub fn new(mut ranges: Vec<Range<u64>>) -> Self {
+        ranges.sort_by_key(|r| r.start);
+        Self { ranges, offset: 0 }
+    }
+
+    /// Block map from the XML of a bmap file
+    pub fn from_xml(xml: &str) -> Result<Self, LayoutError> {
+        let bmap =
+            bmap_parser::Bmap::from_xml(xml).map_err(|e| LayoutError::BlockMap(e.to_string()))?;
+        Ok(Self::new(
+            bmap.block_map()
+                .map(|r| r.offset()..r.offset() + r.length())
+                .collect(),
+        ))
+    }
+}
+
+impl Layout for BlockMap {
+    fn push(&mut self, data: Bytes) -> Result<Vec<Placement>, LayoutError> {
+        let start = self.offset;
+        let end = start + data.len() as u64;
+        self.offset = end;
+        let out = self
+            .ranges
+            .iter()
+            .filter(|r| r.start < end && r.end > start)
+            .map(|r| {
+                let from = r.start.max(start);
+                let to = r.end.min(end);
+                (
+                    from,
+                    data.slice((from - start) as usize..(to - start) as usize),
+                )
+            })
+            .collect();
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<(), LayoutError> {
+        match self.ranges.last() {
+            Some(last) if last.end > self.offset => Err(LayoutError::Truncated),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_map() {
+        let mut layout = BlockMap::new(vec![6..10, 2..4]);
+        let out = layout.push(Bytes::from_static(b"01234567")).unwrap();
+        assert_eq!(
+            out,
+            vec![
+                (2, Bytes::from_static(b"23")),
+                (6, Bytes::from_static(b"67"))
+            ]
+        );
+        assert!(layout.finish().is_err());
+        let out = layout.push(Bytes::from_static(b"89ab")).unwrap();
+        assert_eq!(out, vec![(8, Bytes::from_static(b"89"))]);
+        layout.finish().unwrap();
+    }
+
+    #[test]
+    fn sparse() {
+        let header = FileHeader {
+            block_size: 4,
+            blocks: 4,
+            chunks: 3,
+            checksum: 0,
+        };
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&ChunkHeader::new_raw(1, 4).to_bytes());
+        image.extend_from_slice(b"data");
+        image.extend_from_slice(&ChunkHeader::new_dontcare(2).to_bytes());
+        image.extend_from_slice(&ChunkHeader::new_fill(1).to_bytes());
+        image.extend_from_slice(&[0xaa; 4]);
+
+        let mut layout = AndroidSparse::new();
+        let (first, second) = image.split_at(30);
+        let mut out = layout.push(Bytes::copy_from_slice(first)).unwrap();
+        out.extend(layout.push(Bytes::copy_from_slice(second)).unwrap());
+        layout.finish().unwrap();
+        assert_eq!(
+            out,
+            vec![
+                (0, Bytes::from_static(b"data")),
+                (12, Bytes::from_static(b"\xaa\xaa\xaa\xaa"))
+            ]
+        );
+    }
+}
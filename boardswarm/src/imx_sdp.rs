@@ -0,0 +1,405 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Context};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use nusb::transfer::{ControlOut, ControlType, Recipient, RequestBuffer};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry, udev::DeviceEvent, Server, Volume, VolumeError, VolumeTarget, VolumeTargetInfo,
+};
+
+pub const PROVIDER: &str = "imx-sdp";
+pub const TARGET: &str = "sdp";
+
+/// Serial download protocol commands
+const WRITE_FILE: u16 = 0x0404;
+const JUMP_ADDRESS: u16 = 0x0b0b;
+
+/// HID reports used by the protocol
+const REPORT_COMMAND: u8 = 1;
+const REPORT_DATA: u8 = 2;
+const REPORT_HAB: u8 = 3;
+const REPORT_STATUS: u8 = 4;
+
+/// Status reported once a file was written completely
+const STATUS_COMPLETE: u32 = 0x88888888;
+
+const HID_SET_REPORT: u8 = 0x09;
+const HID_REPORT_OUTPUT: u16 = 0x02;
+const INTERRUPT_IN: u8 = 0x81;
+const MAX_DATA: usize = 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper limit for the image to be uploaded; Images are loaded into on-chip or dram memory
+const MAX_IMAGE: u64 = 16 * 1024 * 1024;
+
+/// SoCs in serial download mode using the (HID based) serial download protocol
+fn soc(vendor: u16, product: u16) -> Option<&'static str> {
+    match (vendor, product) {
+        (0x15a2, 0x0054) => Some("imx6q"),
+        (0x15a2, 0x0061) => Some("imx6dl"),
+        (0x15a2, 0x0063) => Some("imx6sl"),
+        (0x15a2, 0x0071) => Some("imx6sx"),
+        (0x15a2, 0x007d) => Some("imx6ul"),
+        (0x15a2, 0x0080) => Some("imx6ull"),
+        (0x15a2, 0x0076) => Some("imx7d"),
+        (0x1fc9, 0x0128) => Some("imx6sll"),
+        (0x1fc9, 0x012b) => Some("imx8mq"),
+        (0x1fc9, 0x0134) => Some("imx8mm"),
+        _ => None,
+    }
+}
+
+#[instrument(skip(server))]
+pub async fn start_provider(name: String, server: Server) {
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+    let mut registrations = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("usb").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                if device.devnode().is_none() {
+                    continue;
+                }
+                let (Some(vendor), Some(product)) = (
+                    device
+                        .property_u64("ID_VENDOR_ID", 16)
+                        .and_then(|v| v.try_into().ok()),
+                    device
+                        .property_u64("ID_MODEL_ID", 16)
+                        .and_then(|v| v.try_into().ok()),
+                ) else {
+                    continue;
+                };
+                let Some(soc) = soc(vendor, product) else {
+                    continue;
+                };
+                let (Some(busnum), Some(devnum)) = (
+                    device
+                        .property_u64("BUSNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                    device
+                        .property_u64("DEVNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                ) else {
+                    continue;
+                };
+
+                info!(
+                    "Found {} in serial download mode at {}/{}",
+                    soc, busnum, devnum
+                );
+                let mut properties = device.properties(format!("{}/{} {}", busnum, devnum, soc));
+                properties.extend(provider_properties);
+                properties.insert(format!("{PROVIDER}.soc"), soc);
+                let id = server.register_volume(properties, SdpVolume::new(busnum, devnum));
+                registrations.insert(device.syspath().to_path_buf(), id);
+            }
+            DeviceEvent::Remove(device) => {
+                if let Some(id) = registrations.remove(device.syspath()) {
+                    server.unregister_volume(id)
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+/// Image vector table at the start of a bootable i.MX image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ivt {
+    /// Offset of the ivt in the image
+    offset: usize,
+    /// Address the ivt itself gets loaded to
+    address: u32,
+    dcd: u32,
+}
+
+impl Ivt {
+    const TAG: u8 = 0xd1;
+    const SIZE: usize = 32;
+
+    /// Find the ivt at the start of .imx images or after the boot area of sd card images
+    fn find(image: &[u8]) -> Option<Self> {
+        [0, 0x400].into_iter().find_map(|offset| {
+            let header = image.get(offset..offset + Self::SIZE)?;
+            if header[0] != Self::TAG || !(0x40..=0x43).contains(&header[3]) {
+                return None;
+            }
+            let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+            Some(Ivt {
+                offset,
+                address: word(20),
+                dcd: word(12),
+            })
+        })
+    }
+
+    /// Address the image is loaded to
+    fn load_address(&self) -> Option<u32> {
+        self.address.checked_sub(self.offset as u32)
+    }
+}
+
+struct Sdp {
+    interface: nusb::Interface,
+}
+
+impl Sdp {
+    fn open(bus: u8, dev: u8) -> anyhow::Result<Self> {
+        let Some(info) = crate::utils::nusb_info_from_bus_dev(bus, dev) else {
+            bail!("Couldn't find device")
+        };
+        let device = info.open()?;
+        let interface = device.detach_and_claim_interface(0)?;
+        Ok(Self { interface })
+    }
+
+    async fn set_report(&self, report: u8, data: &[u8]) -> anyhow::Result<()> {
+        let mut buf = Vec::with_capacity(data.len() + 1);
+        buf.push(report);
+        buf.extend_from_slice(data);
+        let transfer = self.interface.control_out(ControlOut {
+            control_type: ControlType::Class,
+            recipient: Recipient::Interface,
+            request: HID_SET_REPORT,
+            value: (HID_REPORT_OUTPUT << 8) | report as u16,
+            index: 0,
+            data: &buf,
+        });
+        tokio::time::timeout(TIMEOUT, transfer)
+            .await
+            .context("Timeout sending report")?
+            .into_result()?;
+        Ok(())
+    }
+
+    /// Read the 4 byte payload of a report from the device
+    async fn get_report(&self, report: u8) -> anyhow::Result<u32> {
+        let transfer = self
+            .interface
+            .interrupt_in(INTERRUPT_IN, RequestBuffer::new(64));
+        let data = tokio::time::timeout(TIMEOUT, transfer)
+            .await
+            .context("Timeout waiting for report")?
+            .into_result()?;
+        match data.as_slice() {
+            [r, value @ ..] if *r == report && value.len() >= 4 => {
+                Ok(u32::from_be_bytes(value[..4].try_into().unwrap()))
+            }
+            _ => bail!("Unexpected report: {:x?}", data),
+        }
+    }
+
+    async fn command(&self, command: u16, address: u32, count: u32) -> anyhow::Result<()> {
+        let mut report = Vec::with_capacity(16);
+        report.extend_from_slice(&command.to_be_bytes());
+        report.extend_from_slice(&address.to_be_bytes());
+        // format
+        report.push(0);
+        report.extend_from_slice(&count.to_be_bytes());
+        // data and reserved
+        report.extend_from_slice(&[0; 5]);
+        self.set_report(REPORT_COMMAND, &report).await
+    }
+
+    async fn write_file(&self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.command(WRITE_FILE, address, data.len() as u32).await?;
+        for chunk in data.chunks(MAX_DATA) {
+            self.set_report(REPORT_DATA, chunk).await?;
+        }
+        let hab = self.get_report(REPORT_HAB).await?;
+        info!("HAB mode: {:08x}", hab);
+        match self.get_report(REPORT_STATUS).await? {
+            STATUS_COMPLETE => Ok(()),
+            status => bail!("Write failed with status {:08x}", status),
+        }
+    }
+
+    async fn jump(&self, address: u32) -> anyhow::Result<()> {
+        self.command(JUMP_ADDRESS, address, 0).await?;
+        self.get_report(REPORT_HAB).await?;
+        // Only an error status is reported; The device drops off the bus when the jump succeeds
+        match self.get_report(REPORT_STATUS).await {
+            Ok(status) => bail!("Jump failed with status {:08x}", status),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+async fn write_image(sdp: &Sdp, image: &[u8]) -> anyhow::Result<Ivt> {
+    let Some(ivt) = Ivt::find(image) else {
+        bail!("No image vector table found in image");
+    };
+    if ivt.dcd != 0 {
+        bail!("Images with a device configuration data block are not supported");
+    }
+    let Some(address) = ivt.load_address() else {
+        bail!("Invalid image vector table address");
+    };
+    info!("Writing {} bytes to {:08x}", image.len(), address);
+    sdp.write_file(address, image).await?;
+    Ok(ivt)
+}
+
+enum SdpCommand {
+    Write(Bytes, oneshot::Sender<anyhow::Result<()>>),
+    Jump(oneshot::Sender<anyhow::Result<()>>),
+}
+
+async fn process(bus: u8, dev: u8, mut commands: mpsc::Receiver<SdpCommand>) {
+    let sdp = match Sdp::open(bus, dev) {
+        Ok(sdp) => sdp,
+        Err(e) => {
+            warn!("Failed to open device: {e}");
+            return;
+        }
+    };
+    // ivt of the last written image
+    let mut ivt = None;
+    while let Some(command) = commands.recv().await {
+        match command {
+            SdpCommand::Write(image, sender) => {
+                let r = write_image(&sdp, &image).await.map(|written| {
+                    ivt = Some(written);
+                });
+                let _ = sender.send(r);
+            }
+            SdpCommand::Jump(sender) => {
+                let r = match ivt.take() {
+                    Some(ivt) => {
+                        info!("Jumping to {:08x}", ivt.address);
+                        sdp.jump(ivt.address).await
+                    }
+                    None => Err(anyhow::anyhow!("No image written")),
+                };
+                let _ = sender.send(r);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SdpVolume {
+    device: mpsc::Sender<SdpCommand>,
+    targets: [VolumeTargetInfo; 1],
+}
+
+impl SdpVolume {
+    fn new(bus: u8, dev: u8) -> Self {
+        let (device, commands) = mpsc::channel(16);
+        tokio::spawn(process(bus, dev, commands));
+        Self {
+            device,
+            targets: [VolumeTargetInfo {
+                name: String::from(TARGET),
+                readable: false,
+                writable: true,
+                seekable: false,
+                size: None,
+                blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                sha256: None,
+            }],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Volume for SdpVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (&self.targets, true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        if target != TARGET {
+            return Err(VolumeError::UnknownTargetRequested);
+        }
+        if length.is_some_and(|l| l > MAX_IMAGE) {
+            return Err(VolumeError::Failure("Image too big".to_string()));
+        }
+        Ok((
+            self.targets[0].clone(),
+            Box::new(SdpTarget {
+                device: self.device.clone(),
+                data: BytesMut::with_capacity(length.unwrap_or(1024 * 1024) as usize),
+            }),
+        ))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        let (tx, rx) = oneshot::channel();
+        self.device
+            .send(SdpCommand::Jump(tx))
+            .await
+            .map_err(|e| VolumeError::Internal(e.to_string()))?;
+        rx.await
+            .map_err(|e| VolumeError::Internal(e.to_string()))?
+            .map_err(|e| VolumeError::Failure(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Image to be written; Collected completely as the load address is determined by its ivt
+struct SdpTarget {
+    device: mpsc::Sender<SdpCommand>,
+    data: BytesMut,
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for SdpTarget {
+    async fn write(&mut self, data: Bytes, offset: u64, completion: crate::WriteCompletion) {
+        if offset as usize != self.data.len() {
+            completion.complete(Err(tonic::Status::out_of_range("Invalid offset")));
+        } else if (data.len() + self.data.len()) as u64 > MAX_IMAGE {
+            completion.complete(Err(tonic::Status::out_of_range("Image too big")));
+        } else {
+            self.data.extend_from_slice(&data);
+            completion.complete(Ok(data.len() as u64));
+        }
+    }
+
+    async fn shutdown(&mut self, completion: crate::ShutdownCompletion) {
+        let (tx, rx) = oneshot::channel();
+        let write = SdpCommand::Write(self.data.split().freeze(), tx);
+        if let Err(e) = self.device.send(write).await {
+            completion.complete(Err(tonic::Status::internal(e.to_string())));
+            return;
+        };
+        match rx.await {
+            Ok(Ok(())) => completion.complete(Ok(())),
+            Ok(Err(e)) => {
+                completion.complete(Err(tonic::Status::failed_precondition(e.to_string())))
+            }
+            Err(e) => completion.complete(Err(tonic::Status::internal(e.to_string()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ivt() {
+        let mut image = vec![0; 0x800];
+        image[0x400..0x404].copy_from_slice(&[Ivt::TAG, 0x00, 0x20, 0x41]);
+        image[0x414..0x418].copy_from_slice(&0x877ff400u32.to_le_bytes());
+        let ivt = Ivt::find(&image).unwrap();
+        assert_eq!(ivt.offset, 0x400);
+        assert_eq!(ivt.dcd, 0);
+        assert_eq!(ivt.load_address(), Some(0x877ff000));
+
+        assert_eq!(Ivt::find(&image[..0x410]), None);
+    }
+}
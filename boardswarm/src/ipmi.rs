@@ -0,0 +1,236 @@
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Sink, StreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    ActuatorError, ConsoleError, Server,
+};
+
+pub const PROVIDER: &str = "ipmi";
+
+/// Delay before restarting the serial-over-lan session after it ended
+const SOL_RESTART: Duration = Duration::from_secs(5);
+
+fn default_interface() -> String {
+    "lanplus".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct Bmc {
+    name: String,
+    host: String,
+    username: String,
+    password: Option<String>,
+    /// ipmitool interface to use
+    #[serde(default = "default_interface")]
+    interface: String,
+}
+
+impl Bmc {
+    /// ipmitool invocation for the bmc; The password is passed through the environment so it
+    /// doesn't show up in the process list
+    fn command(&self) -> Command {
+        let mut command = Command::new("ipmitool");
+        command
+            .args([
+                "-I",
+                self.interface.as_str(),
+                "-H",
+                self.host.as_str(),
+                "-U",
+                self.username.as_str(),
+            ])
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(password) = &self.password {
+            command.arg("-E").env("IPMI_PASSWORD", password);
+        }
+        command
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct IpmiParameters {
+    bmcs: Vec<Bmc>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: IpmiParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for bmc in parameters.bmcs {
+        let mut properties = Properties::new(&bmc.name);
+        properties.extend(provider_properties);
+        properties.insert(format!("{PROVIDER}.host"), bmc.host.as_str());
+
+        let bmc = Arc::new(bmc);
+        let (input, rx) = mpsc::channel(16);
+        let output = broadcast::channel(64).0;
+        tokio::spawn(run_sol(bmc.clone(), rx, output.clone()));
+
+        server.register_actuator(properties.clone(), IpmiPower(bmc));
+        server.register_console(properties, IpmiSol { input, output });
+    }
+}
+
+/// Keep a serial-over-lan session to the bmc running for as long as the console exists
+async fn run_sol(bmc: Arc<Bmc>, input: mpsc::Receiver<Bytes>, output: broadcast::Sender<Bytes>) {
+    let input = Arc::new(Mutex::new(input));
+    loop {
+        // Clear a stale session, e.g. left behind by an earlier run
+        let _ = bmc
+            .command()
+            .args(["sol", "deactivate"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+
+        let child = bmc
+            .command()
+            .args(["sol", "activate"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to start ipmitool for {}: {}", bmc.name, e);
+                return;
+            }
+        };
+        info!("Serial over lan session started for {}", bmc.name);
+
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let input = input.clone();
+        let feed = async move {
+            let mut input = input.lock().await;
+            while let Some(data) = input.recv().await {
+                if stdin.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let read = async {
+            let mut buf = BytesMut::with_capacity(4096);
+            loop {
+                buf.reserve(4096);
+                match stdout.read_buf(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        let _ = output.send(buf.split().freeze());
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            _ = feed => return,
+            _ = read => (),
+        }
+        let _ = child.wait().await;
+        warn!("Serial over lan session ended for {}", bmc.name);
+        tokio::time::sleep(SOL_RESTART).await;
+    }
+}
+
+#[derive(Debug)]
+struct IpmiPower(Arc<Bmc>);
+
+#[async_trait::async_trait]
+impl crate::Actuator for IpmiPower {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        if !matches!(parameters.mode.as_str(), "on" | "off" | "cycle" | "reset") {
+            warn!("Unsupported ipmi power mode: {}", parameters.mode);
+            return Err(ActuatorError {});
+        }
+        let output = self
+            .0
+            .command()
+            .args(["chassis", "power", parameters.mode.as_str()])
+            .output()
+            .await
+            .map_err(|e| {
+                warn!("Failed to run ipmitool: {}", e);
+                ActuatorError {}
+            })?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            warn!(
+                "Changing power of {} failed: {}",
+                self.0.name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Err(ActuatorError {})
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IpmiSol {
+    input: mpsc::Sender<Bytes>,
+    output: broadcast::Sender<Bytes>,
+}
+
+#[async_trait::async_trait]
+impl crate::Console for IpmiSol {
+    fn configure(
+        &self,
+        _parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        Ok(())
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let input = self.input.clone();
+        Ok(Box::pin(futures::sink::unfold(
+            input,
+            |input, data: Bytes| async move {
+                input.send(data).await.map_err(|_| ConsoleError::Closed)?;
+                Ok(input)
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("Serial over lan output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Deserialize;
+use tracing::{instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    snmp::{self, SnmpOutlet},
+    tcpconsole::{Endpoint, TcpConsole},
+    Server,
+};
+
+pub const PROVIDER: &str = "labgrid-resources";
+
+fn default_speed() -> u32 {
+    115_200
+}
+
+fn default_protocol() -> String {
+    "rfc2217".to_string()
+}
+
+/// Parameters of the labgrid resource classes that can be configured; Resources are only taken
+/// from the configuration, no labgrid exporter or coordinator is contacted
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cls", content = "params")]
+enum Resource {
+    /// Serial port exported over the network by a labgrid exporter (ser2net)
+    NetworkSerialPort {
+        host: String,
+        port: u16,
+        #[serde(default = "default_speed")]
+        speed: u32,
+        /// Either rfc2217 or raw
+        #[serde(default = "default_protocol")]
+        protocol: String,
+    },
+    /// Network controlled power outlet
+    NetworkPowerPort {
+        model: String,
+        host: String,
+        index: u32,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct LabgridResource {
+    name: String,
+    #[serde(flatten)]
+    resource: Resource,
+}
+
+#[derive(Deserialize, Debug)]
+struct LabgridParameters {
+    resources: Vec<LabgridResource>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: LabgridParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for LabgridResource { name, resource } in parameters.resources {
+        let mut properties = Properties::new(&name);
+        properties.extend(provider_properties);
+        match resource {
+            Resource::NetworkSerialPort {
+                host,
+                port,
+                speed,
+                protocol,
+            } => {
+                let rfc2217 = match protocol.as_str() {
+                    "rfc2217" => true,
+                    "raw" => false,
+                    _ => {
+                        warn!("Unsupported serial protocol for {}: {}", name, protocol);
+                        continue;
+                    }
+                };
+                properties.insert("labgrid.cls", "NetworkSerialPort");
+                let endpoint = Endpoint {
+                    name,
+                    address: format!("{host}:{port}"),
+                    telnet: false,
+                    rfc2217,
+                };
+                server.register_console(properties, TcpConsole::new(endpoint, Some(speed)));
+            }
+            Resource::NetworkPowerPort { model, host, index } => {
+                let model = match model.as_str() {
+                    "apc" => snmp::Model::Apc,
+                    "raritan" => snmp::Model::Raritan,
+                    _ => {
+                        warn!("Unsupported power port model for {}: {}", name, model);
+                        continue;
+                    }
+                };
+                properties.insert("labgrid.cls", "NetworkPowerPort");
+                server.register_actuator(
+                    properties,
+                    SnmpOutlet::new(
+                        host,
+                        snmp::default_community(),
+                        snmp::default_version(),
+                        model,
+                        index,
+                    ),
+                );
+            }
+        }
+    }
+}
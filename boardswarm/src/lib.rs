@@ -0,0 +1,4909 @@
+use anyhow::{bail, Context};
+use boardswarm_protocol::item_event::Event;
+use boardswarm_protocol::{
+    audio_stream_reply, console_input_request, console_register_request, console_signal_request,
+    volume_io_reply, volume_io_request, ConsoleCompression, ConsoleConfigureRequest,
+    ConsoleExpectReply, ConsoleExpectRequest, ConsoleInputRequest, ConsoleOutputRequest,
+    ConsoleRegisterReply, ConsoleRegisterRequest, ConsoleSignalRequest, ConsoleTimestamps,
+    ItemEvent, ItemList, ItemPropertiesMsg, ItemPropertiesRequest, ItemRemoveRequest,
+    ItemTypeRequest, LoginInfoList, Property, RemovedItem, RemovedItemList, VolumeEraseRequest,
+    VolumeInfoMsg, VolumeIoTargetReply, VolumeRequest,
+};
+use bytes::Bytes;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use mediatek_brom::MediatekBromProvider;
+use property_match::{PropertyMatch, PropertyMatchError};
+use registry::{Properties, Registry, ValueMatch};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tonic::Streaming;
+use tracing::{debug, info, instrument, warn};
+
+use boardswarm_provider::{
+    Actuator, ActuatorError, Audio, AudioError, AudioInfo, Console, ConsoleError, ConsoleSignal,
+    FlushCompletion, ReadCompletion, Registrar, ShutdownCompletion, Volume, VolumeError,
+    VolumeTarget, VolumeTargetInfo, WriteCompletion,
+};
+
+mod adb;
+mod alsa;
+mod audio;
+mod auth;
+mod client_console;
+mod config;
+mod config_device;
+mod console_filter;
+mod console_handle;
+mod console_input;
+mod console_line;
+mod console_log;
+mod console_output;
+mod device_console;
+mod dfu;
+mod docker;
+mod external;
+mod fastboot;
+mod gpio;
+mod group;
+mod hidrelay;
+mod image_cache;
+mod image_layout;
+mod imx_sdp;
+mod ipmi;
+mod labgrid;
+mod listen;
+mod mediatek_brom;
+mod mqtt;
+mod pdudaemon;
+mod power_meter;
+mod property_match;
+mod qemu;
+mod ratelimit;
+mod recording;
+mod redfish;
+mod registry;
+mod remote;
+mod rockusb;
+mod sdmux;
+mod serial;
+mod session;
+mod snmp;
+mod state;
+mod sunxi_fel;
+mod task;
+mod tcpconsole;
+mod transform;
+mod tunnel;
+mod udev;
+mod upload_slots;
+mod upload_verify;
+mod usbhub;
+mod utils;
+mod volume_fetch;
+mod volume_transaction;
+mod worker;
+mod ykush;
+
+pub use listen::parse_listen_address;
+
+type ConsoleOutputStream =
+    stream::BoxStream<'static, Result<boardswarm_protocol::ConsoleOutput, tonic::Status>>;
+
+/// Compress console output as part of the zstd stream of the encoder
+fn compress_console_output(
+    encoder: &mut zstd::stream::write::Encoder<'static, Vec<u8>>,
+    mut output: boardswarm_protocol::ConsoleOutput,
+) -> std::io::Result<boardswarm_protocol::ConsoleOutput> {
+    use std::io::Write;
+    encoder.write_all(&output.data)?;
+    encoder.flush()?;
+    output.data = std::mem::take(encoder.get_mut()).into();
+    output.set_compression(ConsoleCompression::Zstd);
+    Ok(output)
+}
+
+type VolumeIoReplyStream =
+    ReceiverStream<Result<boardswarm_protocol::VolumeIoReply, tonic::Status>>;
+
+enum VolumeIoReply {
+    Target(VolumeTargetInfo),
+    Read(oneshot::Receiver<Result<Bytes, tonic::Status>>),
+    Write(oneshot::Receiver<Result<u64, tonic::Status>>),
+    Flush(oneshot::Receiver<Result<(), tonic::Status>>),
+    Shutdown(oneshot::Receiver<Result<(), tonic::Status>>),
+    FatalError(tonic::Status),
+}
+
+/// Result of an operation whose completion got dropped, e.g. as the target went away
+fn not_completed<T>(operation: &str) -> Result<T, tonic::Status> {
+    Err(tonic::Status::aborted(format!("{operation} not completed")))
+}
+
+pub struct VolumeIoReplies {
+    completion_tx: tokio::sync::mpsc::UnboundedSender<VolumeIoReply>,
+}
+
+impl VolumeIoReplies {
+    fn new() -> (Self, VolumeIoReplyStream) {
+        let (reply_tx, reply_rx) = mpsc::channel(8);
+        let (completion_tx, mut completion_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(completion) = completion_rx.recv().await {
+                let reply = match completion {
+                    VolumeIoReply::Target(t) => Ok(boardswarm_protocol::VolumeIoReply {
+                        reply: Some(volume_io_reply::Reply::Target(VolumeIoTargetReply {
+                            target: Some(t),
+                        })),
+                    }),
+                    VolumeIoReply::Read(r) => {
+                        let r = r.await.unwrap_or_else(|_| not_completed("Read"));
+                        r.map(|data| boardswarm_protocol::VolumeIoReply {
+                            reply: Some(volume_io_reply::Reply::Read(
+                                boardswarm_protocol::VolumeIoReadReply { data },
+                            )),
+                        })
+                    }
+                    VolumeIoReply::Write(w) => {
+                        let w = w.await.unwrap_or_else(|_| not_completed("Write"));
+                        w.map(|written| boardswarm_protocol::VolumeIoReply {
+                            reply: Some(volume_io_reply::Reply::Write(
+                                boardswarm_protocol::VolumeIoWriteReply { written },
+                            )),
+                        })
+                    }
+                    VolumeIoReply::Flush(f) => {
+                        let f = f.await.unwrap_or_else(|_| not_completed("Flush"));
+                        f.map(|_| boardswarm_protocol::VolumeIoReply {
+                            reply: Some(volume_io_reply::Reply::Flush(
+                                boardswarm_protocol::VolumeIoFlushReply {},
+                            )),
+                        })
+                    }
+                    VolumeIoReply::Shutdown(s) => {
+                        let s = s.await.unwrap_or_else(|_| not_completed("Shutdown"));
+                        s.map(|_| boardswarm_protocol::VolumeIoReply {
+                            reply: Some(volume_io_reply::Reply::Shutdown(
+                                boardswarm_protocol::VolumeIoShutdownReply {},
+                            )),
+                        })
+                    }
+                    VolumeIoReply::FatalError(e) => Err(e),
+                };
+                // An error ends the stream, so nothing after it reaches the client anyway
+                let failed = reply.is_err();
+                if reply_tx.send(reply).await.is_err() || failed {
+                    break;
+                };
+            }
+        });
+        (Self { completion_tx }, ReceiverStream::new(reply_rx))
+    }
+
+    fn enqueue_target_reply(&mut self, info: VolumeTargetInfo) {
+        let _ = self.completion_tx.send(VolumeIoReply::Target(info));
+    }
+
+    fn enqueue_write_reply(&mut self, rx: oneshot::Receiver<Result<u64, tonic::Status>>) {
+        let _ = self.completion_tx.send(VolumeIoReply::Write(rx));
+    }
+
+    fn enqueue_read_reply(&mut self, rx: oneshot::Receiver<Result<Bytes, tonic::Status>>) {
+        let _ = self.completion_tx.send(VolumeIoReply::Read(rx));
+    }
+
+    fn enqueue_flush_reply(&mut self, rx: oneshot::Receiver<Result<(), tonic::Status>>) {
+        let _ = self.completion_tx.send(VolumeIoReply::Flush(rx));
+    }
+
+    fn enqueue_shutdown_reply(&mut self, rx: oneshot::Receiver<Result<(), tonic::Status>>) {
+        let _ = self.completion_tx.send(VolumeIoReply::Shutdown(rx));
+    }
+
+    fn enqueue_fatal_error(&mut self, error: tonic::Status) {
+        let _ = self.completion_tx.send(VolumeIoReply::FatalError(error));
+    }
+}
+
+trait DeviceConfigItem {
+    fn matches(&self, properties: &Properties) -> bool;
+}
+
+impl DeviceConfigItem for config::Console {
+    #[instrument(fields(name = self.name), skip_all, level="error")]
+    fn matches(&self, properties: &Properties) -> bool {
+        if self.match_.is_empty() {
+            warn!("Console matches is empty - will match any console");
+        }
+        properties.matches(&self.match_)
+    }
+}
+
+impl DeviceConfigItem for config::Volume {
+    #[instrument(fields(name = self.name), skip_all, level="error")]
+    fn matches(&self, properties: &Properties) -> bool {
+        if self.match_.is_empty() {
+            warn!("Volume matches is empty - will match any volume");
+        }
+        properties.matches(&self.match_)
+    }
+}
+
+impl DeviceConfigItem for config::ModeStep {
+    #[instrument(skip_all, level = "error")]
+    fn matches(&self, properties: &Properties) -> bool {
+        match self {
+            config::ModeStep::Actuator(step) => {
+                if step.match_.is_empty() {
+                    warn!("ModeStep matches is empty - will match any device");
+                }
+                properties.matches(&step.match_)
+            }
+            // Console and boot steps refer to the device consoles instead, while wait steps only
+            // look for items
+            config::ModeStep::Console(_)
+            | config::ModeStep::Boot(_)
+            | config::ModeStep::Wait(_)
+            | config::ModeStep::Sleep(_) => false,
+        }
+    }
+}
+
+impl From<&dyn Device> for boardswarm_protocol::Device {
+    fn from(d: &dyn Device) -> Self {
+        let consoles = d
+            .consoles()
+            .into_iter()
+            .map(|c| boardswarm_protocol::Console {
+                name: c.name,
+                id: c.id,
+            })
+            .collect();
+        let volumes = d
+            .volumes()
+            .into_iter()
+            .map(|v| boardswarm_protocol::Volume {
+                name: v.name,
+                id: v.id,
+                mode: v.mode,
+            })
+            .collect();
+        let modes = d
+            .modes()
+            .into_iter()
+            .map(|m| boardswarm_protocol::Mode {
+                name: m.name,
+                depends: m.depends,
+                available: m.available,
+            })
+            .collect();
+        let current_mode = d.current_mode();
+        boardswarm_protocol::Device {
+            consoles,
+            volumes,
+            current_mode,
+            modes,
+            mode_change: d.mode_change(),
+            health: d.health().map(Into::into),
+            power: d.power().map(Into::into),
+            // Kept by the server rather than the device itself
+            labels: HashMap::new(),
+            counters: None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Device is no longer there")]
+struct DeviceGone();
+#[derive(Debug, Error)]
+enum DeviceSetModeError {
+    #[error("Mode not found")]
+    ModeNotFound,
+    #[error("Wrong current mode")]
+    WrongCurrentMode,
+    #[error("Actuator failed: {0}")]
+    ActuatorFailed(#[from] ActuatorError),
+    #[error("Console interaction failed: {0}")]
+    ConsoleFailed(String),
+    #[error("Switching to mode {0} in progress")]
+    Busy(String),
+    #[error("Timed out waiting for {0}")]
+    WaitTimeout(String),
+}
+
+impl From<DeviceSetModeError> for tonic::Status {
+    fn from(e: DeviceSetModeError) -> Self {
+        match e {
+            DeviceSetModeError::ModeNotFound => tonic::Status::not_found("No mode by that name"),
+            DeviceSetModeError::WrongCurrentMode => {
+                tonic::Status::failed_precondition("Mode can't be reached from the current mode")
+            }
+            DeviceSetModeError::ActuatorFailed(_) => tonic::Status::aborted("Actuator failed"),
+            e @ DeviceSetModeError::ConsoleFailed(_) => tonic::Status::aborted(e.to_string()),
+            e @ DeviceSetModeError::Busy(_) => tonic::Status::unavailable(e.to_string()),
+            e @ DeviceSetModeError::WaitTimeout(_) => {
+                tonic::Status::deadline_exceeded(e.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum DeviceSelfTestError {
+    #[error("Self-test not supported")]
+    Unsupported,
+    #[error("Self-test failed to run: {0}")]
+    Failed(String),
+}
+
+struct DeviceMonitor {
+    receiver: broadcast::Receiver<()>,
+}
+
+impl DeviceMonitor {
+    async fn wait(&mut self) -> Result<(), DeviceGone> {
+        while let Err(e) = self.receiver.recv().await {
+            match e {
+                broadcast::error::RecvError::Closed => return Err(DeviceGone()),
+                broadcast::error::RecvError::Lagged(_) => continue,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct DeviceConsole {
+    name: String,
+    id: Option<u64>,
+}
+
+struct DeviceVolume {
+    name: String,
+    id: Option<u64>,
+    /// Mode the device needs to be in for the volume to be used
+    mode: Option<String>,
+}
+
+struct DeviceMode {
+    name: String,
+    depends: Option<String>,
+    available: bool,
+}
+
+/// Actuator used by one of the modes of a device
+struct DeviceActuator {
+    mode: String,
+    id: u64,
+}
+
+/// Result of a single check of a device self-test
+#[derive(Clone)]
+struct DeviceCheck {
+    name: String,
+    passed: bool,
+    message: Option<String>,
+}
+
+impl DeviceCheck {
+    fn new<E: std::fmt::Display>(name: String, result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => DeviceCheck {
+                name,
+                passed: true,
+                message: None,
+            },
+            Err(e) => DeviceCheck {
+                name,
+                passed: false,
+                message: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+impl From<DeviceCheck> for boardswarm_protocol::DeviceCheck {
+    fn from(c: DeviceCheck) -> Self {
+        boardswarm_protocol::DeviceCheck {
+            name: c.name,
+            passed: c.passed,
+            message: c.message,
+        }
+    }
+}
+
+/// Result of the last health check of a device
+#[derive(Clone)]
+struct DeviceHealth {
+    checked: SystemTime,
+    checks: Vec<DeviceCheck>,
+}
+
+impl DeviceHealth {
+    fn healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+}
+
+impl From<boardswarm_protocol::DeviceHealth> for DeviceHealth {
+    fn from(h: boardswarm_protocol::DeviceHealth) -> Self {
+        DeviceHealth {
+            checked: SystemTime::UNIX_EPOCH + Duration::from_secs(h.checked),
+            checks: h
+                .checks
+                .into_iter()
+                .map(|c| DeviceCheck {
+                    name: c.name,
+                    passed: c.passed,
+                    message: c.message,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<DeviceHealth> for boardswarm_protocol::DeviceHealth {
+    fn from(h: DeviceHealth) -> Self {
+        boardswarm_protocol::DeviceHealth {
+            healthy: h.healthy(),
+            checked: h
+                .checked
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            checks: h.checks.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Whether the console, volume or actuator is one of the items of the device, or a console bound
+/// behind one of them
+fn device_uses(device: &dyn Device, type_: boardswarm_protocol::ItemType, id: u64) -> bool {
+    match type_ {
+        boardswarm_protocol::ItemType::Console => {
+            device.consoles().iter().any(|c| c.id == Some(id))
+                || device
+                    .bound_consoles()
+                    .iter()
+                    .any(|&(_, bound)| bound == id)
+        }
+        boardswarm_protocol::ItemType::Volume => {
+            device.volume_entries().iter().any(|v| v.id == Some(id))
+        }
+        boardswarm_protocol::ItemType::Actuator => device.actuators().iter().any(|a| a.id == id),
+        _ => false,
+    }
+}
+
+/// Receives the progress of the steps run while switching modes
+type ModeProgress = mpsc::UnboundedSender<boardswarm_protocol::DeviceModeProgress>;
+
+#[async_trait::async_trait]
+trait Device: Send + Sync {
+    /// Switch to the mode once any other mode change in progress finished
+    async fn set_mode(&self, mode: &str) -> Result<(), DeviceSetModeError>;
+    /// Switch to the mode, failing if another mode change is in progress
+    async fn try_set_mode(&self, mode: &str) -> Result<(), DeviceSetModeError> {
+        if let Some(mode) = self.mode_change() {
+            return Err(DeviceSetModeError::Busy(mode));
+        }
+        self.set_mode(mode).await
+    }
+    /// Switch to the mode like [Device::set_mode] or [Device::try_set_mode], reporting the
+    /// progress of the steps run along the way
+    async fn set_mode_with_progress(
+        &self,
+        mode: &str,
+        wait: bool,
+        _progress: ModeProgress,
+    ) -> Result<(), DeviceSetModeError> {
+        if wait {
+            self.set_mode(mode).await
+        } else {
+            self.try_set_mode(mode).await
+        }
+    }
+    fn updates(&self) -> DeviceMonitor;
+    fn consoles(&self) -> Vec<DeviceConsole>;
+    /// Consoles used behind consoles of the device as (console, bound console) pairs, e.g. the
+    /// console currently backing a persistent console
+    fn bound_consoles(&self) -> Vec<(u64, u64)> {
+        Vec::new()
+    }
+    fn volumes(&self) -> Vec<DeviceVolume>;
+    /// All volumes of the device; Unlike [Device::volumes] this includes volumes hidden by
+    /// another volume of the same name, such that access and mode checks cover them
+    fn volume_entries(&self) -> Vec<DeviceVolume> {
+        self.volumes()
+    }
+    fn modes(&self) -> Vec<DeviceMode>;
+    /// Actuators currently bound to the mode sequences of the device
+    fn actuators(&self) -> Vec<DeviceActuator> {
+        Vec::new()
+    }
+    fn current_mode(&self) -> Option<String>;
+    /// Mode the device is switching to, while a mode change is in progress
+    fn mode_change(&self) -> Option<String> {
+        None
+    }
+    /// Mode to switch to once a session reserving the device ends
+    fn safe_mode(&self) -> Option<String> {
+        None
+    }
+    /// Order in which callers queued for the device get it
+    fn queue_order(&self) -> session::QueueOrder {
+        session::QueueOrder::Fifo
+    }
+    /// Names of the users allowed to use the device; None if everyone is allowed
+    fn access(&self) -> Option<Vec<String>> {
+        None
+    }
+    /// Attach a console registered at runtime; Returns false if the device doesn't support it
+    async fn attach_console(&self, _name: String, _id: u64) -> bool {
+        false
+    }
+    async fn detach_console(&self, _id: u64) {}
+    /// Check the device items are available and run through the configured self-test
+    async fn self_test(&self) -> Result<Vec<DeviceCheck>, DeviceSelfTestError> {
+        Err(DeviceSelfTestError::Unsupported)
+    }
+    /// Run the health probes of the device, switching it between modes
+    async fn health_check(&self) -> Result<DeviceHealth, DeviceSelfTestError> {
+        Err(DeviceSelfTestError::Unsupported)
+    }
+    /// Result of the last health check, if any
+    fn health(&self) -> Option<DeviceHealth> {
+        None
+    }
+    /// Last reading of the power sensor of the device, if it has one
+    fn power(&self) -> Option<DevicePower> {
+        None
+    }
+    /// The device as configured in the configuration file, if it is one; Such devices follow the
+    /// registry changes of the items they use
+    fn config_device(&self) -> Option<config_device::Device> {
+        None
+    }
+}
+
+/// Last reading of the power sensor of a device
+#[derive(Clone)]
+struct DevicePower {
+    watts: f64,
+    /// Joules used since measuring started
+    energy: f64,
+    sampled: SystemTime,
+}
+
+impl From<boardswarm_protocol::DevicePower> for DevicePower {
+    fn from(p: boardswarm_protocol::DevicePower) -> Self {
+        DevicePower {
+            watts: p.watts,
+            energy: p.energy,
+            sampled: SystemTime::UNIX_EPOCH + Duration::from_secs(p.sampled),
+        }
+    }
+}
+
+impl From<DevicePower> for boardswarm_protocol::DevicePower {
+    fn from(p: DevicePower) -> Self {
+        boardswarm_protocol::DevicePower {
+            watts: p.watts,
+            energy: p.energy,
+            sampled: p
+                .sampled
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+/// Default stabilisation periods for actuator mode steps
+#[derive(Default)]
+struct Stabilisation {
+    /// By provider type (`boardswarm.provider`)
+    types: HashMap<String, Duration>,
+    /// By provider name (`boardswarm.provider.name`); Takes precedence over the type
+    providers: HashMap<String, Duration>,
+}
+
+impl Stabilisation {
+    fn from_config(server: &config::Server, providers: &[config::Provider]) -> Self {
+        let types = server
+            .stabilisation
+            .iter()
+            .map(|(provider, duration)| (provider.clone(), **duration))
+            .collect();
+        let providers = providers
+            .iter()
+            .filter_map(|p| p.stabilisation.map(|s| (p.name.clone(), s)))
+            .collect();
+        Self { types, providers }
+    }
+
+    fn for_item(&self, properties: &Properties) -> Option<Duration> {
+        properties
+            .get(registry::PROVIDER_NAME)
+            .and_then(|name| self.providers.get(name))
+            .or_else(|| {
+                properties
+                    .get(registry::PROVIDER)
+                    .and_then(|type_| self.types.get(type_))
+            })
+            .copied()
+    }
+}
+
+/// Settings of a console from the device configuration
+#[derive(Clone, Debug, Default)]
+struct ConsoleSettings {
+    /// Filters applied to the output streamed to clients, unless they request otherwise
+    filters: Vec<console_filter::Filter>,
+    input: console_input::InputPacing,
+    line: console_line::LineDiscipline,
+}
+
+/// Client stream using an item; Counted so items aren't removed while in use
+struct ActiveStream {
+    server: Server,
+    key: (boardswarm_protocol::ItemType, u64),
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        let mut active = self.server.inner.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+struct ServerInner {
+    /// Name of this server; Attached to all items registered on it
+    name: Option<String>,
+    /// Addresses this server can be reached at; Attached to all items registered on it
+    advertise: Option<String>,
+    config_dir: PathBuf,
+    channels: config::Channels,
+    stabilisation: Stabilisation,
+    recording: config::Recording,
+    overrides: Vec<config::Override>,
+    tasks: Vec<Arc<config::Task>>,
+    /// Rate of each upload
+    upload_rate: Option<u64>,
+    /// Shared by all uploads
+    upload_limit: Option<ratelimit::RateLimit>,
+    upload_slots: upload_slots::UploadSlots,
+    /// Volumes whose last upload with an expected checksum wasn't verified; Commit is refused
+    /// until a verified upload succeeds
+    unverified_uploads: Mutex<HashSet<u64>>,
+    /// SHA-256 of the last verified image written to each volume target
+    target_digests: Mutex<HashMap<(u64, String), [u8; 32]>>,
+    /// Urls images may be downloaded from on behalf of clients
+    fetch: Option<config::Fetch>,
+    image_cache: Option<image_cache::ImageCache>,
+    transactions: volume_transaction::Transactions,
+    /// Cancelled to abort the uploads in progress to a volume
+    upload_cancellation: Mutex<HashMap<u64, CancellationToken>>,
+    workers: Arc<worker::Workers>,
+    interactions: broadcast::Sender<recording::Interaction>,
+    active: Mutex<HashMap<(boardswarm_protocol::ItemType, u64), usize>>,
+    sessions: session::Sessions,
+    recordings: recording::Recordings,
+    console_handles: console_handle::Handles,
+    console_outputs: console_output::Outputs,
+    console_logs: console_log::Loggers,
+    /// Settings of consoles used by devices
+    console_settings: Mutex<HashMap<u64, ConsoleSettings>>,
+    /// Kept across restarts if configured
+    state: Option<Arc<state::Store>>,
+    auth_info: Vec<config::Authentication>,
+    devices: Registry<Arc<dyn Device>>,
+    consoles: Registry<Arc<dyn Console>>,
+    actuators: Registry<Arc<dyn Actuator>>,
+    volumes: Registry<Arc<dyn Volume>>,
+    audio: Registry<Arc<dyn Audio>>,
+}
+
+fn to_removed_list<T: Clone>(registry: &Registry<T>) -> RemovedItemList {
+    let item = registry
+        .removed()
+        .into_iter()
+        .map(|r| RemovedItem {
+            id: r.id,
+            property: to_property_list(&r.properties),
+            removed: r
+                .at
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+        .collect();
+    RemovedItemList { item }
+}
+
+fn to_property_list(properties: &Properties) -> Vec<Property> {
+    properties
+        .iter()
+        .map(|(k, v)| Property {
+            key: k.clone(),
+            value: v.clone(),
+        })
+        .collect()
+}
+
+/// Item as listed to clients, optionally along with all its properties
+fn to_item<T: Clone>(
+    id: u64,
+    item: &registry::Item<T>,
+    properties: bool,
+) -> boardswarm_protocol::Item {
+    let p = item.properties();
+    boardswarm_protocol::Item {
+        id,
+        name: p.name().to_string(),
+        instance: p.instance().map(ToOwned::to_owned),
+        identity: p.identity().map(ToOwned::to_owned),
+        property: if properties {
+            to_property_list(&p)
+        } else {
+            Vec::new()
+        },
+    }
+}
+
+/// Parse the filter given by a client, with the values written as in configuration matches
+fn parse_filter(
+    filter: HashMap<String, String>,
+) -> Result<HashMap<String, PropertyMatch>, tonic::Status> {
+    filter
+        .into_iter()
+        .map(|(k, v)| {
+            v.parse()
+                .map(|m| (k, m))
+                .map_err(|e: PropertyMatchError| tonic::Status::invalid_argument(e.to_string()))
+        })
+        .collect()
+}
+
+/// Whether the item has all the properties of the filter given by a client
+fn matches_filter<T>(item: &registry::Item<T>, filter: &HashMap<String, PropertyMatch>) -> bool {
+    let p = item.properties();
+    filter
+        .iter()
+        .all(|(k, m)| p.get(k).is_some_and(|v| m.matches_value(v)))
+}
+
+/// Event to send to a monitor knowing about the `known` items matching its filter; Items are
+/// added or removed as their updated properties start or stop matching
+fn to_item_event<T: Clone>(
+    change: registry::RegistryChange<T>,
+    filter: &HashMap<String, PropertyMatch>,
+    known: &mut BTreeSet<u64>,
+    properties: bool,
+) -> Option<Event> {
+    match change {
+        registry::RegistryChange::Added { id, item } => {
+            (matches_filter(&item, filter) && known.insert(id)).then(|| {
+                Event::Add(ItemList {
+                    item: vec![to_item(id, &item, properties)],
+                })
+            })
+        }
+        registry::RegistryChange::Updated { id, item } => {
+            let list = ItemList {
+                item: vec![to_item(id, &item, properties)],
+            };
+            match (matches_filter(&item, filter), known.contains(&id)) {
+                (true, true) => Some(Event::Update(list)),
+                (true, false) => {
+                    known.insert(id);
+                    Some(Event::Add(list))
+                }
+                (false, true) => {
+                    known.remove(&id);
+                    Some(Event::Remove(id))
+                }
+                (false, false) => None,
+            }
+        }
+        // Only items that were announced are known, so removals of filtered out items are
+        // skipped as well
+        registry::RegistryChange::Removed(id) => known.remove(&id).then_some(Event::Remove(id)),
+    }
+}
+
+fn to_item_list<T: Clone>(
+    registry: &Registry<T>,
+    filter: &HashMap<String, PropertyMatch>,
+    properties: bool,
+) -> ItemList {
+    let item = registry
+        .contents()
+        .into_iter()
+        .filter(|(_, item)| matches_filter(item, filter))
+        .map(|(id, item)| to_item(id, &item, properties))
+        .collect();
+    ItemList { item }
+}
+
+/// Free-form labels among the properties of an item, i.e. those not set by boardswarm itself
+fn labels(properties: &Properties) -> HashMap<String, String> {
+    properties
+        .iter()
+        .filter(|(k, _)| !k.starts_with(registry::RESERVED))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+fn to_topology_items<T: Clone>(
+    items: &Registry<T>,
+    type_: boardswarm_protocol::ItemType,
+) -> impl Iterator<Item = boardswarm_protocol::TopologyItem> {
+    items.contents().into_iter().map(move |(id, item)| {
+        let properties = item.properties();
+        boardswarm_protocol::TopologyItem {
+            r#type: type_.into(),
+            id,
+            name: properties.name().to_string(),
+            provider: properties
+                .get(registry::PROVIDER_NAME)
+                .map(ToOwned::to_owned),
+            server: properties.get(registry::SERVER).map(ToOwned::to_owned),
+            instance: properties.instance().map(ToOwned::to_owned),
+        }
+    })
+}
+
+#[derive(Clone)]
+pub struct Server {
+    inner: Arc<ServerInner>,
+}
+
+impl Server {
+    fn new(
+        auth_info: Vec<config::Authentication>,
+        config_dir: PathBuf,
+        config: &config::Server,
+        stabilisation: Stabilisation,
+        upload_slots: upload_slots::UploadSlots,
+        overrides: Vec<config::Override>,
+        tasks: Vec<config::Task>,
+    ) -> Self {
+        let channels = config.channels.clone();
+        let image_cache = config
+            .image_cache
+            .as_ref()
+            .map(|c| image_cache::ImageCache::new(&config_dir, c));
+        let state = config
+            .state
+            .as_ref()
+            .map(|d| Arc::new(state::Store::load(&config_dir, d)));
+        Self {
+            inner: Arc::new(ServerInner {
+                name: config.name.clone(),
+                advertise: (!config.advertise.is_empty()).then(|| config.advertise.join(",")),
+                auth_info,
+                config_dir,
+                consoles: Registry::with_capacity(channels.registry),
+                devices: Registry::with_capacity(channels.registry),
+                actuators: Registry::with_capacity(channels.registry),
+                volumes: Registry::with_capacity(channels.registry),
+                audio: Registry::with_capacity(channels.registry),
+                channels,
+                stabilisation,
+                recording: config.recording.clone(),
+                overrides,
+                tasks: tasks.into_iter().map(Arc::new).collect(),
+                upload_rate: config.upload_limit.upload,
+                upload_limit: config.upload_limit.global.map(ratelimit::RateLimit::new),
+                upload_slots,
+                unverified_uploads: Mutex::default(),
+                target_digests: Mutex::default(),
+                fetch: config.fetch.clone(),
+                image_cache,
+                transactions: volume_transaction::Transactions::default(),
+                upload_cancellation: Mutex::default(),
+                workers: Arc::new(worker::Workers::new(
+                    config.workers.unwrap_or_else(worker::Workers::default_jobs),
+                )),
+                interactions: broadcast::channel(recording::CAPACITY).0,
+                active: Mutex::new(HashMap::new()),
+                sessions: session::Sessions::default(),
+                recordings: recording::Recordings::new(config.recording.session_size),
+                console_handles: console_handle::Handles::default(),
+                console_outputs: console_output::Outputs::new(config.console_history),
+                console_logs: console_log::Loggers::new(config.console_log.clone()),
+                console_settings: Mutex::default(),
+                state,
+            }),
+        }
+    }
+
+    fn config_dir(&self) -> &Path {
+        &self.inner.config_dir
+    }
+
+    /// Pool to run heavy work on
+    fn workers(&self) -> Arc<worker::Workers> {
+        self.inner.workers.clone()
+    }
+
+    /// Attach the name and advertised addresses of the server to a newly registered item; Items
+    /// of remote servers keep the ones of the server they're attached to
+    fn tag_server(&self, properties: &mut Properties) {
+        if let Some(name) = &self.inner.name {
+            if properties.get(registry::SERVER).is_none() {
+                properties.insert(registry::SERVER, name.as_str());
+            }
+        }
+        if let Some(advertise) = &self.inner.advertise {
+            if properties.get(registry::SERVER_ADDRESS).is_none() {
+                properties.insert(registry::SERVER_ADDRESS, advertise.as_str());
+            }
+        }
+    }
+
+    /// Apply the configured overrides to the properties of a newly registered item
+    fn apply_overrides(&self, properties: &mut Properties) {
+        for o in &self.inner.overrides {
+            if properties.matches(&o.match_) {
+                if let Some(name) = &o.name {
+                    properties.insert(registry::NAME, name.as_str());
+                }
+                if let Some(identity) = &o.identity {
+                    properties.insert(registry::IDENTITY, identity.as_str());
+                }
+                properties.extend(o.properties.iter());
+            }
+        }
+    }
+
+    /// Replace the properties of a registered item in place, e.g. when the provider noticed them
+    /// changing, rather than removing and re-adding it
+    fn update_item(
+        &self,
+        type_: boardswarm_protocol::ItemType,
+        id: u64,
+        mut properties: Properties,
+    ) {
+        self.tag_server(&mut properties);
+        if type_ == boardswarm_protocol::ItemType::Device {
+            if properties.identity().is_none() {
+                let name = properties.name().to_string();
+                properties.insert(registry::IDENTITY, name);
+            }
+        } else {
+            self.apply_overrides(&mut properties);
+            self.identify(&mut properties);
+        }
+        let inner = &self.inner;
+        let updated = match type_ {
+            boardswarm_protocol::ItemType::Actuator => inner
+                .actuators
+                .update(id, properties)
+                .map(|i| i.to_string()),
+            boardswarm_protocol::ItemType::Device => {
+                inner.devices.update(id, properties).map(|i| i.to_string())
+            }
+            boardswarm_protocol::ItemType::Console => {
+                inner.consoles.update(id, properties).map(|i| i.to_string())
+            }
+            boardswarm_protocol::ItemType::Volume => {
+                inner.volumes.update(id, properties).map(|i| i.to_string())
+            }
+            boardswarm_protocol::ItemType::Audio => {
+                inner.audio.update(id, properties).map(|i| i.to_string())
+            }
+        };
+        if let Some(item) = updated {
+            info!("Updated {:?} properties: {} - {}", type_, id, item);
+        }
+    }
+
+    /// Derive a stable identity for a newly registered item from its persistent hardware serial,
+    /// unless it already got one from an override or a remote server
+    fn identify(&self, properties: &mut Properties) {
+        if properties.identity().is_some() {
+            return;
+        }
+        let Some(serial) = properties.get("udev.ID_SERIAL") else {
+            return;
+        };
+        let mut identity = match properties.get(registry::PROVIDER_NAME) {
+            Some(provider) => format!("{}:{}", provider, serial),
+            None => serial.to_string(),
+        };
+        // Multi-function USB devices expose one item per interface
+        if let Some(interface) = properties.get("udev.ID_USB_INTERFACE_NUM") {
+            identity = format!("{}:{}", identity, interface);
+        }
+        properties.insert(registry::IDENTITY, identity);
+    }
+
+    fn register_actuator<A>(&self, mut properties: Properties, actuator: A) -> u64
+    where
+        A: Actuator + 'static,
+    {
+        self.tag_server(&mut properties);
+        self.apply_overrides(&mut properties);
+        self.identify(&mut properties);
+        let (id, item) = self.inner.actuators.add(properties, Arc::new(actuator));
+        info!("Registered actuator: {} - {}", id, item);
+        id
+    }
+
+    fn get_actuator(&self, id: u64) -> Option<Arc<dyn Actuator>> {
+        self.inner
+            .actuators
+            .lookup(id)
+            .map(|item| item.inner().clone())
+    }
+
+    fn find_actuator<'a, K, V, I>(
+        &self,
+        matches: &'a I,
+    ) -> Option<registry::Item<Arc<dyn Actuator>>>
+    where
+        K: AsRef<str>,
+        V: ValueMatch,
+        &'a I: IntoIterator<Item = (K, V)>,
+    {
+        self.inner.actuators.find(matches).map(|(_, item)| item)
+    }
+
+    /// Default stabilisation period after using an actuator
+    fn actuator_stabilisation(
+        &self,
+        actuator: &registry::Item<Arc<dyn Actuator>>,
+    ) -> Option<Duration> {
+        self.inner.stabilisation.for_item(&actuator.properties())
+    }
+
+    fn unregister_actuator(&self, id: u64) {
+        if let Some(item) = self.inner.actuators.lookup(id) {
+            info!("Unregistering actuator: {} - {}", id, item);
+            self.inner.actuators.remove(id);
+        }
+    }
+
+    fn register_console<C>(&self, mut properties: Properties, console: C) -> u64
+    where
+        C: Console + 'static,
+    {
+        self.tag_server(&mut properties);
+        self.apply_overrides(&mut properties);
+        self.identify(&mut properties);
+        let (id, item) = self.inner.consoles.add(properties, Arc::new(console));
+        info!("Registered console: {} - {}", id, item);
+        id
+    }
+
+    fn unregister_console(&self, id: u64) {
+        if let Some(item) = self.inner.consoles.lookup(id) {
+            info!("Unregistering console: {} - {}", id, item);
+            self.inner.consoles.remove(id);
+            self.inner.console_handles.close_console(id);
+            self.inner.console_outputs.remove(id);
+            self.inner.console_settings.lock().unwrap().remove(&id);
+        }
+    }
+
+    fn get_console(&self, id: u64) -> Option<Arc<dyn Console>> {
+        self.inner
+            .consoles
+            .lookup(id)
+            .map(|item| item.inner().clone())
+    }
+
+    /// Output of the console, optionally starting with its buffered history; The console itself
+    /// is only read once, with its output shared by all users
+    async fn console_output(
+        &self,
+        id: u64,
+        history: bool,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        self.shared_console_output(id)
+            .await?
+            .stream(history)
+            .ok_or(ConsoleError::Closed)
+    }
+
+    /// Like [Self::console_output], with each chunk paired with the time it was read
+    async fn console_output_timestamped(
+        &self,
+        id: u64,
+        history: bool,
+    ) -> Result<BoxStream<'static, Result<(SystemTime, Bytes), ConsoleError>>, ConsoleError> {
+        self.shared_console_output(id)
+            .await?
+            .stream_timestamped(history)
+            .ok_or(ConsoleError::Closed)
+    }
+
+    async fn shared_console_output(
+        &self,
+        id: u64,
+    ) -> Result<Arc<console_output::SharedOutput>, ConsoleError> {
+        let console = self
+            .get_console(id)
+            .ok_or_else(|| ConsoleError::Unavailable("Console not found".to_string()))?;
+        self.inner
+            .console_outputs
+            .get_or_start(id, console.as_ref())
+            .await
+    }
+
+    /// Start reading the console such that its history is kept, if enabled; Only done once the
+    /// console is configured, such that e.g. serial ports get opened with the right settings
+    fn keep_console_history(&self, id: u64) {
+        if !self.inner.console_outputs.keeps_history() {
+            return;
+        }
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.console_output(id, false).await {
+                warn!("Failed to keep history of console {}: {}", id, e);
+            }
+        });
+    }
+
+    /// Set the filters and input pacing from the device configuration of the console
+    fn set_console_settings(&self, id: u64, settings: ConsoleSettings) {
+        self.inner
+            .console_settings
+            .lock()
+            .unwrap()
+            .insert(id, settings);
+    }
+
+    fn console_settings(&self, id: u64) -> ConsoleSettings {
+        self.inner
+            .console_settings
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Input of the console, paced and translated as configured for it
+    async fn console_input(
+        &self,
+        id: u64,
+        console: &dyn Console,
+    ) -> Result<console_input::ConsoleInput, ConsoleError> {
+        let settings = self.console_settings(id);
+        let input = console_input::paced(console.input().await?, settings.input);
+        let server = self.clone();
+        Ok(console_line::input(input, settings.line, move |data| {
+            server.inner.console_outputs.echo(id, data)
+        }))
+    }
+
+    /// Write the output of a device console to log files, if enabled
+    fn log_console(&self, device: &str, console: &str, id: u64) {
+        self.inner.console_logs.start(self, device, console, id);
+    }
+
+    fn register_volume<V>(&self, mut properties: Properties, volume: V) -> u64
+    where
+        V: Volume + 'static,
+    {
+        self.tag_server(&mut properties);
+        self.apply_overrides(&mut properties);
+        self.identify(&mut properties);
+        let (id, item) = self.inner.volumes.add(properties, Arc::new(volume));
+        info!("Registered volume: {} - {}", id, item);
+        id
+    }
+
+    fn unregister_volume(&self, id: u64) {
+        if let Some(item) = self.inner.volumes.lookup(id) {
+            info!("Unregistering volume: {} - {}", id, item.name());
+            self.inner.volumes.remove(id);
+            self.inner.unverified_uploads.lock().unwrap().remove(&id);
+            self.inner
+                .target_digests
+                .lock()
+                .unwrap()
+                .retain(|(volume, _), _| *volume != id);
+            self.inner.transactions.remove_volume(id);
+            self.cancel_uploads(id);
+        }
+    }
+
+    fn register_audio<A>(&self, mut properties: Properties, audio: A) -> u64
+    where
+        A: Audio + 'static,
+    {
+        self.tag_server(&mut properties);
+        self.apply_overrides(&mut properties);
+        self.identify(&mut properties);
+        let (id, item) = self.inner.audio.add(properties, Arc::new(audio));
+        info!("Registered audio: {} - {}", id, item);
+        id
+    }
+
+    fn unregister_audio(&self, id: u64) {
+        if let Some(item) = self.inner.audio.lookup(id) {
+            info!("Unregistering audio: {} - {}", id, item.name());
+            self.inner.audio.remove(id);
+        }
+    }
+
+    fn get_audio(&self, id: u64) -> Option<Arc<dyn Audio>> {
+        self.inner.audio.lookup(id).map(registry::Item::into_inner)
+    }
+
+    /// Device using the volume and the mode it needs to be in for the volume to be used, if any
+    fn volume_mode(&self, volume: u64) -> Option<(registry::Item<Arc<dyn Device>>, String)> {
+        self.inner
+            .devices
+            .contents()
+            .into_iter()
+            .find_map(|(_, device)| {
+                let mode = device
+                    .inner()
+                    .volume_entries()
+                    .into_iter()
+                    .find(|v| v.id == Some(volume))?
+                    .mode?;
+                Some((device, mode))
+            })
+    }
+
+    /// Check the caller is allowed to use the device
+    fn check_access(
+        device: &registry::Item<Arc<dyn Device>>,
+        identity: Option<&auth::Identity>,
+    ) -> Result<(), tonic::Status> {
+        let Some(access) = device.inner().access() else {
+            return Ok(());
+        };
+        let name = identity.and_then(|i| i.name.as_deref());
+        if name.is_some_and(|name| access.iter().any(|a| a == name)) {
+            Ok(())
+        } else {
+            Err(tonic::Status::permission_denied(format!(
+                "Not allowed to use device {}",
+                device.name()
+            )))
+        }
+    }
+
+    /// Check the caller is allowed to use all devices the console, volume or actuator belongs to
+    fn check_item_access(
+        &self,
+        type_: boardswarm_protocol::ItemType,
+        id: u64,
+        identity: Option<&auth::Identity>,
+    ) -> Result<(), tonic::Status> {
+        for (_, device) in self.inner.devices.contents() {
+            if device_uses(device.inner().as_ref(), type_, id) {
+                Self::check_access(&device, identity)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether input to the console may be sent without a handle; Consoles bound behind a
+    /// console of a device are locked along with it
+    fn check_console_unlocked(&self, console: u64) -> Result<(), console_handle::HandleError> {
+        self.inner.console_handles.check_unlocked(console)?;
+        for (_, device) in self.inner.devices.contents() {
+            for (id, bound) in device.inner().bound_consoles() {
+                if bound == console {
+                    self.inner.console_handles.check_unlocked(id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether `session` (if any) may be used by the caller for the console or volume, i.e.
+    /// no device using it is reserved by another session
+    fn check_item_session(
+        &self,
+        type_: boardswarm_protocol::ItemType,
+        id: u64,
+        session: Option<u64>,
+        identity: Option<&auth::Identity>,
+    ) -> Result<(), tonic::Status> {
+        let holder = identity.and_then(|i| i.name.as_deref());
+        for (device_id, device) in self.inner.devices.contents() {
+            if device_uses(device.inner().as_ref(), type_, id) {
+                self.inner.sessions.check(device_id, session, holder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifier for an upload to the volume if an expected checksum was given; The volume can't be
+    /// committed until the upload is verified
+    fn start_verification(
+        &self,
+        volume: u64,
+        sha256: Option<&[u8]>,
+    ) -> Result<Option<upload_verify::Verifier>, upload_verify::VerifyError> {
+        let Some(sha256) = sha256 else {
+            return Ok(None);
+        };
+        let verifier = upload_verify::Verifier::new(sha256)?;
+        self.inner.unverified_uploads.lock().unwrap().insert(volume);
+        Ok(Some(verifier))
+    }
+
+    /// Check the upload to the volume target against its expected checksum, allowing the volume to
+    /// be committed again if it matches
+    fn finish_verification(
+        &self,
+        volume: u64,
+        target: &str,
+        verifier: upload_verify::Verifier,
+    ) -> Result<(), upload_verify::VerifyError> {
+        let digest = verifier.image_digest();
+        match verifier.finish() {
+            Ok(()) => {
+                self.inner
+                    .unverified_uploads
+                    .lock()
+                    .unwrap()
+                    .remove(&volume);
+                self.set_target_digest(volume, target, digest);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Upload to volume {} failed verification: {}", volume, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Record the digest of the content of the volume target, if known
+    fn set_target_digest(&self, volume: u64, target: &str, digest: Option<[u8; 32]>) {
+        let mut digests = self.inner.target_digests.lock().unwrap();
+        match digest {
+            Some(digest) => digests.insert((volume, target.to_string()), digest),
+            None => digests.remove(&(volume, target.to_string())),
+        };
+    }
+
+    /// Token cancelled once the uploads to the volume get cancelled
+    fn upload_token(&self, volume: u64) -> CancellationToken {
+        self.inner
+            .upload_cancellation
+            .lock()
+            .unwrap()
+            .entry(volume)
+            .or_default()
+            .child_token()
+    }
+
+    /// Cancel all uploads to the volume in progress; Later uploads aren't affected
+    fn cancel_uploads(&self, volume: u64) {
+        if let Some(token) = self
+            .inner
+            .upload_cancellation
+            .lock()
+            .unwrap()
+            .remove(&volume)
+        {
+            token.cancel();
+        }
+    }
+
+    /// Check the device using the volume is in the mode required by the volume, if any
+    fn check_volume_mode(&self, volume: u64) -> Result<(), tonic::Status> {
+        if let Some((device, mode)) = self.volume_mode(volume) {
+            if device.inner().current_mode().as_ref() != Some(&mode) {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "Volume requires device {} to be in mode {}",
+                    device.name(),
+                    mode
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn session_info(&self, id: u64) -> Result<boardswarm_protocol::Session, tonic::Status> {
+        let session = self
+            .inner
+            .sessions
+            .get(id)
+            .ok_or(session::SessionError::NotFound)?;
+        Ok(session.to_message(id))
+    }
+
+    /// Close the session once its deadline has passed
+    async fn expire_session(self, id: u64) {
+        while let Some(session) = self.inner.sessions.get(id) {
+            if session.deadline <= tokio::time::Instant::now() {
+                info!("Session {} expired", id);
+                let _ = self.end_session(id).await;
+                return;
+            }
+            tokio::time::sleep_until(session.deadline).await;
+        }
+    }
+
+    /// Close the session and switch its devices to their safe mode
+    async fn end_session(&self, id: u64) -> Result<(), session::SessionError> {
+        let devices = self.inner.sessions.close(id)?;
+        self.inner.recordings.stop(id);
+        for id in devices {
+            let Some(device) = self.get_device(id) else {
+                continue;
+            };
+            if let Some(mode) = device.safe_mode() {
+                if let Err(e) = device.set_mode(&mode).await {
+                    warn!(
+                        "Failed to switch device {} to safe mode {}: {}",
+                        id, mode, e
+                    );
+                }
+            }
+        }
+        // Hand the devices to queued callers only once they're back in their safe mode
+        self.inner.sessions.schedule();
+        self.save_sessions();
+        Ok(())
+    }
+
+    /// Keep the open sessions across restarts, naming their devices as ids may change
+    fn save_sessions(&self) {
+        let Some(state) = &self.inner.state else {
+            return;
+        };
+        let now = tokio::time::Instant::now();
+        let sessions = self
+            .inner
+            .sessions
+            .list()
+            .into_iter()
+            .map(|(id, session)| state::SessionState {
+                id,
+                devices: session
+                    .devices
+                    .iter()
+                    .filter_map(|&d| self.inner.devices.lookup(d))
+                    .map(|item| item.properties().name().to_string())
+                    .collect(),
+                expires: (SystemTime::now() + session.deadline.saturating_duration_since(now))
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                holder: session.holder,
+            })
+            .collect();
+        state.set_sessions(self.inner.sessions.last_id(), sessions);
+    }
+
+    /// Reopen the sessions kept from before a restart that are still valid; Only sessions of
+    /// which all devices are configured on this server can be restored
+    fn restore_sessions(&self) {
+        let Some(state) = &self.inner.state else {
+            return;
+        };
+        let (last, saved) = state.sessions();
+        let devices = self.inner.devices.contents();
+        let mut restored = Vec::new();
+        for saved in saved {
+            let remaining = saved.remaining();
+            if remaining.is_zero() {
+                continue;
+            }
+            let ids: Option<Vec<u64>> = saved
+                .devices
+                .iter()
+                .map(|name| {
+                    devices
+                        .iter()
+                        .find(|(_, item)| {
+                            let properties = item.properties();
+                            properties.name() == name && properties.instance().is_none()
+                        })
+                        .map(|(id, _)| *id)
+                })
+                .collect();
+            let Some(ids) = ids else {
+                warn!("Not restoring session {}: Devices are gone", saved.id);
+                continue;
+            };
+            info!("Restored session {}", saved.id);
+            restored.push((
+                saved.id,
+                session::Session {
+                    devices: ids,
+                    deadline: tokio::time::Instant::now() + remaining,
+                    holder: saved.holder,
+                },
+            ));
+        }
+        let ids: Vec<u64> = restored.iter().map(|(id, _)| *id).collect();
+        self.inner.sessions.restore(last, restored);
+        for id in ids {
+            tokio::spawn(self.clone().expire_session(id));
+        }
+        self.save_sessions();
+    }
+
+    /// Usage counters of a device configured on this server, if state is kept
+    fn device_counters(
+        &self,
+        properties: &Properties,
+    ) -> Option<boardswarm_protocol::DeviceCounters> {
+        let state = self.inner.state.as_ref()?;
+        if properties.instance().is_some() {
+            return None;
+        }
+        Some(state.device(properties.name()).into())
+    }
+
+    pub fn get_volume(&self, id: u64) -> Option<Arc<dyn Volume>> {
+        self.inner
+            .volumes
+            .lookup(id)
+            .map(registry::Item::into_inner)
+    }
+
+    fn register_device<D>(&self, mut properties: Properties, device: D) -> u64
+    where
+        D: Device + 'static,
+    {
+        self.tag_server(&mut properties);
+        // Configured device names are unique and persistent
+        if properties.identity().is_none() {
+            let name = properties.name().to_string();
+            properties.insert(registry::IDENTITY, name);
+        }
+        let (id, item) = self.inner.devices.add(properties, Arc::new(device));
+        info!("Registered device: {} - {}", id, item);
+        id
+    }
+
+    fn unregister_device(&self, id: u64) {
+        if let Some(item) = self.inner.devices.lookup(id) {
+            info!("Unregistering device: {} - {}", id, item.name());
+            self.inner.devices.remove(id);
+        }
+    }
+
+    fn get_device(&self, id: u64) -> Option<Arc<dyn Device>> {
+        self.inner
+            .devices
+            .lookup(id)
+            .map(registry::Item::into_inner)
+    }
+
+    /// Let recordings know about a client interaction with an item
+    fn record(&self, interaction: recording::Interaction) {
+        if let (
+            Some(state),
+            recording::Interaction::Volume {
+                volume,
+                operation,
+                length,
+                ..
+            },
+        ) = (&self.inner.state, &interaction)
+        {
+            // Writes with a known length and server side fetches are the uploads of images
+            if *operation == "fetch" || (*operation == "open" && *length > 0) {
+                for (_, item) in self.inner.devices.contents() {
+                    let properties = item.properties();
+                    if properties.instance().is_none()
+                        && device_uses(
+                            &**item.inner(),
+                            boardswarm_protocol::ItemType::Volume,
+                            *volume,
+                        )
+                    {
+                        state.update_device(properties.name(), |d| d.uploads += 1);
+                    }
+                }
+            }
+        }
+        let _ = self.inner.interactions.send(interaction);
+    }
+
+    fn interactions(&self) -> broadcast::Receiver<recording::Interaction> {
+        self.inner.interactions.subscribe()
+    }
+
+    fn recording_config(&self) -> &config::Recording {
+        &self.inner.recording
+    }
+
+    /// Mark an item as used by a client stream for as long as the returned guard is alive
+    fn stream_guard(&self, type_: boardswarm_protocol::ItemType, id: u64) -> ActiveStream {
+        let key = (type_, id);
+        *self.inner.active.lock().unwrap().entry(key).or_default() += 1;
+        ActiveStream {
+            server: self.clone(),
+            key,
+        }
+    }
+
+    fn active_streams(&self, type_: boardswarm_protocol::ItemType, id: u64) -> usize {
+        self.inner
+            .active
+            .lock()
+            .unwrap()
+            .get(&(type_, id))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn topology(&self) -> boardswarm_protocol::TopologyReply {
+        use boardswarm_protocol::{ItemType, TopologyLink};
+        let items = to_topology_items(&self.inner.devices, ItemType::Device)
+            .chain(to_topology_items(&self.inner.consoles, ItemType::Console))
+            .chain(to_topology_items(&self.inner.actuators, ItemType::Actuator))
+            .chain(to_topology_items(&self.inner.volumes, ItemType::Volume))
+            .chain(to_topology_items(&self.inner.audio, ItemType::Audio))
+            .collect();
+
+        let mut links = Vec::new();
+        for (device, item) in self.inner.devices.contents() {
+            let link = |type_: ItemType, id, role| TopologyLink {
+                device,
+                r#type: type_.into(),
+                id,
+                role,
+            };
+            let d = item.inner();
+            links.extend(
+                d.consoles()
+                    .into_iter()
+                    .filter_map(|c| Some(link(ItemType::Console, c.id?, c.name))),
+            );
+            links.extend(
+                d.volumes()
+                    .into_iter()
+                    .filter_map(|v| Some(link(ItemType::Volume, v.id?, v.name))),
+            );
+            links.extend(
+                d.actuators()
+                    .into_iter()
+                    .map(|a| link(ItemType::Actuator, a.id, a.mode)),
+            );
+        }
+
+        boardswarm_protocol::TopologyReply { items, links }
+    }
+
+    fn item_list_for(
+        &self,
+        type_: boardswarm_protocol::ItemType,
+        filter: &HashMap<String, PropertyMatch>,
+        properties: bool,
+    ) -> ItemList {
+        let inner = &self.inner;
+        match type_ {
+            boardswarm_protocol::ItemType::Actuator => {
+                to_item_list(&inner.actuators, filter, properties)
+            }
+            boardswarm_protocol::ItemType::Device => {
+                to_item_list(&inner.devices, filter, properties)
+            }
+            boardswarm_protocol::ItemType::Console => {
+                to_item_list(&inner.consoles, filter, properties)
+            }
+            boardswarm_protocol::ItemType::Volume => {
+                to_item_list(&inner.volumes, filter, properties)
+            }
+            boardswarm_protocol::ItemType::Audio => to_item_list(&inner.audio, filter, properties),
+        }
+    }
+}
+
+impl Registrar for Server {
+    fn register_actuator<A>(&self, properties: Properties, actuator: A) -> u64
+    where
+        A: Actuator + 'static,
+    {
+        Server::register_actuator(self, properties, actuator)
+    }
+
+    fn unregister_actuator(&self, id: u64) {
+        Server::unregister_actuator(self, id)
+    }
+
+    fn register_console<C>(&self, properties: Properties, console: C) -> u64
+    where
+        C: Console + 'static,
+    {
+        Server::register_console(self, properties, console)
+    }
+
+    fn unregister_console(&self, id: u64) {
+        Server::unregister_console(self, id)
+    }
+
+    fn register_volume<V>(&self, properties: Properties, volume: V) -> u64
+    where
+        V: Volume + 'static,
+    {
+        Server::register_volume(self, properties, volume)
+    }
+
+    fn unregister_volume(&self, id: u64) {
+        Server::unregister_volume(self, id)
+    }
+
+    fn register_audio<A>(&self, properties: Properties, audio: A) -> u64
+    where
+        A: Audio + 'static,
+    {
+        Server::register_audio(self, properties, audio)
+    }
+
+    fn unregister_audio(&self, id: u64) {
+        Server::unregister_audio(self, id)
+    }
+}
+
+type ItemMonitorStream = BoxStream<'static, Result<boardswarm_protocol::ItemEvent, tonic::Status>>;
+
+#[async_trait::async_trait]
+impl boardswarm_protocol::boardswarm_server::Boardswarm for Server {
+    async fn login_info(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<LoginInfoList>, tonic::Status> {
+        let info = self
+            .inner
+            .auth_info
+            .iter()
+            .filter_map(|a| match a {
+                config::Authentication::Oidc {
+                    description,
+                    uri,
+                    client,
+                    ..
+                } => Some(boardswarm_protocol::LoginInfo {
+                    description: description.clone(),
+                    method: Some(boardswarm_protocol::login_info::Method::Oidc(
+                        boardswarm_protocol::OidcInfo {
+                            url: uri.clone(),
+                            client_id: client.clone(),
+                        },
+                    )),
+                }),
+                config::Authentication::Jwks { .. } | config::Authentication::Token { .. } => None,
+            })
+            .collect();
+        Ok(tonic::Response::new(LoginInfoList { info }))
+    }
+
+    async fn list(
+        &self,
+        request: tonic::Request<ItemTypeRequest>,
+    ) -> Result<tonic::Response<ItemList>, tonic::Status> {
+        let request = request.into_inner();
+        let type_ = request
+            .r#type
+            .try_into()
+            .map_err(|_e| tonic::Status::invalid_argument("Unknown item type "))?;
+
+        let filter = parse_filter(request.filter)?;
+        Ok(tonic::Response::new(self.item_list_for(
+            type_,
+            &filter,
+            request.properties,
+        )))
+    }
+
+    type MonitorStream = ItemMonitorStream;
+    async fn monitor(
+        &self,
+        request: tonic::Request<ItemTypeRequest>,
+    ) -> Result<tonic::Response<Self::MonitorStream>, tonic::Status> {
+        let request = request.into_inner();
+        let type_ = request
+            .r#type
+            .try_into()
+            .map_err(|_e| tonic::Status::invalid_argument("Unknown item type "))?;
+
+        fn to_item_stream<T>(
+            server: Server,
+            registry: fn(&ServerInner) -> &Registry<T>,
+            filter: HashMap<String, PropertyMatch>,
+            properties: bool,
+        ) -> ItemMonitorStream
+        where
+            T: Clone + Send + Sync + 'static,
+        {
+            let monitor = registry(&server.inner).monitor();
+            let initial = to_item_list(registry(&server.inner), &filter, properties);
+            let filter = Arc::new(filter);
+            let known: BTreeSet<u64> = initial.item.iter().map(|i| i.id).collect();
+            let initial = Ok(ItemEvent {
+                event: Some(Event::Add(initial)),
+            });
+            stream::once(async move { initial })
+                .chain(
+                    stream::unfold((monitor, known), move |(mut monitor, mut known)| {
+                        let server = server.clone();
+                        let filter = filter.clone();
+                        async move {
+                            let changes = match monitor.recv().await {
+                                Ok(change) => vec![change],
+                                // Missed some changes; Catch up with the current contents
+                                Err(broadcast::error::RecvError::Lagged(_)) => {
+                                    registry(&server.inner).resync(&known)
+                                }
+                                Err(broadcast::error::RecvError::Closed) => return None,
+                            };
+                            let events: Vec<_> = changes
+                                .into_iter()
+                                .filter_map(|change| {
+                                    to_item_event(change, &filter, &mut known, properties)
+                                        .map(|event| Ok(ItemEvent { event: Some(event) }))
+                                })
+                                .collect();
+                            Some((stream::iter(events), (monitor, known)))
+                        }
+                    })
+                    .flatten(),
+                )
+                .boxed()
+        }
+        let server = self.clone();
+        let (f, p) = (parse_filter(request.filter)?, request.properties);
+        let response = match type_ {
+            boardswarm_protocol::ItemType::Actuator => {
+                to_item_stream(server, |s| &s.actuators, f, p)
+            }
+            boardswarm_protocol::ItemType::Device => to_item_stream(server, |s| &s.devices, f, p),
+            boardswarm_protocol::ItemType::Console => to_item_stream(server, |s| &s.consoles, f, p),
+            boardswarm_protocol::ItemType::Volume => to_item_stream(server, |s| &s.volumes, f, p),
+            boardswarm_protocol::ItemType::Audio => to_item_stream(server, |s| &s.audio, f, p),
+        };
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn item_properties(
+        &self,
+        request: tonic::Request<ItemPropertiesRequest>,
+    ) -> Result<tonic::Response<ItemPropertiesMsg>, tonic::Status> {
+        let request = request.into_inner();
+        let type_ = request
+            .r#type
+            .try_into()
+            .map_err(|_e| tonic::Status::invalid_argument("Unknown item type "))?;
+        let properties = match type_ {
+            boardswarm_protocol::ItemType::Actuator => self
+                .inner
+                .actuators
+                .lookup(request.item)
+                .ok_or_else(|| tonic::Status::not_found("Item not found"))?
+                .properties(),
+            boardswarm_protocol::ItemType::Device => self
+                .inner
+                .devices
+                .lookup(request.item)
+                .ok_or_else(|| tonic::Status::not_found("Item not found"))?
+                .properties(),
+            boardswarm_protocol::ItemType::Console => self
+                .inner
+                .consoles
+                .lookup(request.item)
+                .ok_or_else(|| tonic::Status::not_found("Item not found"))?
+                .properties(),
+            boardswarm_protocol::ItemType::Volume => self
+                .inner
+                .volumes
+                .lookup(request.item)
+                .ok_or_else(|| tonic::Status::not_found("Item not found"))?
+                .properties(),
+            boardswarm_protocol::ItemType::Audio => self
+                .inner
+                .audio
+                .lookup(request.item)
+                .ok_or_else(|| tonic::Status::not_found("Item not found"))?
+                .properties(),
+        };
+
+        Ok(tonic::Response::new(ItemPropertiesMsg {
+            property: to_property_list(&properties),
+        }))
+    }
+
+    async fn item_removed(
+        &self,
+        request: tonic::Request<ItemTypeRequest>,
+    ) -> Result<tonic::Response<RemovedItemList>, tonic::Status> {
+        let request = request.into_inner();
+        let type_ = request
+            .r#type
+            .try_into()
+            .map_err(|_e| tonic::Status::invalid_argument("Unknown item type "))?;
+        let removed = match type_ {
+            boardswarm_protocol::ItemType::Actuator => to_removed_list(&self.inner.actuators),
+            boardswarm_protocol::ItemType::Device => to_removed_list(&self.inner.devices),
+            boardswarm_protocol::ItemType::Console => to_removed_list(&self.inner.consoles),
+            boardswarm_protocol::ItemType::Volume => to_removed_list(&self.inner.volumes),
+            boardswarm_protocol::ItemType::Audio => to_removed_list(&self.inner.audio),
+        };
+        Ok(tonic::Response::new(removed))
+    }
+
+    async fn topology(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<boardswarm_protocol::TopologyReply>, tonic::Status> {
+        Ok(tonic::Response::new(Server::topology(self)))
+    }
+
+    async fn item_remove(
+        &self,
+        request: tonic::Request<ItemRemoveRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let type_ = request
+            .r#type
+            .try_into()
+            .map_err(|_e| tonic::Status::invalid_argument("Unknown item type "))?;
+        let exists = match type_ {
+            boardswarm_protocol::ItemType::Actuator => {
+                self.inner.actuators.lookup(request.item).is_some()
+            }
+            boardswarm_protocol::ItemType::Device => {
+                self.inner.devices.lookup(request.item).is_some()
+            }
+            boardswarm_protocol::ItemType::Console => {
+                self.inner.consoles.lookup(request.item).is_some()
+            }
+            boardswarm_protocol::ItemType::Volume => {
+                self.inner.volumes.lookup(request.item).is_some()
+            }
+            boardswarm_protocol::ItemType::Audio => self.inner.audio.lookup(request.item).is_some(),
+        };
+        if !exists {
+            return Err(tonic::Status::not_found("Item not found"));
+        }
+        if type_ == boardswarm_protocol::ItemType::Device {
+            if let Some(device) = self.inner.devices.lookup(request.item) {
+                Self::check_access(&device, identity.as_ref())?;
+            }
+        } else {
+            self.check_item_access(type_, request.item, identity.as_ref())?;
+        }
+
+        let active = self.active_streams(type_, request.item);
+        if active > 0 {
+            if !request.force {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "Item is used by {} active streams",
+                    active
+                )));
+            }
+            warn!(
+                "Forcefully removing {:?} {} with {} active streams",
+                type_, request.item, active
+            );
+        }
+
+        match type_ {
+            boardswarm_protocol::ItemType::Actuator => self.unregister_actuator(request.item),
+            boardswarm_protocol::ItemType::Device => self.unregister_device(request.item),
+            boardswarm_protocol::ItemType::Console => self.unregister_console(request.item),
+            boardswarm_protocol::ItemType::Volume => self.unregister_volume(request.item),
+            boardswarm_protocol::ItemType::Audio => self.unregister_audio(request.item),
+        }
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn console_configure(
+        &self,
+        request: tonic::Request<ConsoleConfigureRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let inner = request.into_inner();
+        if let Some(console) = self.get_console(inner.console) {
+            self.check_item_access(
+                boardswarm_protocol::ItemType::Console,
+                inner.console,
+                identity.as_ref(),
+            )?;
+            let parameters = inner.parameters.unwrap_or_default();
+            let line: console_line::LineDiscipline =
+                serde::Deserialize::deserialize(parameters.clone())
+                    .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+            let pacing: console_input::ParameterPacing =
+                serde::Deserialize::deserialize(parameters.clone())
+                    .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+            console.configure(Box::new(<dyn erased_serde::Deserializer>::erase(
+                parameters,
+            )))?;
+            {
+                let mut settings = self.inner.console_settings.lock().unwrap();
+                let settings = settings.entry(inner.console).or_default();
+                settings.line = line;
+                settings.input = settings.input.with_parameters(pacing);
+            }
+            self.keep_console_history(inner.console);
+            Ok(tonic::Response::new(()))
+        } else {
+            Err(tonic::Status::invalid_argument("Can't find console"))
+        }
+    }
+
+    type ConsoleStreamOutputStream = ConsoleOutputStream;
+    async fn console_stream_output(
+        &self,
+        request: tonic::Request<ConsoleOutputRequest>,
+    ) -> Result<tonic::Response<Self::ConsoleStreamOutputStream>, tonic::Status> {
+        let inner = request.into_inner();
+        if self.get_console(inner.console).is_some() {
+            let guard = self.stream_guard(boardswarm_protocol::ItemType::Console, inner.console);
+            let filters = match &inner.filters {
+                Some(filters) => filters
+                    .filter()
+                    .filter_map(console_filter::Filter::from_protocol)
+                    .collect(),
+                None => self.console_settings(inner.console).filters,
+            };
+            let mut pipeline = console_filter::Pipeline::new(&filters);
+            let mut hex_dump = self
+                .console_settings(inner.console)
+                .line
+                .hex_dump
+                .then(console_line::HexDump::default);
+            let timestamps = inner.timestamps();
+            let mut line_start = true;
+            let mut stream = self
+                .console_output_timestamped(inner.console, inner.history)
+                .await?
+                .map(move |output| {
+                    // Keep the guard alive for as long as the stream
+                    let _guard = &guard;
+                    let (at, data) = output?;
+                    let mut data = pipeline.apply(data);
+                    if let Some(hex_dump) = &mut hex_dump {
+                        data = hex_dump.apply(&data);
+                    }
+                    let output = match timestamps {
+                        ConsoleTimestamps::None => boardswarm_protocol::ConsoleOutput {
+                            data,
+                            ..Default::default()
+                        },
+                        ConsoleTimestamps::Field => boardswarm_protocol::ConsoleOutput {
+                            data,
+                            timestamp: Some(
+                                at.duration_since(std::time::UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_micros() as u64,
+                            ),
+                            ..Default::default()
+                        },
+                        ConsoleTimestamps::Prefix => boardswarm_protocol::ConsoleOutput {
+                            data: console_output::timestamp_lines(at, &data, &mut line_start),
+                            ..Default::default()
+                        },
+                    };
+                    Ok::<_, tonic::Status>(output)
+                })
+                .boxed();
+            if inner.compression() == ConsoleCompression::Zstd {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)
+                    .map_err(|e| tonic::Status::internal(e.to_string()))?;
+                stream = stream
+                    .map(move |output| {
+                        compress_console_output(&mut encoder, output?)
+                            .map_err(|e| tonic::Status::internal(e.to_string()))
+                    })
+                    .boxed();
+            }
+            Ok(tonic::Response::new(stream))
+        } else {
+            Err(tonic::Status::invalid_argument("Can't find output console"))
+        }
+    }
+
+    async fn console_expect(
+        &self,
+        request: tonic::Request<ConsoleExpectRequest>,
+    ) -> Result<tonic::Response<ConsoleExpectReply>, tonic::Status> {
+        let inner = request.into_inner();
+        if self.get_console(inner.console).is_none() {
+            return Err(tonic::Status::invalid_argument("Can't find console"));
+        }
+        let pattern = regex::bytes::Regex::new(&inner.pattern)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+
+        let _guard = self.stream_guard(boardswarm_protocol::ItemType::Console, inner.console);
+        let mut output = self.console_output(inner.console, inner.history).await?;
+        let wait = console_output::wait_for(&mut output, &pattern);
+        let (output, matched) = match inner.timeout_ms {
+            Some(timeout) => tokio::time::timeout(Duration::from_millis(timeout), wait)
+                .await
+                .map_err(|_| tonic::Status::deadline_exceeded("Pattern not found in time"))??,
+            None => wait.await?,
+        };
+        Ok(tonic::Response::new(ConsoleExpectReply {
+            matched: output.slice(matched),
+            output: if inner.capture { output } else { Bytes::new() },
+        }))
+    }
+
+    async fn console_signal(
+        &self,
+        request: tonic::Request<ConsoleSignalRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let inner = request.into_inner();
+        // Signals are input as well, so respect the input lock
+        match inner.handle {
+            Some(handle) => {
+                let h = self
+                    .inner
+                    .console_handles
+                    .get(handle, identity.as_ref().and_then(|i| i.name.as_deref()))?;
+                if h.console != inner.console {
+                    return Err(tonic::Status::invalid_argument(
+                        "Handle is for a different console",
+                    ));
+                }
+            }
+            None => self.check_console_unlocked(inner.console)?,
+        }
+        let console = self
+            .get_console(inner.console)
+            .ok_or_else(|| tonic::Status::not_found("Can't find console"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Console,
+            inner.console,
+            identity.as_ref(),
+        )?;
+        let signal = match inner.signal {
+            Some(console_signal_request::Signal::BreakMs(ms)) => {
+                ConsoleSignal::Break(Duration::from_millis(ms.into()))
+            }
+            Some(console_signal_request::Signal::Dtr(on)) => ConsoleSignal::Dtr(on),
+            Some(console_signal_request::Signal::Rts(on)) => ConsoleSignal::Rts(on),
+            None => return Err(tonic::Status::invalid_argument("No signal given")),
+        };
+        console.signal(signal).await?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn console_stream_input(
+        &self,
+        request: tonic::Request<Streaming<ConsoleInputRequest>>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let mut rx = request.into_inner();
+
+        /* First message must select the target */
+        let msg = match rx.message().await? {
+            Some(msg) => msg,
+            None => return Ok(tonic::Response::new(())),
+        };
+        let session = msg.session;
+        let (console, handle) = match msg.target_or_data {
+            Some(console_input_request::TargetOrData::Console(console)) => {
+                self.check_console_unlocked(console)?;
+                (console, None)
+            }
+            Some(console_input_request::TargetOrData::Handle(handle)) => {
+                let h = self
+                    .inner
+                    .console_handles
+                    .get(handle, identity.as_ref().and_then(|i| i.name.as_deref()))?;
+                (h.console, Some(h))
+            }
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "Target should be set first",
+                ))
+            }
+        };
+        let id = console;
+        let console = self
+            .get_console(id)
+            .ok_or_else(|| tonic::Status::not_found("No serial console by that name"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Console,
+            id,
+            identity.as_ref(),
+        )?;
+        self.check_item_session(
+            boardswarm_protocol::ItemType::Console,
+            id,
+            session,
+            identity.as_ref(),
+        )?;
+        let closed = handle.map(|h| h.closed).unwrap_or_default();
+
+        let _guard = self.stream_guard(boardswarm_protocol::ItemType::Console, id);
+        let mut input = self.console_input(id, console.as_ref()).await?;
+        loop {
+            let request = tokio::select! {
+                request = rx.message() => request?,
+                _ = closed.cancelled() => {
+                    return Err(tonic::Status::aborted("Console handle closed"))
+                }
+            };
+            let Some(request) = request else {
+                break;
+            };
+            match request.target_or_data {
+                Some(console_input_request::TargetOrData::Data(data)) => {
+                    self.record(recording::Interaction::ConsoleInput {
+                        console: id,
+                        data: data.clone(),
+                    });
+                    input.send(data).await.unwrap()
+                }
+                _ => return Err(tonic::Status::invalid_argument("Target cannot be changed")),
+            }
+        }
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn console_open(
+        &self,
+        request: tonic::Request<boardswarm_protocol::ConsoleOpenRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::ConsoleHandle>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        if self.get_console(request.console).is_none() {
+            return Err(tonic::Status::not_found("No console by that id"));
+        }
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Console,
+            request.console,
+            identity.as_ref(),
+        )?;
+        self.check_item_session(
+            boardswarm_protocol::ItemType::Console,
+            request.console,
+            request.session,
+            identity.as_ref(),
+        )?;
+
+        let owner = identity.and_then(|i| i.name);
+        let id = self
+            .inner
+            .console_handles
+            .open(request.console, owner.clone())?;
+        info!(
+            "Opened console handle {} for console {} by {}",
+            id,
+            request.console,
+            owner.as_deref().unwrap_or("unknown")
+        );
+        let handle = self.inner.console_handles.get(id, owner.as_deref())?;
+        Ok(tonic::Response::new(handle.to_message(id)))
+    }
+
+    async fn console_close(
+        &self,
+        request: tonic::Request<boardswarm_protocol::ConsoleCloseRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let owner = identity.as_ref().and_then(|i| i.name.as_deref());
+        let request = request.into_inner();
+        let handle = self.inner.console_handles.get(request.handle, owner)?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Console,
+            handle.console,
+            identity.as_ref(),
+        )?;
+        self.inner.console_handles.close(request.handle, owner)?;
+        info!(
+            "Closed console handle {} for console {}",
+            request.handle, handle.console
+        );
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn console_handle_list(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<boardswarm_protocol::ConsoleHandleListReply>, tonic::Status> {
+        let handles = self
+            .inner
+            .console_handles
+            .list()
+            .into_iter()
+            .map(|(id, handle)| handle.to_message(id))
+            .collect();
+        Ok(tonic::Response::new(
+            boardswarm_protocol::ConsoleHandleListReply { handles },
+        ))
+    }
+
+    type ConsoleRegisterStream = ReceiverStream<Result<ConsoleRegisterReply, tonic::Status>>;
+    async fn console_register(
+        &self,
+        request: tonic::Request<Streaming<ConsoleRegisterRequest>>,
+    ) -> Result<tonic::Response<Self::ConsoleRegisterStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let mut rx = request.into_inner();
+
+        /* First message must be the registration */
+        let register = match rx.message().await? {
+            Some(ConsoleRegisterRequest {
+                register_or_data: Some(console_register_request::RegisterOrData::Register(r)),
+            }) => r,
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "Registration should be sent first",
+                ))
+            }
+        };
+        let device = register
+            .device
+            .map(|d| {
+                let item = self
+                    .inner
+                    .devices
+                    .lookup(d)
+                    .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+                Self::check_access(&item, identity.as_ref())?;
+                Ok::<_, tonic::Status>(item.into_inner())
+            })
+            .transpose()?;
+
+        let console = client_console::ClientConsole::new();
+        let output = console.sender();
+        let mut properties = Properties::new(&register.name);
+        properties.extend(&[
+            (registry::PROVIDER_NAME, client_console::PROVIDER),
+            (registry::PROVIDER, client_console::PROVIDER),
+        ]);
+        let id = self.register_console(properties, console);
+        if let Some(device) = &device {
+            if !device.attach_console(register.name, id).await {
+                self.unregister_console(id);
+                return Err(tonic::Status::failed_precondition(
+                    "Device doesn't support attaching consoles",
+                ));
+            }
+        }
+
+        let (reply_tx, reply_rx) = mpsc::channel(1);
+        let _ = reply_tx
+            .send(Ok(ConsoleRegisterReply { console: id }))
+            .await;
+        let server = self.clone();
+        tokio::spawn(async move {
+            // Keep the reply stream open for as long as the client is feeding the console
+            while let Ok(Some(request)) = rx.message().await {
+                match request.register_or_data {
+                    Some(console_register_request::RegisterOrData::Data(data)) => {
+                        let _ = output.send(data);
+                    }
+                    _ => {
+                        let _ = reply_tx
+                            .send(Err(tonic::Status::invalid_argument(
+                                "Console cannot be registered again",
+                            )))
+                            .await;
+                        break;
+                    }
+                }
+            }
+            if let Some(device) = device {
+                device.detach_console(id).await;
+            }
+            server.unregister_console(id);
+        });
+
+        Ok(tonic::Response::new(ReceiverStream::new(reply_rx)))
+    }
+
+    type DeviceInfoStream = BoxStream<'static, Result<boardswarm_protocol::Device, tonic::Status>>;
+    async fn device_info(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceRequest>,
+    ) -> Result<tonic::Response<Self::DeviceInfoStream>, tonic::Status> {
+        let request = request.into_inner();
+        if let Some(item) = self.inner.devices.lookup(request.device) {
+            let properties = item.properties();
+            let labels = labels(&properties);
+            let device = item.into_inner();
+            let info = boardswarm_protocol::Device {
+                labels: labels.clone(),
+                counters: self.device_counters(&properties),
+                ..(&*device).into()
+            };
+            let monitor = device.updates();
+            let stream = Box::pin(stream::once(async move { Ok(info) }).chain(stream::unfold(
+                (self.clone(), properties, device, monitor, labels),
+                |(server, properties, device, mut monitor, labels)| async move {
+                    monitor.wait().await.ok()?;
+                    let info = boardswarm_protocol::Device {
+                        labels: labels.clone(),
+                        counters: server.device_counters(&properties),
+                        ..(&*device).into()
+                    };
+                    Some((Ok(info), (server, properties, device, monitor, labels)))
+                },
+            )));
+            Ok(tonic::Response::new(stream))
+        } else {
+            Err(tonic::Status::not_found("No such device"))
+        }
+    }
+
+    type DeviceInfoChangesStream =
+        BoxStream<'static, Result<boardswarm_protocol::DeviceChange, tonic::Status>>;
+    async fn device_info_changes(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceRequest>,
+    ) -> Result<tonic::Response<Self::DeviceInfoChangesStream>, tonic::Status> {
+        let request = request.into_inner();
+        let item = self
+            .inner
+            .devices
+            .lookup(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No such device"))?;
+        let properties = item.properties();
+        let labels = labels(&properties);
+        let device = item.into_inner();
+        // Labels don't change, so they're only part of the snapshot
+        let info = boardswarm_protocol::Device {
+            labels,
+            counters: self.device_counters(&properties),
+            ..(&*device).into()
+        };
+        let snapshot = boardswarm_protocol::DeviceChange {
+            change: Some(boardswarm_protocol::device_change::Change::Snapshot(
+                info.clone(),
+            )),
+        };
+        let monitor = device.updates();
+        let changes = stream::unfold(
+            (self.clone(), properties, device, monitor, info),
+            |(server, properties, device, mut monitor, info)| async move {
+                monitor.wait().await.ok()?;
+                let new = boardswarm_protocol::Device {
+                    counters: server.device_counters(&properties),
+                    ..(&*device).into()
+                };
+                let changes = info.changes(&new);
+                Some((changes, (server, properties, device, monitor, new)))
+            },
+        )
+        .flat_map(|changes| {
+            stream::iter(changes.into_iter().map(|change| {
+                Ok(boardswarm_protocol::DeviceChange {
+                    change: Some(change),
+                })
+            }))
+        });
+        Ok(tonic::Response::new(Box::pin(
+            stream::once(async move { Ok(snapshot) }).chain(changes),
+        )))
+    }
+
+    type DeviceChangeModeStream =
+        ReceiverStream<Result<boardswarm_protocol::DeviceModeProgress, tonic::Status>>;
+    async fn device_change_mode(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceModeRequest>,
+    ) -> Result<tonic::Response<Self::DeviceChangeModeStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let item = self
+            .inner
+            .devices
+            .lookup(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        Self::check_access(&item, identity.as_ref())?;
+        let device = item.into_inner();
+        self.inner.sessions.check(
+            request.device,
+            request.session,
+            identity.as_ref().and_then(|i| i.name.as_deref()),
+        )?;
+
+        let (tx, rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+            let mut change =
+                device.set_mode_with_progress(&request.mode, !request.no_wait, progress_tx);
+            // Forward progress while the mode change runs; A client going away doesn't stop the
+            // mode change
+            let result = loop {
+                tokio::select! {
+                    result = &mut change => break result,
+                    Some(progress) = progress_rx.recv() => {
+                        let _ = tx.send(Ok(progress)).await;
+                    }
+                }
+            };
+            while let Ok(progress) = progress_rx.try_recv() {
+                let _ = tx.send(Ok(progress)).await;
+            }
+            if let Err(e) = result {
+                let _ = tx.send(Err(e.into())).await;
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    type DeviceRecordStream = recording::RecordStream;
+    async fn device_record(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceRequest>,
+    ) -> Result<tonic::Response<Self::DeviceRecordStream>, tonic::Status> {
+        let request = request.into_inner();
+        let device = self
+            .get_device(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        let guard = self.stream_guard(boardswarm_protocol::ItemType::Device, request.device);
+        Ok(tonic::Response::new(recording::record(
+            self.clone(),
+            request.device,
+            device,
+            guard,
+        )))
+    }
+
+    type DeviceConsoleOutputStream = ConsoleOutputStream;
+    async fn device_console_output(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceConsoleOutputRequest>,
+    ) -> Result<tonic::Response<Self::DeviceConsoleOutputStream>, tonic::Status> {
+        let request = request.into_inner();
+        let target = request
+            .target
+            .ok_or_else(|| tonic::Status::invalid_argument("No target given"))?;
+        let device = self
+            .get_device(target.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        if !device.consoles().iter().any(|c| c.name == target.console) {
+            return Err(tonic::Status::not_found("No console by that name"));
+        }
+        let guard = self.stream_guard(boardswarm_protocol::ItemType::Device, target.device);
+        Ok(tonic::Response::new(device_console::output(
+            self.clone(),
+            device,
+            target.console,
+            request.history,
+            guard,
+        )))
+    }
+
+    async fn device_console_input(
+        &self,
+        request: tonic::Request<Streaming<boardswarm_protocol::DeviceConsoleInputRequest>>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let mut rx = request.into_inner();
+
+        /* First message must select the target */
+        let (target, session) = match rx.message().await? {
+            Some(boardswarm_protocol::DeviceConsoleInputRequest {
+                target_or_data:
+                    Some(boardswarm_protocol::device_console_input_request::TargetOrData::Target(
+                        target,
+                    )),
+                session,
+            }) => (target, session),
+            Some(_) => {
+                return Err(tonic::Status::invalid_argument(
+                    "Target should be set first",
+                ))
+            }
+            None => return Ok(tonic::Response::new(())),
+        };
+        let item = self
+            .inner
+            .devices
+            .lookup(target.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        Self::check_access(&item, identity.as_ref())?;
+        self.inner.sessions.check(
+            target.device,
+            session,
+            identity.as_ref().and_then(|i| i.name.as_deref()),
+        )?;
+        let device = item.into_inner();
+        if !device.consoles().iter().any(|c| c.name == target.console) {
+            return Err(tonic::Status::not_found("No console by that name"));
+        }
+
+        let _guard = self.stream_guard(boardswarm_protocol::ItemType::Device, target.device);
+        device_console::input(self.clone(), device, target.console, rx).await?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn device_self_test(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::DeviceSelfTestReport>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let item = self
+            .inner
+            .devices
+            .lookup(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        Self::check_access(&item, identity.as_ref())?;
+        // The self-test switches modes, which would disrupt whoever holds the device
+        self.inner.sessions.check(request.device, None, None)?;
+        let device = item.into_inner();
+        let checks: Vec<_> = match device.self_test().await {
+            Ok(checks) => checks
+                .into_iter()
+                .map(boardswarm_protocol::DeviceCheck::from)
+                .collect(),
+            Err(e @ DeviceSelfTestError::Unsupported) => {
+                return Err(tonic::Status::unimplemented(e.to_string()))
+            }
+            Err(e @ DeviceSelfTestError::Failed(_)) => {
+                return Err(tonic::Status::aborted(e.to_string()))
+            }
+        };
+        let passed = checks.iter().all(|c| c.passed);
+        Ok(tonic::Response::new(
+            boardswarm_protocol::DeviceSelfTestReport { passed, checks },
+        ))
+    }
+
+    async fn device_health_check(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::DeviceHealth>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let item = self
+            .inner
+            .devices
+            .lookup(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        Self::check_access(&item, identity.as_ref())?;
+        // Probes switch modes, which would disrupt whoever holds the device
+        self.inner.sessions.check(request.device, None, None)?;
+        let device = item.into_inner();
+        match device.health_check().await {
+            Ok(health) => Ok(tonic::Response::new(health.into())),
+            Err(e @ DeviceSelfTestError::Unsupported) => {
+                Err(tonic::Status::unimplemented(e.to_string()))
+            }
+            Err(e @ DeviceSelfTestError::Failed(_)) => Err(tonic::Status::aborted(e.to_string())),
+        }
+    }
+
+    async fn device_wait_for(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceWaitRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::DeviceWaitReply>, tonic::Status> {
+        let request = request.into_inner();
+        let device = self
+            .get_device(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        let type_ = request.r#type();
+        let available = |device: &dyn Device| {
+            let ids: Vec<Option<u64>> = match type_ {
+                boardswarm_protocol::ItemType::Console => device
+                    .consoles()
+                    .into_iter()
+                    .filter(|c| c.name == request.name)
+                    .map(|c| c.id)
+                    .collect(),
+                boardswarm_protocol::ItemType::Volume => device
+                    .volumes()
+                    .into_iter()
+                    .filter(|v| v.name == request.name)
+                    .map(|v| v.id)
+                    .collect(),
+                _ => {
+                    return Err(tonic::Status::invalid_argument(
+                        "Only consoles and volumes can be waited for",
+                    ))
+                }
+            };
+            if ids.is_empty() {
+                return Err(tonic::Status::not_found(format!(
+                    "Device has no item named {}",
+                    request.name
+                )));
+            }
+            Ok(ids.into_iter().flatten().next())
+        };
+
+        // Subscribed before checking, such that the item appearing meanwhile isn't missed
+        let mut monitor = device.updates();
+        let wait = async {
+            loop {
+                if let Some(id) = available(&*device)? {
+                    return Ok::<_, tonic::Status>(id);
+                }
+                monitor
+                    .wait()
+                    .await
+                    .map_err(|e| tonic::Status::not_found(e.to_string()))?;
+            }
+        };
+        let id = match request.timeout_ms {
+            Some(timeout) => tokio::time::timeout(Duration::from_millis(timeout), wait)
+                .await
+                .map_err(|_| tonic::Status::deadline_exceeded("Not available in time"))??,
+            None => wait.await?,
+        };
+        Ok(tonic::Response::new(boardswarm_protocol::DeviceWaitReply {
+            id,
+        }))
+    }
+
+    async fn actuator_change_mode(
+        &self,
+        request: tonic::Request<boardswarm_protocol::ActuatorModeRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let inner = request.into_inner();
+        if let Some(actuator) = self.get_actuator(inner.actuator) {
+            self.check_item_access(
+                boardswarm_protocol::ItemType::Actuator,
+                inner.actuator,
+                identity.as_ref(),
+            )?;
+            let parameters = inner
+                .parameters
+                .ok_or_else(|| tonic::Status::invalid_argument("No mode parameters given"))?;
+            actuator
+                .set_mode(Box::new(<dyn erased_serde::Deserializer>::erase(
+                    parameters,
+                )))
+                .await
+                .map_err(|e| tonic::Status::internal(format!("Actuator failed: {e}")))?;
+            Ok(tonic::Response::new(()))
+        } else {
+            Err(tonic::Status::invalid_argument("Can't find actuator"))
+        }
+    }
+
+    type VolumeIoStream = VolumeIoReplyStream;
+    async fn volume_io(
+        &self,
+        request: tonic::Request<tonic::Streaming<boardswarm_protocol::VolumeIoRequest>>,
+    ) -> Result<tonic::Response<Self::VolumeIoStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let mut rx = request.into_inner();
+        let msg = match rx.message().await? {
+            Some(msg) => msg,
+            None => {
+                return Err(tonic::Status::invalid_argument(
+                    "No uploader/target selection",
+                ))
+            }
+        };
+
+        if let Some(volume_io_request::TargetOrRequest::Target(target)) = msg.target_or_request {
+            let item = self
+                .inner
+                .volumes
+                .lookup(target.volume)
+                .ok_or_else(|| tonic::Status::not_found("No volume by that name"))?;
+            self.check_item_access(
+                boardswarm_protocol::ItemType::Volume,
+                target.volume,
+                identity.as_ref(),
+            )?;
+            self.check_item_session(
+                boardswarm_protocol::ItemType::Volume,
+                target.volume,
+                target.session,
+                identity.as_ref(),
+            )?;
+            self.check_volume_mode(target.volume)?;
+            let mut verifier = self.start_verification(target.volume, target.sha256.as_deref())?;
+            // Verified uploads of a complete image get added to the cache
+            let mut cache = match (&self.inner.image_cache, target.sha256.as_deref()) {
+                (Some(cache), Some(sha256)) => cache.insert(sha256).await,
+                _ => None,
+            };
+
+            let (mut reply, reply_stream) = VolumeIoReplies::new();
+            let slot = self.inner.upload_slots.acquire(&item.properties()).await;
+            let (info, mut io) = item
+                .into_inner()
+                .open(&target.target, target.length)
+                .await?;
+            let part = target
+                .transaction
+                .map(|t| {
+                    self.inner
+                        .transactions
+                        .join(t, target.volume, &target.target)
+                })
+                .transpose()?;
+            reply.enqueue_target_reply(info);
+            let guard = self.stream_guard(boardswarm_protocol::ItemType::Volume, target.volume);
+            let length = target.length.unwrap_or_default();
+            let volume_id = target.volume;
+            let target_name = target.target.clone();
+            let record = move |operation: &'static str, offset: u64, length: u64| {
+                recording::Interaction::Volume {
+                    volume: target.volume,
+                    target: target.target.clone(),
+                    operation,
+                    offset,
+                    length,
+                }
+            };
+            self.record(record("open", 0, length));
+
+            let server = self.clone();
+            let upload_limit = self.inner.upload_rate.map(ratelimit::RateLimit::new);
+            let cancel = self.upload_token(volume_id);
+            tokio::spawn(async move {
+                let _guard = guard;
+                let _slot = slot;
+                // Bytes written and whether the upload was shut down, to detect short uploads
+                let mut written = 0;
+                let mut shut_down = false;
+                let handle = async {
+                    while let Some(msg) = rx.message().await.transpose() {
+                        let request = match msg {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("Received error: {}", e);
+                                reply.enqueue_fatal_error(e);
+                                break;
+                            }
+                        };
+
+                        let Some(request) = request.target_or_request else {
+                            warn!("Invalid request, no actualy request");
+                            reply.enqueue_fatal_error(tonic::Status::invalid_argument(
+                                "Request without operation",
+                            ));
+                            break;
+                        };
+
+                        if server.inner.volumes.lookup(volume_id).is_none() {
+                            reply.enqueue_fatal_error(tonic::Status::unavailable(
+                                "Volume went away during the upload",
+                            ));
+                            break;
+                        }
+
+                        match request {
+                            volume_io_request::TargetOrRequest::Target(_) => {
+                                reply.enqueue_fatal_error(tonic::Status::invalid_argument(
+                                    "Target request sent out of order",
+                                ));
+                                break;
+                            }
+                            volume_io_request::TargetOrRequest::Read(read) => {
+                                server.record(record("read", read.offset, read.length));
+                                let (completion, rx) = ReadCompletion::new();
+                                reply.enqueue_read_reply(rx);
+                                io.read(read.length, read.offset, completion).await;
+                            }
+                            volume_io_request::TargetOrRequest::Write(write) => {
+                                let length = write.data.len() as u64;
+                                server.record(record("write", write.offset, length));
+                                let verified = write
+                                    .crc32
+                                    .map(|crc| {
+                                        upload_verify::check_chunk(&write.data, write.offset, crc)
+                                    })
+                                    .transpose()
+                                    .and_then(|_| match &mut verifier {
+                                        Some(verifier) => {
+                                            verifier.update(write.offset, &write.data)
+                                        }
+                                        None => Ok(()),
+                                    });
+                                if let Err(e) = verified {
+                                    warn!("Upload verification failed: {}", e);
+                                    reply.enqueue_fatal_error(e.into());
+                                    verifier = None;
+                                    break;
+                                }
+                                if let Some(insert) = &mut cache {
+                                    if let Err(e) = insert.write(write.offset, &write.data).await {
+                                        debug!("Not caching upload: {}", e);
+                                        cache = None;
+                                    }
+                                }
+                                if let Some(limit) = &upload_limit {
+                                    limit.acquire(length).await;
+                                }
+                                if let Some(limit) = &server.inner.upload_limit {
+                                    limit.acquire(length).await;
+                                }
+                                if written == 0 {
+                                    // The content no longer matches a previous verified image
+                                    server.set_target_digest(volume_id, &target_name, None);
+                                }
+                                written += length;
+                                let (completion, rx) = WriteCompletion::new();
+                                match &part {
+                                    Some(part) => reply.enqueue_write_reply(part.track(rx)),
+                                    None => reply.enqueue_write_reply(rx),
+                                }
+                                io.write(write.data, write.offset, completion).await;
+                            }
+                            volume_io_request::TargetOrRequest::Flush(_f) => {
+                                let (completion, rx) = FlushCompletion::new();
+                                reply.enqueue_flush_reply(rx);
+                                io.flush(completion).await;
+                            }
+                            volume_io_request::TargetOrRequest::Shutdown(_s) => {
+                                // Verify before shutting down, as that may already finalize the
+                                // upload
+                                if let Some(verifier) = verifier.take() {
+                                    if let Err(e) = server.finish_verification(
+                                        volume_id,
+                                        &target_name,
+                                        verifier,
+                                    ) {
+                                        reply.enqueue_fatal_error(e.into());
+                                        break;
+                                    }
+                                    if let (Some(insert), Some(image_cache)) =
+                                        (cache.take(), &server.inner.image_cache)
+                                    {
+                                        insert.commit(image_cache).await;
+                                    }
+                                }
+                                shut_down = true;
+                                let (completion, rx) = ShutdownCompletion::new();
+                                match &part {
+                                    Some(part) => {
+                                        // The part only succeeds once the shutdown did, so wait for it
+                                        io.shutdown(completion).await;
+                                        let result = rx.await.unwrap_or_else(|_| {
+                                            Err(tonic::Status::aborted("Shutdown not completed"))
+                                        });
+                                        part.finish(&result);
+                                        let (tx, rx) = oneshot::channel();
+                                        let _ = tx.send(result);
+                                        reply.enqueue_shutdown_reply(rx);
+                                    }
+                                    None => {
+                                        reply.enqueue_shutdown_reply(rx);
+                                        io.shutdown(completion).await;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                };
+                let cancelled = tokio::select! {
+                    () = handle => false,
+                    () = cancel.cancelled() => true,
+                };
+                if cancelled {
+                    reply.enqueue_fatal_error(tonic::Status::cancelled("Upload cancelled"));
+                }
+                let short = written > 0 && !shut_down;
+                if short && !cancelled {
+                    let expected = if length > 0 {
+                        format!(" of {length}")
+                    } else {
+                        String::new()
+                    };
+                    reply.enqueue_fatal_error(tonic::Status::aborted(format!(
+                        "Upload ended after {written}{expected} bytes without shutdown"
+                    )));
+                }
+                if short || cancelled {
+                    io.abort().await;
+                }
+                if let Some(verifier) = verifier {
+                    let _ = server.finish_verification(volume_id, &target_name, verifier);
+                }
+                if let Some(part) = part {
+                    part.end();
+                }
+            });
+
+            Ok(tonic::Response::new(reply_stream))
+        } else {
+            Err(tonic::Status::invalid_argument(
+                "Target should be set first",
+            ))
+        }
+    }
+
+    async fn volume_commit(
+        &self,
+        request: tonic::Request<VolumeRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let volume = self
+            .get_volume(request.volume)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            identity.as_ref(),
+        )?;
+        self.check_volume_mode(request.volume)?;
+        if self
+            .inner
+            .unverified_uploads
+            .lock()
+            .unwrap()
+            .contains(&request.volume)
+        {
+            return Err(tonic::Status::failed_precondition(
+                "Last upload to the volume failed verification",
+            ));
+        }
+        self.record(recording::Interaction::Volume {
+            volume: request.volume,
+            target: String::new(),
+            operation: "commit",
+            offset: 0,
+            length: 0,
+        });
+        volume.commit().await?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn volume_erase(
+        &self,
+        request: tonic::Request<VolumeEraseRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let volume = self
+            .get_volume(request.volume)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            identity.as_ref(),
+        )?;
+        self.check_item_session(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            request.session,
+            identity.as_ref(),
+        )?;
+        self.check_volume_mode(request.volume)?;
+        self.record(recording::Interaction::Volume {
+            volume: request.volume,
+            target: request.target.clone(),
+            operation: "erase",
+            offset: 0,
+            length: 0,
+        });
+        self.set_target_digest(request.volume, &request.target, None);
+        volume.erase(&request.target).await?;
+        Ok(tonic::Response::new(()))
+    }
+
+    type VolumeFetchStream =
+        ReceiverStream<Result<boardswarm_protocol::VolumeFetchProgress, tonic::Status>>;
+    async fn volume_fetch(
+        &self,
+        request: tonic::Request<boardswarm_protocol::VolumeFetchRequest>,
+    ) -> Result<tonic::Response<Self::VolumeFetchStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let volume = self
+            .get_volume(request.volume)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            identity.as_ref(),
+        )?;
+        self.check_item_session(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            request.session,
+            identity.as_ref(),
+        )?;
+        self.check_volume_mode(request.volume)?;
+
+        let layout = volume_fetch::layout(
+            self.inner.fetch.as_ref(),
+            request.format(),
+            request.bmap_url.as_deref(),
+        )
+        .await?;
+        let sha256 = request.sha256.as_deref();
+        let cached = match (&self.inner.image_cache, sha256) {
+            (Some(cache), Some(sha256)) => cache.open(sha256).await,
+            _ => None,
+        };
+        let source = match cached {
+            Some(cached) => volume_fetch::Source::Cached(cached),
+            None if request.url.is_empty() => {
+                return Err(volume_fetch::FetchError::NotCached.into())
+            }
+            None => volume_fetch::Source::Download(
+                volume_fetch::download(self.inner.fetch.as_ref(), &request.url).await?,
+            ),
+        };
+        let verifier = self.start_verification(request.volume, sha256)?;
+        self.set_target_digest(request.volume, &request.target, None);
+        // Downloaded images get added to the cache once verified
+        let cache = match (&source, &self.inner.image_cache, sha256) {
+            (volume_fetch::Source::Download(_), Some(cache), Some(sha256)) => {
+                cache.insert(sha256).await
+            }
+            _ => None,
+        };
+        // The size on the target is only known upfront for raw images
+        let length = match request.format() {
+            boardswarm_protocol::ImageFormat::Raw => source.length(),
+            boardswarm_protocol::ImageFormat::AndroidSparse => None,
+        };
+        let slot = match self.inner.volumes.lookup(request.volume) {
+            Some(item) => self.inner.upload_slots.acquire(&item.properties()).await,
+            None => None,
+        };
+        let (_info, io) = volume.open(&request.target, length).await?;
+        let part = request
+            .transaction
+            .map(|t| {
+                self.inner
+                    .transactions
+                    .join(t, request.volume, &request.target)
+            })
+            .transpose()?;
+        self.record(recording::Interaction::Volume {
+            volume: request.volume,
+            target: request.target.clone(),
+            operation: "fetch",
+            offset: 0,
+            length: source.length().unwrap_or_default(),
+        });
+
+        let (tx, rx) = mpsc::channel(8);
+        let guard = self.stream_guard(boardswarm_protocol::ItemType::Volume, request.volume);
+        let upload = volume_fetch::Upload {
+            checks: volume_fetch::Checks {
+                volume: request.volume,
+                target: request.target.clone(),
+                verifier,
+                cache,
+            },
+            part,
+            cancel: self.upload_token(request.volume),
+        };
+        let run = volume_fetch::run(self.clone(), upload, source, layout, io, tx);
+        tokio::spawn(async move {
+            let _guard = guard;
+            let _slot = slot;
+            run.await
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn volume_cancel(
+        &self,
+        request: tonic::Request<VolumeRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        self.get_volume(request.volume)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            identity.as_ref(),
+        )?;
+        self.record(recording::Interaction::Volume {
+            volume: request.volume,
+            target: String::new(),
+            operation: "cancel",
+            offset: 0,
+            length: 0,
+        });
+        self.cancel_uploads(request.volume);
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn volume_transaction_open(
+        &self,
+        request: tonic::Request<VolumeRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::VolumeTransaction>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        self.get_volume(request.volume)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            request.volume,
+            identity.as_ref(),
+        )?;
+        let id = self.inner.transactions.open(request.volume);
+        Ok(tonic::Response::new(
+            boardswarm_protocol::VolumeTransaction { id },
+        ))
+    }
+
+    async fn volume_transaction_commit(
+        &self,
+        request: tonic::Request<boardswarm_protocol::VolumeTransactionRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let volume_id = self.inner.transactions.volume(request.transaction)?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            volume_id,
+            identity.as_ref(),
+        )?;
+        let volume = self
+            .get_volume(volume_id)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+        self.check_volume_mode(volume_id)?;
+        self.inner.transactions.close(request.transaction)?;
+        if self
+            .inner
+            .unverified_uploads
+            .lock()
+            .unwrap()
+            .contains(&volume_id)
+        {
+            return Err(tonic::Status::failed_precondition(
+                "Last upload to the volume failed verification",
+            ));
+        }
+        self.record(recording::Interaction::Volume {
+            volume: volume_id,
+            target: String::new(),
+            operation: "commit",
+            offset: 0,
+            length: 0,
+        });
+        volume.commit().await?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn volume_transaction_abort(
+        &self,
+        request: tonic::Request<boardswarm_protocol::VolumeTransactionRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let volume = self.inner.transactions.volume(request.transaction)?;
+        self.check_item_access(
+            boardswarm_protocol::ItemType::Volume,
+            volume,
+            identity.as_ref(),
+        )?;
+        self.inner.transactions.abort(request.transaction)?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn image_cache_lookup(
+        &self,
+        request: tonic::Request<boardswarm_protocol::ImageCacheRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::ImageCacheReply>, tonic::Status> {
+        let request = request.into_inner();
+        let cached = match &self.inner.image_cache {
+            Some(cache) => cache.contains(&request.sha256).await,
+            None => false,
+        };
+        Ok(tonic::Response::new(boardswarm_protocol::ImageCacheReply {
+            cached,
+        }))
+    }
+
+    async fn volume_info(
+        &self,
+        request: tonic::Request<VolumeRequest>,
+    ) -> Result<tonic::Response<VolumeInfoMsg>, tonic::Status> {
+        let request = request.into_inner();
+        let volume = self
+            .get_volume(request.volume)
+            .ok_or_else(|| tonic::Status::not_found("Volume not found"))?;
+
+        let (target, exhaustive) = volume.targets();
+        let digests = self.inner.target_digests.lock().unwrap();
+        let target = target
+            .iter()
+            .map(|t| {
+                let mut t = t.clone();
+                if let Some(digest) = digests.get(&(request.volume, t.name.clone())) {
+                    t.sha256 = Some(Bytes::copy_from_slice(digest));
+                }
+                t
+            })
+            .collect();
+        drop(digests);
+
+        let info = VolumeInfoMsg {
+            target,
+            exhaustive,
+            mode: self.volume_mode(request.volume).map(|(_, mode)| mode),
+        };
+        Ok(tonic::Response::new(info))
+    }
+
+    type AudioStreamStream =
+        BoxStream<'static, Result<boardswarm_protocol::AudioStreamReply, tonic::Status>>;
+    async fn audio_stream(
+        &self,
+        request: tonic::Request<boardswarm_protocol::AudioStreamRequest>,
+    ) -> Result<tonic::Response<Self::AudioStreamStream>, tonic::Status> {
+        let request = request.into_inner();
+        let audio = self
+            .get_audio(request.audio)
+            .ok_or_else(|| tonic::Status::not_found("Audio not found"))?;
+        let (mut info, mut data) = audio.capture().await?;
+        match request.encoding() {
+            boardswarm_protocol::AudioEncoding::Pcm => (),
+            boardswarm_protocol::AudioEncoding::OggOpus => {
+                (info, data) = audio::encode_opus(info, data)?;
+            }
+        }
+
+        let guard = self.stream_guard(boardswarm_protocol::ItemType::Audio, request.audio);
+        let info = boardswarm_protocol::AudioStreamReply {
+            reply: Some(audio_stream_reply::Reply::Info(info)),
+        };
+        let stream = stream::once(future::ready(Ok(info)))
+            .chain(data.map(move |data| {
+                // Keep the guard alive for as long as the stream
+                let _guard = &guard;
+                Ok(boardswarm_protocol::AudioStreamReply {
+                    reply: Some(audio_stream_reply::Reply::Data(data?)),
+                })
+            }))
+            .boxed();
+        Ok(tonic::Response::new(stream))
+    }
+
+    async fn session_open(
+        &self,
+        request: tonic::Request<boardswarm_protocol::SessionOpenRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::Session>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let holder = identity.as_ref().and_then(|i| i.name.clone());
+        let request = request.into_inner();
+        if request.ttl == 0 {
+            return Err(tonic::Status::invalid_argument(
+                "Session ttl must be non-zero",
+            ));
+        }
+        if request.devices.is_empty() {
+            return Err(tonic::Status::invalid_argument("No devices to reserve"));
+        }
+        for &id in &request.devices {
+            let device = self
+                .inner
+                .devices
+                .lookup(id)
+                .ok_or_else(|| tonic::Status::not_found(format!("No device with id {}", id)))?;
+            Self::check_access(&device, identity.as_ref())?;
+        }
+
+        let id =
+            self.inner
+                .sessions
+                .open(request.devices, Duration::from_secs(request.ttl), holder)?;
+        info!("Opened session {}", id);
+        if request.record {
+            recording::record_session(self, id);
+        }
+        tokio::spawn(self.clone().expire_session(id));
+        self.save_sessions();
+        Ok(tonic::Response::new(self.session_info(id)?))
+    }
+
+    async fn session_renew(
+        &self,
+        request: tonic::Request<boardswarm_protocol::SessionRenewRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::Session>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        if request.ttl == 0 {
+            return Err(tonic::Status::invalid_argument(
+                "Session ttl must be non-zero",
+            ));
+        }
+        self.inner.sessions.renew(
+            request.session,
+            Duration::from_secs(request.ttl),
+            identity.as_ref().and_then(|i| i.name.as_deref()),
+        )?;
+        self.save_sessions();
+        Ok(tonic::Response::new(self.session_info(request.session)?))
+    }
+
+    async fn session_close(
+        &self,
+        request: tonic::Request<boardswarm_protocol::SessionRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        self.inner.sessions.held_by(
+            request.session,
+            identity.as_ref().and_then(|i| i.name.as_deref()),
+        )?;
+        self.end_session(request.session).await?;
+        info!("Closed session {}", request.session);
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn session_list(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<boardswarm_protocol::SessionListReply>, tonic::Status> {
+        let sessions = self
+            .inner
+            .sessions
+            .list()
+            .into_iter()
+            .map(|(id, session)| session.to_message(id))
+            .collect();
+        Ok(tonic::Response::new(
+            boardswarm_protocol::SessionListReply { sessions },
+        ))
+    }
+
+    async fn device_reservation(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceRequest>,
+    ) -> Result<tonic::Response<boardswarm_protocol::DeviceReservationReply>, tonic::Status> {
+        let request = request.into_inner();
+        if self.get_device(request.device).is_none() {
+            return Err(tonic::Status::not_found("No device by that id"));
+        }
+        let session = self
+            .inner
+            .sessions
+            .holding(request.device)
+            .map(|(id, session)| session.to_message(id));
+        Ok(tonic::Response::new(
+            boardswarm_protocol::DeviceReservationReply {
+                session,
+                queued: self.inner.sessions.queued(request.device),
+            },
+        ))
+    }
+
+    type SessionQueueStream =
+        ReceiverStream<Result<boardswarm_protocol::SessionQueueUpdate, tonic::Status>>;
+    async fn session_queue(
+        &self,
+        request: tonic::Request<boardswarm_protocol::SessionQueueRequest>,
+    ) -> Result<tonic::Response<Self::SessionQueueStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let holder = identity.as_ref().and_then(|i| i.name.clone());
+        let request = request.into_inner();
+        if request.ttl == 0 {
+            return Err(tonic::Status::invalid_argument(
+                "Session ttl must be non-zero",
+            ));
+        }
+        if request.devices.is_empty() {
+            return Err(tonic::Status::invalid_argument("No devices to reserve"));
+        }
+        let mut devices = Vec::new();
+        for &id in &request.devices {
+            let device = self
+                .inner
+                .devices
+                .lookup(id)
+                .ok_or_else(|| tonic::Status::not_found(format!("No device with id {}", id)))?;
+            Self::check_access(&device, identity.as_ref())?;
+            devices.push((id, device.inner().queue_order()));
+        }
+
+        // Callers can't jump the queue beyond what their authentication allows
+        let priority = match &identity {
+            Some(identity) => request.priority.min(identity.max_priority),
+            None => request.priority,
+        };
+        let record = request.record;
+        let (ticket, mut state) = self.inner.sessions.enqueue(
+            devices,
+            priority,
+            Duration::from_secs(request.ttl),
+            holder,
+        );
+        let (tx, rx) = mpsc::channel(1);
+        let server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let current = *state.borrow_and_update();
+                match current {
+                    session::QueueState::Waiting(position) => {
+                        let update = boardswarm_protocol::SessionQueueUpdate {
+                            update: Some(
+                                boardswarm_protocol::session_queue_update::Update::Position(
+                                    position,
+                                ),
+                            ),
+                        };
+                        let _ = tx.send(Ok(update)).await;
+                    }
+                    session::QueueState::Granted(id) => {
+                        info!("Opened session {} for queued caller", id);
+                        if record {
+                            recording::record_session(&server, id);
+                        }
+                        tokio::spawn(server.clone().expire_session(id));
+                        server.save_sessions();
+                        let update = server.session_info(id).map(|session| {
+                            boardswarm_protocol::SessionQueueUpdate {
+                                update: Some(
+                                    boardswarm_protocol::session_queue_update::Update::Session(
+                                        session,
+                                    ),
+                                ),
+                            }
+                        });
+                        // Nobody is left to use the session if the caller went away meanwhile
+                        if tx.send(update).await.is_err() {
+                            let _ = server.end_session(id).await;
+                        }
+                        return;
+                    }
+                }
+                tokio::select! {
+                    changed = state.changed() => {
+                        // The queue lets go of callers once they got their devices
+                        if changed.is_err()
+                            && !matches!(*state.borrow(), session::QueueState::Granted(_))
+                        {
+                            return;
+                        }
+                    }
+                    _ = tx.closed() => {
+                        server.inner.sessions.dequeue(ticket);
+                        // Don't hold on to the state while ending the session
+                        let granted = match *state.borrow() {
+                            session::QueueState::Granted(id) => Some(id),
+                            _ => None,
+                        };
+                        if let Some(id) = granted {
+                            let _ = server.end_session(id).await;
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    type SessionRecordingStream =
+        BoxStream<'static, Result<boardswarm_protocol::SessionRecordEvent, tonic::Status>>;
+    async fn session_recording(
+        &self,
+        request: tonic::Request<boardswarm_protocol::SessionRequest>,
+    ) -> Result<tonic::Response<Self::SessionRecordingStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let (holder, devices) = self
+            .inner
+            .recordings
+            .owner(request.session)
+            .ok_or_else(|| tonic::Status::not_found("No recording for that session"))?;
+        // Recordings contain console input, so are only available to the holder of the session or
+        // callers allowed to use all of its devices
+        if holder.as_deref() != identity.as_ref().and_then(|i| i.name.as_deref()) {
+            for id in devices {
+                let device = self.inner.devices.lookup(id).ok_or_else(|| {
+                    tonic::Status::permission_denied("Recorded device is no longer there")
+                })?;
+                Self::check_access(&device, identity.as_ref())?;
+            }
+        }
+        let events = self
+            .inner
+            .recordings
+            .get(request.session)
+            .ok_or_else(|| tonic::Status::not_found("No recording for that session"))?;
+        Ok(tonic::Response::new(
+            stream::iter(events.into_iter().map(Ok)).boxed(),
+        ))
+    }
+
+    async fn group_list(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<boardswarm_protocol::GroupListReply>, tonic::Status> {
+        Ok(tonic::Response::new(boardswarm_protocol::GroupListReply {
+            groups: group::groups(self),
+        }))
+    }
+
+    type GroupChangeModeStream = group::GroupStream;
+    async fn group_change_mode(
+        &self,
+        request: tonic::Request<boardswarm_protocol::GroupModeRequest>,
+    ) -> Result<tonic::Response<Self::GroupChangeModeStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let progress = group::run(
+            self,
+            &request.group,
+            |device| {
+                let server = self.clone();
+                let mode = group::request(
+                    boardswarm_protocol::DeviceModeRequest {
+                        device,
+                        mode: request.mode.clone(),
+                        session: request.session,
+                        no_wait: request.no_wait,
+                    },
+                    identity.clone(),
+                );
+                async move {
+                    let progress =
+                        boardswarm_protocol::boardswarm_server::Boardswarm::device_change_mode(
+                            &server, mode,
+                        )
+                        .await?;
+                    Ok(progress.into_inner())
+                }
+            },
+            boardswarm_protocol::group_progress::Progress::Mode,
+        )
+        .await?;
+        Ok(tonic::Response::new(progress))
+    }
+
+    type GroupVolumeFetchStream = group::GroupStream;
+    async fn group_volume_fetch(
+        &self,
+        request: tonic::Request<boardswarm_protocol::GroupVolumeFetchRequest>,
+    ) -> Result<tonic::Response<Self::GroupVolumeFetchStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let progress = group::run(
+            self,
+            &request.group,
+            |device| {
+                let server = self.clone();
+                let request = request.clone();
+                let identity = identity.clone();
+                async move {
+                    let volume = server
+                        .get_device(device)
+                        .and_then(|d| {
+                            d.volumes()
+                                .into_iter()
+                                .find(|v| v.name == request.volume && v.id.is_some())
+                        })
+                        .and_then(|v| v.id)
+                        .ok_or_else(|| tonic::Status::not_found("Volume not available"))?;
+                    let commit = request.commit;
+                    let fetch = group::request(
+                        boardswarm_protocol::VolumeFetchRequest {
+                            volume,
+                            target: request.target,
+                            url: request.url,
+                            sha256: request.sha256,
+                            format: request.format,
+                            bmap_url: request.bmap_url,
+                            transaction: None,
+                            session: request.session,
+                        },
+                        identity.clone(),
+                    );
+                    let mut progress =
+                        boardswarm_protocol::boardswarm_server::Boardswarm::volume_fetch(
+                            &server, fetch,
+                        )
+                        .await?
+                        .into_inner();
+
+                    // Commit once the image got written, as part of the operation on the device
+                    let (tx, rx) = mpsc::channel(8);
+                    tokio::spawn(async move {
+                        while let Some(p) = progress.next().await {
+                            let failed = p.is_err();
+                            let _ = tx.send(p).await;
+                            if failed {
+                                return;
+                            }
+                        }
+                        if commit {
+                            let request = group::request(VolumeRequest { volume }, identity);
+                            if let Err(e) =
+                                boardswarm_protocol::boardswarm_server::Boardswarm::volume_commit(
+                                    &server, request,
+                                )
+                                .await
+                            {
+                                let _ = tx.send(Err(e)).await;
+                            }
+                        }
+                    });
+                    Ok(ReceiverStream::new(rx))
+                }
+            },
+            boardswarm_protocol::group_progress::Progress::Fetch,
+        )
+        .await?;
+        Ok(tonic::Response::new(progress))
+    }
+
+    async fn task_list(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<boardswarm_protocol::TaskListReply>, tonic::Status> {
+        Ok(tonic::Response::new(boardswarm_protocol::TaskListReply {
+            tasks: self.inner.tasks.iter().map(|t| (&**t).into()).collect(),
+        }))
+    }
+
+    type DeviceRunTaskStream = task::TaskStream;
+    async fn device_run_task(
+        &self,
+        request: tonic::Request<boardswarm_protocol::DeviceTaskRequest>,
+    ) -> Result<tonic::Response<Self::DeviceRunTaskStream>, tonic::Status> {
+        let identity = request.extensions().get::<auth::Identity>().cloned();
+        let request = request.into_inner();
+        let task = self
+            .inner
+            .tasks
+            .iter()
+            .find(|t| t.name == request.task)
+            .cloned()
+            .ok_or_else(|| tonic::Status::not_found("No task by that name"))?;
+        let item = self
+            .inner
+            .devices
+            .lookup(request.device)
+            .ok_or_else(|| tonic::Status::not_found("No device by that id"))?;
+        // Checked upfront as well as by each step, such that the task doesn't fail halfway
+        Self::check_access(&item, identity.as_ref())?;
+        self.inner.sessions.check(
+            request.device,
+            request.session,
+            identity.as_ref().and_then(|i| i.name.as_deref()),
+        )?;
+        Ok(tonic::Response::new(task::run(
+            self.clone(),
+            request.device,
+            task,
+            request.session,
+            identity,
+        )))
+    }
+}
+
+/// Tls configuration only accepting clients presenting a certificate signed by `client_ca`
+fn mutual_tls_config(
+    chain: &Path,
+    key: &Path,
+    client_ca: &Path,
+) -> anyhow::Result<rustls::ServerConfig> {
+    fn open(path: &Path) -> anyhow::Result<std::io::BufReader<std::fs::File>> {
+        let f = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(std::io::BufReader::new(f))
+    }
+
+    let chain = rustls_pemfile::certs(&mut open(chain)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates from {}", chain.display()))?;
+    let key = rustls_pemfile::private_key(&mut open(key)?)
+        .with_context(|| format!("Failed to parse private key from {}", key.display()))?
+        .with_context(|| format!("No private key found in {}", key.display()))?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    for ca in rustls_pemfile::certs(&mut open(client_ca)?) {
+        let ca = ca.with_context(|| {
+            format!("Failed to parse certificates from {}", client_ca.display())
+        })?;
+        roots
+            .add(ca)
+            .with_context(|| format!("Invalid client CA certificate in {}", client_ca.display()))?;
+    }
+    if roots.is_empty() {
+        bail!("No client CA certificates found in {}", client_ca.display());
+    }
+
+    let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+    let mut config = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(chain, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Builder to set up and run a boardswarm server from its configuration file
+pub struct ServerBuilder {
+    config: config::Config,
+    /// Relative paths in the configuration are relative to the configuration file
+    config_path: PathBuf,
+    listen: Vec<SocketAddr>,
+    listeners: Vec<std::net::TcpListener>,
+    interface: Option<String>,
+    /// Registration of items by the embedding application
+    registrations: Vec<Box<dyn FnOnce(Server)>>,
+}
+
+impl ServerBuilder {
+    pub fn from_config_file<P: Into<PathBuf>>(path: P) -> anyhow::Result<Self> {
+        let config_path = path.into();
+        let config = config::Config::from_file(&config_path).context(format!(
+            "Failed to load configuration file {}",
+            config_path.display()
+        ))?;
+        Ok(Self {
+            config,
+            config_path,
+            listen: Vec::new(),
+            listeners: Vec::new(),
+            interface: None,
+            registrations: Vec::new(),
+        })
+    }
+
+    /// Listen on the given address rather than the configured addresses; Can be called multiple
+    /// times
+    pub fn listen(&mut self, addr: SocketAddr) {
+        self.listen.push(addr);
+    }
+
+    /// Serve on an already bound listener, e.g. bound to port 0 in tests; Like with
+    /// [ServerBuilder::listen] the configured addresses are not used then. The listener has to be
+    /// non-blocking
+    pub fn listener(&mut self, listener: std::net::TcpListener) {
+        self.listeners.push(listener);
+    }
+
+    /// Only accept connections on the given network interface
+    pub fn interface<S: Into<String>>(&mut self, interface: S) {
+        self.interface = Some(interface.into());
+    }
+
+    /// Register items of the embedding application, e.g. from a custom provider; `f` is called
+    /// with the [Server], which implements [Registrar], once the configured providers got
+    /// started. It's called on the local task set of the server, so it can spawn local tasks
+    pub fn register<F>(&mut self, f: F)
+    where
+        F: FnOnce(Server) + 'static,
+    {
+        self.registrations.push(Box::new(f));
+    }
+
+    /// Start all configured providers and serve the API until serving fails
+    ///
+    /// Some providers run on the local task set of the server, so the returned future has to be
+    /// driven by the runtime thread itself, e.g. from `#[tokio::main]`
+    pub async fn run(self) -> anyhow::Result<()> {
+        let config = self.config;
+        if let Some(name) = &config.server.name {
+            info!("Starting boardswarm server {}", name);
+        }
+        let listen_addrs = if !self.listen.is_empty() || !self.listeners.is_empty() {
+            self.listen
+        } else if config.server.listen.is_empty() && config.server.unix.is_some() {
+            Vec::new()
+        } else if !config.server.listen.is_empty() {
+            let mut addrs = Vec::new();
+            for l in &config.server.listen {
+                addrs.extend(
+                    listen::resolve_listen_address(l)
+                        .context(format!("Failed to resolve listen address {}", l))?,
+                );
+            }
+            addrs
+        } else {
+            vec![SocketAddr::new(
+                "::1".parse().unwrap(),
+                boardswarm_protocol::DEFAULT_PORT,
+            )]
+        };
+
+        let interface = self.interface.or(config.server.interface.clone());
+        let mut listeners = self.listeners;
+        listeners.extend(listen::bind_all(&listen_addrs, interface.as_deref())?);
+
+        let authentication: Vec<_> = config
+            .server
+            .authentication
+            .iter()
+            .map(|a| {
+                if let config::Authentication::Jwks {
+                    path,
+                    role,
+                    max_priority,
+                } = a
+                {
+                    config::Authentication::Jwks {
+                        path: self.config_path.with_file_name(path),
+                        role: *role,
+                        max_priority: *max_priority,
+                    }
+                } else {
+                    a.clone()
+                }
+            })
+            .collect();
+
+        if authentication.is_empty() {
+            bail!("No authentication methods found in configuration");
+        }
+
+        let unix = match &config.server.unix {
+            Some(unix) => {
+                let path = self.config_path.with_file_name(&unix.path);
+                let listener = listen::bind_unix(&path, unix.mode)
+                    .context(format!("Failed to listen on {}", path.display()))?;
+                Some((path, listener, unix.role))
+            }
+            None => None,
+        };
+
+        for mut t in config.server.tunnels.iter().cloned() {
+            t.identity = t.identity.map(|i| self.config_path.with_file_name(i));
+            let Some(listener) = listeners.first() else {
+                bail!("Tunnels require a TCP listen address");
+            };
+            tokio::spawn(tunnel::run(t, listener.local_addr()?));
+        }
+
+        let stabilisation = Stabilisation::from_config(&config.server, &config.providers);
+        let upload_slots =
+            upload_slots::UploadSlots::from_config(&config.server, &config.providers);
+        let server = Server::new(
+            authentication,
+            self.config_path
+                .parent()
+                .unwrap_or_else(|| Path::new("."))
+                .to_path_buf(),
+            &config.server,
+            stabilisation,
+            upload_slots,
+            config.overrides,
+            config.tasks,
+        );
+        for d in config.devices {
+            let device = crate::config_device::Device::from_config(d, server.clone());
+            let mut properties = Properties::new(device.name());
+            for tag in device.tags() {
+                properties.insert(format!("{}{}", registry::TAG, tag), "");
+            }
+            properties.extend(device.labels());
+            let id = server.register_device(properties, device.clone());
+            if let Some(interval) = device.health_interval() {
+                tokio::spawn(config_device::health_monitor(device.clone(), id, interval));
+            }
+            if device.has_recovery() {
+                tokio::spawn(config_device::recovery_monitor(device.clone(), id));
+            }
+            if device.has_power_meter() {
+                tokio::spawn(config_device::power_monitor(device.clone()));
+            }
+        }
+        server.restore_sessions();
+        if let Some(state) = server.inner.state.clone() {
+            tokio::spawn(async move { state.run().await });
+        }
+        tokio::spawn(config_device::monitor_devices(server.clone()));
+
+        let local = tokio::task::LocalSet::new();
+        let serial = config
+            .providers
+            .iter()
+            .find(|p| p.name == serial::PROVIDER)
+            .map(|p| serial::SerialDevices::new(&p.name, server.clone()));
+        for p in config.providers {
+            match p.provider.as_str() {
+                dfu::PROVIDER => {
+                    local.spawn_local(dfu::start_provider(p.name, server.clone()));
+                }
+                imx_sdp::PROVIDER => {
+                    local.spawn_local(imx_sdp::start_provider(p.name, server.clone()));
+                }
+                sunxi_fel::PROVIDER => {
+                    local.spawn_local(sunxi_fel::start_provider(p.name, server.clone()));
+                }
+                mediatek_brom::PROVIDER => match serial {
+                    Some(ref s) => {
+                        s.add_provider(MediatekBromProvider::new(p.name, server.clone()))
+                    }
+                    None => {
+                        bail!("Mediatek brom provider requires the serial provider to be enabled")
+                    }
+                },
+                rockusb::PROVIDER => {
+                    local.spawn_local(rockusb::start_provider(p.name, server.clone()));
+                }
+                serial::PROVIDER => {
+                    // Precreated already
+                }
+                fastboot::PROVIDER => {
+                    local.spawn_local(fastboot::start_provider(
+                        p.name,
+                        p.parameters,
+                        server.clone(),
+                    ));
+                }
+                gpio::PROVIDER => {
+                    local.spawn_local(gpio::start_provider(
+                        p.name,
+                        p.parameters.context("Missing gpio provider parameters")?,
+                        server.clone(),
+                    ));
+                }
+                adb::PROVIDER => {
+                    local.spawn_local(adb::start_provider(p.name, p.parameters, server.clone()));
+                }
+                alsa::PROVIDER => {
+                    local.spawn_local(alsa::start_provider(p.name, p.parameters, server.clone()));
+                }
+                hidrelay::PROVIDER => {
+                    local.spawn_local(hidrelay::start_provider(
+                        p.name,
+                        p.parameters,
+                        server.clone(),
+                    ));
+                }
+                ykush::PROVIDER => {
+                    local.spawn_local(ykush::start_provider(
+                        p.name,
+                        p.parameters.context("Missing ykush provider parameters")?,
+                        server.clone(),
+                    ));
+                }
+                usbhub::PROVIDER => {
+                    local.spawn_local(usbhub::start_provider(
+                        p.name,
+                        p.parameters.context("Missing usbhub provider parameters")?,
+                        server.clone(),
+                    ));
+                }
+                ipmi::PROVIDER => ipmi::start_provider(
+                    p.name,
+                    p.parameters.context("Missing ipmi provider parameters")?,
+                    server.clone(),
+                ),
+                docker::PROVIDER => docker::start_provider(
+                    p.name,
+                    p.parameters.context("Missing docker provider parameters")?,
+                    server.clone(),
+                ),
+                qemu::PROVIDER => qemu::start_provider(
+                    p.name,
+                    p.parameters.context("Missing qemu provider parameters")?,
+                    server.clone(),
+                ),
+                labgrid::PROVIDER => labgrid::start_provider(
+                    p.name,
+                    p.parameters
+                        .context("Missing labgrid-resources provider parameters")?,
+                    server.clone(),
+                ),
+                tcpconsole::PROVIDER => tcpconsole::start_provider(
+                    p.name,
+                    p.parameters
+                        .context("Missing tcpconsole provider parameters")?,
+                    server.clone(),
+                ),
+                snmp::PROVIDER => snmp::start_provider(
+                    p.name,
+                    p.parameters.context("Missing snmp provider parameters")?,
+                    server.clone(),
+                ),
+                redfish::PROVIDER => redfish::start_provider(
+                    p.name,
+                    p.parameters
+                        .context("Missing redfish provider parameters")?,
+                    server.clone(),
+                ),
+                mqtt::PROVIDER => mqtt::start_provider(
+                    p.name,
+                    p.parameters.context("Missing mqtt provider parameters")?,
+                    server.clone(),
+                ),
+                sdmux::PROVIDER => sdmux::start_provider(
+                    p.name,
+                    p.parameters.context("Missing sdmux provider parameters")?,
+                    server.clone(),
+                ),
+                pdudaemon::PROVIDER => pdudaemon::start_provider(
+                    p.name,
+                    p.parameters
+                        .context("Missing pdudaemon provider parameters")?,
+                    server.clone(),
+                ),
+                transform::PROVIDER => {
+                    local.spawn_local(transform::start_provider(
+                        p.name,
+                        p.parameters
+                            .context("Missing transform provider parameters")?,
+                        server.clone(),
+                    ));
+                }
+                external::PROVIDER => {
+                    local.spawn_local(external::start_provider(
+                        p.name,
+                        p.parameters
+                            .context("Missing external provider parameters")?,
+                        server.clone(),
+                    ));
+                }
+                remote::PROVIDER => remote::start_provider(
+                    p.name,
+                    p.parameters
+                        .context("Missing boardswarm provider parameters")?,
+                    server.clone(),
+                ),
+                t => warn!("Unknown provider: {t}"),
+            }
+        }
+        if let Some(serial) = serial {
+            local.spawn_local(serial.start());
+        }
+        for f in self.registrations {
+            let server = server.clone();
+            local.spawn_local(async move { f(server) });
+        }
+
+        let boardswarm = tonic::service::Routes::new(
+            boardswarm_protocol::boardswarm_server::BoardswarmServer::new(server.clone()),
+        );
+
+        let auth = Arc::new(auth::Auth::from_config(&server.inner.auth_info).await?);
+        let router = boardswarm
+            .into_axum_router()
+            .layer(axum::middleware::from_fn_with_state(
+                auth,
+                auth::authenticate,
+            ))
+            .route_service(
+                &format!("/{}/LoginInfo",
+              <boardswarm_protocol::boardswarm_server::BoardswarmServer<Server>
+              as tonic::server::NamedService>::NAME),
+                boardswarm_protocol::boardswarm_server::BoardswarmServer::new(server.clone()),
+            );
+
+        let tls_config = if let Some(cert) = config.server.certificate {
+            if let Some(client_ca) = cert.client_ca {
+                Some(axum_server::tls_rustls::RustlsConfig::from_config(
+                    Arc::new(mutual_tls_config(&cert.chain, &cert.key, &client_ca)?),
+                ))
+            } else {
+                Some(
+                    axum_server::tls_rustls::RustlsConfig::from_pem_file(cert.chain, cert.key)
+                        .await?,
+                )
+            }
+        } else {
+            None
+        };
+
+        let mut servers = Vec::new();
+        for listener in listeners {
+            info!("Server listening on {}", listener.local_addr()?);
+            let service = router.clone().into_make_service();
+            let s = match tls_config {
+                Some(ref tls_config) => axum_server::from_tcp_rustls(listener, tls_config.clone())
+                    .serve(service)
+                    .boxed(),
+                None => axum_server::from_tcp(listener).serve(service).boxed(),
+            };
+            servers.push(s);
+        }
+        if let Some((path, listener, role)) = unix {
+            info!("Server listening on {}", path.display());
+            let router = match role {
+                Some(role) => router.layer(axum::Extension(auth::LocalRole(role))),
+                None => router,
+            };
+            servers.push(listen::serve_unix(listener, router).boxed());
+        }
+        tokio::join!(local, future::try_join_all(servers)).1?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use boardswarm_protocol::boardswarm_client::BoardswarmClient;
+
+    /// Device only `alice` and `carol` are allowed to use; Takes any console attached to it
+    struct TestDevice {
+        consoles: Mutex<Vec<DeviceConsole>>,
+        actuators: Vec<u64>,
+        updates: broadcast::Sender<()>,
+    }
+
+    impl TestDevice {
+        fn new(actuators: &[u64]) -> Self {
+            Self {
+                consoles: Mutex::default(),
+                actuators: actuators.to_vec(),
+                updates: broadcast::channel(1).0,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestActuator();
+
+    #[async_trait::async_trait]
+    impl Actuator for TestActuator {
+        async fn set_mode(
+            &self,
+            _parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+        ) -> Result<(), ActuatorError> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestVolume();
+
+    #[async_trait::async_trait]
+    impl Volume for TestVolume {
+        fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+            (&[], true)
+        }
+        async fn open(
+            &self,
+            _target: &str,
+            _length: Option<u64>,
+        ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+            Err(VolumeError::UnknownTargetRequested)
+        }
+        async fn commit(&self) -> Result<(), VolumeError> {
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Device for TestDevice {
+        async fn set_mode(&self, _mode: &str) -> Result<(), DeviceSetModeError> {
+            Ok(())
+        }
+        fn queue_order(&self) -> session::QueueOrder {
+            session::QueueOrder::Priority
+        }
+        fn updates(&self) -> DeviceMonitor {
+            DeviceMonitor {
+                receiver: self.updates.subscribe(),
+            }
+        }
+        fn consoles(&self) -> Vec<DeviceConsole> {
+            self.consoles
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|c| DeviceConsole {
+                    name: c.name.clone(),
+                    id: c.id,
+                })
+                .collect()
+        }
+        fn volumes(&self) -> Vec<DeviceVolume> {
+            Vec::new()
+        }
+        fn modes(&self) -> Vec<DeviceMode> {
+            Vec::new()
+        }
+        fn actuators(&self) -> Vec<DeviceActuator> {
+            self.actuators
+                .iter()
+                .map(|&id| DeviceActuator {
+                    mode: "on".to_string(),
+                    id,
+                })
+                .collect()
+        }
+        fn current_mode(&self) -> Option<String> {
+            None
+        }
+        fn access(&self) -> Option<Vec<String>> {
+            Some(vec!["alice".to_string(), "carol".to_string()])
+        }
+        async fn attach_console(&self, name: String, id: u64) -> bool {
+            self.consoles
+                .lock()
+                .unwrap()
+                .push(DeviceConsole { name, id: Some(id) });
+            let _ = self.updates.send(());
+            true
+        }
+        async fn detach_console(&self, id: u64) {
+            self.consoles.lock().unwrap().retain(|c| c.id != Some(id));
+            let _ = self.updates.send(());
+        }
+    }
+
+    pub(crate) fn test_server() -> Server {
+        let config = config::Server::default();
+        Server::new(
+            Vec::new(),
+            PathBuf::new(),
+            &config,
+            Stabilisation::default(),
+            upload_slots::UploadSlots::from_config(&config, &[]),
+            Vec::new(),
+            Vec::new(),
+        )
+    }
+
+    fn identity(name: &str) -> auth::Identity {
+        auth::Identity {
+            name: Some(name.to_string()),
+            role: config::Role::Operator,
+            max_priority: 0,
+        }
+    }
+
+    /// Serve on a local port with all requests made as `name`
+    async fn connect(server: &Server, name: &str) -> BoardswarmClient<tonic::transport::Channel> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = tonic::service::Routes::new(
+            boardswarm_protocol::boardswarm_server::BoardswarmServer::new(server.clone()),
+        )
+        .into_axum_router()
+        .layer(axum::Extension(identity(name)));
+        tokio::spawn(axum_server::from_tcp(listener).serve(router.into_make_service()));
+        BoardswarmClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap()
+    }
+
+    fn register(
+        name: &str,
+        device: u64,
+    ) -> (
+        mpsc::Sender<ConsoleRegisterRequest>,
+        ReceiverStream<ConsoleRegisterRequest>,
+    ) {
+        let (tx, rx) = mpsc::channel(4);
+        tx.try_send(ConsoleRegisterRequest {
+            register_or_data: Some(console_register_request::RegisterOrData::Register(
+                boardswarm_protocol::ConsoleRegister {
+                    name: name.to_string(),
+                    device: Some(device),
+                },
+            )),
+        })
+        .unwrap();
+        (tx, ReceiverStream::new(rx))
+    }
+
+    #[tokio::test]
+    async fn console_register() {
+        let server = test_server();
+        let device_id = server.register_device(Properties::new("test"), TestDevice::new(&[]));
+        let device = server.get_device(device_id).unwrap();
+
+        let mut bob = connect(&server, "bob").await;
+        let (_tx, requests) = register("bob", device_id);
+        let status = bob.console_register(requests).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert!(server.inner.consoles.contents().is_empty());
+        assert!(device.consoles().is_empty());
+
+        let mut alice = connect(&server, "alice").await;
+        let (tx, requests) = register("alice", device_id);
+        let mut replies = alice.console_register(requests).await.unwrap().into_inner();
+        let console = replies.message().await.unwrap().unwrap().console;
+        assert!(server.get_console(console).is_some());
+        assert_eq!(device.consoles()[0].id, Some(console));
+
+        let mut output = alice
+            .console_stream_output(boardswarm_protocol::ConsoleOutputRequest {
+                console,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        tx.send(ConsoleRegisterRequest {
+            register_or_data: Some(console_register_request::RegisterOrData::Data(
+                Bytes::from_static(b"hello"),
+            )),
+        })
+        .await
+        .unwrap();
+        assert_eq!(output.message().await.unwrap().unwrap().data, "hello");
+
+        // The console goes away along with the client
+        let mut monitor = device.updates();
+        drop(tx);
+        while !device.consoles().is_empty() {
+            monitor.wait().await.unwrap();
+        }
+        while server.get_console(console).is_some() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn denied<T>(result: Result<T, tonic::Status>) -> bool {
+        matches!(result, Err(s) if s.code() == tonic::Code::PermissionDenied)
+    }
+
+    #[tokio::test]
+    async fn access() {
+        let server = test_server();
+        let actuator = server.register_actuator(Properties::new("power"), TestActuator());
+        let device = server.register_device(Properties::new("test"), TestDevice::new(&[actuator]));
+        let console = server.register_console(
+            Properties::new("serial"),
+            client_console::ClientConsole::new(),
+        );
+        let d = server.get_device(device).unwrap();
+        assert!(d.attach_console("serial".to_string(), console).await);
+
+        let mut bob = connect(&server, "bob").await;
+        assert!(denied(
+            bob.actuator_change_mode(boardswarm_protocol::ActuatorModeRequest {
+                actuator,
+                parameters: None,
+            })
+            .await
+        ));
+        assert!(denied(
+            bob.console_configure(ConsoleConfigureRequest {
+                console,
+                parameters: None,
+            })
+            .await
+        ));
+        assert!(denied(
+            bob.session_open(boardswarm_protocol::SessionOpenRequest {
+                devices: vec![device],
+                ttl: 60,
+                record: false,
+            })
+            .await
+        ));
+        assert!(denied(
+            bob.session_queue(boardswarm_protocol::SessionQueueRequest {
+                devices: vec![device],
+                ttl: 60,
+                priority: 0,
+                record: false,
+            })
+            .await
+        ));
+        for (type_, item) in [
+            (boardswarm_protocol::ItemType::Device, device),
+            (boardswarm_protocol::ItemType::Console, console),
+            (boardswarm_protocol::ItemType::Actuator, actuator),
+        ] {
+            assert!(denied(
+                bob.item_remove(ItemRemoveRequest {
+                    r#type: type_.into(),
+                    item,
+                    force: false,
+                })
+                .await
+            ));
+        }
+        let (_tx, requests) = register("bob", device);
+        assert!(denied(bob.console_register(requests).await));
+        let request = boardswarm_protocol::DeviceRequest { device };
+        assert!(denied(bob.device_self_test(request).await));
+        assert!(denied(bob.device_health_check(request).await));
+        assert!(server.get_device(device).is_some());
+        assert!(server.get_console(console).is_some());
+        assert!(server.get_actuator(actuator).is_some());
+        assert!(server.inner.sessions.holding(device).is_none());
+
+        let mut alice = connect(&server, "alice").await;
+        alice
+            .actuator_change_mode(boardswarm_protocol::ActuatorModeRequest {
+                actuator,
+                parameters: Some(Default::default()),
+            })
+            .await
+            .unwrap();
+        alice
+            .session_open(boardswarm_protocol::SessionOpenRequest {
+                devices: vec![device],
+                ttl: 60,
+                record: false,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn session_holder() {
+        let server = test_server();
+        let device = server.register_device(Properties::new("test"), TestDevice::new(&[]));
+        let console = server.register_console(
+            Properties::new("serial"),
+            client_console::ClientConsole::new(),
+        );
+        let d = server.get_device(device).unwrap();
+        assert!(d.attach_console("serial".to_string(), console).await);
+
+        let mut alice = connect(&server, "alice").await;
+        let session = alice
+            .session_open(boardswarm_protocol::SessionOpenRequest {
+                devices: vec![device],
+                ttl: 60,
+                record: false,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .id;
+
+        // Carol may use the device, but not through the session of alice
+        let mut carol = connect(&server, "carol").await;
+        assert!(denied(
+            carol
+                .device_change_mode(boardswarm_protocol::DeviceModeRequest {
+                    device,
+                    mode: "on".to_string(),
+                    session: Some(session),
+                    no_wait: false,
+                })
+                .await
+        ));
+        let input = ConsoleInputRequest {
+            target_or_data: Some(console_input_request::TargetOrData::Console(console)),
+            session: Some(session),
+        };
+        assert!(denied(
+            carol.console_stream_input(stream::iter([input])).await
+        ));
+        assert!(denied(
+            carol
+                .console_open(boardswarm_protocol::ConsoleOpenRequest {
+                    console,
+                    session: Some(session),
+                })
+                .await
+        ));
+        assert!(denied(
+            carol
+                .session_renew(boardswarm_protocol::SessionRenewRequest { session, ttl: 60 })
+                .await
+        ));
+        assert!(denied(
+            carol
+                .session_close(boardswarm_protocol::SessionRequest { session })
+                .await
+        ));
+        assert!(server.inner.sessions.get(session).is_some());
+
+        alice
+            .session_renew(boardswarm_protocol::SessionRenewRequest { session, ttl: 60 })
+            .await
+            .unwrap();
+        alice
+            .session_close(boardswarm_protocol::SessionRequest { session })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn session_queue_priority() {
+        let server = test_server();
+        let device = server.register_device(Properties::new("test"), TestDevice::new(&[]));
+        let mut alice = connect(&server, "alice").await;
+        alice
+            .session_open(boardswarm_protocol::SessionOpenRequest {
+                devices: vec![device],
+                ttl: 60,
+                record: false,
+            })
+            .await
+            .unwrap();
+
+        let queue = |priority| boardswarm_protocol::SessionQueueRequest {
+            devices: vec![device],
+            priority,
+            ttl: 60,
+            record: false,
+        };
+        let position = |update: boardswarm_protocol::SessionQueueUpdate| match update.update {
+            Some(boardswarm_protocol::session_queue_update::Update::Position(position)) => position,
+            update => panic!("Unexpected queue update: {:?}", update),
+        };
+        let mut waiting = alice.session_queue(queue(0)).await.unwrap().into_inner();
+        assert_eq!(position(waiting.message().await.unwrap().unwrap()), 0);
+
+        // Carol's token doesn't allow raising the priority, so she can't jump the queue
+        let mut carol = connect(&server, "carol").await;
+        let mut jumping = carol
+            .session_queue(queue(i32::MAX))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(position(jumping.message().await.unwrap().unwrap()), 1);
+    }
+
+    #[tokio::test]
+    async fn console_handle_owner() {
+        let server = test_server();
+        let device = server.register_device(Properties::new("test"), TestDevice::new(&[]));
+        let console = server.register_console(
+            Properties::new("serial"),
+            client_console::ClientConsole::new(),
+        );
+        let d = server.get_device(device).unwrap();
+        assert!(d.attach_console("serial".to_string(), console).await);
+
+        let mut alice = connect(&server, "alice").await;
+        let handle = alice
+            .console_open(boardswarm_protocol::ConsoleOpenRequest {
+                console,
+                session: None,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .handle;
+
+        // Knowing the id of the handle isn't enough to use or close it
+        let mut carol = connect(&server, "carol").await;
+        let input = ConsoleInputRequest {
+            target_or_data: Some(console_input_request::TargetOrData::Handle(handle)),
+            session: None,
+        };
+        assert!(denied(
+            carol.console_stream_input(stream::iter([input])).await
+        ));
+        let close = boardswarm_protocol::ConsoleCloseRequest { handle };
+        assert!(denied(carol.console_close(close).await));
+        let mut bob = connect(&server, "bob").await;
+        assert!(denied(bob.console_close(close).await));
+        assert!(server
+            .inner
+            .console_handles
+            .get(handle, Some("alice"))
+            .is_ok());
+
+        alice.console_close(close).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn session_recording() {
+        let server = test_server();
+        let device = server.register_device(Properties::new("test"), TestDevice::new(&[]));
+        let mut alice = connect(&server, "alice").await;
+        let (tx, requests) = register("serial", device);
+        let mut replies = alice.console_register(requests).await.unwrap().into_inner();
+        replies.message().await.unwrap().unwrap();
+
+        let open = |record| boardswarm_protocol::SessionOpenRequest {
+            devices: vec![device],
+            ttl: 60,
+            record,
+        };
+        let session = alice
+            .session_open(open(true))
+            .await
+            .unwrap()
+            .into_inner()
+            .id;
+
+        let recorded = |events: Vec<boardswarm_protocol::SessionRecordEvent>| {
+            events.into_iter().any(|e| {
+                e.device == "test"
+                    && matches!(
+                        e.event.and_then(|e| e.event),
+                        Some(boardswarm_protocol::device_record_event::Event::ConsoleOutput(c))
+                            if c.console == "serial" && c.data == "hello"
+                    )
+            })
+        };
+        // Output is only recorded once the recording picked up the console
+        while !recorded(server.inner.recordings.get(session).unwrap()) {
+            tx.send(ConsoleRegisterRequest {
+                register_or_data: Some(console_register_request::RegisterOrData::Data(
+                    Bytes::from_static(b"hello"),
+                )),
+            })
+            .await
+            .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The recording can still be downloaded once the session ended
+        alice
+            .session_close(boardswarm_protocol::SessionRequest { session })
+            .await
+            .unwrap();
+        let events: Vec<_> = alice
+            .session_recording(boardswarm_protocol::SessionRequest { session })
+            .await
+            .unwrap()
+            .into_inner()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(recorded(events));
+
+        // Others only get the recording if they're allowed to use the device
+        let mut bob = connect(&server, "bob").await;
+        assert!(denied(
+            bob.session_recording(boardswarm_protocol::SessionRequest { session })
+                .await
+        ));
+        let mut carol = connect(&server, "carol").await;
+        carol
+            .session_recording(boardswarm_protocol::SessionRequest { session })
+            .await
+            .unwrap();
+
+        let session = alice
+            .session_open(open(false))
+            .await
+            .unwrap()
+            .into_inner()
+            .id;
+        let status = alice
+            .session_recording(boardswarm_protocol::SessionRequest { session })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn monitor_late_devices() {
+        let server = test_server();
+        tokio::spawn(config_device::monitor_devices(server.clone()));
+
+        let config: config::Device = serde_yaml::from_str(
+            "
+            name: late
+            consoles:
+              - name: main
+                parameters: {}
+                match:
+                  boardswarm.name: serial
+            modes: []
+            ",
+        )
+        .unwrap();
+        let device = config_device::Device::from_config(config, server.clone());
+        let id = server.register_device(Properties::new("late"), device);
+        let device = server.get_device(id).unwrap();
+        let mut updates = device.updates();
+        let console = server.register_console(
+            Properties::new("serial"),
+            client_console::ClientConsole::new(),
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while device.consoles()[0].id != Some(console) {
+                updates.wait().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn persistent_console_access() {
+        let server = test_server();
+        tokio::spawn(config_device::monitor_devices(server.clone()));
+
+        let config: config::Device = serde_yaml::from_str(
+            "
+            name: board
+            access: [alice]
+            consoles:
+              - name: main
+                persistent: true
+                parameters: {}
+                match:
+                  boardswarm.name: serial
+            modes: []
+            ",
+        )
+        .unwrap();
+        let device = config_device::Device::from_config(config, server.clone());
+        let id = server.register_device(Properties::new("board"), device);
+        let device = server.get_device(id).unwrap();
+        let mut updates = device.updates();
+        let raw = server.register_console(
+            Properties::new("serial"),
+            client_console::ClientConsole::new(),
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while device.bound_consoles().is_empty() {
+                updates.wait().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        let persistent = device.consoles()[0].id.unwrap();
+        assert_ne!(persistent, raw);
+
+        // The console behind the persistent console is subject to the checks of the device
+        let input = |console| ConsoleInputRequest {
+            target_or_data: Some(console_input_request::TargetOrData::Console(console)),
+            session: None,
+        };
+        let mut bob = connect(&server, "bob").await;
+        assert!(denied(
+            bob.console_stream_input(stream::iter([input(raw)])).await
+        ));
+
+        let mut alice = connect(&server, "alice").await;
+        alice
+            .console_open(boardswarm_protocol::ConsoleOpenRequest {
+                console: persistent,
+                session: None,
+            })
+            .await
+            .unwrap();
+        let status = alice
+            .console_stream_input(stream::iter([input(raw)]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn shared_volume_names() {
+        let server = test_server();
+        tokio::spawn(config_device::monitor_devices(server.clone()));
+
+        let config: config::Device = serde_yaml::from_str(
+            "
+            name: board
+            access: [alice]
+            consoles: []
+            volumes:
+              - name: rockusb
+                mode: maskrom
+                match:
+                  boardswarm.name: maskrom
+              - name: rockusb
+                mode: loader
+                match:
+                  boardswarm.name: loader
+            modes: []
+            ",
+        )
+        .unwrap();
+        let device = config_device::Device::from_config(config, server.clone());
+        let id = server.register_device(Properties::new("board"), device);
+        let device = server.get_device(id).unwrap();
+        let mut updates = device.updates();
+        server.register_volume(Properties::new("maskrom"), TestVolume());
+        let loader = server.register_volume(Properties::new("loader"), TestVolume());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while device.volume_entries().iter().any(|v| v.id.is_none()) {
+                updates.wait().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        // Only one of the volumes is listed for the device
+        assert_eq!(device.volumes().len(), 1);
+
+        // Yet access and mode checks apply to both
+        let erase = VolumeEraseRequest {
+            volume: loader,
+            target: "flash".to_string(),
+            session: None,
+        };
+        let mut bob = connect(&server, "bob").await;
+        assert!(denied(bob.volume_erase(erase.clone()).await));
+        let mut alice = connect(&server, "alice").await;
+        let status = alice.volume_erase(erase).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+}
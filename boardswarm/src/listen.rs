@@ -0,0 +1,247 @@
+use std::{
+    ffi::CString,
+    fs::Permissions,
+    net::{AddrParseError, IpAddr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    num::ParseIntError,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tokio::net::UnixListener;
+use tracing::debug;
+
+#[derive(Debug, Error)]
+pub enum ListenAddressError {
+    #[error("Invalid address: {0}")]
+    Address(#[from] AddrParseError),
+    #[error("Invalid port: {0}")]
+    Port(#[from] ParseIntError),
+    #[error("Unknown network interface: {0}")]
+    UnknownInterface(String),
+    #[error("Scopes are only supported for IPv6 addresses")]
+    ScopeNotSupported,
+}
+
+#[derive(Debug, Error)]
+#[error("Failed to listen on {addr}")]
+pub struct BindError {
+    addr: SocketAddr,
+    #[source]
+    source: std::io::Error,
+}
+
+fn scope_id(scope: &str) -> Result<u32, ListenAddressError> {
+    if let Ok(id) = scope.parse() {
+        return Ok(id);
+    }
+    let name =
+        CString::new(scope).map_err(|_| ListenAddressError::UnknownInterface(scope.to_string()))?;
+    // SAFETY: name is a valid nul-terminated string
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(ListenAddressError::UnknownInterface(scope.to_string())),
+        id => Ok(id),
+    }
+}
+
+/// Parse an address to listen on; The port is optional and IPv6 addresses can have a scope
+/// either as a number or as an interface name (e.g. `[fe80::1%eth0]:6683`) as is typically
+/// needed for link-local addresses
+pub fn parse_listen_address(addr: &str) -> Result<SocketAddr, ListenAddressError> {
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        match rest.split_once("]:") {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest.trim_end_matches(']'), None),
+        }
+    } else {
+        match addr.rsplit_once(':') {
+            // More than one colon means a bare IPv6 address without a port
+            Some((host, port)) if !host.contains(':') => (host, Some(port)),
+            _ => (addr, None),
+        }
+    };
+
+    let port = port
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or(boardswarm_protocol::DEFAULT_PORT);
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+
+    match (ip.parse()?, scope) {
+        (IpAddr::V6(ip), Some(scope)) => {
+            Ok(SocketAddrV6::new(ip, port, 0, scope_id(scope)?).into())
+        }
+        (IpAddr::V4(_), Some(_)) => Err(ListenAddressError::ScopeNotSupported),
+        (ip, None) => Ok(SocketAddr::new(ip, port)),
+    }
+}
+
+/// Resolve an address to listen on; Besides the addresses accepted by [parse_listen_address]
+/// host names are allowed, which can resolve to multiple addresses
+pub fn resolve_listen_address(addr: &str) -> Result<Vec<SocketAddr>, ListenAddressError> {
+    match parse_listen_address(addr) {
+        Err(ListenAddressError::Address(e)) => {
+            let (host, port) = match addr.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse()?),
+                None => (addr, boardswarm_protocol::DEFAULT_PORT),
+            };
+            let addrs: Vec<_> = (host, port)
+                .to_socket_addrs()
+                .map_err(|_| ListenAddressError::Address(e.clone()))?
+                .collect();
+            if addrs.is_empty() {
+                Err(ListenAddressError::Address(e))
+            } else {
+                Ok(addrs)
+            }
+        }
+        r => r.map(|addr| vec![addr]),
+    }
+}
+
+/// Create listening sockets for all addresses, optionally only accepting connections coming in on
+/// a specific network interface; Duplicates, e.g. from host names resolving to the same address as
+/// another listen address, are only bound once
+pub fn bind_all(
+    addrs: &[SocketAddr],
+    interface: Option<&str>,
+) -> Result<Vec<std::net::TcpListener>, BindError> {
+    let mut bound: Vec<SocketAddr> = Vec::new();
+    let mut listeners = Vec::new();
+    for &addr in addrs {
+        if bound.contains(&addr) {
+            continue;
+        }
+        // A dual-stack socket would also claim the port of an IPv4 listener on the same port
+        let only_v6 = addrs.iter().any(|a| a.is_ipv4() && a.port() == addr.port());
+        let listener =
+            bind(addr, interface, only_v6).map_err(|source| BindError { addr, source })?;
+        listeners.push(listener);
+        bound.push(addr);
+    }
+    Ok(listeners)
+}
+
+fn bind(
+    addr: SocketAddr,
+    interface: Option<&str>,
+    only_v6: bool,
+) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Be dual-stack when listening on IPv6 independent of the system default, unless IPv4 is
+    // listened on separately
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    if let Some(interface) = interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Create a listening unix socket at `path`, replacing a stale socket left behind by an earlier
+/// run
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Serve `router` on a unix socket until accepting connections fails
+pub async fn serve_unix(listener: UnixListener, router: axum::Router) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = TowerToHyperService::new(router.clone());
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Unix socket connection failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let default = boardswarm_protocol::DEFAULT_PORT;
+        assert_eq!(
+            parse_listen_address("127.0.0.1").unwrap(),
+            SocketAddr::new([127, 0, 0, 1].into(), default)
+        );
+        assert_eq!(
+            parse_listen_address("127.0.0.1:1234").unwrap(),
+            SocketAddr::new([127, 0, 0, 1].into(), 1234)
+        );
+        assert_eq!(
+            parse_listen_address("::").unwrap(),
+            SocketAddr::new("::".parse().unwrap(), default)
+        );
+        assert_eq!(
+            parse_listen_address("[::1]:1234").unwrap(),
+            SocketAddr::new("::1".parse().unwrap(), 1234)
+        );
+        assert_eq!(
+            parse_listen_address("fe80::1%3").unwrap(),
+            SocketAddrV6::new("fe80::1".parse().unwrap(), default, 0, 3).into()
+        );
+        assert_eq!(
+            parse_listen_address("[fe80::1%3]:1234").unwrap(),
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 1234, 0, 3).into()
+        );
+        assert!(parse_listen_address("127.0.0.1%3").is_err());
+        assert!(parse_listen_address("[fe80::1%does-not-exist]").is_err());
+        assert!(parse_listen_address("localhost").is_err());
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(
+            resolve_listen_address("127.0.0.1:1234").unwrap(),
+            vec![SocketAddr::new([127, 0, 0, 1].into(), 1234)]
+        );
+        let localhost = resolve_listen_address("localhost:1234").unwrap();
+        assert!(!localhost.is_empty());
+        assert!(localhost
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 1234));
+        assert!(resolve_listen_address("localhost:port").is_err());
+    }
+
+    #[test]
+    fn bind_dual_stack() {
+        let port = std::net::TcpListener::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let v4 = parse_listen_address(&format!("0.0.0.0:{port}")).unwrap();
+        let v6 = parse_listen_address(&format!("[::]:{port}")).unwrap();
+        let listeners = bind_all(&[v4, v6, v4], None).unwrap();
+        let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_eq!(addrs, vec![v4, v6]);
+    }
+}
@@ -155,6 +155,8 @@ impl MediatekBromVolume {
                 seekable: false,
                 size: None,
                 blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                sha256: None,
             }],
         }
     }
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::Deserialize;
+use tracing::{instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    ActuatorError, Server,
+};
+
+pub const PROVIDER: &str = "mqtt";
+
+const DEFAULT_PORT: u16 = 1883;
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+fn default_on() -> String {
+    "ON".to_string()
+}
+
+fn default_off() -> String {
+    "OFF".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct Plug {
+    name: String,
+    /// Topic to publish the payloads on, e.g. `cmnd/<device>/POWER` for Tasmota
+    topic: String,
+    #[serde(default = "default_on")]
+    on: String,
+    #[serde(default = "default_off")]
+    off: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MqttParameters {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    plugs: Vec<Plug>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: MqttParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let mut options = MqttOptions::new(
+        format!("boardswarm-{}-{}", std::process::id(), name),
+        parameters.host,
+        parameters.port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = parameters.username {
+        options.set_credentials(username, parameters.password.unwrap_or_default());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+    // The event loop has to be polled for publishes to be sent; It reconnects on the next poll
+    // after an error
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = eventloop.poll().await {
+                warn!("MQTT connection failure: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    });
+
+    for plug in parameters.plugs {
+        let mut properties = Properties::new(&plug.name);
+        properties.extend(provider_properties);
+        properties.insert(format!("{PROVIDER}.topic"), plug.topic.as_str());
+        server.register_actuator(
+            properties,
+            MqttActuator {
+                client: client.clone(),
+                plug,
+            },
+        );
+    }
+}
+
+#[derive(Debug)]
+struct MqttActuator {
+    client: AsyncClient,
+    plug: Plug,
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for MqttActuator {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let payload = match parameters.mode.as_str() {
+            "on" => &self.plug.on,
+            "off" => &self.plug.off,
+            mode => {
+                warn!("Unsupported mqtt plug mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        };
+        self.client
+            .publish(
+                &self.plug.topic,
+                QoS::AtLeastOnce,
+                false,
+                payload.as_bytes().to_vec(),
+            )
+            .await
+            .map_err(|e| {
+                warn!("Failed to publish to {}: {}", self.plug.topic, e);
+                ActuatorError {}
+            })
+    }
+}
@@ -0,0 +1,100 @@
+// Readings of the sensors measuring the power drawn by devices
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::config::PowerSource;
+
+const HWMON: &str = "/sys/class/hwmon";
+
+#[derive(Error, Debug)]
+pub enum PowerMeterError {
+    #[error("No hwmon device named {0}")]
+    NotFound(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Command failed: {0}")]
+    Command(String),
+    #[error("Unparsable reading: {0:?}")]
+    Reading(String),
+}
+
+/// Directory of the hwmon device, either given as such or found by its name
+async fn hwmon_directory(hwmon: &str) -> Result<PathBuf, PowerMeterError> {
+    if hwmon.starts_with('/') {
+        return Ok(PathBuf::from(hwmon));
+    }
+    let mut entries = tokio::fs::read_dir(HWMON).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = tokio::fs::read_to_string(entry.path().join("name"))
+            .await
+            .unwrap_or_default();
+        if name.trim() == hwmon {
+            return Ok(entry.path());
+        }
+    }
+    Err(PowerMeterError::NotFound(hwmon.to_string()))
+}
+
+fn parse(reading: &str) -> Result<f64, PowerMeterError> {
+    reading
+        .trim()
+        .parse()
+        .map_err(|_| PowerMeterError::Reading(reading.trim().to_string()))
+}
+
+/// Read the power currently drawn in watts
+pub async fn read(source: &PowerSource) -> Result<f64, PowerMeterError> {
+    match source {
+        PowerSource::Hwmon { hwmon, sensor } => {
+            let path = hwmon_directory(hwmon)
+                .await?
+                .join(format!("{}_input", sensor));
+            let reading = tokio::fs::read_to_string(path).await?;
+            // hwmon reports power in microwatts
+            Ok(parse(&reading)? / 1_000_000.0)
+        }
+        PowerSource::Command { command, scale } => {
+            let Some((program, args)) = command.split_first() else {
+                return Err(PowerMeterError::Command("empty command".to_string()));
+            };
+            let output = Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output()
+                .await?;
+            if !output.status.success() {
+                return Err(PowerMeterError::Command(
+                    String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                ));
+            }
+            Ok(parse(&String::from_utf8_lossy(&output.stdout))? * scale)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn command() {
+        let source = PowerSource::Command {
+            command: vec!["echo".to_string(), "2500".to_string()],
+            scale: 0.001,
+        };
+        assert_eq!(read(&source).await.unwrap(), 2.5);
+
+        let source = PowerSource::Command {
+            command: vec!["echo".to_string(), "n/a".to_string()],
+            scale: 1.0,
+        };
+        assert!(matches!(
+            read(&source).await,
+            Err(PowerMeterError::Reading(r)) if r == "n/a"
+        ));
+    }
+}
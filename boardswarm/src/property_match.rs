@@ -0,0 +1,223 @@
+// Matching of item property values beyond plain equality, shared by the match blocks in the
+// configuration and the filters given by clients
+use std::fmt;
+use std::str::FromStr;
+
+use boardswarm_provider::properties::{parse_bool, ValueMatch};
+use regex::Regex;
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PropertyMatchError {
+    #[error("Invalid pattern {0:?}: {1}")]
+    Pattern(String, regex::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn operator(&self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+}
+
+/// Test of a property value, given as a string: `glob:<pattern>`, `regex:<pattern>`, a numeric
+/// comparison like `>=3` or otherwise the exact value. In the configuration plain integers and
+/// booleans compare against the property value interpreted as such.
+#[derive(Clone)]
+pub enum PropertyMatch {
+    Exact(String),
+    Int(i64),
+    Bool(bool),
+    Glob(String, Regex),
+    Regex(Regex),
+    Compare(Comparison, f64),
+}
+
+/// Anchored regular expression matching the same strings as the shell-style glob
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+impl FromStr for PropertyMatch {
+    type Err = PropertyMatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern =
+            |p: &str| Regex::new(p).map_err(|e| PropertyMatchError::Pattern(s.to_string(), e));
+        if let Some(glob) = s.strip_prefix("glob:") {
+            return Ok(PropertyMatch::Glob(
+                glob.to_string(),
+                pattern(&glob_to_regex(glob))?,
+            ));
+        }
+        if let Some(regex) = s.strip_prefix("regex:") {
+            return Ok(PropertyMatch::Regex(pattern(regex)?));
+        }
+        // Longest operators first, as `<` is a prefix of `<=`
+        let comparisons = [
+            Comparison::LessOrEqual,
+            Comparison::GreaterOrEqual,
+            Comparison::Less,
+            Comparison::Greater,
+        ];
+        for comparison in comparisons {
+            let number = s
+                .strip_prefix(comparison.operator())
+                .and_then(|n| n.trim().parse().ok());
+            if let Some(number) = number {
+                return Ok(PropertyMatch::Compare(comparison, number));
+            }
+        }
+        Ok(PropertyMatch::Exact(s.to_string()))
+    }
+}
+
+impl ValueMatch for PropertyMatch {
+    fn matches_value(&self, value: &str) -> bool {
+        match self {
+            PropertyMatch::Exact(exact) => exact == value,
+            PropertyMatch::Int(i) => value.trim().parse::<i64>().ok() == Some(*i),
+            PropertyMatch::Bool(b) => parse_bool(value) == Some(*b),
+            PropertyMatch::Glob(_, regex) | PropertyMatch::Regex(regex) => regex.is_match(value),
+            PropertyMatch::Compare(comparison, number) => {
+                let Ok(value) = value.trim().parse::<f64>() else {
+                    return false;
+                };
+                match comparison {
+                    Comparison::Less => value < *number,
+                    Comparison::LessOrEqual => value <= *number,
+                    Comparison::Greater => value > *number,
+                    Comparison::GreaterOrEqual => value >= *number,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for PropertyMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyMatch::Exact(exact) => write!(f, "{}", exact),
+            PropertyMatch::Int(i) => write!(f, "{}", i),
+            PropertyMatch::Bool(b) => write!(f, "{}", b),
+            PropertyMatch::Glob(glob, _) => write!(f, "glob:{}", glob),
+            PropertyMatch::Regex(regex) => write!(f, "regex:{}", regex),
+            PropertyMatch::Compare(comparison, number) => {
+                write!(f, "{}{}", comparison.operator(), number)
+            }
+        }
+    }
+}
+
+// Shown the way it was written, as matches are logged as part of their maps
+impl fmt::Debug for PropertyMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PropertyMatch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct PropertyMatchVisitor;
+
+        impl Visitor<'_> for PropertyMatchVisitor {
+            type Value = PropertyMatch;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a property value, pattern or comparison")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(PropertyMatch::Int(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map(PropertyMatch::Int)
+                    .map_err(|_| E::custom("integer out of range"))
+            }
+
+            // Only matches the shortest way of writing the number, like YAML itself does
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(PropertyMatch::Exact(v.to_string()))
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(PropertyMatch::Bool(v))
+            }
+        }
+
+        deserializer.deserialize_any(PropertyMatchVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(m: &str, value: &str) -> bool {
+        m.parse::<PropertyMatch>().unwrap().matches_value(value)
+    }
+
+    #[test]
+    fn parse() {
+        assert!(matches("ttyUSB0", "ttyUSB0"));
+        assert!(!matches("ttyUSB0", "ttyUSB1"));
+        assert!(matches("glob:ttyUSB*", "ttyUSB12"));
+        assert!(!matches("glob:ttyUSB?", "ttyUSB12"));
+        assert!(matches(
+            "glob:*-usb-0:1.3*",
+            "platform-xhci-hcd.1.auto-usb-0:1.3.2:1.0"
+        ));
+        assert!(!matches("glob:1.3", "103"));
+        assert!(matches("regex:^A10[0-9A-Z]+$", "A10KM3X4"));
+        assert!(matches(">=3", "3"));
+        assert!(!matches(">3", "3"));
+        assert!(matches("<2.5", "002"));
+        assert!(!matches("<=2", "two"));
+        // Not a number, so compared as is
+        assert!(matches(">foo", ">foo"));
+        assert!("regex:(".parse::<PropertyMatch>().is_err());
+    }
+
+    #[test]
+    fn deserialize() {
+        let m: std::collections::HashMap<String, PropertyMatch> =
+            serde_yaml::from_str("{busnum: 3, powered: true, name: \"glob:rack-*\"}").unwrap();
+        assert!(m["busnum"].matches_value("003"));
+        assert!(m["powered"].matches_value("yes"));
+        assert!(!m["powered"].matches_value("off"));
+        assert!(m["name"].matches_value("rack-7-uart"));
+        assert_eq!(format!("{:?}", m["name"]), "\"glob:rack-*\"");
+    }
+}
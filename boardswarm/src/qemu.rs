@@ -0,0 +1,346 @@
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Sink, StreamExt};
+use serde::Deserialize;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    utils::FileTarget,
+    ActuatorError, ConsoleError, Server, Volume, VolumeError, VolumeTarget, VolumeTargetInfo,
+};
+
+pub const PROVIDER: &str = "qemu";
+
+/// Name of the single target of the disk volume
+const TARGET: &str = "disk";
+
+fn default_qemu() -> String {
+    "qemu-system-x86_64".to_string()
+}
+
+fn default_interface() -> String {
+    "virtio".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct Machine {
+    name: String,
+    /// QEMU binary to run
+    #[serde(default = "default_qemu")]
+    qemu: String,
+    /// Extra arguments, e.g. machine type, memory and firmware
+    #[serde(default)]
+    args: Vec<String>,
+    /// Raw disk image attached to the machine and exposed as volume
+    image: Option<PathBuf>,
+    /// Interface to attach the disk image with
+    #[serde(default = "default_interface")]
+    interface: String,
+}
+
+impl Machine {
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.qemu);
+        command
+            .args(&self.args)
+            .args(["-display", "none", "-monitor", "none", "-serial", "stdio"]);
+        if let Some(image) = &self.image {
+            command.arg("-drive").arg(format!(
+                "file={},format=raw,if={}",
+                image.display(),
+                self.interface
+            ));
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        command
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct QemuParameters {
+    machines: Vec<Machine>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: QemuParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for machine in parameters.machines {
+        let mut properties = Properties::new(&machine.name);
+        properties.extend(provider_properties);
+        properties.insert(format!("{PROVIDER}.binary"), machine.qemu.as_str());
+
+        let (input, rx) = mpsc::channel(16);
+        let has_image = machine.image.is_some();
+        let vm = Arc::new(Vm {
+            machine,
+            input: Arc::new(Mutex::new(rx)),
+            output: broadcast::channel(64).0,
+            running: Mutex::new(None),
+        });
+
+        server.register_actuator(properties.clone(), VmPower(vm.clone()));
+        server.register_console(
+            properties.clone(),
+            VmConsole {
+                input,
+                output: vm.output.clone(),
+            },
+        );
+        if has_image {
+            server.register_volume(properties, VmDisk::new(vm));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Vm {
+    machine: Machine,
+    input: Arc<Mutex<mpsc::Receiver<Bytes>>>,
+    output: broadcast::Sender<Bytes>,
+    /// Stop token and task of the running QEMU instance
+    running: Mutex<Option<(CancellationToken, JoinHandle<()>)>>,
+}
+
+impl Vm {
+    async fn start(&self) -> std::io::Result<()> {
+        let mut running = self.running.lock().await;
+        if running
+            .as_ref()
+            .is_some_and(|(_, task)| !task.is_finished())
+        {
+            return Ok(());
+        }
+
+        let mut child = self.machine.command().spawn()?;
+        info!("Started virtual machine {}", self.machine.name);
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let input = self.input.clone();
+        let output = self.output.clone();
+        let name = self.machine.name.clone();
+        let stop = CancellationToken::new();
+        let task = tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                let feed = async move {
+                    let mut input = input.lock().await;
+                    while let Some(data) = input.recv().await {
+                        if stdin.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                };
+                let read = async {
+                    let mut buf = BytesMut::with_capacity(4096);
+                    loop {
+                        buf.reserve(4096);
+                        match stdout.read_buf(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) => {
+                                let _ = output.send(buf.split().freeze());
+                            }
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = feed => (),
+                    _ = read => (),
+                    _ = stop.cancelled() => (),
+                }
+                let _ = child.kill().await;
+                info!("Virtual machine {} stopped", name);
+            }
+        });
+        *running = Some((stop, task));
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        if let Some((stop, task)) = self.running.lock().await.take() {
+            stop.cancel();
+            let _ = task.await;
+        }
+    }
+
+    async fn is_running(&self) -> bool {
+        self.running
+            .lock()
+            .await
+            .as_ref()
+            .is_some_and(|(_, task)| !task.is_finished())
+    }
+}
+
+#[derive(Debug)]
+struct VmPower(Arc<Vm>);
+
+#[async_trait::async_trait]
+impl crate::Actuator for VmPower {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        match parameters.mode.as_str() {
+            "on" => (),
+            "off" => {
+                self.0.stop().await;
+                return Ok(());
+            }
+            "reset" => self.0.stop().await,
+            mode => {
+                warn!("Unsupported qemu power mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        }
+        self.0.start().await.map_err(|e| {
+            warn!("Failed to start {}: {}", self.0.machine.qemu, e);
+            ActuatorError {}
+        })
+    }
+}
+
+#[derive(Debug)]
+struct VmConsole {
+    input: mpsc::Sender<Bytes>,
+    output: broadcast::Sender<Bytes>,
+}
+
+#[async_trait::async_trait]
+impl crate::Console for VmConsole {
+    fn configure(
+        &self,
+        _parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        Ok(())
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let input = self.input.clone();
+        Ok(Box::pin(futures::sink::unfold(
+            input,
+            |input, data: Bytes| async move {
+                input.send(data).await.map_err(|_| ConsoleError::Closed)?;
+                Ok(input)
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("Virtual machine output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
+
+#[derive(Debug)]
+struct VmDisk {
+    vm: Arc<Vm>,
+    targets: [VolumeTargetInfo; 1],
+}
+
+impl VmDisk {
+    fn new(vm: Arc<Vm>) -> Self {
+        Self {
+            vm,
+            targets: [VolumeTargetInfo {
+                name: TARGET.to_string(),
+                readable: true,
+                writable: true,
+                seekable: true,
+                size: None,
+                blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::BlockDevice.into(),
+                sha256: None,
+            }],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Volume for VmDisk {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (&self.targets, true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        if target != TARGET {
+            return Err(VolumeError::UnknownTargetRequested);
+        }
+        if self.vm.is_running().await {
+            return Err(VolumeError::Failure(
+                "Virtual machine is running".to_string(),
+            ));
+        }
+        let image = self.vm.machine.image.as_ref().unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(image)
+            .await
+            .map_err(|e| {
+                VolumeError::Failure(format!("Failed to open {}: {}", image.display(), e))
+            })?;
+        // A new image of known length is being provisioned
+        if let Some(length) = length {
+            file.set_len(length)
+                .await
+                .map_err(|e| VolumeError::Failure(e.to_string()))?;
+        }
+        let size = file
+            .seek(SeekFrom::End(0))
+            .await
+            .map_err(|e| VolumeError::Failure(e.to_string()))?;
+
+        let mut info = self.targets[0].clone();
+        info.size = Some(size);
+        Ok((info, Box::new(FileTarget::new(file))))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        Ok(())
+    }
+}
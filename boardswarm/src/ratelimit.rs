@@ -0,0 +1,55 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Amount of data that can pass without delay after being idle, as time at the configured rate
+const BURST: Duration = Duration::from_secs(1);
+
+/// Token bucket limiting the rate of data passing through
+#[derive(Debug)]
+pub struct RateLimit {
+    /// Bytes per second
+    rate: u64,
+    /// Moment the bucket is full again given the data passed so far
+    full: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            full: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `bytes` are allowed to pass
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut full = self.full.lock().unwrap();
+            let now = Instant::now();
+            *full = (*full).max(now) + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            full.saturating_duration_since(now + BURST)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn limit() {
+        let limit = RateLimit::new(1_000_000);
+        let start = Instant::now();
+        // A burst of a second worth of data passes right away
+        limit.acquire(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        // Any more has to wait for the bucket to drain
+        limit.acquire(250_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
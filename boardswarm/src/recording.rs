@@ -0,0 +1,551 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use boardswarm_protocol::{
+    device_record_event, DeviceRecordConsole, DeviceRecordEvent, DeviceRecordRecovery,
+    DeviceRecordVolume, DeviceRecoveryState, SessionRecordEvent,
+};
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::{wrappers::ReceiverStream, StreamMap};
+use tracing::warn;
+
+use crate::{ActiveStream, ConsoleError, Device, Server};
+
+/// Number of interactions buffered for recordings before they start missing some
+pub const CAPACITY: usize = 256;
+
+/// Number of session recordings kept for download; The oldest ones are dropped first
+const SESSIONS: usize = 16;
+
+/// Interaction with an item done by a client, as seen by recordings
+#[derive(Clone, Debug)]
+pub enum Interaction {
+    ConsoleInput {
+        console: u64,
+        data: Bytes,
+    },
+    Volume {
+        volume: u64,
+        target: String,
+        operation: &'static str,
+        offset: u64,
+        length: u64,
+    },
+    Recovery {
+        device: u64,
+        reason: String,
+        attempt: u32,
+        attempts: u32,
+        state: DeviceRecoveryState,
+        error: Option<String>,
+    },
+}
+
+/// Collapses identical console lines repeated within a window into a single marker line
+struct LineDedup {
+    window: Duration,
+    /// Incomplete line held back until it's completed or flushed
+    partial: Vec<u8>,
+    last: Vec<u8>,
+    last_seen: Option<Instant>,
+    repeats: u64,
+}
+
+impl LineDedup {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            partial: Vec::new(),
+            last: Vec::new(),
+            last_seen: None,
+            repeats: 0,
+        }
+    }
+
+    fn marker(&mut self, out: &mut Vec<u8>) {
+        if self.repeats > 0 {
+            let end = if self.last.ends_with(b"\r\n") {
+                "\r\n"
+            } else {
+                "\n"
+            };
+            out.extend_from_slice(
+                format!("last line repeated {} times{}", self.repeats, end).as_bytes(),
+            );
+            self.repeats = 0;
+        }
+    }
+
+    fn feed(&mut self, data: &[u8], now: Instant) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.partial.extend_from_slice(data);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let repeated = line == self.last
+                && self
+                    .last_seen
+                    .is_some_and(|seen| now.duration_since(seen) <= self.window);
+            self.last_seen = Some(now);
+            if repeated {
+                self.repeats += 1;
+            } else {
+                self.marker(&mut out);
+                out.extend_from_slice(&line);
+                self.last = line;
+            }
+        }
+        out
+    }
+
+    /// Output pending markers and incomplete lines once the window has passed
+    fn flush(&mut self, now: Instant) -> Vec<u8> {
+        let mut out = Vec::new();
+        let expired = self
+            .last_seen
+            .is_none_or(|seen| now.duration_since(seen) > self.window);
+        if expired {
+            self.marker(&mut out);
+        }
+        if !self.partial.is_empty() {
+            self.marker(&mut out);
+            out.append(&mut self.partial);
+            self.last.clear();
+            self.last_seen = None;
+        }
+        out
+    }
+}
+
+pub type RecordStream = ReceiverStream<Result<DeviceRecordEvent, tonic::Status>>;
+
+struct Recorder {
+    start: Instant,
+    id: u64,
+    device: Arc<dyn Device>,
+    /// Console and volume ids to their names on the device
+    consoles: HashMap<u64, String>,
+    volumes: HashMap<u64, String>,
+    mode: Option<String>,
+    outputs: StreamMap<String, BoxStream<'static, Result<Bytes, ConsoleError>>>,
+    dedup: Option<Duration>,
+    filters: HashMap<String, LineDedup>,
+}
+
+impl Recorder {
+    fn event(&self, event: device_record_event::Event) -> DeviceRecordEvent {
+        DeviceRecordEvent {
+            timestamp: self.start.elapsed().as_millis() as u64,
+            event: Some(event),
+        }
+    }
+
+    /// Pick up changes to the device; Returns the new mode if it changed
+    async fn update(&mut self, server: &Server) -> Option<String> {
+        self.consoles = self
+            .device
+            .consoles()
+            .into_iter()
+            .filter_map(|c| Some((c.id?, c.name)))
+            .collect();
+        self.volumes = self
+            .device
+            .volume_entries()
+            .into_iter()
+            .filter_map(|v| Some((v.id?, v.name)))
+            .collect();
+
+        let gone: Vec<String> = self
+            .outputs
+            .keys()
+            .filter(|name| !self.consoles.values().any(|c| c == *name))
+            .cloned()
+            .collect();
+        for name in gone {
+            self.outputs.remove(&name);
+            self.filters.remove(&name);
+        }
+        for (&id, name) in &self.consoles {
+            if self.outputs.contains_key(name) {
+                continue;
+            }
+            if server.get_console(id).is_none() {
+                continue;
+            }
+            match server.console_output(id, false).await {
+                Ok(output) => {
+                    self.outputs.insert(name.clone(), output);
+                }
+                Err(e) => warn!("Failed to record console {}: {}", name, e),
+            }
+        }
+
+        let mode = self.device.current_mode();
+        if mode.is_some() && mode != self.mode {
+            self.mode = mode.clone();
+            mode
+        } else {
+            None
+        }
+    }
+
+    /// Pass console output through the dedup filter, if enabled
+    fn output(&mut self, console: &str, data: Bytes) -> Option<Bytes> {
+        let Some(window) = self.dedup else {
+            return Some(data);
+        };
+        let data = self
+            .filters
+            .entry(console.to_string())
+            .or_insert_with(|| LineDedup::new(window))
+            .feed(&data, Instant::now());
+        (!data.is_empty()).then(|| data.into())
+    }
+
+    /// Events for console output held back by the dedup filters
+    fn flush(&mut self) -> Vec<device_record_event::Event> {
+        let now = Instant::now();
+        self.filters
+            .iter_mut()
+            .filter_map(|(console, filter)| {
+                let data = filter.flush(now);
+                (!data.is_empty()).then(|| {
+                    device_record_event::Event::ConsoleOutput(DeviceRecordConsole {
+                        console: console.clone(),
+                        data: data.into(),
+                    })
+                })
+            })
+            .collect()
+    }
+
+    fn interaction(&self, interaction: Interaction) -> Option<device_record_event::Event> {
+        match interaction {
+            Interaction::ConsoleInput { console, data } => {
+                let console = self.consoles.get(&console)?.clone();
+                Some(device_record_event::Event::ConsoleInput(
+                    DeviceRecordConsole { console, data },
+                ))
+            }
+            Interaction::Volume {
+                volume,
+                target,
+                operation,
+                offset,
+                length,
+            } => {
+                let volume = self.volumes.get(&volume)?.clone();
+                Some(device_record_event::Event::Volume(DeviceRecordVolume {
+                    volume,
+                    target,
+                    operation: operation.to_string(),
+                    offset,
+                    length,
+                }))
+            }
+            Interaction::Recovery {
+                device,
+                reason,
+                attempt,
+                attempts,
+                state,
+                error,
+            } => (device == self.id).then(|| {
+                device_record_event::Event::Recovery(DeviceRecordRecovery {
+                    reason,
+                    attempt,
+                    attempts,
+                    state: state.into(),
+                    error,
+                })
+            }),
+        }
+    }
+}
+
+/// Record all interactions with a device for as long as the returned stream is consumed
+pub fn record(
+    server: Server,
+    id: u64,
+    device: Arc<dyn Device>,
+    guard: ActiveStream,
+) -> RecordStream {
+    let (tx, rx) = mpsc::channel(64);
+    let mut interactions = server.interactions();
+    let mut monitor = device.updates();
+    let dedup = server.recording_config().dedup;
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut recorder = Recorder {
+            start: Instant::now(),
+            id,
+            device,
+            consoles: HashMap::new(),
+            volumes: HashMap::new(),
+            mode: None,
+            outputs: StreamMap::new(),
+            dedup,
+            filters: HashMap::new(),
+        };
+        let mut flush = tokio::time::interval(dedup.unwrap_or(Duration::from_secs(1)));
+
+        let mut mode = recorder.update(&server).await;
+        loop {
+            if let Some(mode) = mode.take() {
+                let event = recorder.event(device_record_event::Event::Mode(mode));
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+
+            let event = tokio::select! {
+                r = monitor.wait() => {
+                    if r.is_err() {
+                        return;
+                    }
+                    mode = recorder.update(&server).await;
+                    continue;
+                }
+                Some((console, data)) = recorder.outputs.next() => {
+                    match data.map(|data| recorder.output(&console, data)) {
+                        Ok(Some(data)) => device_record_event::Event::ConsoleOutput(
+                            DeviceRecordConsole { console, data }
+                        ),
+                        Ok(None) => continue,
+                        Err(_) => {
+                            recorder.outputs.remove(&console);
+                            continue;
+                        }
+                    }
+                }
+                _ = flush.tick(), if dedup.is_some() => {
+                    for event in recorder.flush() {
+                        if tx.send(Ok(recorder.event(event))).await.is_err() {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                interaction = interactions.recv() => {
+                    match interaction {
+                        Ok(interaction) => match recorder.interaction(interaction) {
+                            Some(event) => event,
+                            None => continue,
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!("Recording missed {} interactions", missed);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    }
+                }
+                _ = tx.closed() => return,
+            };
+
+            if tx.send(Ok(recorder.event(event))).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+/// Interactions recorded for the devices of a session
+struct Bundle {
+    session: u64,
+    /// Caller that opened the session, if known
+    holder: Option<String>,
+    devices: Vec<u64>,
+    events: VecDeque<SessionRecordEvent>,
+    /// Bytes of console data in `events`
+    size: usize,
+    /// Dropped once the session ends, which stops the recording
+    active: Option<watch::Sender<()>>,
+}
+
+/// Recordings of sessions opened with recording enabled
+pub struct Recordings {
+    /// Bytes of console data kept per session; The oldest events are dropped beyond that
+    limit: usize,
+    bundles: Mutex<VecDeque<Bundle>>,
+}
+
+/// Bytes of console data in the event
+fn event_size(event: &SessionRecordEvent) -> usize {
+    match event.event.as_ref().and_then(|e| e.event.as_ref()) {
+        Some(device_record_event::Event::ConsoleOutput(c))
+        | Some(device_record_event::Event::ConsoleInput(c)) => c.data.len(),
+        _ => 0,
+    }
+}
+
+impl Recordings {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            bundles: Mutex::default(),
+        }
+    }
+
+    fn start(&self, session: u64, holder: Option<String>, devices: &[u64]) -> watch::Receiver<()> {
+        let (active, stopped) = watch::channel(());
+        let mut bundles = self.bundles.lock().unwrap();
+        while bundles.len() >= SESSIONS {
+            bundles.pop_front();
+        }
+        bundles.push_back(Bundle {
+            session,
+            holder,
+            devices: devices.to_vec(),
+            events: VecDeque::new(),
+            size: 0,
+            active: Some(active),
+        });
+        stopped
+    }
+
+    fn push(&self, session: u64, event: SessionRecordEvent) {
+        let mut bundles = self.bundles.lock().unwrap();
+        let Some(bundle) = bundles.iter_mut().find(|b| b.session == session) else {
+            return;
+        };
+        bundle.size += event_size(&event);
+        bundle.events.push_back(event);
+        while bundle.size > self.limit {
+            let Some(dropped) = bundle.events.pop_front() else {
+                break;
+            };
+            bundle.size -= event_size(&dropped);
+        }
+    }
+
+    /// Stop recording the session, if it's being recorded
+    pub fn stop(&self, session: u64) {
+        let mut bundles = self.bundles.lock().unwrap();
+        if let Some(bundle) = bundles.iter_mut().find(|b| b.session == session) {
+            bundle.active = None;
+        }
+    }
+
+    /// Holder of the recorded session and the devices it recorded, if it was recorded
+    pub fn owner(&self, session: u64) -> Option<(Option<String>, Vec<u64>)> {
+        self.bundles
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|b| b.session == session)
+            .map(|b| (b.holder.clone(), b.devices.clone()))
+    }
+
+    /// Events recorded so far for the session, if it was recorded
+    pub fn get(&self, session: u64) -> Option<Vec<SessionRecordEvent>> {
+        self.bundles
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|b| b.session == session)
+            .map(|b| b.events.iter().cloned().collect())
+    }
+}
+
+/// Record all interactions with the devices of the session until it's stopped
+pub fn record_session(server: &Server, session: u64) {
+    let Some(s) = server.inner.sessions.get(session) else {
+        return;
+    };
+    let stopped = server.inner.recordings.start(session, s.holder, &s.devices);
+    for &id in &s.devices {
+        let Some(device) = server.inner.devices.lookup(id) else {
+            continue;
+        };
+        let name = device.name().to_string();
+        let guard = server.stream_guard(boardswarm_protocol::ItemType::Device, id);
+        let mut events = record(server.clone(), id, device.inner().clone(), guard);
+        let mut stopped = stopped.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = events.next() => {
+                        let Some(Ok(event)) = event else {
+                            return;
+                        };
+                        let event = SessionRecordEvent {
+                            device: name.clone(),
+                            event: Some(event),
+                        };
+                        server.inner.recordings.push(session, event);
+                    }
+                    _ = stopped.changed() => return,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dedup() {
+        let start = Instant::now();
+        let window = Duration::from_secs(1);
+        let mut dedup = LineDedup::new(window);
+
+        assert_eq!(dedup.feed(b"error\r\nerr", start), b"error\r\n");
+        assert_eq!(dedup.feed(b"or\r\nerror\r\n", start), b"");
+        assert_eq!(
+            dedup.feed(b"done\r\n", start),
+            b"last line repeated 2 times\r\ndone\r\n"
+        );
+
+        // Repeats outside of the window are kept
+        assert_eq!(dedup.feed(b"done\r\n", start + window * 2), b"done\r\n");
+
+        // Markers and incomplete lines are output by flushing
+        assert_eq!(dedup.feed(b"done\r\nlogin: ", start + window * 2), b"");
+        assert_eq!(
+            dedup.flush(start + window * 4),
+            b"last line repeated 1 times\r\nlogin: "
+        );
+        assert_eq!(dedup.flush(start + window * 6), b"");
+    }
+
+    #[test]
+    fn session_size() {
+        let recordings = Recordings::new(8);
+        let output = |data: &'static [u8]| SessionRecordEvent {
+            device: "test".to_string(),
+            event: Some(DeviceRecordEvent {
+                timestamp: 0,
+                event: Some(device_record_event::Event::ConsoleOutput(
+                    DeviceRecordConsole {
+                        console: "main".to_string(),
+                        data: Bytes::from_static(data),
+                    },
+                )),
+            }),
+        };
+        let _stopped = recordings.start(1, Some("alice".to_string()), &[2]);
+        recordings.push(1, output(b"first"));
+        recordings.push(1, output(b"second"));
+        recordings.push(2, output(b"other"));
+
+        // The oldest events are dropped once over the limit
+        let events = recordings.get(1).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(event_size(&events[0]), 6);
+        assert_eq!(
+            recordings.owner(1),
+            Some((Some("alice".to_string()), vec![2]))
+        );
+        assert!(recordings.get(2).is_none());
+    }
+}
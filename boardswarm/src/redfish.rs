@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use reqwest::{header::HeaderValue, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::{debug, instrument, warn};
+use url::Url;
+
+use crate::{
+    registry::{self, Properties},
+    ActuatorError, Server,
+};
+
+pub const PROVIDER: &str = "redfish";
+
+const SESSIONS: &str = "/redfish/v1/SessionService/Sessions";
+const AUTH_TOKEN: &str = "X-Auth-Token";
+
+#[derive(Deserialize, Debug)]
+struct System {
+    name: String,
+    /// Path of the system resource, e.g. /redfish/v1/Systems/1
+    path: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RedfishParameters {
+    uri: Url,
+    username: String,
+    password: String,
+    /// Accept invalid (e.g. self-signed) certificates of the BMC
+    #[serde(default)]
+    insecure: bool,
+    systems: Vec<System>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: RedfishParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let http = reqwest::Client::builder()
+        .danger_accept_invalid_certs(parameters.insecure)
+        .build()
+        .unwrap();
+    let session = Arc::new(Session {
+        http,
+        uri: parameters.uri,
+        username: parameters.username,
+        password: parameters.password,
+        token: Mutex::new(None),
+    });
+
+    for system in parameters.systems {
+        let mut properties = Properties::new(&system.name);
+        properties.extend(provider_properties);
+        properties.insert(format!("{PROVIDER}.system"), system.path.as_str());
+        server.register_actuator(
+            properties,
+            RedfishSystem {
+                session: session.clone(),
+                path: system.path,
+            },
+        );
+    }
+}
+
+#[derive(Debug)]
+enum RedfishError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    MissingToken,
+}
+
+impl std::fmt::Display for RedfishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RedfishError::Request(e) => write!(f, "Request failed: {}", e),
+            RedfishError::Status(s) => write!(f, "Unexpected status: {}", s),
+            RedfishError::MissingToken => f.write_str("No session token returned"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for RedfishError {
+    fn from(e: reqwest::Error) -> Self {
+        RedfishError::Request(e)
+    }
+}
+
+/// Session with the BMC shared by all its systems; Logged in on first use and again once the
+/// token is rejected
+#[derive(Debug)]
+struct Session {
+    http: reqwest::Client,
+    uri: Url,
+    username: String,
+    password: String,
+    token: Mutex<Option<HeaderValue>>,
+}
+
+impl Session {
+    async fn login(&self) -> Result<HeaderValue, RedfishError> {
+        let response = self
+            .http
+            .post(self.uri.join(SESSIONS).unwrap())
+            .json(&json!({
+                "UserName": self.username,
+                "Password": self.password,
+            }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(RedfishError::Status(response.status()));
+        }
+        response
+            .headers()
+            .get(AUTH_TOKEN)
+            .cloned()
+            .ok_or(RedfishError::MissingToken)
+    }
+
+    async fn post(&self, path: &str, body: serde_json::Value) -> Result<(), RedfishError> {
+        let url = self.uri.join(path).unwrap();
+        let mut token = self.token.lock().await;
+        for retry in [false, true] {
+            let current = match &*token {
+                Some(t) if !retry => t.clone(),
+                _ => {
+                    debug!("Logging in to {}", self.uri);
+                    let t = self.login().await?;
+                    *token = Some(t.clone());
+                    t
+                }
+            };
+            let response = self
+                .http
+                .post(url.clone())
+                .header(AUTH_TOKEN, current)
+                .json(&body)
+                .send()
+                .await?;
+            match response.status() {
+                StatusCode::UNAUTHORIZED if !retry => continue,
+                s if s.is_success() => return Ok(()),
+                s => return Err(RedfishError::Status(s)),
+            }
+        }
+        Err(RedfishError::Status(StatusCode::UNAUTHORIZED))
+    }
+}
+
+#[derive(Debug)]
+struct RedfishSystem {
+    session: Arc<Session>,
+    path: String,
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for RedfishSystem {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let reset_type = match parameters.mode.as_str() {
+            "on" => "On",
+            "off" => "ForceOff",
+            "shutdown" => "GracefulShutdown",
+            "reset" => "ForceRestart",
+            "cycle" => "PowerCycle",
+            mode => {
+                warn!("Unsupported redfish power mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        };
+        let action = format!(
+            "{}/Actions/ComputerSystem.Reset",
+            self.path.trim_end_matches('/')
+        );
+        self.session
+            .post(&action, json!({ "ResetType": reset_type }))
+            .await
+            .map_err(|e| {
+                warn!("Failed to reset {}: {}", self.path, e);
+                ActuatorError {}
+            })
+    }
+}
@@ -1,122 +1,42 @@
 use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::SystemTime;
 
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
 
-pub const NAME: &str = "boardswarm.name";
-pub const INSTANCE: &str = "boardswarm.instance";
-pub const PROVIDER: &str = "boardswarm.provider";
-pub const PROVIDER_NAME: &str = "boardswarm.provider.name";
+pub use boardswarm_provider::properties::{
+    Properties, ValueMatch, IDENTITY, INSTANCE, NAME, PROVIDER, PROVIDER_NAME, RESERVED, SERVER,
+    SERVER_ADDRESS, TAG,
+};
 
-#[derive(Clone, Debug)]
-pub struct Properties {
-    properties: HashMap<String, String>,
-}
-
-impl Properties {
-    pub fn new<N: Into<String>>(name: N) -> Self {
-        let mut properties = HashMap::new();
-        properties.insert(NAME.to_string(), name.into());
-
-        Self { properties }
-    }
-
-    pub fn name(&self) -> &str {
-        self.get(NAME).unwrap_or_default()
-    }
-
-    pub fn instance(&self) -> Option<&str> {
-        self.get(INSTANCE)
-    }
-
-    pub fn get(&self, prop: &str) -> Option<&str> {
-        self.properties.get(prop).map(String::as_ref)
-    }
-
-    /// Tests if matches is a subset of the properties
-    ///
-    /// If properties is from a remote instance (`boardswarm.instance` is set) that has to be
-    /// explicitly matched otherwise it's a pure subset match (e.g. an empty set matches)
-    pub fn matches<K, V, I>(&self, matches: I) -> bool
-    where
-        K: AsRef<str>,
-        V: AsRef<str>,
-        I: IntoIterator<Item = (K, V)>,
-    {
-        let mut matched_instance = false;
-        let matched = matches.into_iter().all(|(k, v)| {
-            matched_instance |= k.as_ref() == INSTANCE;
-            if let Some(prop) = self.get(k.as_ref()) {
-                prop == v.as_ref()
-            } else {
-                false
-            }
-        });
-
-        // All the properties need to match and if the instance is declared in the properties that
-        // also needed to be matched against
-        matched && matched_instance == self.instance().is_some()
-    }
-
-    pub fn insert<K, V>(&mut self, key: K, value: V)
-    where
-        K: Into<String>,
-        V: Into<String>,
-    {
-        self.properties.insert(key.into(), value.into());
-    }
+/// Number of changes kept for monitors before they start lagging
+pub const DEFAULT_CAPACITY: usize = 16;
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
-        self.properties.iter()
-    }
-}
-
-impl<K, V> Extend<(K, V)> for Properties
-where
-    K: Into<String>,
-    V: Into<String>,
-{
-    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
-        for (key, value) in iter.into_iter() {
-            self.properties.insert(key.into(), value.into());
-        }
-    }
-}
-
-impl<'a, K, V> Extend<&'a (K, V)> for Properties
-where
-    K: ToString,
-    V: ToString,
-{
-    fn extend<T: IntoIterator<Item = &'a (K, V)>>(&mut self, iter: T) {
-        for (key, value) in iter.into_iter() {
-            self.properties.insert(key.to_string(), value.to_string());
-        }
-    }
-}
-
-impl From<HashMap<String, String>> for Properties {
-    fn from(properties: HashMap<String, String>) -> Self {
-        Properties { properties }
-    }
-}
+/// Number of removed items remembered
+pub const REMOVED_CAPACITY: usize = 32;
 
 #[derive(Clone, Debug)]
 pub struct Item<T> {
     properties: Arc<Properties>,
     item: T,
+    /// Number of times the properties were updated since the item was added
+    revision: u64,
 }
 
 impl<T> std::fmt::Display for Item<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())?;
         if let Some(instance) = self.instance() {
-            write!(f, "{} on {}", self.name(), instance)
-        } else {
-            write!(f, "{}", self.name())
+            write!(f, " on {}", instance)?;
+        }
+        if let Some(identity) = self.properties.identity() {
+            write!(f, " [{}]", identity)?;
         }
+        Ok(())
     }
 }
 
@@ -125,6 +45,7 @@ impl<T> Item<T> {
         Item {
             properties: Arc::new(properties),
             item,
+            revision: 0,
         }
     }
     pub fn name(&self) -> &str {
@@ -148,9 +69,25 @@ impl<T> Item<T> {
     }
 }
 
+/// Last known state of an item removed from the registry
+#[derive(Clone, Debug)]
+pub struct Removed {
+    pub id: u64,
+    pub properties: Arc<Properties>,
+    pub at: SystemTime,
+}
+
 #[derive(Clone)]
 pub enum RegistryChange<T> {
-    Added { id: u64, item: Item<T> },
+    Added {
+        id: u64,
+        item: Item<T>,
+    },
+    /// The properties of the item changed in place
+    Updated {
+        id: u64,
+        item: Item<T>,
+    },
     Removed(u64),
 }
 
@@ -158,6 +95,8 @@ pub enum RegistryChange<T> {
 struct RegistryInner<T> {
     next: u64,
     contents: BTreeMap<u64, Item<T>>,
+    /// Most recently removed items, oldest first
+    removed: VecDeque<Removed>,
 }
 
 #[derive(Debug)]
@@ -171,11 +110,16 @@ where
     T: Clone,
 {
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
-            monitor: broadcast::channel(16).0,
+            monitor: broadcast::channel(capacity.max(1)).0,
             inner: RwLock::new(RegistryInner {
                 next: 0,
                 contents: BTreeMap::new(),
+                removed: VecDeque::new(),
             }),
         }
     }
@@ -193,13 +137,41 @@ where
         (id, item)
     }
 
+    /// Replace the properties of a registered item, keeping its id
+    pub fn update(&self, id: u64, properties: Properties) -> Option<Item<T>> {
+        let mut inner = self.inner.write().unwrap();
+        let item = inner.contents.get_mut(&id)?;
+        item.properties = Arc::new(properties);
+        item.revision += 1;
+        let item = item.clone();
+        let _ = self.monitor.send(RegistryChange::Updated {
+            id,
+            item: item.clone(),
+        });
+        Some(item)
+    }
+
     pub fn remove(&self, id: u64) {
         let mut inner = self.inner.write().unwrap();
-        if let Some(_item) = inner.contents.remove(&id) {
+        if let Some(item) = inner.contents.remove(&id) {
+            if inner.removed.len() == REMOVED_CAPACITY {
+                inner.removed.pop_front();
+            }
+            inner.removed.push_back(Removed {
+                id,
+                properties: item.properties,
+                at: SystemTime::now(),
+            });
             let _ = self.monitor.send(RegistryChange::Removed(id));
         }
     }
 
+    /// Recently removed items, most recent first
+    pub fn removed(&self) -> Vec<Removed> {
+        let inner = self.inner.read().unwrap();
+        inner.removed.iter().rev().cloned().collect()
+    }
+
     pub fn lookup(&self, id: u64) -> Option<Item<T>> {
         let inner = self.inner.read().unwrap();
         inner.contents.get(&id).cloned()
@@ -223,7 +195,7 @@ where
     pub fn find<'a, K, V, I>(&self, matches: &'a I) -> Option<(u64, Item<T>)>
     where
         K: AsRef<str>,
-        V: AsRef<str>,
+        V: ValueMatch,
         &'a I: IntoIterator<Item = (K, V)>,
     {
         let inner = self.inner.read().unwrap();
@@ -234,9 +206,65 @@ where
             .map(|(&id, item)| (id, item.clone()))
     }
 
+    /// Wait for an item matching the properties to be registered
+    pub async fn wait_for<'a, K, V, I>(&self, matches: &'a I) -> (u64, Item<T>)
+    where
+        K: AsRef<str>,
+        V: ValueMatch,
+        &'a I: IntoIterator<Item = (K, V)>,
+    {
+        // Monitor before looking, so items added in between aren't missed
+        let mut monitor = self.monitor();
+        loop {
+            if let Some(found) = self.find(matches) {
+                return found;
+            }
+            loop {
+                match monitor.recv().await {
+                    Ok(
+                        RegistryChange::Added { id, item } | RegistryChange::Updated { id, item },
+                    ) if item.properties.matches(matches) => return (id, item),
+                    Ok(_) => (),
+                    // Missed some changes, so look again
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+                }
+            }
+        }
+    }
+
     pub fn monitor(&self) -> Receiver<RegistryChange<T>> {
         self.monitor.subscribe()
     }
+
+    /// Changes needed to bring a view only knowing about the `known` ids up to date
+    ///
+    /// Used to recover when a monitor lagged and missed some changes; As it's not known which
+    /// updates were missed, known items that were ever updated are reported as updated
+    pub fn resync(&self, known: &BTreeSet<u64>) -> Vec<RegistryChange<T>> {
+        let inner = self.inner.read().unwrap();
+        known
+            .iter()
+            .filter_map(|&id| match inner.contents.get(&id) {
+                None => Some(RegistryChange::Removed(id)),
+                Some(item) if item.revision > 0 => Some(RegistryChange::Updated {
+                    id,
+                    item: item.clone(),
+                }),
+                Some(_) => None,
+            })
+            .chain(
+                inner
+                    .contents
+                    .iter()
+                    .filter(|(id, _)| !known.contains(id))
+                    .map(|(&id, item)| RegistryChange::Added {
+                        id,
+                        item: item.clone(),
+                    }),
+            )
+            .collect()
+    }
 }
 
 impl<T> Default for Registry<T>
@@ -253,29 +281,98 @@ mod test {
     use super::*;
 
     #[test]
-    fn properties() {
-        let mut props = Properties::new("test");
-        props.insert("udev.BADGER", "5");
-
-        assert_eq!(props.get(NAME), Some("test"));
-        assert_eq!(props.name(), "test");
-
-        let mut t = HashMap::new();
-        t.insert(NAME.to_string(), "test".to_string());
-        assert!(props.matches(&t));
-
-        let empty: HashMap<String, String> = HashMap::new();
-        assert!(props.matches(empty));
+    fn resync() {
+        let registry = Registry::with_capacity(1);
+        let mut monitor = registry.monitor();
+        let (first, _) = registry.add(Properties::new("first"), ());
+        let (second, _) = registry.add(Properties::new("second"), ());
+        registry.remove(first);
+        assert!(matches!(
+            monitor.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(_))
+        ));
+
+        let known = BTreeSet::from([first]);
+        let changes = registry.resync(&known);
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(changes[0], RegistryChange::Removed(id) if id == first));
+        assert!(matches!(
+            changes[1],
+            RegistryChange::Added { id, ref item } if id == second && item.name() == "second"
+        ));
+
+        let known = BTreeSet::from([second]);
+        assert!(registry.resync(&known).is_empty());
+    }
 
-        assert!(props.matches([(NAME, "test")]));
-        assert!(props.matches([("udev.BADGER", "5")]));
+    #[test]
+    fn update() {
+        let registry = Registry::new();
+        let (id, _) = registry.add(Properties::new("ttyUSB0"), ());
+        let mut monitor = registry.monitor();
+        let mut properties = Properties::new("ttyUSB0");
+        properties.insert("udev.ID_MODEL", "FT232R");
+        registry.update(id, properties).unwrap();
+        assert!(registry.update(id + 1, Properties::new("other")).is_none());
+
+        assert!(matches!(
+            monitor.try_recv(),
+            Ok(RegistryChange::Updated { id: updated, ref item })
+                if updated == id && item.properties().get("udev.ID_MODEL") == Some("FT232R")
+        ));
+        assert_eq!(
+            registry
+                .lookup(id)
+                .unwrap()
+                .properties()
+                .get("udev.ID_MODEL"),
+            Some("FT232R")
+        );
+
+        // Updates may have been missed by a lagging monitor
+        let changes = registry.resync(&BTreeSet::from([id]));
+        assert!(
+            matches!(changes[..], [RegistryChange::Updated { id: updated, .. }] if updated == id)
+        );
+    }
 
-        assert!(!props.matches([(NAME, "no")]));
-        assert!(!props.matches([("udev.BADGER", "7")]));
-        assert!(!props.matches([("udev.SNAKE", "7")]));
+    #[test]
+    fn removed() {
+        let registry = Registry::new();
+        let ids: Vec<u64> = (0..=REMOVED_CAPACITY)
+            .map(|i| registry.add(Properties::new(format!("item{i}")), ()).0)
+            .collect();
+        for &id in &ids {
+            registry.remove(id);
+        }
+        // Removing an unknown item doesn't add to the history
+        registry.remove(ids[0]);
+
+        let removed = registry.removed();
+        assert_eq!(removed.len(), REMOVED_CAPACITY);
+        assert_eq!(removed[0].id, ids[REMOVED_CAPACITY]);
+        assert_eq!(
+            removed[0].properties.name(),
+            format!("item{REMOVED_CAPACITY}")
+        );
+        assert_eq!(removed[REMOVED_CAPACITY - 1].id, ids[1]);
+    }
 
-        assert!(props.matches([(NAME, "test"), ("udev.BADGER", "5")]));
-        assert!(!props.matches([(NAME, "test"), ("udev.BADGER", "7")]));
-        assert!(!props.matches([(NAME, "test"), ("udev.SNAKE", "5")]));
+    #[tokio::test]
+    async fn wait_for() {
+        let registry = Arc::new(Registry::new());
+        let matches = std::collections::HashMap::from([(NAME, "dfu")]);
+        let (existing, _) = registry.add(Properties::new("dfu"), ());
+        assert_eq!(registry.wait_for(&matches).await.0, existing);
+        registry.remove(existing);
+
+        let waiting = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.wait_for(&matches).await.0 }
+        });
+        tokio::task::yield_now().await;
+        registry.add(Properties::new("other"), ());
+        let (added, _) = registry.add(Properties::new("dfu"), ());
+        assert_eq!(waiting.await.unwrap(), added);
     }
 }
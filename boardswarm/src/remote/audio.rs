@@ -0,0 +1,35 @@
+use boardswarm_client::client::Boardswarm;
+use boardswarm_protocol::AudioEncoding;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+
+use crate::{AudioError, AudioInfo};
+
+#[derive(Debug)]
+pub struct BoardswarmAudio {
+    id: u64,
+    remote: Boardswarm,
+}
+
+impl BoardswarmAudio {
+    pub fn new(id: u64, remote: Boardswarm) -> Self {
+        Self { id, remote }
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Audio for BoardswarmAudio {
+    async fn capture(
+        &self,
+    ) -> Result<(AudioInfo, BoxStream<'static, Result<Bytes, AudioError>>), AudioError> {
+        let mut remote = self.remote.clone();
+        let (info, data) = remote
+            .audio_stream(self.id, AudioEncoding::Pcm)
+            .await
+            .map_err(|e| AudioError::Unavailable(e.message().to_string()))?;
+        let data = data
+            .map(|data| data.map_err(|_e| AudioError::Closed))
+            .boxed();
+        Ok((info, data))
+    }
+}
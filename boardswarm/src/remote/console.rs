@@ -1,12 +1,14 @@
 use std::{pin::Pin, sync::Mutex};
 
 use boardswarm_client::client::Boardswarm;
-use boardswarm_protocol::Parameters;
+use boardswarm_protocol::{console_signal_request::Signal, Parameters};
 use bytes::Bytes;
 use futures::StreamExt;
 use serde::Deserialize;
 use tokio::sync::mpsc::Receiver;
 
+use crate::{ConsoleError, ConsoleSignal};
+
 #[derive(Debug)]
 pub struct BoardswarmConsole {
     id: u64,
@@ -88,4 +90,22 @@ impl crate::Console for BoardswarmConsole {
             remote.console_stream_output(self.id).await.unwrap().map(Ok),
         ))
     }
+
+    async fn signal(&self, signal: ConsoleSignal) -> Result<(), ConsoleError> {
+        let signal = match signal {
+            ConsoleSignal::Break(duration) => {
+                Signal::BreakMs(duration.as_millis().try_into().unwrap_or(u32::MAX))
+            }
+            ConsoleSignal::Dtr(on) => Signal::Dtr(on),
+            ConsoleSignal::Rts(on) => Signal::Rts(on),
+        };
+        let mut remote = self.remote.clone();
+        remote
+            .console_signal(self.id, None, signal)
+            .await
+            .map_err(|e| match e.code() {
+                tonic::Code::Unimplemented => ConsoleError::Unsupported,
+                _ => ConsoleError::Unavailable(e.message().to_string()),
+            })
+    }
 }
@@ -8,7 +8,10 @@ use futures::{pin_mut, Stream, StreamExt};
 use tokio::sync::broadcast;
 use tracing::{trace, warn};
 
-use crate::{DeviceMonitor, DeviceSetModeError};
+use crate::{
+    DeviceCheck, DeviceHealth, DeviceMonitor, DevicePower, DeviceSelfTestError, DeviceSetModeError,
+    ModeProgress,
+};
 
 use super::Provider;
 
@@ -53,6 +56,18 @@ impl BoardswarmDevice {
 
         Ok(s)
     }
+
+    /// Map the status of a failed remote mode change back to a mode change error
+    fn mode_error(&self, e: tonic::Status) -> DeviceSetModeError {
+        match e.code() {
+            tonic::Code::NotFound => DeviceSetModeError::ModeNotFound,
+            tonic::Code::FailedPrecondition => DeviceSetModeError::WrongCurrentMode,
+            tonic::Code::Unavailable => {
+                DeviceSetModeError::Busy(crate::Device::mode_change(self).unwrap_or_default())
+            }
+            _ => DeviceSetModeError::ActuatorFailed(crate::ActuatorError {}),
+        }
+    }
 }
 
 impl BoardswarmDeviceInner {
@@ -161,12 +176,52 @@ impl crate::Device for BoardswarmDevice {
         client
             .device_change_mode(self.id, mode.to_string())
             .await
+            .map_err(|e| self.mode_error(e))
+    }
+
+    async fn try_set_mode(&self, mode: &str) -> Result<(), DeviceSetModeError> {
+        let mut client = self.remote.clone();
+        client
+            .device_try_change_mode(self.id, mode.to_string())
+            .await
+            .map_err(|e| self.mode_error(e))
+    }
+
+    async fn set_mode_with_progress(
+        &self,
+        mode: &str,
+        wait: bool,
+        progress: ModeProgress,
+    ) -> Result<(), DeviceSetModeError> {
+        let mut client = self.remote.clone();
+        let mut remote = client
+            .device_change_mode_progress(self.id, mode.to_string(), !wait)
+            .await
+            .map_err(|e| self.mode_error(e))?;
+        while let Some(p) = remote.message().await.map_err(|e| self.mode_error(e))? {
+            let _ = progress.send(p);
+        }
+        Ok(())
+    }
+
+    async fn self_test(&self) -> Result<Vec<DeviceCheck>, DeviceSelfTestError> {
+        let mut client = self.remote.clone();
+        let report = client
+            .device_self_test(self.id)
+            .await
             .map_err(|e| match e.code() {
-                tonic::Code::NotFound => DeviceSetModeError::ModeNotFound,
-                tonic::Code::FailedPrecondition => DeviceSetModeError::WrongCurrentMode,
-                tonic::Code::Aborted => DeviceSetModeError::ActuatorFailed(crate::ActuatorError {}),
-                _ => DeviceSetModeError::ActuatorFailed(crate::ActuatorError {}),
+                tonic::Code::Unimplemented => DeviceSelfTestError::Unsupported,
+                _ => DeviceSelfTestError::Failed(e.message().to_string()),
+            })?;
+        Ok(report
+            .checks
+            .into_iter()
+            .map(|c| DeviceCheck {
+                name: c.name,
+                passed: c.passed,
+                message: c.message,
             })
+            .collect())
     }
 
     fn updates(&self) -> DeviceMonitor {
@@ -197,6 +252,7 @@ impl crate::Device for BoardswarmDevice {
             .map(|v| crate::DeviceVolume {
                 name: v.name.to_string(),
                 id: v.id.and_then(|id| inner.volume_mapping.get(&id).copied()),
+                mode: v.mode.clone(),
             })
             .collect()
     }
@@ -219,4 +275,31 @@ impl crate::Device for BoardswarmDevice {
         let inner = self.inner.lock().unwrap();
         inner.info.current_mode.clone()
     }
+
+    fn mode_change(&self) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.info.mode_change.clone()
+    }
+
+    async fn health_check(&self) -> Result<DeviceHealth, DeviceSelfTestError> {
+        let mut client = self.remote.clone();
+        let health = client
+            .device_health_check(self.id)
+            .await
+            .map_err(|e| match e.code() {
+                tonic::Code::Unimplemented => DeviceSelfTestError::Unsupported,
+                _ => DeviceSelfTestError::Failed(e.message().to_string()),
+            })?;
+        Ok(health.into())
+    }
+
+    fn health(&self) -> Option<DeviceHealth> {
+        let inner = self.inner.lock().unwrap();
+        inner.info.health.clone().map(Into::into)
+    }
+
+    fn power(&self) -> Option<DevicePower> {
+        let inner = self.inner.lock().unwrap();
+        inner.info.power.clone().map(Into::into)
+    }
 }
@@ -6,7 +6,7 @@ use std::time::Duration;
 
 use boardswarm_client::client::BoardswarmBuilder;
 use boardswarm_client::client::{Boardswarm, ItemEvent};
-use boardswarm_protocol::ItemType;
+use boardswarm_protocol::{Item, ItemType};
 use futures::{pin_mut, TryStreamExt};
 use serde::Deserialize;
 use tokio::join;
@@ -18,11 +18,13 @@ use tracing::warn;
 use crate::{registry::Properties, Server};
 
 use self::actuator::BoardswarmActuator;
+use self::audio::BoardswarmAudio;
 use self::console::BoardswarmConsole;
 use self::device::BoardswarmDevice;
 use self::volume::BoardswarmVolume;
 
 mod actuator;
+mod audio;
 mod console;
 mod device;
 mod volume;
@@ -33,6 +35,9 @@ pub const PROVIDER: &str = "boardswarm";
 struct BoardswarmParameters {
     uri: String,
     token: PathBuf,
+    /// Request compressed console output from the remote server
+    #[serde(default)]
+    compress: bool,
 }
 
 pub struct Provider {
@@ -41,6 +46,7 @@ pub struct Provider {
     consoles: Mutex<HashMap<u64, u64>>,
     devices: Mutex<HashMap<u64, u64>>,
     volumes: Mutex<HashMap<u64, u64>>,
+    audio: Mutex<HashMap<u64, u64>>,
     notifier: broadcast::Sender<()>,
 }
 
@@ -50,11 +56,13 @@ impl Provider {
         let consoles = Mutex::new(HashMap::new());
         let volumes = Mutex::new(HashMap::new());
         let devices = Mutex::new(HashMap::new());
+        let audio = Mutex::new(HashMap::new());
         Self {
             actuators,
             consoles,
             volumes,
             devices,
+            audio,
             notifier: broadcast::channel(1).0,
         }
     }
@@ -77,17 +85,36 @@ impl Provider {
     }
 }
 
+async fn item_properties(
+    remote: &mut Boardswarm,
+    type_: ItemType,
+    item: Item,
+    instance: &str,
+) -> Properties {
+    // Older servers don't include the properties in the monitor events
+    let properties: HashMap<String, String> = if item.property.is_empty() {
+        remote.properties(type_, item.id).await.unwrap()
+    } else {
+        item.property
+            .into_iter()
+            .map(|p| (p.key, p.value))
+            .collect()
+    };
+    let mut properties: Properties = properties.into();
+    properties.insert(crate::registry::INSTANCE, instance);
+    properties
+}
+
 async fn add_item(
     provider: Arc<Provider>,
     type_: ItemType,
     server: &Server,
     mut remote: Boardswarm,
-    id: u64,
+    item: Item,
     instance: &str,
 ) {
-    let properties = remote.properties(type_, id).await.unwrap();
-    let mut properties: Properties = properties.into();
-    properties.insert(crate::registry::INSTANCE, instance);
+    let id = item.id;
+    let properties = item_properties(&mut remote, type_, item, instance).await;
 
     match type_ {
         ItemType::Console => {
@@ -112,10 +139,36 @@ async fn add_item(
             }
             Err(e) => warn!("Failed to setup remote volume: {e}"),
         },
+        ItemType::Audio => {
+            let local = server.register_audio(properties, BoardswarmAudio::new(id, remote));
+            provider.audio.lock().unwrap().insert(id, local);
+        }
     }
     let _ = provider.notifier.send(());
 }
 
+async fn update_item(
+    provider: &Provider,
+    type_: ItemType,
+    server: &Server,
+    mut remote: Boardswarm,
+    item: Item,
+    instance: &str,
+) {
+    let id = item.id;
+    let local = match type_ {
+        ItemType::Console => provider.consoles.lock().unwrap().get(&id).copied(),
+        ItemType::Actuator => provider.actuators.lock().unwrap().get(&id).copied(),
+        ItemType::Device => provider.devices.lock().unwrap().get(&id).copied(),
+        ItemType::Volume => provider.volumes.lock().unwrap().get(&id).copied(),
+        ItemType::Audio => provider.audio.lock().unwrap().get(&id).copied(),
+    };
+    if let Some(local) = local {
+        let properties = item_properties(&mut remote, type_, item, instance).await;
+        server.update_item(type_, local, properties);
+    }
+}
+
 fn remove_item(provider: &Provider, type_: ItemType, server: &Server, id: u64) {
     match type_ {
         ItemType::Console => {
@@ -142,6 +195,12 @@ fn remove_item(provider: &Provider, type_: ItemType, server: &Server, id: u64) {
                 server.unregister_volume(local)
             }
         }
+        ItemType::Audio => {
+            let mut audio = provider.audio.lock().unwrap();
+            if let Some(local) = audio.remove(&id) {
+                server.unregister_audio(local)
+            }
+        }
     }
     let _ = provider.notifier.send(());
 }
@@ -153,7 +212,15 @@ async fn monitor_items(
     server: Server,
     instance: &str,
 ) {
-    let monitor = remote.monitor(type_).await.unwrap();
+    let monitor = match remote.monitor_with_properties(type_).await {
+        Ok(monitor) => monitor,
+        // Older servers don't know about all item types
+        Err(e) if e.code() == tonic::Code::InvalidArgument => {
+            warn!("Remote doesn't support monitoring {:?} items", type_);
+            return;
+        }
+        Err(e) => panic!("Failed to monitor {:?} items: {}", type_, e),
+    };
     pin_mut!(monitor);
     while let Ok(Some(event)) = monitor.try_next().await {
         match event {
@@ -164,7 +231,7 @@ async fn monitor_items(
                         type_,
                         &server,
                         remote.clone(),
-                        i.id,
+                        i,
                         instance,
                     )
                     .await
@@ -173,6 +240,11 @@ async fn monitor_items(
             ItemEvent::Removed(removed) => {
                 remove_item(&provider, type_, &server, removed);
             }
+            ItemEvent::Updated(items) => {
+                for i in items {
+                    update_item(&provider, type_, &server, remote.clone(), i, instance).await
+                }
+            }
         }
     }
 
@@ -198,6 +270,11 @@ async fn monitor_items(
                 server.unregister_volume(local);
             }
         }
+        ItemType::Audio => {
+            for (_remote, local) in provider.audio.lock().unwrap().drain() {
+                server.unregister_audio(local);
+            }
+        }
     }
 }
 
@@ -219,6 +296,7 @@ pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Serve
             let _span = tracing::span!(tracing::Level::INFO, "boardswarm", name);
             let mut boardswarm = BoardswarmBuilder::new(uri.clone());
             boardswarm.auth_static(&token);
+            boardswarm.compress_console_output(parameters.compress);
             if let Ok(remote) = boardswarm.connect().await {
                 info!("Connected to {}", name);
                 let consoles = monitor_items(
@@ -245,12 +323,19 @@ pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Serve
                 let volumes = monitor_items(
                     provider.clone(),
                     ItemType::Volume,
+                    remote.clone(),
+                    server.clone(),
+                    &name,
+                );
+                let audio = monitor_items(
+                    provider.clone(),
+                    ItemType::Audio,
                     remote,
                     server.clone(),
                     &name,
                 );
 
-                join!(consoles, actuators, devices, volumes);
+                join!(consoles, actuators, devices, volumes, audio);
                 info!("Connection to {} failed", name);
             }
             // TODO move to exponential backoff
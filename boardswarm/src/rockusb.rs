@@ -15,6 +15,26 @@ use crate::{
 
 pub const PROVIDER: &str = "rockusb";
 
+/// Rockchip SoC family by USB product id; The loader reuses the product id of the maskrom
+fn soc(product: u64) -> Option<&'static str> {
+    Some(match product {
+        0x300a => "rk3066",
+        0x301a => "rk3036",
+        0x310b => "rk3188",
+        0x310c => "rk3128",
+        0x320a => "rk3288",
+        0x320b => "rk3229",
+        0x320c => "rk3328",
+        0x330a => "rk3368",
+        0x330c => "rk3399",
+        0x330d => "px30",
+        0x330e => "rk3308",
+        0x350a => "rk3568",
+        0x350b => "rk3588",
+        _ => return None,
+    })
+}
+
 #[instrument(skip(server))]
 pub async fn start_provider(name: String, server: Server) {
     let provider_properties = &[
@@ -50,7 +70,7 @@ pub async fn start_provider(name: String, server: Server) {
                 let name = if let Some(model) = device.property("ID_MODEL_ID") {
                     format!("{}/{} {}", busnum, devnum, model)
                 } else {
-                    format!("{}/{}", devnum, devnum)
+                    format!("{}/{}", busnum, devnum)
                 };
                 info!("New rockusb volume: {name}");
 
@@ -58,6 +78,10 @@ pub async fn start_provider(name: String, server: Server) {
                     Ok(rockusb) => {
                         let mut properties = device.properties(name);
                         properties.extend(provider_properties);
+                        properties.insert(format!("{PROVIDER}.mode"), rockusb.mode_name());
+                        if let Some(soc) = device.property_u64("ID_MODEL_ID", 16).and_then(soc) {
+                            properties.insert(format!("{PROVIDER}.soc"), soc);
+                        }
                         let id = server.register_volume(properties, rockusb);
                         registrations.insert(device.syspath().to_path_buf(), id);
                     }
@@ -71,6 +95,7 @@ pub async fn start_provider(name: String, server: Server) {
                     server.unregister_volume(id)
                 }
             }
+            DeviceEvent::Change(_) => (),
         }
     }
 }
@@ -140,6 +165,8 @@ impl Rockusb {
                     seekable: false,
                     size: None,
                     blocksize: None,
+                    kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                    sha256: None,
                 },
                 VolumeTargetInfo {
                     name: "472".to_string(),
@@ -148,6 +175,8 @@ impl Rockusb {
                     seekable: false,
                     size: None,
                     blocksize: None,
+                    kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                    sha256: None,
                 },
             ],
             RockUsbMode::Loader(l) => vec![VolumeTargetInfo {
@@ -157,6 +186,8 @@ impl Rockusb {
                 seekable: true,
                 size: Some(l.1),
                 blocksize: Some(512),
+                kind: boardswarm_protocol::VolumeTargetKind::BlockDevice.into(),
+                sha256: None,
             }],
         };
 
@@ -167,6 +198,14 @@ impl Rockusb {
         })
     }
 
+    /// Name of the mode the device is in, either maskrom or loader
+    fn mode_name(&self) -> &'static str {
+        match self.mode {
+            RockUsbMode::MaskRom => "maskrom",
+            RockUsbMode::Loader(_) => "loader",
+        }
+    }
+
     async fn determine_mode(
         commands: &mpsc::Sender<RockUsbCommand>,
     ) -> Result<RockUsbMode, RockUsbError> {
@@ -337,6 +376,15 @@ impl VolumeTarget for RockUsbTarget {
     async fn flush(&mut self, completion: crate::FlushCompletion) {
         completion.complete(self.do_flush().await);
     }
+
+    async fn abort(&mut self) {
+        // Close the device once the operation in progress finished, without writing out a
+        // partial block, such that it can be opened again as soon as the upload ended
+        let (tx, rx) = oneshot::channel();
+        if self.operations.send(RockUsbIO::Abort(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -351,6 +399,7 @@ enum RockUsbIO {
     Write(Bytes, u64, oneshot::Sender<Result<(), std::io::Error>>),
     Read(u64, u64, oneshot::Sender<Result<Bytes, std::io::Error>>),
     Flush(oneshot::Sender<Result<(), std::io::Error>>),
+    Abort(oneshot::Sender<()>),
 }
 
 fn open_device(info: DeviceInfo) -> Result<Transport, RockUsbError> {
@@ -404,6 +453,11 @@ async fn handle_io(
                 let r = io.flush().await;
                 let _ = tx.send(r);
             }
+            RockUsbIO::Abort(tx) => {
+                drop(io);
+                let _ = tx.send(());
+                return;
+            }
         }
     }
 }
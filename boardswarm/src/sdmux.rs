@@ -0,0 +1,186 @@
+use std::io::SeekFrom;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncSeekExt;
+use tokio::process::Command;
+use tracing::{instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    utils::FileTarget,
+    ActuatorError, Server, Volume, VolumeError, VolumeTarget, VolumeTargetInfo,
+};
+
+pub const PROVIDER: &str = "sdmux";
+
+/// Name of the single target of the card volume
+const TARGET: &str = "card";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Control {
+    /// Linux Automation usbsdmux, controlled through its SCSI generic device
+    Usbsdmux { control: PathBuf },
+    /// SDWire (and other sd-mux-ctrl compatible muxes), identified by the serial of its FTDI chip
+    Sdwire { serial: String },
+}
+
+#[derive(Deserialize, Debug)]
+struct Mux {
+    name: String,
+    #[serde(flatten)]
+    control: Control,
+    /// Block device of the card while it's attached to the host, preferably a stable
+    /// /dev/disk/by-id or by-path link
+    disk: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct SdMuxParameters {
+    muxes: Vec<Mux>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: SdMuxParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for mux in parameters.muxes {
+        let mut properties = Properties::new(&mux.name);
+        properties.extend(provider_properties);
+        properties.insert(
+            format!("{PROVIDER}.disk"),
+            mux.disk.to_string_lossy().into_owned(),
+        );
+
+        server.register_actuator(properties.clone(), SdMuxActuator(mux.control));
+        server.register_volume(properties, SdMuxVolume::new(mux.disk));
+    }
+}
+
+#[derive(Debug)]
+struct SdMuxActuator(Control);
+
+impl SdMuxActuator {
+    fn command(&self, mode: &str) -> Option<Command> {
+        match &self.0 {
+            Control::Usbsdmux { control } => {
+                if !matches!(mode, "host" | "dut" | "off") {
+                    return None;
+                }
+                let mut command = Command::new("usbsdmux");
+                command.arg(control).arg(mode);
+                Some(command)
+            }
+            Control::Sdwire { serial } => {
+                let switch = match mode {
+                    "host" => "--ts",
+                    "dut" => "--dut",
+                    _ => return None,
+                };
+                let mut command = Command::new("sd-mux-ctrl");
+                command.arg(format!("--device-serial={serial}")).arg(switch);
+                Some(command)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for SdMuxActuator {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let Some(mut command) = self.command(&parameters.mode) else {
+            warn!("Unsupported sd mux mode: {}", parameters.mode);
+            return Err(ActuatorError {});
+        };
+        match command.status().await {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => {
+                warn!("Switching sd mux failed: {}", status);
+                Err(ActuatorError {})
+            }
+            Err(e) => {
+                warn!("Failed to run sd mux control tool: {}", e);
+                Err(ActuatorError {})
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SdMuxVolume {
+    disk: PathBuf,
+    targets: [VolumeTargetInfo; 1],
+}
+
+impl SdMuxVolume {
+    fn new(disk: PathBuf) -> Self {
+        Self {
+            disk,
+            targets: [VolumeTargetInfo {
+                name: TARGET.to_string(),
+                readable: true,
+                writable: true,
+                seekable: true,
+                size: None,
+                blocksize: Some(512),
+                kind: boardswarm_protocol::VolumeTargetKind::BlockDevice.into(),
+                sha256: None,
+            }],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Volume for SdMuxVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (&self.targets, true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        _length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        if target != TARGET {
+            return Err(VolumeError::UnknownTargetRequested);
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.disk)
+            .await
+            .map_err(|e| {
+                VolumeError::Failure(format!(
+                    "Card not available on the host ({}): {}",
+                    self.disk.display(),
+                    e
+                ))
+            })?;
+        let size = file
+            .seek(SeekFrom::End(0))
+            .await
+            .map_err(|e| VolumeError::Failure(e.to_string()))?;
+
+        let mut info = self.targets[0].clone();
+        info.size = Some(size);
+        Ok((info, Box::new(FileTarget::new(file))))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        Ok(())
+    }
+}
@@ -4,9 +4,15 @@ use futures::ready;
 use serde::Deserialize;
 use std::{
     collections::HashMap,
+    os::unix::{io::AsRawFd, io::RawFd},
+    path::PathBuf,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 use tracing::instrument;
 use tracing::warn;
@@ -16,12 +22,22 @@ use futures::prelude::*;
 use futures::stream::Stream;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, WriteHalf},
-    sync::{broadcast, Mutex as AsyncMutex},
+    sync::{broadcast, mpsc, Mutex as AsyncMutex},
 };
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
 pub const PROVIDER: &str = "serial";
 
+/// Property set on consoles for ports that failed to open
+pub const ERROR_PROPERTY: &str = "serial.error";
+
+/// Delay before retrying the first open of a port; Doubled for each next attempt
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const OPEN_ATTEMPTS: u32 = 6;
+
+/// Outcome of opening a port, identified by its syspath; None when it opened
+type OpenStatus = (PathBuf, Option<String>);
+
 pub trait SerialProvider {
     fn handle(&mut self, device: &crate::udev::Device, seqnum: u64) -> bool;
     fn remove(&mut self, device: &crate::udev::Device);
@@ -53,45 +69,135 @@ impl SerialDevices {
             (registry::PROVIDER_NAME, self.name.as_str()),
             (registry::PROVIDER, PROVIDER),
         ];
-        let mut registrations = HashMap::new();
+        let mut registrations: HashMap<PathBuf, (u64, Properties)> = HashMap::new();
+        let (status_tx, mut status) = mpsc::unbounded_channel();
         let mut devices = crate::udev::DeviceStream::new("tty").unwrap();
-        while let Some(event) = devices.next().await {
-            match event {
-                DeviceEvent::Add { device, seqnum } => {
-                    if device.parent().is_none() {
-                        continue;
-                    }
-                    let mut providers = self.providers.lock().unwrap();
-                    // Check if one of the providers wants to handle it, if so skip
-                    if providers.iter_mut().any(|p| p.handle(&device, seqnum)) {
-                        continue;
-                    }
-                    if let Some(node) = device.devnode() {
-                        if let Some(name) = node.file_name() {
-                            let name = name.to_string_lossy().into_owned();
-                            let path = node.to_string_lossy().into_owned();
-                            let console = SerialPort::new(path);
-                            let mut properties = device.properties(name);
+        loop {
+            tokio::select! {
+                event = devices.next() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    match event {
+                        DeviceEvent::Add { device, seqnum } => {
+                            if device.parent().is_none() {
+                                continue;
+                            }
+                            let mut providers = self.providers.lock().unwrap();
+                            // Check if one of the providers wants to handle it, if so skip
+                            if providers.iter_mut().any(|p| p.handle(&device, seqnum)) {
+                                continue;
+                            }
+                            if let Some(node) = device.devnode() {
+                                if let Some(name) = node.file_name() {
+                                    let name = name.to_string_lossy().into_owned();
+                                    let path = node.to_string_lossy().into_owned();
+                                    let mut properties = device.properties(name);
+                                    properties.extend(provider_properties);
+                                    let syspath = device.syspath().to_path_buf();
+                                    let console =
+                                        SerialPort::new(path, syspath.clone(), status_tx.clone());
+                                    let id = self
+                                        .server
+                                        .register_console(properties.clone(), console);
+                                    registrations.insert(syspath, (id, properties));
+                                }
+                            }
+                        }
+                        DeviceEvent::Remove(device) => {
+                            let mut providers = self.providers.lock().unwrap();
+                            providers.iter_mut().for_each(|p| p.remove(&device));
+                            if let Some((id, _)) = registrations.remove(device.syspath()) {
+                                self.server.unregister_console(id)
+                            }
+                        }
+                        // Refresh the properties in place, so users of the console keep it
+                        DeviceEvent::Change(device) => {
+                            let Some((id, registered)) = registrations.get_mut(device.syspath())
+                            else {
+                                continue;
+                            };
+                            let Some(name) = device.devnode().and_then(|n| n.file_name()) else {
+                                continue;
+                            };
+                            let mut properties = device.properties(name.to_string_lossy());
                             properties.extend(provider_properties);
-                            let id = self.server.register_console(properties, console);
-                            registrations.insert(device.syspath().to_path_buf(), id);
+                            *registered = properties.clone();
+                            // The port isn't opened again, so keep a failure from opening it
+                            let error = self.server.inner.consoles.lookup(*id).and_then(|c| {
+                                c.properties().get(ERROR_PROPERTY).map(ToOwned::to_owned)
+                            });
+                            if let Some(error) = error {
+                                properties.insert(ERROR_PROPERTY, error);
+                            }
+                            self.server.update_item(
+                                boardswarm_protocol::ItemType::Console,
+                                *id,
+                                properties,
+                            );
                         }
                     }
                 }
-                DeviceEvent::Remove(device) => {
-                    let mut providers = self.providers.lock().unwrap();
-                    providers.iter_mut().for_each(|p| p.remove(&device));
-                    if let Some(id) = registrations.remove(device.syspath()) {
-                        self.server.unregister_console(id)
+                Some((syspath, error)) = status.recv() => {
+                    let Some((id, properties)) = registrations.get(&syspath) else {
+                        continue;
+                    };
+                    let mut properties = properties.clone();
+                    if let Some(error) = error {
+                        properties.insert(ERROR_PROPERTY, error);
                     }
+                    self.server.update_item(
+                        boardswarm_protocol::ItemType::Console,
+                        *id,
+                        properties,
+                    );
                 }
             }
         }
     }
 }
 
+/// Open a port, retrying with a backoff if `retry` is set; For a newly appeared port the udev
+/// rules might not have applied the permissions yet or another process (e.g. ModemManager) may be
+/// probing the port
+async fn open_port(
+    path: &str,
+    rate: u32,
+    retry: bool,
+) -> Result<SerialStream, tokio_serial::Error> {
+    let attempts = if retry { OPEN_ATTEMPTS } else { 1 };
+    let mut delay = OPEN_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        // Opening a tty blocks until e.g. the modem lines are set up
+        let builder = tokio_serial::new(path, rate);
+        let result = tokio::task::spawn_blocking(move || builder.open_native_async())
+            .await
+            .expect("Opening serial port panicked");
+        match result {
+            Ok(port) => return Ok(port),
+            Err(e)
+                if attempt < attempts
+                    && !matches!(
+                        e.kind,
+                        tokio_serial::ErrorKind::NoDevice
+                            | tokio_serial::ErrorKind::Io(std::io::ErrorKind::NotFound)
+                    ) =>
+            {
+                warn!("Failed to open {} (attempt {}): {}", path, attempt, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct SerialOpen {
+    /// Descriptor of the port for modem control; Kept open by the read and write halves
+    fd: RawFd,
     write: Arc<AsyncMutex<WriteHalf<SerialStream>>>,
     broadcast: broadcast::Sender<Bytes>,
 }
@@ -101,20 +207,54 @@ pub(crate) struct SerialPort {
     path: String,
     rate: Mutex<u32>,
     open: AsyncMutex<Option<SerialOpen>>,
+    syspath: PathBuf,
+    status: mpsc::UnboundedSender<OpenStatus>,
+    /// Error of the last attempt to open the port
+    error: Mutex<Option<String>>,
+    /// Only the first open retries, as the port may not be usable right after it appeared
+    first_open: AtomicBool,
 }
-use crate::{registry, udev::DeviceEvent, ConsoleError, Server};
+use crate::{
+    registry::{self, Properties},
+    udev::DeviceEvent,
+    ConsoleError, ConsoleSignal, Server,
+};
 
 impl SerialPort {
-    pub fn new(path: String) -> Self {
+    fn new(path: String, syspath: PathBuf, status: mpsc::UnboundedSender<OpenStatus>) -> Self {
         let open = AsyncMutex::new(None);
         let rate = Mutex::new(115_200);
-        SerialPort { path, rate, open }
+        SerialPort {
+            path,
+            rate,
+            open,
+            syspath,
+            status,
+            error: Mutex::new(None),
+            first_open: AtomicBool::new(true),
+        }
+    }
+
+    /// Let the provider update the error property when the outcome of opening the port changes
+    fn report(&self, error: Option<String>) {
+        let mut last = self.error.lock().unwrap();
+        if *last != error {
+            if let Some(error) = &error {
+                warn!("Failed to open {}: {}", self.path, error);
+            }
+            *last = error.clone();
+            let _ = self.status.send((self.syspath.clone(), error));
+        }
     }
 
     pub async fn open(&self) -> Result<()> {
         let rate = *self.rate.lock().unwrap();
         let mut open = self.open.lock().await;
-        let port = tokio_serial::new(&self.path, rate).open_native_async()?;
+        let retry = self.first_open.swap(false, Ordering::Relaxed);
+        let result = open_port(&self.path, rate, retry).await;
+        self.report(result.as_ref().err().map(ToString::to_string));
+        let port = result?;
+        let fd = port.as_raw_fd();
 
         let (mut read, write) = tokio::io::split(port);
 
@@ -133,7 +273,11 @@ impl SerialPort {
                 let _ = b_clone.send(data.freeze());
             }
         });
-        *open = Some(SerialOpen { write, broadcast });
+        *open = Some(SerialOpen {
+            fd,
+            write,
+            broadcast,
+        });
         Ok(())
     }
 
@@ -213,6 +357,55 @@ impl crate::Console for SerialPort {
     > {
         Ok(Box::pin(SerialPortOutput::new(self.get_reader().await?)))
     }
+
+    async fn signal(&self, signal: ConsoleSignal) -> Result<(), ConsoleError> {
+        // Hold the writer such that no input gets sent while e.g. a break is in progress
+        let writer = self.get_writer().await?;
+        let _writer = writer.lock().await;
+        let fd = self
+            .open
+            .lock()
+            .await
+            .as_ref()
+            .map(|open| open.fd)
+            .ok_or(ConsoleError::Closed)?;
+        let result = match signal {
+            ConsoleSignal::Break(duration) => match set_break(fd, true) {
+                Ok(()) => {
+                    tokio::time::sleep(duration).await;
+                    set_break(fd, false)
+                }
+                Err(e) => Err(e),
+            },
+            ConsoleSignal::Dtr(on) => set_modem_lines(fd, libc::TIOCM_DTR, on),
+            ConsoleSignal::Rts(on) => set_modem_lines(fd, libc::TIOCM_RTS, on),
+        };
+        result.map_err(|e| {
+            ConsoleError::Unavailable(format!("Failed to signal '{}' serial: {}", self.path, e))
+        })
+    }
+}
+
+fn check_ioctl(result: libc::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Start or stop sending a break condition
+fn set_break(fd: RawFd, on: bool) -> std::io::Result<()> {
+    let request = if on { libc::TIOCSBRK } else { libc::TIOCCBRK };
+    // SAFETY: fd is an open serial port and the break requests take no argument
+    check_ioctl(unsafe { libc::ioctl(fd, request) })
+}
+
+/// Assert or deassert modem control lines
+fn set_modem_lines(fd: RawFd, lines: libc::c_int, on: bool) -> std::io::Result<()> {
+    let request = if on { libc::TIOCMBIS } else { libc::TIOCMBIC };
+    // SAFETY: fd is an open serial port and the request reads a single int
+    check_ioctl(unsafe { libc::ioctl(fd, request, &lines as *const libc::c_int) })
 }
 
 pub struct SerialPortOutput {
@@ -0,0 +1,500 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SessionError {
+    #[error("Session not found")]
+    NotFound,
+    #[error("Device {device} is reserved by session {session}")]
+    Reserved { device: u64, session: u64 },
+    #[error("Callers are queued for device {0}")]
+    Queued(u64),
+    #[error("Session {0} is held by another caller")]
+    NotHolder(u64),
+}
+
+impl From<SessionError> for tonic::Status {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::NotFound => tonic::Status::not_found(e.to_string()),
+            SessionError::Reserved { .. } | SessionError::Queued(_) => {
+                tonic::Status::failed_precondition(e.to_string())
+            }
+            SessionError::NotHolder(_) => tonic::Status::permission_denied(e.to_string()),
+        }
+    }
+}
+
+/// Devices reserved under one handle until the deadline
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub devices: Vec<u64>,
+    pub deadline: Instant,
+    /// Name of the caller that opened the session, if known
+    pub holder: Option<String>,
+}
+
+impl Session {
+    /// Sessions can only be used by the caller that opened them
+    fn check_holder(&self, id: u64, holder: Option<&str>) -> Result<(), SessionError> {
+        if self.holder.as_deref() == holder {
+            Ok(())
+        } else {
+            Err(SessionError::NotHolder(id))
+        }
+    }
+
+    pub fn to_message(&self, id: u64) -> boardswarm_protocol::Session {
+        boardswarm_protocol::Session {
+            id,
+            devices: self.devices.clone(),
+            remaining: self
+                .deadline
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+            holder: self.holder.clone(),
+        }
+    }
+}
+
+/// Order in which callers queued for a device get it
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueOrder {
+    /// First come, first served
+    #[default]
+    Fifo,
+    /// Higher priorities first, first come first served among equal priorities
+    Priority,
+}
+
+/// State of a caller queued for devices
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueState {
+    /// Number of callers ahead in the queue of any of the devices
+    Waiting(u32),
+    /// Id of the session opened for the caller
+    Granted(u64),
+}
+
+/// Caller waiting for devices to become free
+struct Waiter {
+    ticket: u64,
+    /// Devices to reserve along with the order of their queue
+    devices: Vec<(u64, QueueOrder)>,
+    priority: i32,
+    ttl: Duration,
+    holder: Option<String>,
+    state: watch::Sender<QueueState>,
+}
+
+impl Waiter {
+    /// Whether the waiter is ahead of `other` in the queue of any device they both wait for
+    fn ahead_of(&self, other: &Waiter) -> bool {
+        self.devices.iter().any(|&(device, order)| {
+            other.devices.iter().any(|&(d, _)| d == device)
+                && match order {
+                    QueueOrder::Fifo => self.ticket < other.ticket,
+                    QueueOrder::Priority => {
+                        (Reverse(self.priority), self.ticket)
+                            < (Reverse(other.priority), other.ticket)
+                    }
+                }
+        })
+    }
+}
+
+/// Sessions by id
+#[derive(Default)]
+pub struct Sessions {
+    next: Mutex<u64>,
+    sessions: Mutex<HashMap<u64, Session>>,
+    /// Callers waiting for reserved devices; Only locked with the sessions locked
+    queue: Mutex<Vec<Waiter>>,
+}
+
+impl Sessions {
+    /// Reserve all `devices` for `ttl`; Fails without reserving anything if any of them is
+    /// already reserved
+    pub fn open(
+        &self,
+        devices: Vec<u64>,
+        ttl: Duration,
+        holder: Option<String>,
+    ) -> Result<u64, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        for (&session, s) in sessions.iter() {
+            if let Some(&device) = s.devices.iter().find(|d| devices.contains(d)) {
+                return Err(SessionError::Reserved { device, session });
+            }
+        }
+        // Callers already waiting for a device get it first
+        let queue = self.queue.lock().unwrap();
+        if let Some(&device) = devices
+            .iter()
+            .find(|d| queue.iter().any(|w| w.devices.iter().any(|(q, _)| q == *d)))
+        {
+            return Err(SessionError::Queued(device));
+        }
+
+        Ok(self.insert(&mut sessions, devices, ttl, holder))
+    }
+
+    fn insert(
+        &self,
+        sessions: &mut HashMap<u64, Session>,
+        devices: Vec<u64>,
+        ttl: Duration,
+        holder: Option<String>,
+    ) -> u64 {
+        let mut next = self.next.lock().unwrap();
+        *next += 1;
+        sessions.insert(
+            *next,
+            Session {
+                devices,
+                deadline: Instant::now() + ttl,
+                holder,
+            },
+        );
+        *next
+    }
+
+    /// Add sessions kept from before a restart; Ids handed out afterwards continue after `last`
+    pub fn restore(&self, last: u64, restored: Vec<(u64, Session)>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut next = self.next.lock().unwrap();
+        *next = (*next).max(last);
+        for (id, session) in restored {
+            *next = (*next).max(id);
+            sessions.insert(id, session);
+        }
+    }
+
+    /// Last id handed out to a session or queued caller
+    pub fn last_id(&self) -> u64 {
+        *self.next.lock().unwrap()
+    }
+
+    /// Queue for reserving all `devices` for `ttl` once they're free; Returns the ticket of the
+    /// caller in the queue and its state, which turns into the id of the session once granted
+    pub fn enqueue(
+        &self,
+        devices: Vec<(u64, QueueOrder)>,
+        priority: i32,
+        ttl: Duration,
+        holder: Option<String>,
+    ) -> (u64, watch::Receiver<QueueState>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let ticket = {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            *next
+        };
+        let (state, rx) = watch::channel(QueueState::Waiting(0));
+        self.queue.lock().unwrap().push(Waiter {
+            ticket,
+            devices,
+            priority,
+            ttl,
+            holder,
+            state,
+        });
+        self.schedule_locked(&mut sessions);
+        (ticket, rx)
+    }
+
+    /// Leave the queue, if still waiting
+    pub fn dequeue(&self, ticket: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.queue.lock().unwrap().retain(|w| w.ticket != ticket);
+        self.schedule_locked(&mut sessions);
+    }
+
+    /// Number of callers queued for the device
+    pub fn queued(&self, device: u64) -> u32 {
+        let _sessions = self.sessions.lock().unwrap();
+        self.queue
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|w| w.devices.iter().any(|&(d, _)| d == device))
+            .count() as u32
+    }
+
+    /// Open sessions for queued callers whose devices are free and who are first in line, and
+    /// update the position of the others
+    pub fn schedule(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.schedule_locked(&mut sessions);
+    }
+
+    fn schedule_locked(&self, sessions: &mut HashMap<u64, Session>) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let next = queue.iter().position(|w| {
+                !queue.iter().any(|o| o.ahead_of(w))
+                    && !sessions.values().any(|s| {
+                        s.devices
+                            .iter()
+                            .any(|d| w.devices.iter().any(|(q, _)| q == d))
+                    })
+            });
+            let Some(next) = next else {
+                break;
+            };
+            let w = queue.remove(next);
+            let devices = w.devices.iter().map(|&(d, _)| d).collect();
+            let id = self.insert(sessions, devices, w.ttl, w.holder);
+            w.state.send_replace(QueueState::Granted(id));
+        }
+        for w in queue.iter() {
+            let position = queue.iter().filter(|o| o.ahead_of(w)).count() as u32;
+            w.state.send_if_modified(|state| {
+                let changed = *state != QueueState::Waiting(position);
+                *state = QueueState::Waiting(position);
+                changed
+            });
+        }
+    }
+
+    /// Move the deadline of the session to `ttl` from now; Only the holder may renew it
+    pub fn renew(
+        &self,
+        id: u64,
+        ttl: Duration,
+        holder: Option<&str>,
+    ) -> Result<Session, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&id).ok_or(SessionError::NotFound)?;
+        session.check_holder(id, holder)?;
+        session.deadline = Instant::now() + ttl;
+        Ok(session.clone())
+    }
+
+    /// End the session; Returns the devices it reserved
+    pub fn close(&self, id: u64) -> Result<Vec<u64>, SessionError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|s| s.devices)
+            .ok_or(SessionError::NotFound)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Session> {
+        self.sessions.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Check the session was opened by `holder`
+    pub fn held_by(&self, id: u64, holder: Option<&str>) -> Result<(), SessionError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .ok_or(SessionError::NotFound)?
+            .check_holder(id, holder)
+    }
+
+    pub fn list(&self) -> Vec<(u64, Session)> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, s)| (id, s.clone()))
+            .collect();
+        sessions.sort_by_key(|(id, _)| *id);
+        sessions
+    }
+
+    /// Session reserving the device, if any
+    pub fn holding(&self, device: u64) -> Option<(u64, Session)> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, s)| s.devices.contains(&device))
+            .map(|(&id, s)| (id, s.clone()))
+    }
+
+    /// Check whether `session` (if any) may use `device` on behalf of `holder`
+    pub fn check(
+        &self,
+        device: u64,
+        session: Option<u64>,
+        holder: Option<&str>,
+    ) -> Result<(), SessionError> {
+        let sessions = self.sessions.lock().unwrap();
+        if let Some(session) = session {
+            sessions
+                .get(&session)
+                .ok_or(SessionError::NotFound)?
+                .check_holder(session, holder)?;
+        }
+        match sessions.iter().find(|(_, s)| s.devices.contains(&device)) {
+            Some((&holder, _)) if Some(holder) != session => Err(SessionError::Reserved {
+                device,
+                session: holder,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reservations() {
+        let sessions = Sessions::default();
+        let ttl = Duration::from_secs(60);
+
+        let first = sessions
+            .open(vec![1, 2], ttl, Some("ci".to_string()))
+            .unwrap();
+        assert_eq!(
+            sessions.open(vec![3, 2], ttl, None),
+            Err(SessionError::Reserved {
+                device: 2,
+                session: first
+            })
+        );
+        let second = sessions.open(vec![3], ttl, None).unwrap();
+        let (holder, session) = sessions.holding(2).unwrap();
+        assert_eq!(holder, first);
+        assert_eq!(session.holder.as_deref(), Some("ci"));
+        assert!(sessions.holding(4).is_none());
+
+        assert_eq!(sessions.check(1, Some(first), Some("ci")), Ok(()));
+        assert_eq!(sessions.check(4, None, None), Ok(()));
+        // Only the holder can use the session
+        assert_eq!(
+            sessions.check(1, Some(first), Some("other")),
+            Err(SessionError::NotHolder(first))
+        );
+        assert_eq!(
+            sessions.check(1, Some(first), None),
+            Err(SessionError::NotHolder(first))
+        );
+        assert_eq!(
+            sessions.renew(first, ttl, Some("other")).unwrap_err(),
+            SessionError::NotHolder(first)
+        );
+        assert_eq!(
+            sessions.held_by(first, None),
+            Err(SessionError::NotHolder(first))
+        );
+        assert_eq!(sessions.held_by(first, Some("ci")), Ok(()));
+        assert_eq!(sessions.held_by(second, None), Ok(()));
+        assert_eq!(
+            sessions.check(1, None, Some("ci")),
+            Err(SessionError::Reserved {
+                device: 1,
+                session: first
+            })
+        );
+        assert_eq!(
+            sessions.check(3, Some(first), Some("ci")),
+            Err(SessionError::Reserved {
+                device: 3,
+                session: second
+            })
+        );
+
+        assert_eq!(sessions.close(first), Ok(vec![1, 2]));
+        assert_eq!(sessions.close(first), Err(SessionError::NotFound));
+        assert_eq!(sessions.check(1, None, None), Ok(()));
+        assert_eq!(
+            sessions.check(1, Some(first), Some("ci")),
+            Err(SessionError::NotFound)
+        );
+        assert!(sessions.holding(1).is_none());
+        assert_eq!(
+            sessions
+                .list()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            vec![second]
+        );
+    }
+
+    #[test]
+    fn queue() {
+        let sessions = Sessions::default();
+        let ttl = Duration::from_secs(60);
+        let first = sessions.open(vec![1], ttl, None).unwrap();
+
+        let fifo = |d| vec![(d, QueueOrder::Fifo)];
+        let (_, a) = sessions.enqueue(fifo(1), 0, ttl, None);
+        let (_, b) = sessions.enqueue(fifo(1), 10, ttl, None);
+        assert_eq!(*a.borrow(), QueueState::Waiting(0));
+        assert_eq!(*b.borrow(), QueueState::Waiting(1));
+        assert_eq!(
+            sessions.open(vec![1], ttl, None),
+            Err(SessionError::Reserved {
+                device: 1,
+                session: first
+            })
+        );
+
+        sessions.close(first).unwrap();
+        // Reserving directly doesn't skip the queue
+        assert_eq!(
+            sessions.open(vec![1], ttl, None),
+            Err(SessionError::Queued(1))
+        );
+        sessions.schedule();
+        let QueueState::Granted(granted) = *a.borrow() else {
+            panic!("First in line didn't get the device");
+        };
+        assert_eq!(sessions.holding(1).unwrap().0, granted);
+        assert_eq!(*b.borrow(), QueueState::Waiting(0));
+
+        let priority = |d| vec![(d, QueueOrder::Priority)];
+        let second = sessions.open(vec![2], ttl, None).unwrap();
+        let (_, low) = sessions.enqueue(priority(2), 0, ttl, None);
+        let (high_ticket, high) = sessions.enqueue(priority(2), 5, ttl, None);
+        assert_eq!(*low.borrow(), QueueState::Waiting(1));
+        assert_eq!(*high.borrow(), QueueState::Waiting(0));
+        sessions.dequeue(high_ticket);
+        assert_eq!(*low.borrow(), QueueState::Waiting(0));
+        assert_eq!(sessions.queued(2), 1);
+
+        sessions.close(second).unwrap();
+        sessions.schedule();
+        assert!(matches!(*low.borrow(), QueueState::Granted(_)));
+        assert_eq!(sessions.queued(2), 0);
+    }
+
+    #[test]
+    fn restore() {
+        let sessions = Sessions::default();
+        let ttl = Duration::from_secs(60);
+        let session = Session {
+            devices: vec![1],
+            deadline: Instant::now() + ttl,
+            holder: None,
+        };
+        sessions.restore(5, vec![(7, session)]);
+        assert_eq!(sessions.holding(1).unwrap().0, 7);
+        assert_eq!(sessions.last_id(), 7);
+        assert_eq!(
+            sessions.open(vec![1], ttl, None),
+            Err(SessionError::Reserved {
+                device: 1,
+                session: 7
+            })
+        );
+        assert_eq!(sessions.open(vec![2], ttl, None), Ok(8));
+    }
+}
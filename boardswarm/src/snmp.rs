@@ -0,0 +1,175 @@
+use std::process::Stdio;
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    ActuatorError, Server,
+};
+
+pub const PROVIDER: &str = "snmp";
+
+pub fn default_community() -> String {
+    "private".to_string()
+}
+
+pub fn default_version() -> String {
+    "2c".to_string()
+}
+
+/// Outlet control table of the PDU; The outlet number is appended to the OID
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Model {
+    /// APC switched rack PDU (PowerNet-MIB rPDUOutletControlOutletCommand)
+    Apc,
+    /// Older APC masterswitch PDUs (PowerNet-MIB sPDUOutletCtl)
+    ApcLegacy,
+    /// Raritan PX2/PX3 (PDU2-MIB switchingOperation of the first pdu)
+    Raritan,
+}
+
+impl Model {
+    fn oid(self) -> &'static str {
+        match self {
+            Model::Apc => ".1.3.6.1.4.1.318.1.1.12.3.3.1.1.4",
+            Model::ApcLegacy => ".1.3.6.1.4.1.318.1.1.4.4.2.1.3",
+            Model::Raritan => ".1.3.6.1.4.1.13742.6.4.1.2.1.2.1.1",
+        }
+    }
+
+    /// Integer value to set for a mode
+    fn value(self, mode: &str) -> Option<u32> {
+        match (self, mode) {
+            (Model::Apc | Model::ApcLegacy, "on") => Some(1),
+            (Model::Apc | Model::ApcLegacy, "off") => Some(2),
+            (Model::Apc | Model::ApcLegacy, "reboot") => Some(3),
+            (Model::Raritan, "off") => Some(0),
+            (Model::Raritan, "on") => Some(1),
+            (Model::Raritan, "reboot") => Some(2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Outlet {
+    name: String,
+    outlet: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct SnmpParameters {
+    host: String,
+    model: Model,
+    #[serde(default = "default_community")]
+    community: String,
+    /// SNMP protocol version, either 1 or 2c
+    #[serde(default = "default_version")]
+    version: String,
+    outlets: Vec<Outlet>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: SnmpParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for outlet in parameters.outlets {
+        let mut properties = Properties::new(&outlet.name);
+        properties.extend(provider_properties);
+        properties.insert(format!("{PROVIDER}.host"), parameters.host.as_str());
+        properties.insert(format!("{PROVIDER}.outlet"), outlet.outlet.to_string());
+        server.register_actuator(
+            properties,
+            SnmpOutlet::new(
+                parameters.host.clone(),
+                parameters.community.clone(),
+                parameters.version.clone(),
+                parameters.model,
+                outlet.outlet,
+            ),
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct SnmpOutlet {
+    host: String,
+    community: String,
+    version: String,
+    model: Model,
+    outlet: u32,
+}
+
+impl SnmpOutlet {
+    pub fn new(
+        host: String,
+        community: String,
+        version: String,
+        model: Model,
+        outlet: u32,
+    ) -> Self {
+        Self {
+            host,
+            community,
+            version,
+            model,
+            outlet,
+        }
+    }
+
+    /// net-snmp snmpset invocation setting the outlet control to value
+    fn command(&self, value: u32) -> Command {
+        let mut command = Command::new("snmpset");
+        command
+            .args(["-v", self.version.as_str(), "-Oq"])
+            .arg("-c")
+            .arg(&self.community)
+            .arg(&self.host)
+            .arg(format!("{}.{}", self.model.oid(), self.outlet))
+            .arg("i")
+            .arg(value.to_string())
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        command
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for SnmpOutlet {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let Some(value) = self.model.value(&parameters.mode) else {
+            warn!("Unsupported snmp outlet mode: {}", parameters.mode);
+            return Err(ActuatorError {});
+        };
+        let output = self.command(value).output().await.map_err(|e| {
+            warn!("Failed to run snmpset: {}", e);
+            ActuatorError {}
+        })?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            warn!(
+                "Switching outlet {} of {} failed: {}",
+                self.outlet,
+                self.host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Err(ActuatorError {})
+        }
+    }
+}
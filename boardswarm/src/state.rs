@@ -0,0 +1,159 @@
+// Device state kept across restarts of the server, such that e.g. a board left powered on in its
+// recovery mode is still known to be in that mode and the dependencies of its modes hold
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::warn;
+
+const STATE_FILE: &str = "state.json";
+
+/// State of a configured device
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceState {
+    /// Mode the device is in; Unset while switching modes, as the device could be in any mode
+    /// on the way
+    pub mode: Option<String>,
+    /// Number of times the device was switched into each mode, e.g. the number of boots
+    #[serde(default)]
+    pub modes: HashMap<String, u64>,
+    /// Number of images written to the volumes of the device
+    #[serde(default)]
+    pub uploads: u64,
+}
+
+impl From<DeviceState> for boardswarm_protocol::DeviceCounters {
+    fn from(state: DeviceState) -> Self {
+        boardswarm_protocol::DeviceCounters {
+            modes: state.modes,
+            uploads: state.uploads,
+        }
+    }
+}
+
+/// Session reserving devices by name, as ids of devices may change across restarts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionState {
+    pub id: u64,
+    pub devices: Vec<String>,
+    /// Seconds since the unix epoch at which the session expires
+    pub expires: u64,
+    pub holder: Option<String>,
+}
+
+impl SessionState {
+    /// Time left until the session expires
+    pub fn remaining(&self) -> Duration {
+        (SystemTime::UNIX_EPOCH + Duration::from_secs(self.expires))
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    devices: BTreeMap<String, DeviceState>,
+    #[serde(default)]
+    sessions: Vec<SessionState>,
+    /// Last session id handed out
+    #[serde(default)]
+    last_session: u64,
+}
+
+pub struct Store {
+    path: PathBuf,
+    state: Mutex<State>,
+    dirty: Notify,
+}
+
+impl Store {
+    /// Load the state from the configured directory, relative to the configuration directory;
+    /// Starts from scratch if there is no usable state
+    pub fn load(config_dir: &Path, directory: &Path) -> Self {
+        let path = config_dir.join(directory).join(STATE_FILE);
+        let state = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable state {}: {}", path.display(), e);
+                State::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                warn!("Failed to read state {}: {}", path.display(), e);
+                State::default()
+            }
+        };
+        Self {
+            path,
+            state: Mutex::new(state),
+            dirty: Notify::new(),
+        }
+    }
+
+    pub fn device(&self, name: &str) -> DeviceState {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn update_device<F: FnOnce(&mut DeviceState)>(&self, name: &str, f: F) {
+        f(self
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .entry(name.to_string())
+            .or_default());
+        self.dirty.notify_one();
+    }
+
+    /// Sessions open when the state was last saved, and the last session id handed out
+    pub fn sessions(&self) -> (u64, Vec<SessionState>) {
+        let state = self.state.lock().unwrap();
+        (state.last_session, state.sessions.clone())
+    }
+
+    pub fn set_sessions(&self, last_session: u64, sessions: Vec<SessionState>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.last_session = last_session;
+            state.sessions = sessions;
+        }
+        self.dirty.notify_one();
+    }
+
+    /// Write the state whenever it changed; Changes made while writing are picked up by the next
+    /// write
+    pub async fn run(&self) {
+        if let Some(directory) = self.path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(directory).await {
+                warn!(
+                    "Failed to create state directory {}: {}",
+                    directory.display(),
+                    e
+                );
+            }
+        }
+        let partial = self.path.with_extension("json.partial");
+        loop {
+            self.dirty.notified().await;
+            let data = serde_json::to_vec_pretty(&*self.state.lock().unwrap())
+                .expect("State serialization can't fail");
+            // Replace the state at once, such that a crash while writing can't corrupt it
+            let write = async {
+                tokio::fs::write(&partial, data).await?;
+                tokio::fs::rename(&partial, &self.path).await
+            };
+            if let Err(e) = write.await {
+                warn!("Failed to write state {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
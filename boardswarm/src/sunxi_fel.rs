@@ -0,0 +1,239 @@
+use std::{collections::HashMap, process::Stdio, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry, udev::DeviceEvent, Server, Volume, VolumeError, VolumeTarget, VolumeTargetInfo,
+};
+
+pub const PROVIDER: &str = "sunxi-fel";
+pub const TARGET: &str = "fel";
+
+const FEL_VENDOR: u64 = 0x1f3a;
+const FEL_PRODUCT: u64 = 0xefe8;
+
+/// Upper limit for the image to be uploaded
+const MAX_IMAGE: u64 = 16 * 1024 * 1024;
+
+fn fel_command(bus: u8, dev: u8) -> Command {
+    let mut command = Command::new("sunxi-fel");
+    command
+        .arg("-d")
+        .arg(format!("{bus}:{dev}"))
+        .kill_on_drop(true);
+    command
+}
+
+/// Parse the SoC name out of the output of `sunxi-fel version`, e.g.
+/// `AWUSBFEX soc=00001680(H3) 00000001 ver=0001 44 08 scratchpad=00007e00 00000000 00000000`
+fn parse_soc(version: &str) -> Option<&str> {
+    let soc = version.split_once("soc=")?.1;
+    let name = soc.split_once('(')?.1.split_once(')')?.0;
+    Some(name)
+}
+
+#[instrument(skip(server))]
+pub async fn start_provider(name: String, server: Server) {
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+    let mut registrations = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("usb").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                if device.devnode().is_none()
+                    || device.property_u64("ID_VENDOR_ID", 16) != Some(FEL_VENDOR)
+                    || device.property_u64("ID_MODEL_ID", 16) != Some(FEL_PRODUCT)
+                {
+                    continue;
+                }
+                let (Some(busnum), Some(devnum)) = (
+                    device
+                        .property_u64("BUSNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                    device
+                        .property_u64("DEVNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                ) else {
+                    continue;
+                };
+
+                let mut properties = device.properties(format!("{}/{} fel", busnum, devnum));
+                properties.extend(provider_properties);
+                match fel_command(busnum, devnum)
+                    .arg("version")
+                    .stdin(Stdio::null())
+                    .output()
+                    .await
+                {
+                    Ok(output) if output.status.success() => {
+                        let version = String::from_utf8_lossy(&output.stdout);
+                        if let Some(soc) = parse_soc(&version) {
+                            info!("Found {} in FEL mode at {}/{}", soc, busnum, devnum);
+                            properties.insert(format!("{PROVIDER}.soc"), soc);
+                        }
+                    }
+                    Ok(output) => warn!(
+                        "Failed to query FEL version: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ),
+                    Err(e) => warn!("Failed to run sunxi-fel: {}", e),
+                }
+
+                let id = server.register_volume(properties, FelVolume::new(busnum, devnum));
+                registrations.insert(device.syspath().to_path_buf(), id);
+            }
+            DeviceEvent::Remove(device) => {
+                if let Some(id) = registrations.remove(device.syspath()) {
+                    server.unregister_volume(id)
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FelVolume {
+    bus: u8,
+    dev: u8,
+    /// Image written to the fel target, loaded and executed on commit
+    image: Arc<Mutex<Option<Bytes>>>,
+    targets: [VolumeTargetInfo; 1],
+}
+
+impl FelVolume {
+    fn new(bus: u8, dev: u8) -> Self {
+        Self {
+            bus,
+            dev,
+            image: Default::default(),
+            targets: [VolumeTargetInfo {
+                name: String::from(TARGET),
+                readable: false,
+                writable: true,
+                seekable: false,
+                size: None,
+                blocksize: None,
+                kind: boardswarm_protocol::VolumeTargetKind::Protocol.into(),
+                sha256: None,
+            }],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Volume for FelVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        (&self.targets, true)
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        if target != TARGET {
+            return Err(VolumeError::UnknownTargetRequested);
+        }
+        if length.is_some_and(|l| l > MAX_IMAGE) {
+            return Err(VolumeError::Failure("Image too big".to_string()));
+        }
+        Ok((
+            self.targets[0].clone(),
+            Box::new(FelTarget {
+                image: self.image.clone(),
+                data: BytesMut::with_capacity(length.unwrap_or(1024 * 1024) as usize),
+                stored: false,
+            }),
+        ))
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        let Some(image) = self.image.lock().await.take() else {
+            return Err(VolumeError::Failure("No image written".to_string()));
+        };
+        // Load the SPL, and U-Boot when present in the image, and execute it
+        let mut child = fel_command(self.bus, self.dev)
+            .args(["uboot", "/dev/stdin"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| VolumeError::Failure(format!("Failed to run sunxi-fel: {e}")))?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin
+            .write_all(&image)
+            .await
+            .map_err(|e| VolumeError::Failure(e.to_string()))?;
+        drop(stdin);
+
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(|e| VolumeError::Internal(e.to_string()))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(VolumeError::Failure(format!(
+                "Loading image failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+struct FelTarget {
+    image: Arc<Mutex<Option<Bytes>>>,
+    data: BytesMut,
+    /// Whether the image got handed over for the commit
+    stored: bool,
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for FelTarget {
+    async fn write(&mut self, data: Bytes, offset: u64, completion: crate::WriteCompletion) {
+        if offset as usize != self.data.len() {
+            completion.complete(Err(tonic::Status::out_of_range("Invalid offset")));
+        } else if (data.len() + self.data.len()) as u64 > MAX_IMAGE {
+            completion.complete(Err(tonic::Status::out_of_range("Image too big")));
+        } else {
+            self.data.extend_from_slice(&data);
+            completion.complete(Ok(data.len() as u64));
+        }
+    }
+
+    async fn shutdown(&mut self, completion: crate::ShutdownCompletion) {
+        *self.image.lock().await = Some(self.data.split().freeze());
+        self.stored = true;
+        completion.complete(Ok(()))
+    }
+
+    async fn abort(&mut self) {
+        // Don't let a commit load the image of an aborted upload
+        if self.stored {
+            *self.image.lock().await = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn soc() {
+        assert_eq!(
+            parse_soc("AWUSBFEX soc=00001680(H3) 00000001 ver=0001 44 08 scratchpad=00007e00"),
+            Some("H3")
+        );
+        assert_eq!(parse_soc("garbage"), None);
+    }
+}
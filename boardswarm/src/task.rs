@@ -0,0 +1,270 @@
+// Tasks defined in the configuration, running a sequence of mode changes, image downloads and
+// console interactions on a device on behalf of a client in a single call
+use std::sync::Arc;
+use std::time::Instant;
+
+use boardswarm_protocol::{
+    boardswarm_server::Boardswarm, device_task_progress::Detail, DeviceModeRequest,
+    DeviceModeStepState, DeviceTaskProgress, ImageFormat, VolumeFetchRequest, VolumeRequest,
+};
+use bytes::Bytes;
+use futures::{SinkExt, Stream, StreamExt};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
+
+use crate::config::{Task, TaskConsoleStep, TaskFetch, TaskStep};
+use crate::{auth, console_output::wait_for, group, ConsoleError, Server};
+
+const DEFAULT_CONSOLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+pub type TaskStream = ReceiverStream<Result<DeviceTaskProgress, tonic::Status>>;
+
+#[derive(Error, Debug)]
+enum TaskError {
+    #[error("{}", .0.message())]
+    Status(#[from] tonic::Status),
+    #[error("No {0} console on the device")]
+    NoConsole(String),
+    #[error("No {0} volume on the device")]
+    NoVolume(String),
+    #[error("Invalid pattern: {0}")]
+    Pattern(#[from] regex::Error),
+    #[error(transparent)]
+    Console(#[from] ConsoleError),
+    #[error("Timed out waiting for the expected output")]
+    Timeout,
+}
+
+fn describe(step: &TaskStep) -> String {
+    match step {
+        TaskStep::Mode(step) => format!("mode {}", step.mode),
+        TaskStep::Fetch(step) => format!(
+            "fetch {} to {} {}",
+            step.fetch.url, step.fetch.volume, step.fetch.target
+        ),
+        TaskStep::Console(step) => format!("console {}", step.console),
+        TaskStep::Sleep(step) => format!("sleep {:?}", step.sleep),
+    }
+}
+
+impl From<&Task> for boardswarm_protocol::Task {
+    fn from(task: &Task) -> Self {
+        boardswarm_protocol::Task {
+            name: task.name.clone(),
+            description: task.description.clone(),
+            steps: task.steps.iter().map(describe).collect(),
+        }
+    }
+}
+
+/// Sends the progress of a single step
+struct Reporter {
+    tx: mpsc::Sender<Result<DeviceTaskProgress, tonic::Status>>,
+    start: Instant,
+    step: u32,
+    description: String,
+}
+
+impl Reporter {
+    async fn report(
+        &self,
+        state: DeviceModeStepState,
+        error: Option<String>,
+        detail: Option<Detail>,
+    ) {
+        let progress = DeviceTaskProgress {
+            step: self.step,
+            description: self.description.clone(),
+            state: state.into(),
+            elapsed: self.start.elapsed().as_millis() as u64,
+            error,
+            detail,
+        };
+        let _ = self.tx.send(Ok(progress)).await;
+    }
+
+    /// Forward the progress of an operation run by the step
+    async fn forward<S, P>(&self, mut progress: S, wrap: fn(P) -> Detail) -> Result<(), TaskError>
+    where
+        S: Stream<Item = Result<P, tonic::Status>> + Unpin,
+    {
+        while let Some(p) = progress.next().await {
+            self.report(DeviceModeStepState::Started, None, Some(wrap(p?)))
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// Device the task runs on, along with the caller it runs for
+struct Target {
+    server: Server,
+    device: u64,
+    session: Option<u64>,
+    identity: Option<auth::Identity>,
+}
+
+impl Target {
+    async fn run_step(&self, step: &TaskStep, reporter: &Reporter) -> Result<(), TaskError> {
+        match step {
+            TaskStep::Mode(step) => {
+                let request = group::request(
+                    DeviceModeRequest {
+                        device: self.device,
+                        mode: step.mode.clone(),
+                        session: self.session,
+                        no_wait: false,
+                    },
+                    self.identity.clone(),
+                );
+                let progress = Boardswarm::device_change_mode(&self.server, request)
+                    .await?
+                    .into_inner();
+                reporter.forward(progress, Detail::Mode).await
+            }
+            TaskStep::Fetch(step) => self.fetch(&step.fetch, reporter).await,
+            TaskStep::Console(step) => self.console(step).await,
+            TaskStep::Sleep(step) => {
+                tokio::time::sleep(step.sleep).await;
+                Ok(())
+            }
+        }
+    }
+
+    async fn fetch(&self, fetch: &TaskFetch, reporter: &Reporter) -> Result<(), TaskError> {
+        let volume = self
+            .server
+            .get_device(self.device)
+            .and_then(|d| d.volumes().into_iter().find(|v| v.name == fetch.volume))
+            .and_then(|v| v.id)
+            .ok_or_else(|| TaskError::NoVolume(fetch.volume.clone()))?;
+        let format = if fetch.sparse {
+            ImageFormat::AndroidSparse
+        } else {
+            ImageFormat::Raw
+        };
+        let request = group::request(
+            VolumeFetchRequest {
+                volume,
+                target: fetch.target.clone(),
+                url: fetch.url.clone(),
+                sha256: fetch.sha256.map(|s| Bytes::copy_from_slice(&s)),
+                format: format.into(),
+                bmap_url: fetch.bmap_url.clone(),
+                transaction: None,
+                session: self.session,
+            },
+            self.identity.clone(),
+        );
+        let progress = Boardswarm::volume_fetch(&self.server, request)
+            .await?
+            .into_inner();
+        reporter.forward(progress, Detail::Fetch).await?;
+        if fetch.commit {
+            let request = group::request(VolumeRequest { volume }, self.identity.clone());
+            Boardswarm::volume_commit(&self.server, request).await?;
+        }
+        Ok(())
+    }
+
+    async fn console(&self, step: &TaskConsoleStep) -> Result<(), TaskError> {
+        let id = self
+            .server
+            .get_device(self.device)
+            .and_then(|d| d.consoles().into_iter().find(|c| c.name == step.console))
+            .and_then(|c| c.id)
+            .ok_or_else(|| TaskError::NoConsole(step.console.clone()))?;
+        let console = self
+            .server
+            .get_console(id)
+            .ok_or_else(|| TaskError::NoConsole(step.console.clone()))?;
+        let expect = step
+            .expect
+            .as_deref()
+            .map(regex::bytes::Regex::new)
+            .transpose()?;
+
+        // Subscribe to the output before sending so the response can't be missed
+        let output = match expect {
+            Some(_) => Some(self.server.console_output(id, false).await?),
+            None => None,
+        };
+
+        if let Some(send) = &step.send {
+            self.server
+                .inner
+                .console_handles
+                .check_unlocked(id)
+                .map_err(tonic::Status::from)?;
+            let mut input = self.server.console_input(id, console.as_ref()).await?;
+            input.send(Bytes::from(send.clone())).await?;
+        }
+
+        if let (Some(expect), Some(mut output)) = (expect, output) {
+            let timeout = step.timeout.unwrap_or(DEFAULT_CONSOLE_TIMEOUT);
+            tokio::time::timeout(timeout, wait_for(&mut output, &expect))
+                .await
+                .map_err(|_| TaskError::Timeout)??;
+        }
+        Ok(())
+    }
+}
+
+/// Run the task on the device, stopping at the first failing step; The task keeps running if the
+/// client goes away, as stopping halfway would leave the device in an unknown state
+pub fn run(
+    server: Server,
+    device: u64,
+    task: Arc<Task>,
+    session: Option<u64>,
+    identity: Option<auth::Identity>,
+) -> TaskStream {
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let target = Target {
+            server,
+            device,
+            session,
+            identity,
+        };
+        info!("Running task {} on device {}", task.name, device);
+        for (index, step) in task.steps.iter().enumerate() {
+            let reporter = Reporter {
+                tx: tx.clone(),
+                start,
+                step: index as u32,
+                description: describe(step),
+            };
+            reporter
+                .report(DeviceModeStepState::Started, None, None)
+                .await;
+            match target.run_step(step, &reporter).await {
+                Ok(()) => {
+                    reporter
+                        .report(DeviceModeStepState::Completed, None, None)
+                        .await
+                }
+                Err(e) => {
+                    warn!(
+                        "Task {} on device {} failed at {}: {}",
+                        task.name, device, reporter.description, e
+                    );
+                    reporter
+                        .report(DeviceModeStepState::Failed, Some(e.to_string()), None)
+                        .await;
+                    let _ = tx
+                        .send(Err(tonic::Status::aborted(format!(
+                            "Step {} failed: {}",
+                            reporter.description, e
+                        ))))
+                        .await;
+                    return;
+                }
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
@@ -0,0 +1,518 @@
+use std::pin::Pin;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Sink, StreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    registry::{self, Properties},
+    ConsoleError, Server,
+};
+
+pub const PROVIDER: &str = "tcpconsole";
+
+/// Reconnection delays; Doubled after every failed attempt up to the maximum
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug)]
+pub struct Endpoint {
+    pub name: String,
+    /// host:port to connect to
+    pub address: String,
+    /// Speak the telnet protocol rather than passing raw data
+    #[serde(default)]
+    pub telnet: bool,
+    /// Control the serial port behind the endpoint using the RFC 2217 telnet extension; Implies
+    /// telnet
+    #[serde(default)]
+    pub rfc2217: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct TcpConsoleParameters {
+    consoles: Vec<Endpoint>,
+}
+
+#[instrument(skip(parameters, server))]
+pub fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: TcpConsoleParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    for endpoint in parameters.consoles {
+        let mut properties = Properties::new(&endpoint.name);
+        properties.extend(provider_properties);
+        properties.insert(format!("{PROVIDER}.address"), endpoint.address.as_str());
+        server.register_console(properties, TcpConsole::new(endpoint, None));
+    }
+}
+
+/// Keep the connection to the endpoint up for as long as the console exists
+async fn run(
+    endpoint: Endpoint,
+    mut input: mpsc::Receiver<Bytes>,
+    output: broadcast::Sender<Bytes>,
+    mut settings: Option<watch::Receiver<Option<PortSettings>>>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match TcpStream::connect(&endpoint.address).await {
+            Ok(stream) => {
+                info!("Connected to {}", endpoint.address);
+                backoff = MIN_BACKOFF;
+                // Drop input queued up while disconnected
+                while input.try_recv().is_ok() {}
+                if !connection(
+                    stream,
+                    endpoint.telnet,
+                    &mut input,
+                    &output,
+                    settings.as_mut(),
+                )
+                .await
+                {
+                    return;
+                }
+                warn!("Connection to {} dropped", endpoint.address);
+            }
+            Err(e) => warn!("Failed to connect to {}: {}", endpoint.address, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Pass data over a connection until it drops; Returns false if the console went away instead
+async fn connection(
+    stream: TcpStream,
+    telnet: bool,
+    input: &mut mpsc::Receiver<Bytes>,
+    output: &broadcast::Sender<Bytes>,
+    mut settings: Option<&mut watch::Receiver<Option<PortSettings>>>,
+) -> bool {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut telnet = (telnet || settings.is_some()).then(|| Telnet {
+        com_port: settings.is_some(),
+        ..Default::default()
+    });
+    let mut buf = BytesMut::with_capacity(4096);
+
+    if let Some(settings) = &mut settings {
+        let mut request = vec![IAC, WILL, COM_PORT_OPTION];
+        if let Some(current) = &*settings.borrow_and_update() {
+            request.extend(current.to_rfc2217());
+        }
+        if writer.write_all(&request).await.is_err() {
+            return true;
+        }
+    }
+
+    loop {
+        let settings_changed = async {
+            match &mut settings {
+                Some(settings) => settings.changed().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            changed = settings_changed => {
+                // The console went away
+                let Ok(()) = changed else {
+                    return false;
+                };
+                let request = settings.as_mut().and_then(|s| {
+                    let current = s.borrow_and_update();
+                    current.as_ref().map(PortSettings::to_rfc2217)
+                });
+                if let Some(request) = request {
+                    if writer.write_all(&request).await.is_err() {
+                        return true;
+                    }
+                }
+            }
+            data = input.recv() => {
+                let Some(data) = data else {
+                    return false;
+                };
+                let data = match telnet {
+                    Some(_) => telnet_escape(&data),
+                    None => data,
+                };
+                if writer.write_all(&data).await.is_err() {
+                    return true;
+                }
+            }
+            r = reader.read_buf(&mut buf) => {
+                match r {
+                    Ok(0) | Err(_) => return true,
+                    Ok(_) => (),
+                }
+                let data = buf.split();
+                let data = match &mut telnet {
+                    Some(telnet) => {
+                        let mut reply = Vec::new();
+                        let data = telnet.receive(&data, &mut reply);
+                        if !reply.is_empty() && writer.write_all(&reply).await.is_err() {
+                            return true;
+                        }
+                        data
+                    }
+                    None => data.freeze(),
+                };
+                if !data.is_empty() {
+                    let _ = output.send(data);
+                }
+                buf.reserve(4096);
+            }
+        }
+    }
+}
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// RFC 2217 com port control option and its client to server commands
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+
+/// Telnet options accepted in both directions; Everything else is refused
+const BINARY: u8 = 0;
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Minimal telnet receiver, stripping commands from the data stream and answering option
+/// negotiation
+#[derive(Debug, Default)]
+struct Telnet {
+    state: TelnetState,
+    /// The com port control option was requested by us
+    com_port: bool,
+}
+
+impl Telnet {
+    /// Process data received from the peer returning the console data; Replies to negotiations are
+    /// appended to reply
+    fn receive(&mut self, data: &[u8], reply: &mut Vec<u8>) -> Bytes {
+        let mut out = BytesMut::with_capacity(data.len());
+        for &b in data {
+            self.state = match (self.state, b) {
+                (TelnetState::Data, IAC) => TelnetState::Iac,
+                (TelnetState::Data, b) => {
+                    out.extend_from_slice(&[b]);
+                    TelnetState::Data
+                }
+                (TelnetState::Iac, IAC) => {
+                    out.extend_from_slice(&[IAC]);
+                    TelnetState::Data
+                }
+                (TelnetState::Iac, command @ (DO | DONT | WILL | WONT)) => {
+                    TelnetState::Negotiation(command)
+                }
+                (TelnetState::Iac, SB) => TelnetState::Subnegotiation,
+                // Other commands (NOP, GA, ...) carry no data
+                (TelnetState::Iac, _) => TelnetState::Data,
+                (TelnetState::Negotiation(command), option) => {
+                    let accepted = matches!(option, BINARY | ECHO | SUPPRESS_GO_AHEAD);
+                    match command {
+                        WILL => reply.extend_from_slice(&[
+                            IAC,
+                            if accepted { DO } else { DONT },
+                            option,
+                        ]),
+                        // Already requested when connecting
+                        DO if option == COM_PORT_OPTION && self.com_port => (),
+                        DO => reply.extend_from_slice(&[
+                            IAC,
+                            if accepted && option != ECHO {
+                                WILL
+                            } else {
+                                WONT
+                            },
+                            option,
+                        ]),
+                        // Disabling requests are never answered to avoid negotiation loops
+                        _ => (),
+                    }
+                    TelnetState::Data
+                }
+                (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationIac,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationIac, SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationIac, _) => TelnetState::Subnegotiation,
+            }
+        }
+        out.freeze()
+    }
+}
+
+/// Escape data to be sent to a telnet peer
+fn telnet_escape(data: &Bytes) -> Bytes {
+    if !data.contains(&IAC) {
+        return data.clone();
+    }
+    let mut out = BytesMut::with_capacity(data.len() + 1);
+    for &b in data.iter() {
+        if b == IAC {
+            out.extend_from_slice(&[IAC, IAC]);
+        } else {
+            out.extend_from_slice(&[b]);
+        }
+    }
+    out.freeze()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum FlowControl {
+    None,
+    Software,
+    Hardware,
+}
+
+/// Serial port settings of an RFC 2217 console; Unset settings are left as is
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+struct PortSettings {
+    rate: u32,
+    data_bits: Option<u8>,
+    parity: Option<Parity>,
+    stop_bits: Option<u8>,
+    flow_control: Option<FlowControl>,
+}
+
+impl PortSettings {
+    /// Subnegotiations applying the settings
+    fn to_rfc2217(&self) -> Vec<u8> {
+        let mut commands = vec![(SET_BAUDRATE, self.rate.to_be_bytes().to_vec())];
+        if let Some(bits) = self.data_bits {
+            commands.push((SET_DATASIZE, vec![bits]));
+        }
+        if let Some(parity) = self.parity {
+            let value = match parity {
+                Parity::None => 1,
+                Parity::Odd => 2,
+                Parity::Even => 3,
+            };
+            commands.push((SET_PARITY, vec![value]));
+        }
+        if let Some(bits) = self.stop_bits {
+            commands.push((SET_STOPSIZE, vec![bits]));
+        }
+        if let Some(flow_control) = self.flow_control {
+            let value = match flow_control {
+                FlowControl::None => 1,
+                FlowControl::Software => 2,
+                FlowControl::Hardware => 3,
+            };
+            commands.push((SET_CONTROL, vec![value]));
+        }
+
+        let mut out = Vec::new();
+        for (command, value) in commands {
+            out.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, command]);
+            for b in value {
+                if b == IAC {
+                    out.push(IAC);
+                }
+                out.push(b);
+            }
+            out.extend_from_slice(&[IAC, SE]);
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpConsole {
+    input: mpsc::Sender<Bytes>,
+    output: broadcast::Sender<Bytes>,
+    /// Serial port settings to apply for RFC 2217 consoles
+    settings: Option<watch::Sender<Option<PortSettings>>>,
+}
+
+impl TcpConsole {
+    /// Console connected to the endpoint; For RFC 2217 endpoints an initial rate can be given
+    pub fn new(endpoint: Endpoint, rate: Option<u32>) -> Self {
+        let (input, rx) = mpsc::channel(16);
+        let output = broadcast::channel(64).0;
+        let settings = endpoint.rfc2217.then(|| {
+            let initial = rate.map(|rate| PortSettings {
+                rate,
+                data_bits: None,
+                parity: None,
+                stop_bits: None,
+                flow_control: None,
+            });
+            watch::channel(initial).0
+        });
+        tokio::spawn(run(
+            endpoint,
+            rx,
+            output.clone(),
+            settings.as_ref().map(watch::Sender::subscribe),
+        ));
+        Self {
+            input,
+            output,
+            settings,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Console for TcpConsole {
+    fn configure(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer>,
+    ) -> Result<(), ConsoleError> {
+        let Some(settings) = &self.settings else {
+            return Ok(());
+        };
+        let new = PortSettings::deserialize(parameters)
+            .map_err(|e| ConsoleError::Unavailable(format!("Invalid port settings: {e}")))?;
+        settings.send_replace(Some(new));
+        Ok(())
+    }
+
+    async fn input(
+        &self,
+    ) -> Result<Pin<Box<dyn Sink<Bytes, Error = ConsoleError> + Send>>, ConsoleError> {
+        let input = self.input.clone();
+        Ok(Box::pin(futures::sink::unfold(
+            input,
+            |input, data: Bytes| async move {
+                input.send(data).await.map_err(|_| ConsoleError::Closed)?;
+                Ok(input)
+            },
+        )))
+    }
+
+    async fn output(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Bytes, ConsoleError>>, ConsoleError> {
+        Ok(BroadcastStream::new(self.output.subscribe())
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => Some(Ok(data)),
+                    Err(BroadcastStreamRecvError::Lagged(lost)) => {
+                        warn!("TCP console output lagged, lost {} messages", lost);
+                        None
+                    }
+                }
+            })
+            .boxed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn telnet_negotiation() {
+        let mut telnet = Telnet::default();
+        let mut reply = Vec::new();
+
+        // Negotiation and subnegotiation split over reads
+        let data = telnet.receive(&[b'a', IAC, WILL, ECHO, IAC, DO, 24, IAC], &mut reply);
+        assert_eq!(&data[..], b"a");
+        assert_eq!(reply, [IAC, DO, ECHO, IAC, WONT, 24]);
+
+        reply.clear();
+        let data = telnet.receive(&[SB, 24, 1, IAC, SE, IAC, IAC, b'b'], &mut reply);
+        assert_eq!(&data[..], &[IAC, b'b']);
+        assert!(reply.is_empty());
+
+        // Com port control is only accepted when requested
+        reply.clear();
+        telnet.receive(&[IAC, DO, COM_PORT_OPTION], &mut reply);
+        assert_eq!(reply, [IAC, WONT, COM_PORT_OPTION]);
+        let mut telnet = Telnet {
+            com_port: true,
+            ..Default::default()
+        };
+        reply.clear();
+        telnet.receive(&[IAC, DO, COM_PORT_OPTION], &mut reply);
+        assert!(reply.is_empty());
+
+        assert_eq!(
+            &telnet_escape(&Bytes::from_static(&[b'c', IAC]))[..],
+            &[b'c', IAC, IAC]
+        );
+    }
+
+    #[test]
+    fn rfc2217_settings() {
+        let settings = PortSettings {
+            rate: 115_200,
+            data_bits: Some(8),
+            parity: Some(Parity::None),
+            stop_bits: None,
+            flow_control: None,
+        };
+        assert_eq!(
+            settings.to_rfc2217(),
+            [
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_BAUDRATE,
+                0,
+                1,
+                0xc2,
+                0,
+                IAC,
+                SE,
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_DATASIZE,
+                8,
+                IAC,
+                SE,
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_PARITY,
+                1,
+                IAC,
+                SE
+            ]
+        );
+    }
+}
@@ -0,0 +1,743 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use android_sparse_image::{
+    ChunkHeader, ChunkType, FileHeader, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    property_match::PropertyMatch,
+    registry::{self, Properties, RegistryChange},
+    worker::Workers,
+    FlushCompletion, ReadCompletion, Server, ShutdownCompletion, Volume, VolumeError, VolumeTarget,
+    VolumeTargetInfo, WriteCompletion,
+};
+
+pub const PROVIDER: &str = "transform";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum TransformConfig {
+    /// gzip decompression
+    Decompress,
+    /// Expand an android sparse image into a raw image
+    SparseExpand,
+    /// Swap the byte order of each word of `width` bytes
+    ByteSwap {
+        #[serde(default = "default_swap_width")]
+        width: usize,
+    },
+    /// Pad the data up to a multiple of `block` bytes
+    Pad {
+        block: usize,
+        #[serde(default)]
+        fill: u8,
+    },
+}
+
+fn default_swap_width() -> usize {
+    4
+}
+
+#[derive(Deserialize, Debug)]
+struct TargetConfig {
+    name: String,
+    transforms: Vec<TransformConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TransformParameters {
+    #[serde(rename = "match")]
+    match_: HashMap<String, PropertyMatch>,
+    targets: Vec<TargetConfig>,
+}
+
+#[derive(Debug, Error)]
+enum TransformError {
+    #[error("Decompression failed: {0}")]
+    Decompress(#[from] std::io::Error),
+    #[error("Invalid sparse image: {0}")]
+    Sparse(#[from] android_sparse_image::ParseError),
+    #[error("Data isn't a multiple of {0} bytes")]
+    Unaligned(usize),
+    #[error("Unexpected end of data")]
+    Truncated,
+}
+
+impl From<TransformError> for tonic::Status {
+    fn from(e: TransformError) -> Self {
+        tonic::Status::invalid_argument(e.to_string())
+    }
+}
+
+/// Most data a transform generates by itself (e.g. expanding a sparse image) per call; The rest
+/// is held back until drained
+const GENERATED_CHUNK: usize = 4 * 1024 * 1024;
+
+/// A single step of a transformation pipeline; Data is pushed through in order and once all
+/// input has been pushed the transform is finished to flush out any remaining output
+trait Transform: Send {
+    fn push(&mut self, data: Bytes) -> Result<Bytes, TransformError>;
+    /// Output held back by earlier pushes to bound its size; Called until it returns no data
+    fn drain(&mut self) -> Result<Bytes, TransformError> {
+        Ok(Bytes::new())
+    }
+    fn finish(&mut self) -> Result<Bytes, TransformError>;
+    /// Length of the output for a given input length if known upfront
+    fn output_length(&self, _length: Option<u64>) -> Option<u64> {
+        None
+    }
+}
+
+struct Decompress {
+    decoder: flate2::write::GzDecoder<Vec<u8>>,
+}
+
+impl Decompress {
+    fn new() -> Self {
+        Self {
+            decoder: flate2::write::GzDecoder::new(Vec::new()),
+        }
+    }
+}
+
+impl Transform for Decompress {
+    fn push(&mut self, data: Bytes) -> Result<Bytes, TransformError> {
+        self.decoder.write_all(&data)?;
+        Ok(std::mem::take(self.decoder.get_mut()).into())
+    }
+
+    fn finish(&mut self) -> Result<Bytes, TransformError> {
+        self.decoder.try_finish()?;
+        Ok(std::mem::take(self.decoder.get_mut()).into())
+    }
+}
+
+enum SparseState {
+    FileHeader,
+    ChunkHeader,
+    Raw(usize),
+    /// Waiting for the fill pattern of the chunk
+    Fill(usize),
+    /// Writing out the fill pattern; Rotated along with the output
+    Pattern([u8; 4], usize),
+    Zero(usize),
+    Skip(usize),
+    Done,
+}
+
+struct SparseExpand {
+    buffer: BytesMut,
+    state: SparseState,
+    header: Option<FileHeader>,
+    chunks_left: u32,
+}
+
+impl SparseExpand {
+    fn new() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            state: SparseState::FileHeader,
+            header: None,
+            chunks_left: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> SparseState {
+        if self.chunks_left == 0 {
+            SparseState::Done
+        } else {
+            self.chunks_left -= 1;
+            SparseState::ChunkHeader
+        }
+    }
+}
+
+impl SparseExpand {
+    /// Expand as much of the buffered input as possible, generating at most [GENERATED_CHUNK]
+    /// bytes of fill or zero data
+    fn expand(&mut self) -> Result<Bytes, TransformError> {
+        let mut out = BytesMut::new();
+        let mut generated = 0;
+        loop {
+            self.state = match self.state {
+                SparseState::FileHeader => {
+                    if self.buffer.len() < FILE_HEADER_BYTES_LEN {
+                        break;
+                    }
+                    let bytes = self.buffer.split_to(FILE_HEADER_BYTES_LEN);
+                    let header = FileHeader::from_bytes(bytes[..].try_into().unwrap())?;
+                    self.chunks_left = header.chunks;
+                    self.header = Some(header);
+                    self.next_chunk()
+                }
+                SparseState::ChunkHeader => {
+                    if self.buffer.len() < CHUNK_HEADER_BYTES_LEN {
+                        break;
+                    }
+                    let bytes = self.buffer.split_to(CHUNK_HEADER_BYTES_LEN);
+                    let chunk = ChunkHeader::from_bytes(bytes[..].try_into().unwrap())?;
+                    let out_size = chunk.out_size(self.header.as_ref().unwrap());
+                    match chunk.chunk_type {
+                        ChunkType::Raw => SparseState::Raw(out_size),
+                        ChunkType::Fill => SparseState::Fill(out_size),
+                        // Output is written out sequentially so don't care areas can't be
+                        // skipped; zero them instead
+                        ChunkType::DontCare => SparseState::Zero(out_size),
+                        ChunkType::Crc32 => SparseState::Skip(chunk.data_size()),
+                    }
+                }
+                SparseState::Raw(left) => {
+                    if left == 0 {
+                        self.next_chunk()
+                    } else if self.buffer.is_empty() {
+                        break;
+                    } else {
+                        let len = left.min(self.buffer.len());
+                        out.extend_from_slice(&self.buffer.split_to(len));
+                        SparseState::Raw(left - len)
+                    }
+                }
+                SparseState::Fill(size) => {
+                    if self.buffer.len() < 4 {
+                        break;
+                    }
+                    let pattern = self.buffer.split_to(4);
+                    SparseState::Pattern(pattern[..].try_into().unwrap(), size)
+                }
+                SparseState::Pattern(mut pattern, left) => {
+                    if left == 0 {
+                        self.next_chunk()
+                    } else if generated == GENERATED_CHUNK {
+                        break;
+                    } else {
+                        let len = left.min(GENERATED_CHUNK - generated);
+                        out.extend(pattern.iter().cycle().take(len));
+                        pattern.rotate_left(len % 4);
+                        generated += len;
+                        SparseState::Pattern(pattern, left - len)
+                    }
+                }
+                SparseState::Zero(left) => {
+                    if left == 0 {
+                        self.next_chunk()
+                    } else if generated == GENERATED_CHUNK {
+                        break;
+                    } else {
+                        let len = left.min(GENERATED_CHUNK - generated);
+                        out.resize(out.len() + len, 0);
+                        generated += len;
+                        SparseState::Zero(left - len)
+                    }
+                }
+                SparseState::Skip(left) => {
+                    if left == 0 {
+                        self.next_chunk()
+                    } else if self.buffer.is_empty() {
+                        break;
+                    } else {
+                        let len = left.min(self.buffer.len());
+                        self.buffer.advance(len);
+                        SparseState::Skip(left - len)
+                    }
+                }
+                SparseState::Done => {
+                    if !self.buffer.is_empty() {
+                        debug!("Ignoring {} bytes after sparse image", self.buffer.len());
+                        self.buffer.clear();
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(out.freeze())
+    }
+}
+
+impl Transform for SparseExpand {
+    fn push(&mut self, data: Bytes) -> Result<Bytes, TransformError> {
+        self.buffer.extend_from_slice(&data);
+        self.expand()
+    }
+
+    fn drain(&mut self) -> Result<Bytes, TransformError> {
+        self.expand()
+    }
+
+    fn finish(&mut self) -> Result<Bytes, TransformError> {
+        match self.state {
+            SparseState::Done => Ok(Bytes::new()),
+            _ => Err(TransformError::Truncated),
+        }
+    }
+}
+
+struct ByteSwap {
+    width: usize,
+    buffer: BytesMut,
+}
+
+impl Transform for ByteSwap {
+    fn push(&mut self, data: Bytes) -> Result<Bytes, TransformError> {
+        self.buffer.extend_from_slice(&data);
+        let len = self.buffer.len() - self.buffer.len() % self.width;
+        let mut out = self.buffer.split_to(len);
+        out.chunks_exact_mut(self.width).for_each(|w| w.reverse());
+        Ok(out.freeze())
+    }
+
+    fn finish(&mut self) -> Result<Bytes, TransformError> {
+        if self.buffer.is_empty() {
+            Ok(Bytes::new())
+        } else {
+            Err(TransformError::Unaligned(self.width))
+        }
+    }
+
+    fn output_length(&self, length: Option<u64>) -> Option<u64> {
+        length
+    }
+}
+
+struct Pad {
+    block: usize,
+    fill: u8,
+    total: usize,
+}
+
+impl Transform for Pad {
+    fn push(&mut self, data: Bytes) -> Result<Bytes, TransformError> {
+        self.total += data.len();
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<Bytes, TransformError> {
+        let padding = self.total.next_multiple_of(self.block) - self.total;
+        self.total += padding;
+        Ok(vec![self.fill; padding].into())
+    }
+
+    fn output_length(&self, length: Option<u64>) -> Option<u64> {
+        length.map(|l| l.next_multiple_of(self.block as u64))
+    }
+}
+
+struct Pipeline {
+    transforms: Vec<Box<dyn Transform>>,
+    /// Number of transforms finished so far
+    finished: usize,
+}
+
+impl Pipeline {
+    fn new(config: &[TransformConfig]) -> Self {
+        let transforms = config
+            .iter()
+            .map(|c| -> Box<dyn Transform> {
+                match c {
+                    TransformConfig::Decompress => Box::new(Decompress::new()),
+                    TransformConfig::SparseExpand => Box::new(SparseExpand::new()),
+                    TransformConfig::ByteSwap { width } => Box::new(ByteSwap {
+                        width: (*width).max(1),
+                        buffer: BytesMut::new(),
+                    }),
+                    TransformConfig::Pad { block, fill } => Box::new(Pad {
+                        block: (*block).max(1),
+                        fill: *fill,
+                        total: 0,
+                    }),
+                }
+            })
+            .collect();
+        Self {
+            transforms,
+            finished: 0,
+        }
+    }
+
+    /// Push data through the transforms starting at `first`
+    fn push_from(&mut self, first: usize, data: Bytes) -> Result<Bytes, TransformError> {
+        self.transforms[first..]
+            .iter_mut()
+            .try_fold(data, |data, t| t.push(data))
+    }
+
+    fn push(&mut self, data: Bytes) -> Result<Bytes, TransformError> {
+        self.push_from(0, data)
+    }
+
+    /// Next part of the output held back by any of the transforms; None once all got drained
+    fn drain(&mut self) -> Result<Option<Bytes>, TransformError> {
+        for i in 0..self.transforms.len() {
+            let data = self.transforms[i].drain()?;
+            if !data.is_empty() {
+                return self.push_from(i + 1, data).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finish the transforms one by one, draining each before finishing it; Returns the next
+    /// part of the remaining output, None once all transforms are finished
+    fn finish(&mut self) -> Result<Option<Bytes>, TransformError> {
+        loop {
+            if let Some(data) = self.drain()? {
+                return Ok(Some(data));
+            }
+            if self.finished == self.transforms.len() {
+                return Ok(None);
+            }
+            let i = self.finished;
+            self.finished += 1;
+            let data = self.transforms[i].finish()?;
+            let data = self.push_from(i + 1, data)?;
+            if !data.is_empty() {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn output_length(&self, length: Option<u64>) -> Option<u64> {
+        self.transforms
+            .iter()
+            .fold(length, |length, t| t.output_length(length))
+    }
+}
+
+/// Volume target passing all written data through a transformation pipeline; As the
+/// transformations are streaming only sequential writes are supported
+struct TransformTarget {
+    inner: Box<dyn VolumeTarget>,
+    /// Shared with the worker running the transformations
+    pipeline: Arc<Mutex<Pipeline>>,
+    workers: Arc<Workers>,
+    input_offset: u64,
+    output_offset: u64,
+}
+
+impl TransformTarget {
+    /// Run a step of the pipeline on the workers
+    fn run_pipeline<F>(
+        &self,
+        f: F,
+    ) -> impl Future<Output = Result<Option<Bytes>, TransformError>> + Send + 'static
+    where
+        F: FnOnce(&mut Pipeline) -> Result<Option<Bytes>, TransformError> + Send + 'static,
+    {
+        let pipeline = self.pipeline.clone();
+        let workers = self.workers.clone();
+        async move { workers.run(move || f(&mut pipeline.lock().unwrap())).await }
+    }
+
+    /// Write out the output of pipeline steps until `f` returns None
+    async fn write_pipeline<F>(&mut self, f: F) -> Result<(), tonic::Status>
+    where
+        F: Fn(&mut Pipeline) -> Result<Option<Bytes>, TransformError> + Clone + Send + 'static,
+    {
+        while let Some(data) = self.run_pipeline(f.clone()).await? {
+            self.write_inner(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Push data through the pipeline, writing out all output it results in
+    async fn write_transformed(&mut self, data: Bytes) -> Result<(), tonic::Status> {
+        let data = self.run_pipeline(move |p| p.push(data).map(Some)).await?;
+        self.write_inner(data.unwrap_or_default()).await?;
+        self.write_pipeline(Pipeline::drain).await
+    }
+
+    async fn write_inner(&mut self, mut data: Bytes) -> Result<(), tonic::Status> {
+        while !data.is_empty() {
+            let (completion, rx) = WriteCompletion::new();
+            self.inner
+                .write(data.clone(), self.output_offset, completion)
+                .await;
+            let written = rx
+                .await
+                .map_err(|_| tonic::Status::internal("Write got dropped"))??;
+            if written == 0 {
+                return Err(tonic::Status::aborted("Target didn't accept any data"));
+            }
+            data.advance(written as usize);
+            self.output_offset += written;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for TransformTarget {
+    async fn read(&mut self, length: u64, offset: u64, completion: ReadCompletion) {
+        self.inner.read(length, offset, completion).await
+    }
+
+    async fn write(&mut self, data: Bytes, offset: u64, completion: WriteCompletion) {
+        if offset != self.input_offset {
+            completion.complete(Err(tonic::Status::out_of_range("Invalid offset")));
+            return;
+        }
+        let len = data.len() as u64;
+        let r = self.write_transformed(data).await;
+        self.input_offset += len;
+        completion.complete(r.map(|_| len));
+    }
+
+    async fn flush(&mut self, completion: FlushCompletion) {
+        self.inner.flush(completion).await
+    }
+
+    async fn shutdown(&mut self, completion: ShutdownCompletion) {
+        match self.write_pipeline(Pipeline::finish).await {
+            Ok(()) => self.inner.shutdown(completion).await,
+            Err(e) => completion.complete(Err(e)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TransformVolume {
+    inner: Arc<dyn Volume>,
+    targets: Arc<Vec<TargetConfig>>,
+    workers: Arc<Workers>,
+}
+
+#[async_trait::async_trait]
+impl Volume for TransformVolume {
+    fn targets(&self) -> (&[VolumeTargetInfo], bool) {
+        self.inner.targets()
+    }
+
+    async fn open(
+        &self,
+        target: &str,
+        length: Option<u64>,
+    ) -> Result<(VolumeTargetInfo, Box<dyn VolumeTarget>), VolumeError> {
+        if let Some(config) = self.targets.iter().find(|t| t.name == target) {
+            let pipeline = Pipeline::new(&config.transforms);
+            let (info, inner) = self
+                .inner
+                .open(target, pipeline.output_length(length))
+                .await?;
+            Ok((
+                info,
+                Box::new(TransformTarget {
+                    inner,
+                    pipeline: Arc::new(Mutex::new(pipeline)),
+                    workers: self.workers.clone(),
+                    input_offset: 0,
+                    output_offset: 0,
+                }),
+            ))
+        } else {
+            self.inner.open(target, length).await
+        }
+    }
+
+    async fn commit(&self) -> Result<(), VolumeError> {
+        self.inner.commit().await
+    }
+
+    async fn erase(&self, target: &str) -> Result<(), VolumeError> {
+        self.inner.erase(target).await
+    }
+}
+
+#[instrument(fields(name), skip_all, level = "error")]
+pub async fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+    let parameters: TransformParameters = serde_yaml::from_value(parameters).unwrap();
+    if parameters.match_.is_empty() {
+        warn!("matches is empty - will match any volume");
+    }
+    let targets = Arc::new(parameters.targets);
+    // Mapping of the wrapped volume ids to the transformed volume ids
+    let mut registrations: HashMap<u64, u64> = HashMap::new();
+
+    let matches = |properties: &Properties| {
+        properties.get(registry::PROVIDER_NAME) != Some(name.as_str())
+            && properties.matches(&parameters.match_)
+    };
+    // The transformed volume is a local item, even when wrapping a remote one
+    let transformed_properties = |properties: &Properties| {
+        let mut properties: Properties = properties
+            .iter()
+            .filter(|(k, _)| {
+                ![
+                    registry::INSTANCE,
+                    registry::SERVER,
+                    registry::SERVER_ADDRESS,
+                ]
+                .contains(&k.as_str())
+            })
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<HashMap<_, _>>()
+            .into();
+        properties.extend(provider_properties);
+        properties
+    };
+
+    let add =
+        |registrations: &mut HashMap<u64, u64>, id: u64, item: registry::Item<Arc<dyn Volume>>| {
+            let properties = item.properties();
+            if registrations.contains_key(&id) || !matches(&properties) {
+                return;
+            }
+            let properties = transformed_properties(&properties);
+            let volume = TransformVolume {
+                inner: item.into_inner(),
+                targets: targets.clone(),
+                workers: server.workers(),
+            };
+            registrations.insert(id, server.register_volume(properties, volume));
+        };
+
+    let mut monitor = server.inner.volumes.monitor();
+    for (id, item) in server.inner.volumes.contents() {
+        add(&mut registrations, id, item);
+    }
+
+    loop {
+        let changes = match monitor.recv().await {
+            Ok(change) => vec![change],
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Missed {missed} volume changes; Resyncing");
+                let known = registrations.keys().copied().collect();
+                server.inner.volumes.resync(&known)
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        for change in changes {
+            match change {
+                RegistryChange::Added { id, item } => add(&mut registrations, id, item),
+                RegistryChange::Updated { id, item } => match registrations.get(&id) {
+                    Some(&transformed) if matches(&item.properties()) => server.update_item(
+                        boardswarm_protocol::ItemType::Volume,
+                        transformed,
+                        transformed_properties(&item.properties()),
+                    ),
+                    Some(_) => {
+                        if let Some(transformed) = registrations.remove(&id) {
+                            server.unregister_volume(transformed);
+                        }
+                    }
+                    None => add(&mut registrations, id, item),
+                },
+                RegistryChange::Removed(id) => {
+                    if let Some(transformed) = registrations.remove(&id) {
+                        server.unregister_volume(transformed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(config: &[TransformConfig], chunks: &[&[u8]]) -> Result<Vec<u8>, TransformError> {
+        let mut pipeline = Pipeline::new(config);
+        let mut out = Vec::new();
+        for c in chunks {
+            out.extend_from_slice(&pipeline.push(Bytes::copy_from_slice(c))?);
+            while let Some(data) = pipeline.drain()? {
+                out.extend_from_slice(&data);
+            }
+        }
+        while let Some(data) = pipeline.finish()? {
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn swap_and_pad() {
+        let config = [
+            TransformConfig::ByteSwap { width: 4 },
+            TransformConfig::Pad {
+                block: 8,
+                fill: 0xff,
+            },
+        ];
+        let out = run(&config, &[&[1, 2, 3], &[4, 5, 6, 7, 8, 9, 10, 11, 12]]).unwrap();
+        assert_eq!(
+            out,
+            [4, 3, 2, 1, 8, 7, 6, 5, 12, 11, 10, 9, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(Pipeline::new(&config).output_length(Some(12)), Some(16));
+
+        assert!(run(&config, &[&[1, 2, 3]]).is_err());
+    }
+
+    #[test]
+    fn decompress_sparse() {
+        let header = FileHeader {
+            block_size: 4,
+            blocks: 4,
+            chunks: 3,
+            checksum: 0,
+        };
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&ChunkHeader::new_raw(1, 4).to_bytes());
+        image.extend_from_slice(&[1, 2, 3, 4]);
+        image.extend_from_slice(&ChunkHeader::new_dontcare(1).to_bytes());
+        image.extend_from_slice(&ChunkHeader::new_fill(2).to_bytes());
+        image.extend_from_slice(&[5, 6, 7, 8]);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&image).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let config = [TransformConfig::Decompress, TransformConfig::SparseExpand];
+        let chunks: Vec<_> = compressed.chunks(5).collect();
+        let out = run(&config, &chunks).unwrap();
+        assert_eq!(out, [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 5, 6, 7, 8]);
+
+        assert!(run(&config, &[&compressed[..compressed.len() / 2]]).is_err());
+    }
+
+    #[test]
+    fn sparse_generated_data_is_bounded() {
+        let block_size = 4096;
+        let header = FileHeader {
+            block_size,
+            blocks: 4096,
+            chunks: 3,
+            checksum: 0,
+        };
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&ChunkHeader::new_dontcare(2048).to_bytes());
+        image.extend_from_slice(&ChunkHeader::new_fill(2047).to_bytes());
+        image.extend_from_slice(&[1, 2, 3, 4]);
+        image.extend_from_slice(&ChunkHeader::new_raw(1, block_size).to_bytes());
+        image.extend_from_slice(&vec![9; block_size as usize]);
+
+        let mut pipeline = Pipeline::new(&[TransformConfig::SparseExpand]);
+        let mut out = pipeline.push(image.into()).unwrap().to_vec();
+        assert_eq!(out.len(), GENERATED_CHUNK);
+        while let Some(data) = pipeline.drain().unwrap() {
+            assert!(data.len() <= GENERATED_CHUNK + block_size as usize);
+            out.extend_from_slice(&data);
+        }
+        assert_eq!(pipeline.finish().unwrap(), None);
+
+        let (zero, rest) = out.split_at(2048 * block_size as usize);
+        let (fill, raw) = rest.split_at(2047 * block_size as usize);
+        assert!(zero.iter().all(|&b| b == 0));
+        assert!(fill.chunks(4).all(|c| c == [1, 2, 3, 4]));
+        assert_eq!(raw, vec![9; block_size as usize]);
+    }
+}
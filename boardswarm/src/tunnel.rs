@@ -0,0 +1,62 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::process::Command;
+use tracing::{info, instrument, warn};
+
+use crate::config;
+
+const DEFAULT_RETRY: Duration = Duration::from_secs(10);
+
+/// Address the tunnel should forward to; The wildcard addresses can't be connected to so use
+/// the loopback address of the same family instead
+fn forward_address(listen: SocketAddr) -> SocketAddr {
+    let ip = match listen.ip() {
+        std::net::IpAddr::V4(ip) if ip.is_unspecified() => std::net::Ipv4Addr::LOCALHOST.into(),
+        std::net::IpAddr::V6(ip) if ip.is_unspecified() => std::net::Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, listen.port())
+}
+
+fn ssh_command(tunnel: &config::Tunnel, listen: SocketAddr) -> Command {
+    let forward = forward_address(listen);
+    let mut command = Command::new("ssh");
+    command
+        .arg("-N")
+        .args(["-o", "BatchMode=yes"])
+        .args(["-o", "ExitOnForwardFailure=yes"])
+        .args(["-o", "ServerAliveInterval=15"])
+        .args(["-o", "ServerAliveCountMax=3"])
+        .arg("-R")
+        .arg(format!(
+            "{}:[{}]:{}",
+            tunnel.remote,
+            forward.ip(),
+            forward.port()
+        ));
+    if let Some(port) = tunnel.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    if let Some(identity) = &tunnel.identity {
+        command.arg("-i").arg(identity);
+    }
+    if let Some(user) = &tunnel.user {
+        command.arg("-l").arg(user);
+    }
+    command.arg(&tunnel.host).kill_on_drop(true);
+    command
+}
+
+/// Keep a reverse ssh tunnel to a hub established so it can reach this server
+#[instrument(fields(host = tunnel.host), skip_all, level = "error")]
+pub async fn run(tunnel: config::Tunnel, listen: SocketAddr) {
+    let retry = tunnel.retry.unwrap_or(DEFAULT_RETRY);
+    loop {
+        info!("Establishing tunnel to {} ({})", tunnel.host, tunnel.remote);
+        match ssh_command(&tunnel, listen).status().await {
+            Ok(status) => warn!("Tunnel closed: {}", status),
+            Err(e) => warn!("Failed to start ssh: {}", e),
+        }
+        tokio::time::sleep(retry).await;
+    }
+}
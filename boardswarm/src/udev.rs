@@ -167,8 +167,13 @@ impl DeviceStream {
 }
 
 pub enum DeviceEvent {
-    Add { device: Device, seqnum: u64 },
+    Add {
+        device: Device,
+        seqnum: u64,
+    },
     Remove(Device),
+    /// The properties of a known device changed, e.g. after udev rules were reloaded
+    Change(Device),
 }
 
 impl Stream for DeviceStream {
@@ -203,6 +208,9 @@ impl Stream for DeviceStream {
                     tokio_udev::EventType::Remove => {
                         return Poll::Ready(Some(DeviceEvent::Remove(Device(event.device()))))
                     }
+                    tokio_udev::EventType::Change => {
+                        return Poll::Ready(Some(DeviceEvent::Change(Device(event.device()))))
+                    }
                     _ => continue,
                 }
             }
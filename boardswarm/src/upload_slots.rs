@@ -0,0 +1,92 @@
+// Limits on the number of uploads running at once per provider, as many parallel transfers (e.g.
+// DFU downloads through a single USB host controller) overload hubs and cause spurious failures;
+// Uploads beyond the limit queue until one of the running uploads finishes
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+use crate::config;
+use crate::registry::{self, Properties};
+
+#[derive(Default)]
+pub struct UploadSlots {
+    /// By provider type (`boardswarm.provider`); Shared by all providers of the type
+    types: HashMap<String, Arc<Semaphore>>,
+    /// By provider name (`boardswarm.provider.name`); Takes precedence over the type
+    providers: HashMap<String, Arc<Semaphore>>,
+}
+
+impl UploadSlots {
+    pub fn from_config(server: &config::Server, providers: &[config::Provider]) -> Self {
+        let types = server
+            .upload_slots
+            .iter()
+            .map(|(provider, slots)| (provider.clone(), Arc::new(Semaphore::new(*slots))))
+            .collect();
+        let providers = providers
+            .iter()
+            .filter_map(|p| {
+                p.max_uploads
+                    .map(|slots| (p.name.clone(), Arc::new(Semaphore::new(slots))))
+            })
+            .collect();
+        Self { types, providers }
+    }
+
+    fn for_item(&self, properties: &Properties) -> Option<&Arc<Semaphore>> {
+        properties
+            .get(registry::PROVIDER_NAME)
+            .and_then(|name| self.providers.get(name))
+            .or_else(|| {
+                properties
+                    .get(registry::PROVIDER)
+                    .and_then(|type_| self.types.get(type_))
+            })
+    }
+
+    /// Wait for a free slot for an upload to the volume with the given properties; The slot is
+    /// held until the permit is dropped. None if uploads of its provider aren't limited
+    pub async fn acquire(&self, properties: &Properties) -> Option<OwnedSemaphorePermit> {
+        let slots = self.for_item(properties)?.clone();
+        match slots.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                info!(
+                    "Upload to {} queued until a running upload finishes",
+                    properties.name()
+                );
+                slots.acquire_owned().await.ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn slots() {
+        let server = config::Server {
+            upload_slots: [("dfu".to_string(), 1)].into(),
+            ..Default::default()
+        };
+        let slots = UploadSlots::from_config(&server, &[]);
+
+        let mut dfu = Properties::new("dfu");
+        dfu.insert(registry::PROVIDER, "dfu");
+        let mut fastboot = Properties::new("fastboot");
+        fastboot.insert(registry::PROVIDER, "fastboot");
+
+        let first = slots.acquire(&dfu).await.unwrap();
+        assert!(slots.acquire(&fastboot).await.is_none());
+        // The second dfu upload has to wait for the first one
+        let second = slots.acquire(&dfu);
+        tokio::pin!(second);
+        assert!(futures::poll!(&mut second).is_pending());
+        drop(first);
+        assert!(second.await.is_some());
+    }
+}
@@ -0,0 +1,137 @@
+// Verification of data uploaded to volumes, as flashing a truncated or corrupted image can leave a
+// board unbootable
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("Invalid SHA-256 of {0} bytes")]
+    InvalidDigest(usize),
+    #[error("CRC32 of write at offset {offset} is {actual:08x}, expected {expected:08x}")]
+    ChunkChecksum {
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("Write at offset {offset} isn't sequential (expected offset {expected}), can't verify the upload")]
+    NotSequential { offset: u64, expected: u64 },
+    #[error("SHA-256 of the {length} uploaded bytes is {actual}, expected {expected}")]
+    Checksum {
+        length: u64,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl From<VerifyError> for tonic::Status {
+    fn from(e: VerifyError) -> Self {
+        match e {
+            VerifyError::InvalidDigest(_) => tonic::Status::invalid_argument(e.to_string()),
+            _ => tonic::Status::data_loss(e.to_string()),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Check the data of a single write against the CRC32 supplied by the client
+pub fn check_chunk(data: &[u8], offset: u64, expected: u32) -> Result<(), VerifyError> {
+    let actual = crc32fast::hash(data);
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(VerifyError::ChunkChecksum {
+            offset,
+            expected,
+            actual,
+        })
+    }
+}
+
+/// Running SHA-256 of the data written to a target; The data has to be written sequentially,
+/// starting from the offset of the first write
+pub struct Verifier {
+    expected: [u8; 32],
+    hasher: Sha256,
+    start: Option<u64>,
+    length: u64,
+}
+
+impl Verifier {
+    pub fn new(expected: &[u8]) -> Result<Self, VerifyError> {
+        let expected = expected
+            .try_into()
+            .map_err(|_| VerifyError::InvalidDigest(expected.len()))?;
+        Ok(Self {
+            expected,
+            hasher: Sha256::new(),
+            start: None,
+            length: 0,
+        })
+    }
+
+    pub fn update(&mut self, offset: u64, data: &[u8]) -> Result<(), VerifyError> {
+        let start = *self.start.get_or_insert(offset);
+        let expected = start + self.length;
+        if offset != expected {
+            return Err(VerifyError::NotSequential { offset, expected });
+        }
+        self.hasher.update(data);
+        self.length += data.len() as u64;
+        Ok(())
+    }
+
+    /// The expected digest if it covers the whole target, i.e. the data was written from its start
+    pub fn image_digest(&self) -> Option<[u8; 32]> {
+        (self.start.unwrap_or_default() == 0).then_some(self.expected)
+    }
+
+    pub fn finish(self) -> Result<(), VerifyError> {
+        let actual = self.hasher.finalize();
+        if actual[..] == self.expected {
+            Ok(())
+        } else {
+            Err(VerifyError::Checksum {
+                length: self.length,
+                expected: hex(&self.expected),
+                actual: hex(&actual),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let expected = Sha256::digest(b"hello world");
+
+        let mut verifier = Verifier::new(&expected).unwrap();
+        verifier.update(512, b"hello").unwrap();
+        verifier.update(517, b" world").unwrap();
+        verifier.finish().unwrap();
+
+        let mut verifier = Verifier::new(&expected).unwrap();
+        verifier.update(0, b"hello").unwrap();
+        assert!(matches!(
+            verifier.finish(),
+            Err(VerifyError::Checksum { length: 5, .. })
+        ));
+
+        let mut verifier = Verifier::new(&expected).unwrap();
+        verifier.update(0, b"hello").unwrap();
+        assert!(verifier.update(0, b"hello").is_err());
+
+        assert!(Verifier::new(b"short").is_err());
+    }
+
+    #[test]
+    fn chunk() {
+        check_chunk(b"data", 0, crc32fast::hash(b"data")).unwrap();
+        assert!(check_chunk(b"data", 0, crc32fast::hash(b"date")).is_err());
+    }
+}
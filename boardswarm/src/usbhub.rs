@@ -0,0 +1,150 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use futures::StreamExt;
+use nusb::transfer::{Control, ControlType, Recipient};
+use serde::Deserialize;
+use tracing::{debug, instrument, warn};
+
+use crate::{property_match::PropertyMatch, registry, udev::DeviceEvent, ActuatorError, Server};
+
+pub const PROVIDER: &str = "usbhub";
+
+/// Hub class requests and the port power feature selector (USB 2.0 spec, 11.24.2)
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const PORT_POWER: u16 = 8;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug)]
+struct Hub {
+    name: String,
+    /// udev properties identifying the hub
+    #[serde(rename = "match")]
+    match_: HashMap<String, PropertyMatch>,
+    /// Number of downstream ports
+    ports: u16,
+}
+
+#[derive(Deserialize, Debug)]
+struct UsbHubParameters {
+    hubs: Vec<Hub>,
+}
+
+#[instrument(skip(parameters, server))]
+pub async fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: UsbHubParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let mut registrations: HashMap<PathBuf, Vec<u64>> = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("usb").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                if device.devnode().is_none() {
+                    continue;
+                }
+                let properties = device.properties(device.syspath().to_string_lossy());
+                let Some(hub) = parameters
+                    .hubs
+                    .iter()
+                    .find(|h| properties.matches(&h.match_))
+                else {
+                    continue;
+                };
+                let (Some(bus), Some(dev)) = (
+                    device
+                        .property_u64("BUSNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                    device
+                        .property_u64("DEVNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                ) else {
+                    continue;
+                };
+                debug!("Found hub {} at {}/{}", hub.name, bus, dev);
+
+                let ids = (1..=hub.ports)
+                    .map(|port| {
+                        let mut properties = properties.clone();
+                        properties.insert(registry::NAME, format!("{}.port-{}", hub.name, port));
+                        properties.extend(provider_properties);
+                        properties.insert(format!("{PROVIDER}.hub"), hub.name.as_str());
+                        properties.insert(format!("{PROVIDER}.port"), port.to_string());
+                        server.register_actuator(properties, HubPort { bus, dev, port })
+                    })
+                    .collect();
+                registrations.insert(device.syspath().to_path_buf(), ids);
+            }
+            DeviceEvent::Remove(device) => {
+                for id in registrations.remove(device.syspath()).unwrap_or_default() {
+                    server.unregister_actuator(id)
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+/// Power switch of a single hub port
+#[derive(Debug)]
+struct HubPort {
+    bus: u8,
+    dev: u8,
+    port: u16,
+}
+
+impl HubPort {
+    fn set_power(bus: u8, dev: u8, port: u16, on: bool) -> Result<(), String> {
+        let info = crate::utils::nusb_info_from_bus_dev(bus, dev)
+            .ok_or_else(|| "Hub not found".to_string())?;
+        let hub = info.open().map_err(|e| e.to_string())?;
+        hub.control_out_blocking(
+            Control {
+                control_type: ControlType::Class,
+                recipient: Recipient::Other,
+                request: if on { SET_FEATURE } else { CLEAR_FEATURE },
+                value: PORT_POWER,
+                index: port,
+            },
+            &[],
+            CONTROL_TIMEOUT,
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for HubPort {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        let on = match parameters.mode.as_str() {
+            "on" => true,
+            "off" => false,
+            mode => {
+                warn!("Unsupported usb hub port mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        };
+
+        let (bus, dev, port) = (self.bus, self.dev, self.port);
+        tokio::task::spawn_blocking(move || Self::set_power(bus, dev, port, on))
+            .await
+            .map_err(|_e| ActuatorError {})?
+            .map_err(|e| {
+                warn!("Failed to switch usb hub port {} power: {}", port, e);
+                ActuatorError {}
+            })
+    }
+}
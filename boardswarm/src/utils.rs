@@ -1,5 +1,13 @@
+use std::io::SeekFrom;
+
+use bytes::{Bytes, BytesMut};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin};
 use tracing::warn;
 
+use crate::VolumeTarget;
+
 pub fn nusb_info_from_bus_dev(bus: u8, dev: u8) -> Option<nusb::DeviceInfo> {
     let mut devices = match nusb::list_devices() {
         Ok(d) => d,
@@ -10,3 +18,143 @@ pub fn nusb_info_from_bus_dev(bus: u8, dev: u8) -> Option<nusb::DeviceInfo> {
     };
     devices.find(|d| d.bus_number() == bus && d.device_address() == dev)
 }
+
+/// Volume target backed by a file or block device
+pub struct FileTarget {
+    file: File,
+}
+
+impl FileTarget {
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    async fn do_read(&mut self, length: u64, offset: u64) -> std::io::Result<Bytes> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = BytesMut::zeroed(length as usize);
+        let mut read = 0;
+        while read < data.len() {
+            match self.file.read(&mut data[read..]).await? {
+                0 => break,
+                r => read += r,
+            }
+        }
+        data.truncate(read);
+        Ok(data.into())
+    }
+
+    async fn do_write(&mut self, data: Bytes, offset: u64) -> std::io::Result<u64> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.write_all(&data).await?;
+        Ok(data.len() as u64)
+    }
+
+    async fn do_flush(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await
+    }
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for FileTarget {
+    async fn read(&mut self, length: u64, offset: u64, completion: crate::ReadCompletion) {
+        completion.complete(
+            self.do_read(length, offset)
+                .await
+                .map_err(|e| tonic::Status::aborted(e.to_string())),
+        );
+    }
+
+    async fn write(&mut self, data: Bytes, offset: u64, completion: crate::WriteCompletion) {
+        completion.complete(
+            self.do_write(data, offset)
+                .await
+                .map_err(|e| tonic::Status::aborted(e.to_string())),
+        );
+    }
+
+    async fn flush(&mut self, completion: crate::FlushCompletion) {
+        completion.complete(
+            self.do_flush()
+                .await
+                .map_err(|e| tonic::Status::aborted(e.to_string())),
+        );
+    }
+
+    async fn abort(&mut self) {
+        // Don't leave writes pending, e.g. for an sdmux switching the card back to the device
+        if let Err(e) = self.do_flush().await {
+            warn!("Failed to flush aborted upload: {}", e);
+        }
+    }
+}
+
+/// Write-only volume target streaming data into the stdin of a process, which should exit
+/// successfully once stdin is closed; Data has to be written sequentially
+pub struct ProcessTarget {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    written: u64,
+    /// Description of the operation for errors
+    operation: &'static str,
+}
+
+impl ProcessTarget {
+    /// The child should have its stdin piped and optionally its stderr for error reporting
+    pub fn new(mut child: Child, operation: &'static str) -> Self {
+        let stdin = child.stdin.take();
+        Self {
+            child,
+            stdin,
+            written: 0,
+            operation,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VolumeTarget for ProcessTarget {
+    async fn write(&mut self, data: Bytes, offset: u64, completion: crate::WriteCompletion) {
+        let Some(stdin) = &mut self.stdin else {
+            completion.complete(Err(tonic::Status::failed_precondition(
+                "Target was shut down",
+            )));
+            return;
+        };
+        if offset != self.written {
+            completion.complete(Err(tonic::Status::out_of_range("Invalid offset")));
+            return;
+        }
+        match stdin.write_all(&data).await {
+            Ok(()) => {
+                self.written += data.len() as u64;
+                completion.complete(Ok(data.len() as u64))
+            }
+            Err(e) => completion.complete(Err(tonic::Status::aborted(e.to_string()))),
+        }
+    }
+
+    async fn shutdown(&mut self, completion: crate::ShutdownCompletion) {
+        self.stdin.take();
+        completion.report_step(format!("{} in progress", self.operation));
+        let mut stderr = String::new();
+        if let Some(mut err) = self.child.stderr.take() {
+            let _ = err.read_to_string(&mut stderr).await;
+        }
+        match self.child.wait().await {
+            Ok(status) if status.success() => completion.complete(Ok(())),
+            Ok(_) => completion.complete(Err(tonic::Status::aborted(format!(
+                "{} failed: {}",
+                self.operation,
+                stderr.trim()
+            )))),
+            Err(e) => completion.complete(Err(tonic::Status::internal(e.to_string()))),
+        }
+    }
+
+    async fn abort(&mut self) {
+        // Kill before closing stdin, so the process doesn't act on the partial data
+        let _ = self.child.kill().await;
+        self.stdin.take();
+    }
+}
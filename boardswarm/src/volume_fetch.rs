@@ -0,0 +1,406 @@
+// Images downloaded by the server (or taken from its image cache) and written to a volume target,
+// such that clients don't have to push them through their own uplink
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use boardswarm_protocol::{ImageFormat, UploadPhase, VolumeFetchProgress};
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{
+    config, image_cache,
+    image_layout::{self, Layout, LayoutError, Placement},
+    ratelimit, upload_verify, volume_transaction, Server, ShutdownCompletion, VolumeTarget,
+    WriteCompletion,
+};
+
+/// Consecutive data is gathered up to this size before writing it to the target
+const WRITE_SIZE: usize = 1024 * 1024;
+/// Progress is sent at least this often while receiving the image
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Period the download rate is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("Fetching images isn't enabled on this server")]
+    Disabled,
+    #[error("Invalid url: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("Fetching from {0} isn't allowed")]
+    NotAllowed(String),
+    #[error("Download failed: {0}")]
+    Download(#[from] reqwest::Error),
+    #[error(transparent)]
+    Layout(#[from] LayoutError),
+    #[error("Image isn't cached")]
+    NotCached,
+    #[error("Failed to read cached image: {0}")]
+    Cache(#[from] std::io::Error),
+}
+
+impl From<FetchError> for tonic::Status {
+    fn from(e: FetchError) -> Self {
+        match e {
+            FetchError::Disabled | FetchError::NotAllowed(_) => {
+                tonic::Status::permission_denied(e.to_string())
+            }
+            FetchError::InvalidUrl(_) | FetchError::Layout(_) => {
+                tonic::Status::invalid_argument(e.to_string())
+            }
+            FetchError::Download(_) => tonic::Status::unavailable(e.to_string()),
+            FetchError::NotCached => tonic::Status::not_found(e.to_string()),
+            FetchError::Cache(_) => tonic::Status::internal(e.to_string()),
+        }
+    }
+}
+
+/// Check the url is one the server is configured to fetch from
+fn check_allowed(config: Option<&config::Fetch>, url: &str) -> Result<url::Url, FetchError> {
+    let config = config.ok_or(FetchError::Disabled)?;
+    let url = url::Url::parse(url)?;
+    if !matches!(url.scheme(), "http" | "https")
+        || !config
+            .allow
+            .iter()
+            .any(|prefix| url.as_str().starts_with(prefix))
+    {
+        return Err(FetchError::NotAllowed(url.to_string()));
+    }
+    Ok(url)
+}
+
+/// Start downloading the image; Fails if the server isn't allowed to fetch from the url
+pub async fn download(
+    config: Option<&config::Fetch>,
+    url: &str,
+) -> Result<reqwest::Response, FetchError> {
+    let url = check_allowed(config, url)?;
+    Ok(reqwest::get(url).await?.error_for_status()?)
+}
+
+/// Data of the image to write
+pub enum Source {
+    Download(reqwest::Response),
+    Cached(image_cache::Cached),
+}
+
+impl Source {
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            Source::Download(response) => response.content_length(),
+            Source::Cached(cached) => Some(cached.length),
+        }
+    }
+
+    async fn chunk(&mut self) -> Result<Option<Bytes>, FetchError> {
+        match self {
+            Source::Download(response) => Ok(response.chunk().await?),
+            Source::Cached(cached) => Ok(cached.chunk().await?),
+        }
+    }
+}
+
+/// Layout of the image on the target; For raw images with a block map the map is downloaded
+/// first
+pub async fn layout(
+    config: Option<&config::Fetch>,
+    format: ImageFormat,
+    bmap_url: Option<&str>,
+) -> Result<Box<dyn Layout>, FetchError> {
+    match (format, bmap_url) {
+        (ImageFormat::Raw, Some(url)) => {
+            let xml = download(config, url).await?.text().await?;
+            Ok(Box::new(image_layout::BlockMap::from_xml(&xml)?))
+        }
+        (ImageFormat::Raw, None) => Ok(Box::<image_layout::Raw>::default()),
+        (ImageFormat::AndroidSparse, _) => Ok(Box::new(image_layout::AndroidSparse::new())),
+    }
+}
+
+/// Writes of the image to the target, merging consecutive placements
+struct Writer<'a> {
+    io: &'a mut dyn VolumeTarget,
+    upload_limit: Option<ratelimit::RateLimit>,
+    /// Offset and data of the pending write
+    pending: Option<(u64, BytesMut)>,
+}
+
+impl Writer<'_> {
+    async fn place(
+        &mut self,
+        server: &Server,
+        (offset, data): Placement,
+    ) -> Result<u64, tonic::Status> {
+        let mut written = 0;
+        if let Some((start, pending)) = &mut self.pending {
+            if *start + pending.len() as u64 == offset && pending.len() < WRITE_SIZE {
+                pending.extend_from_slice(&data);
+                if pending.len() < WRITE_SIZE {
+                    return Ok(0);
+                }
+                return self.flush(server).await;
+            }
+            written = self.flush(server).await?;
+        }
+        self.pending = Some((offset, BytesMut::from(&data[..])));
+        Ok(written)
+    }
+
+    /// Write out the pending data, returning the number of bytes written
+    async fn flush(&mut self, server: &Server) -> Result<u64, tonic::Status> {
+        let Some((offset, data)) = self.pending.take() else {
+            return Ok(0);
+        };
+        let length = data.len() as u64;
+        if let Some(limit) = &self.upload_limit {
+            limit.acquire(length).await;
+        }
+        if let Some(limit) = &server.inner.upload_limit {
+            limit.acquire(length).await;
+        }
+        let (completion, rx) = WriteCompletion::new();
+        self.io.write(data.freeze(), offset, completion).await;
+        rx.await
+            .map_err(|_| tonic::Status::aborted("Write not completed"))??;
+        Ok(length)
+    }
+}
+
+/// Verification of the image, which gets cached once verified
+pub struct Checks {
+    pub volume: u64,
+    pub target: String,
+    pub verifier: Option<upload_verify::Verifier>,
+    pub cache: Option<image_cache::Insert>,
+}
+
+impl Checks {
+    async fn update(&mut self, offset: u64, data: &[u8]) -> Result<(), tonic::Status> {
+        if let Some(verifier) = &mut self.verifier {
+            verifier.update(offset, data)?;
+        }
+        if let Some(insert) = &mut self.cache {
+            // Failing to cache the image shouldn't fail the upload
+            if let Err(e) = insert.write(offset, data).await {
+                warn!("Failed to add image to the cache: {}", e);
+                self.cache = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(self, server: &Server) -> Result<(), tonic::Status> {
+        if let Some(verifier) = self.verifier {
+            server.finish_verification(self.volume, &self.target, verifier)?;
+            if let (Some(insert), Some(cache)) = (self.cache, &server.inner.image_cache) {
+                insert.commit(cache).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks and bookkeeping of the upload to the volume
+pub struct Upload {
+    pub checks: Checks,
+    /// Transaction the upload is part of
+    pub part: Option<volume_transaction::Part>,
+    pub cancel: CancellationToken,
+}
+
+/// Download rate over a sliding window
+#[derive(Default)]
+struct Throughput {
+    /// Time and total bytes downloaded at that time
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    /// Record the total downloaded, returning the rate in bytes per second
+    fn update(&mut self, now: Instant, total: u64) -> u64 {
+        self.samples.push_back((now, total));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        let (start, from) = self.samples[0];
+        let elapsed = now.duration_since(start).as_secs_f64();
+        if elapsed > 0.0 {
+            ((total - from) as f64 / elapsed) as u64
+        } else {
+            0
+        }
+    }
+}
+
+struct Reporter<'a> {
+    progress: &'a mpsc::Sender<Result<VolumeFetchProgress, tonic::Status>>,
+    status: VolumeFetchProgress,
+    throughput: Throughput,
+    last: Instant,
+}
+
+impl Reporter<'_> {
+    async fn send(&mut self, phase: UploadPhase) -> Result<(), tonic::Status> {
+        let now = Instant::now();
+        let status = &mut self.status;
+        status.set_phase(phase);
+        status.rate = self.throughput.update(now, status.downloaded);
+        status.eta = match (status.total, status.rate) {
+            (Some(total), rate) if rate > 0 => Some(total.saturating_sub(status.downloaded) / rate),
+            _ => None,
+        };
+        self.last = now;
+        self.progress
+            .send(Ok(status.clone()))
+            .await
+            .map_err(|_| tonic::Status::cancelled("Client went away"))
+    }
+}
+
+/// Write the downloaded image to the target, sending progress after each write and at least every
+/// second; An error ends the progress stream
+pub async fn run(
+    server: Server,
+    upload: Upload,
+    source: Source,
+    layout: Box<dyn Layout>,
+    mut io: Box<dyn VolumeTarget>,
+    progress: mpsc::Sender<Result<VolumeFetchProgress, tonic::Status>>,
+) {
+    let written = write(
+        &server,
+        source,
+        layout,
+        io.as_mut(),
+        upload.checks,
+        &progress,
+    );
+    let result = tokio::select! {
+        result = written => result,
+        () = upload.cancel.cancelled() => Err(tonic::Status::cancelled("Upload cancelled")),
+    };
+    if result.is_err() {
+        io.abort().await;
+    }
+    if let Some(part) = upload.part {
+        part.finish(&result);
+    }
+    if let Err(e) = result {
+        let _ = progress.send(Err(e)).await;
+    }
+}
+
+async fn write(
+    server: &Server,
+    mut source: Source,
+    mut layout: Box<dyn Layout>,
+    io: &mut dyn VolumeTarget,
+    mut checks: Checks,
+    progress: &mpsc::Sender<Result<VolumeFetchProgress, tonic::Status>>,
+) -> Result<(), tonic::Status> {
+    let mut writer = Writer {
+        io,
+        upload_limit: server.inner.upload_rate.map(ratelimit::RateLimit::new),
+        pending: None,
+    };
+    let mut reporter = Reporter {
+        progress,
+        status: VolumeFetchProgress {
+            total: source.length(),
+            ..Default::default()
+        },
+        throughput: Throughput::default(),
+        last: Instant::now(),
+    };
+    reporter.send(UploadPhase::Receiving).await?;
+    loop {
+        // Keep reporting while the download stalls, so the client sees the rate drop
+        let chunk = match tokio::time::timeout(PROGRESS_INTERVAL, source.chunk()).await {
+            Ok(chunk) => chunk?,
+            Err(_) => {
+                reporter.send(reporter.status.phase()).await?;
+                continue;
+            }
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        checks.update(reporter.status.downloaded, &chunk).await?;
+        reporter.status.downloaded += chunk.len() as u64;
+        let mut written = 0;
+        for placement in layout.push(chunk)? {
+            written += writer.place(server, placement).await?;
+        }
+        if written > 0 {
+            reporter.status.written += written;
+            reporter.send(UploadPhase::Writing).await?;
+        } else if reporter.last.elapsed() >= PROGRESS_INTERVAL {
+            reporter.send(UploadPhase::Receiving).await?;
+        }
+    }
+    layout.finish()?;
+    reporter.status.written += writer.flush(server).await?;
+    reporter.send(UploadPhase::Verifying).await?;
+
+    // Verify before shutting down, as that may already finalize the upload
+    checks.finish(server).await?;
+    let io = writer.io;
+    let (completion, rx, mut steps) = ShutdownCompletion::with_steps();
+    let forward = async {
+        while let Some(step) = steps.recv().await {
+            reporter.status.step = Some(step);
+            reporter.send(UploadPhase::Verifying).await?;
+        }
+        Ok::<_, tonic::Status>(())
+    };
+    let ((), forwarded) = futures::join!(io.shutdown(completion), forward);
+    rx.await
+        .map_err(|_| tonic::Status::aborted("Shutdown not completed"))??;
+    forwarded?;
+    reporter.status.step = None;
+    reporter.send(UploadPhase::Done).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn throughput() {
+        let start = Instant::now();
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.update(start, 0), 0);
+        assert_eq!(
+            throughput.update(start + Duration::from_secs(2), 2000),
+            1000
+        );
+        assert_eq!(
+            throughput.update(start + Duration::from_secs(4), 6000),
+            1500
+        );
+        // Samples older than the window no longer count, so a stall shows
+        assert_eq!(throughput.update(start + Duration::from_secs(8), 6000), 666);
+        assert_eq!(throughput.update(start + Duration::from_secs(14), 6000), 0);
+    }
+
+    #[test]
+    fn allowed() {
+        let config = config::Fetch {
+            allow: vec!["https://mirror.example.com/images/".to_string()],
+        };
+        assert!(check_allowed(Some(&config), "https://mirror.example.com/images/a.img").is_ok());
+        assert!(matches!(
+            check_allowed(Some(&config), "https://mirror.example.com/other/a.img"),
+            Err(FetchError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            check_allowed(None, "https://mirror.example.com/images/a.img"),
+            Err(FetchError::Disabled)
+        ));
+        assert!(check_allowed(Some(&config), "not a url").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+// Uploads to several targets of a volume committed together, e.g. the bootloader, boot and rootfs
+// partitions of a board; The commit is refused unless every upload of the transaction succeeded
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+#[derive(Debug, Error)]
+pub enum TransactionError {
+    #[error("Transaction not found")]
+    NotFound,
+    #[error("Transaction belongs to another volume")]
+    WrongVolume,
+    #[error("Transaction has no uploads")]
+    Empty,
+    #[error("Upload to {0} is still in progress")]
+    InProgress(String),
+    #[error("Upload to {target} failed: {reason}")]
+    Failed { target: String, reason: String },
+}
+
+impl From<TransactionError> for tonic::Status {
+    fn from(e: TransactionError) -> Self {
+        match e {
+            TransactionError::NotFound => tonic::Status::not_found(e.to_string()),
+            TransactionError::WrongVolume => tonic::Status::invalid_argument(e.to_string()),
+            _ => tonic::Status::failed_precondition(e.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum PartState {
+    InProgress,
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct Transaction {
+    volume: u64,
+    /// State of each upload by target
+    parts: Vec<(String, PartState)>,
+}
+
+type Shared = Arc<Mutex<HashMap<u64, Transaction>>>;
+
+/// Open transactions by id
+#[derive(Default)]
+pub struct Transactions {
+    next: Mutex<u64>,
+    transactions: Shared,
+}
+
+impl Transactions {
+    pub fn open(&self, volume: u64) -> u64 {
+        let mut next = self.next.lock().unwrap();
+        *next += 1;
+        self.transactions.lock().unwrap().insert(
+            *next,
+            Transaction {
+                volume,
+                parts: Vec::new(),
+            },
+        );
+        *next
+    }
+
+    /// Volume of the transaction
+    pub fn volume(&self, id: u64) -> Result<u64, TransactionError> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|t| t.volume)
+            .ok_or(TransactionError::NotFound)
+    }
+
+    /// Add an upload to the target to the transaction
+    pub fn join(&self, id: u64, volume: u64, target: &str) -> Result<Part, TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = transactions
+            .get_mut(&id)
+            .ok_or(TransactionError::NotFound)?;
+        if transaction.volume != volume {
+            return Err(TransactionError::WrongVolume);
+        }
+        transaction
+            .parts
+            .push((target.to_string(), PartState::InProgress));
+        Ok(Part {
+            transactions: self.transactions.clone(),
+            id,
+            index: transaction.parts.len() - 1,
+        })
+    }
+
+    /// End the transaction for committing the volume; Fails unless all of its uploads succeeded
+    pub fn close(&self, id: u64) -> Result<u64, TransactionError> {
+        let transaction = self
+            .transactions
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or(TransactionError::NotFound)?;
+        if transaction.parts.is_empty() {
+            return Err(TransactionError::Empty);
+        }
+        for (target, state) in transaction.parts {
+            match state {
+                PartState::Done => (),
+                PartState::InProgress => return Err(TransactionError::InProgress(target)),
+                PartState::Failed(reason) => {
+                    return Err(TransactionError::Failed { target, reason })
+                }
+            }
+        }
+        Ok(transaction.volume)
+    }
+
+    pub fn abort(&self, id: u64) -> Result<(), TransactionError> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(TransactionError::NotFound)
+    }
+
+    /// Drop the transactions of a removed volume
+    pub fn remove_volume(&self, volume: u64) {
+        self.transactions
+            .lock()
+            .unwrap()
+            .retain(|_, t| t.volume != volume);
+    }
+}
+
+/// A single upload of a transaction; It's failed if any of its operations fail or if it ends
+/// without a successful shutdown
+#[derive(Clone)]
+pub struct Part {
+    transactions: Shared,
+    id: u64,
+    index: usize,
+}
+
+impl Part {
+    fn set(&self, state: PartState) {
+        if let Some(transaction) = self.transactions.lock().unwrap().get_mut(&self.id) {
+            let (_, current) = &mut transaction.parts[self.index];
+            // Failures stick, even if reported after the upload finished
+            if !matches!(current, PartState::Failed(_)) {
+                *current = state;
+            }
+        }
+    }
+
+    /// Follow the result of an operation, failing the part if the operation fails
+    pub fn track<T: Send + 'static>(
+        &self,
+        rx: oneshot::Receiver<Result<T, tonic::Status>>,
+    ) -> oneshot::Receiver<Result<T, tonic::Status>> {
+        let (tx, forward) = oneshot::channel();
+        let part = self.clone();
+        tokio::spawn(async move {
+            let result = rx
+                .await
+                .unwrap_or_else(|_| Err(tonic::Status::internal("Operation got dropped")));
+            if let Err(e) = &result {
+                part.set(PartState::Failed(e.message().to_string()));
+            }
+            let _ = tx.send(result);
+        });
+        forward
+    }
+
+    /// Record the result of the upload
+    pub fn finish<T>(&self, result: &Result<T, tonic::Status>) {
+        match result {
+            Ok(_) => self.set(PartState::Done),
+            Err(e) => self.set(PartState::Failed(e.message().to_string())),
+        }
+    }
+
+    /// Fail the part if it didn't finish
+    pub fn end(&self) {
+        if let Some(transaction) = self.transactions.lock().unwrap().get_mut(&self.id) {
+            let (_, current) = &mut transaction.parts[self.index];
+            if matches!(current, PartState::InProgress) {
+                *current = PartState::Failed("Upload ended before shutdown".to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commit() {
+        let transactions = Transactions::default();
+        let id = transactions.open(1);
+        assert!(matches!(
+            transactions.join(id, 2, "boot"),
+            Err(TransactionError::WrongVolume)
+        ));
+        let boot = transactions.join(id, 1, "boot").unwrap();
+        let rootfs = transactions.join(id, 1, "rootfs").unwrap();
+        boot.finish(&Ok::<_, tonic::Status>(()));
+        rootfs.end();
+        assert!(matches!(
+            transactions.close(id),
+            Err(TransactionError::Failed { target, .. }) if target == "rootfs"
+        ));
+        assert!(matches!(
+            transactions.close(id),
+            Err(TransactionError::NotFound)
+        ));
+
+        let id = transactions.open(1);
+        assert!(matches!(
+            transactions.close(id),
+            Err(TransactionError::Empty)
+        ));
+        let id = transactions.open(1);
+        transactions
+            .join(id, 1, "boot")
+            .unwrap()
+            .finish(&Ok::<_, tonic::Status>(()));
+        assert_eq!(transactions.close(id).unwrap(), 1);
+    }
+}
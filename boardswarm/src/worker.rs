@@ -0,0 +1,38 @@
+use tokio::sync::Semaphore;
+
+/// Runs heavy provider work (e.g. decompressing uploads) on the blocking thread pool; The number
+/// of jobs running at once is limited so upload bursts can't starve the async runtime serving
+/// interactive consoles
+#[derive(Debug)]
+pub struct Workers {
+    jobs: Semaphore,
+}
+
+impl Workers {
+    pub fn new(jobs: usize) -> Self {
+        Self {
+            jobs: Semaphore::new(jobs.max(1)),
+        }
+    }
+
+    /// Number of jobs to run at once if not configured; Leaves a cpu for the async runtime
+    pub fn default_jobs() -> usize {
+        std::thread::available_parallelism()
+            .map(|n| n.get().saturating_sub(1))
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// Run `f` once a job slot is available
+    pub async fn run<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.jobs.acquire().await.unwrap();
+        match tokio::task::spawn_blocking(f).await {
+            Ok(r) => r,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Context};
+use futures::StreamExt;
+use nusb::transfer::RequestBuffer;
+use serde::Deserialize;
+use tracing::{debug, instrument, warn};
+
+use crate::{registry, udev::DeviceEvent, ActuatorError, Server};
+
+pub const PROVIDER: &str = "ykush";
+
+const YEPKIT_VENDOR: u16 = 0x04d8;
+
+/// HID report size of the boards
+const REPORT_SIZE: usize = 64;
+const INTERRUPT_OUT: u8 = 0x01;
+const INTERRUPT_IN: u8 = 0x81;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Command selector for all downstream ports
+const ALL_PORTS: u8 = 0x0a;
+
+/// Boards with their number of switchable downstream ports
+fn model(product: u16) -> Option<(&'static str, u8)> {
+    match product {
+        0x0042 | 0xf2f7 => Some(("ykush", 3)),
+        0xf11b => Some(("ykush3", 3)),
+        0xf0cd => Some(("ykushxs", 1)),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Board {
+    name: String,
+    /// USB serial number of the board
+    serial: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct YkushParameters {
+    boards: Vec<Board>,
+}
+
+#[instrument(skip(parameters, server))]
+pub async fn start_provider(name: String, parameters: serde_yaml::Value, server: Server) {
+    let parameters: YkushParameters = serde_yaml::from_value(parameters).unwrap();
+    let provider_properties = &[
+        (registry::PROVIDER_NAME, name.as_str()),
+        (registry::PROVIDER, PROVIDER),
+    ];
+
+    let mut registrations: HashMap<PathBuf, u64> = HashMap::new();
+    let mut devices = crate::udev::DeviceStream::new("usb").unwrap();
+    while let Some(d) = devices.next().await {
+        match d {
+            DeviceEvent::Add { device, .. } => {
+                if device.devnode().is_none()
+                    || device.property_u64("ID_VENDOR_ID", 16) != Some(YEPKIT_VENDOR.into())
+                {
+                    continue;
+                }
+                let Some((model, ports)) = device
+                    .property_u64("ID_MODEL_ID", 16)
+                    .and_then(|v| v.try_into().ok())
+                    .and_then(model)
+                else {
+                    continue;
+                };
+                let Some(board) = device
+                    .property("ID_SERIAL_SHORT")
+                    .and_then(|serial| parameters.boards.iter().find(|b| b.serial == serial))
+                else {
+                    continue;
+                };
+                let (Some(bus), Some(dev)) = (
+                    device
+                        .property_u64("BUSNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                    device
+                        .property_u64("DEVNUM", 10)
+                        .and_then(|v| v.try_into().ok()),
+                ) else {
+                    continue;
+                };
+                debug!("Found {} {} at {}/{}", model, board.name, bus, dev);
+
+                let mut properties = device.properties(&board.name);
+                properties.extend(provider_properties);
+                properties.insert(format!("{PROVIDER}.model"), model);
+                properties.insert(format!("{PROVIDER}.ports"), ports.to_string());
+                let id = server.register_actuator(properties, Ykush { bus, dev, ports });
+                registrations.insert(device.syspath().to_path_buf(), id);
+            }
+            DeviceEvent::Remove(device) => {
+                if let Some(id) = registrations.remove(device.syspath()) {
+                    server.unregister_actuator(id)
+                }
+            }
+            DeviceEvent::Change(_) => (),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ykush {
+    bus: u8,
+    dev: u8,
+    ports: u8,
+}
+
+impl Ykush {
+    /// Command byte switching a port (or all ports); The upper nibble selects on
+    fn command(port: Option<u8>, on: bool) -> u8 {
+        let port = port.unwrap_or(ALL_PORTS);
+        if on {
+            0x10 | port
+        } else {
+            port
+        }
+    }
+
+    async fn send(&self, command: u8) -> anyhow::Result<()> {
+        let Some(info) = crate::utils::nusb_info_from_bus_dev(self.bus, self.dev) else {
+            bail!("Board not found");
+        };
+        let interface = info.open()?.detach_and_claim_interface(0)?;
+
+        let mut report = vec![0; REPORT_SIZE];
+        report[0] = command;
+        tokio::time::timeout(TIMEOUT, interface.interrupt_out(INTERRUPT_OUT, report))
+            .await
+            .context("Timeout sending command")?
+            .into_result()?;
+        let response = tokio::time::timeout(
+            TIMEOUT,
+            interface.interrupt_in(INTERRUPT_IN, RequestBuffer::new(REPORT_SIZE)),
+        )
+        .await
+        .context("Timeout waiting for response")?
+        .into_result()?;
+        // Success is reported as 0x01 by current firmware while older boards echo the command
+        match response.first() {
+            Some(&r) if r == 0x01 || r == command => (),
+            r => bail!("Unexpected response: {:x?}", r),
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Actuator for Ykush {
+    async fn set_mode(
+        &self,
+        parameters: Box<dyn erased_serde::Deserializer<'static> + Send>,
+    ) -> Result<(), ActuatorError> {
+        #[derive(Deserialize)]
+        struct ModeParameters {
+            /// Port to switch; All ports when not set
+            port: Option<u8>,
+            mode: String,
+        }
+        let parameters = ModeParameters::deserialize(parameters).map_err(|_e| ActuatorError {})?;
+        if let Some(port) = parameters.port {
+            if port == 0 || port > self.ports {
+                warn!("Invalid ykush port: {}", port);
+                return Err(ActuatorError {});
+            }
+        }
+        let on = match parameters.mode.as_str() {
+            "on" => true,
+            "off" => false,
+            mode => {
+                warn!("Unsupported ykush mode: {}", mode);
+                return Err(ActuatorError {});
+            }
+        };
+        self.send(Self::command(parameters.port, on))
+            .await
+            .map_err(|e| {
+                warn!("Failed to switch ykush port: {}", e);
+                ActuatorError {}
+            })
+    }
+}